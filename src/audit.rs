@@ -0,0 +1,122 @@
+use crate::daemon;
+use crate::elf::Elf;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+/// A security issue of a rootfs entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    WorldWritable,
+    Setuid,
+    Setgid,
+    /// The owner is not a user of /etc/passwd.
+    Owner(u32),
+    /// The executable is not position independent.
+    NoPie,
+    NoRelro,
+    /// The symbols are resolved lazily: the GOT is writable.
+    PartialRelro,
+    NoStackProtector,
+}
+
+impl Issue {
+    pub fn name(&self) -> String {
+        match self {
+            Self::WorldWritable => "world-writable".into(),
+            Self::Setuid => "setuid".into(),
+            Self::Setgid => "setgid".into(),
+            Self::Owner(uid) => format!("owned by unknown uid {uid}"),
+            Self::NoPie => "no PIE".into(),
+            Self::NoRelro => "no RELRO".into(),
+            Self::PartialRelro => "partial RELRO".into(),
+            Self::NoStackProtector => "no stack protector".into(),
+        }
+    }
+}
+
+/// Return every entry of the rootfs, except the install journal.
+pub fn walk(rootfs: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    let mut dirs = vec![rootfs.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match dir.read_dir_utf8() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path().to_path_buf();
+            if path == rootfs.join(crate::journal::JOURNAL_DIR) {
+                continue;
+            }
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                dirs.push(path.clone());
+            }
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+}
+
+/// Check the permissions, owners and ELF hardening of the rootfs entries.
+pub fn audit(rootfs: &Path, paths: &[PathBuf]) -> Vec<(PathBuf, Issue)> {
+    let passwd = std::fs::read_to_string(rootfs.join("etc/passwd")).unwrap_or_default();
+    let known_uid = |uid: u32| {
+        uid == 0
+            || passwd
+                .lines()
+                .filter_map(|line| line.split(':').next())
+                .any(|user| daemon::find_id(&passwd, user) == Some(uid))
+    };
+
+    let mut issues = vec![];
+    for path in paths {
+        let metadata = match path.symlink_metadata() {
+            Ok(x) if !x.is_symlink() => x,
+            _ => continue,
+        };
+        let image_path = PathBuf::from("/").join(path.strip_prefix(rootfs).unwrap_or(path));
+        let mut report = |issue| issues.push((image_path.clone(), issue));
+        let mode = metadata.permissions().mode();
+
+        // The sticky directories, like /tmp, are expected to be world-writable.
+        if mode & 0o002 != 0 && !(metadata.is_dir() && mode & 0o1000 != 0) {
+            report(Issue::WorldWritable);
+        }
+        if mode & 0o4000 != 0 && !metadata.is_dir() {
+            report(Issue::Setuid);
+        }
+        if mode & 0o2000 != 0 && !metadata.is_dir() {
+            report(Issue::Setgid);
+        }
+        if !known_uid(metadata.uid()) {
+            report(Issue::Owner(metadata.uid()));
+        }
+
+        if !metadata.is_file() || mode & 0o111 == 0 {
+            continue;
+        }
+        let elf = match Elf::parse(path) {
+            Some(x) => x,
+            None => continue,
+        };
+        if elf.is_fixed_executable() {
+            report(Issue::NoPie);
+        }
+        if elf.is_static() {
+            continue;
+        }
+        if !elf.has_relro() {
+            report(Issue::NoRelro);
+        } else if !elf.binds_now() {
+            report(Issue::PartialRelro);
+        }
+        // Rust code is memory safe and does not use the stack protector.
+        let stack_chk = elf.has_dynamic_string("__stack_chk_fail");
+        if stack_chk == Some(false) && !elf.is_rust() {
+            report(Issue::NoStackProtector);
+        }
+    }
+    issues
+}
@@ -0,0 +1,279 @@
+use crate::exec::run;
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::fs::Permissions;
+use std::io::BufRead;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const EXIT_MARKER: &str = "CARGO_ROOTFS_EXIT=";
+const INIT_SCRIPT: &str = "cargo-rootfs-boot.sh";
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum Image {
+    #[default]
+    Initramfs,
+    Ext4,
+}
+
+impl Image {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "initramfs" => Self::Initramfs,
+            "ext4" => Self::Ext4,
+            other => panic!("Unknown boot image {other:?} (expected initramfs or ext4)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootOptions {
+    pub kernel: PathBuf,
+    pub dtb: Option<PathBuf>,
+    pub image: Image,
+    pub command: Option<String>,
+    pub timeout: Duration,
+}
+
+/// qemu-system defaults for an architecture: (machine arguments, console).
+fn machine(arch: &str) -> (Vec<&'static str>, &'static str) {
+    match arch {
+        "x86_64" => (vec!["-machine", "q35"], "ttyS0"),
+        "i386" => (vec!["-machine", "pc"], "ttyS0"),
+        "aarch64" => (vec!["-machine", "virt", "-cpu", "cortex-a57"], "ttyAMA0"),
+        "arm" => (vec!["-machine", "virt"], "ttyAMA0"),
+        "riscv64" | "riscv32" => (vec!["-machine", "virt"], "ttyS0"),
+        "ppc64" | "ppc64le" => (vec!["-machine", "pseries"], "hvc0"),
+        "mips" | "mipsel" | "mips64" | "mips64el" => (vec!["-machine", "malta"], "ttyS0"),
+        other => panic!("No qemu-system machine defaults for {other}"),
+    }
+}
+
+fn init_script(command: &str) -> String {
+    format!(
+        "#!/bin/sh\n\
+         mount -t proc proc /proc 2>/dev/null\n\
+         mount -t sysfs sysfs /sys 2>/dev/null\n\
+         mount -t devtmpfs devtmpfs /dev 2>/dev/null\n\
+         {command}\n\
+         echo \"{EXIT_MARKER}$?\"\n\
+         poweroff -f || reboot -f || echo o > /proc/sysrq-trigger\n"
+    )
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    let mut size = 0;
+    for entry in dir.read_dir_utf8().unwrap().flatten() {
+        let metadata = entry.path().symlink_metadata().unwrap();
+        if metadata.is_dir() {
+            size += dir_size(entry.path());
+        } else {
+            // Count the allocated blocks, not the apparent size of the sparse files.
+            size += (metadata.blocks() * 512).min(metadata.len());
+        }
+    }
+    size
+}
+
+fn count_entries(dir: &Path) -> u64 {
+    let mut count = 0;
+    for entry in dir.read_dir_utf8().unwrap().flatten() {
+        count += 1;
+        if entry.path().symlink_metadata().unwrap().is_dir() {
+            count += count_entries(entry.path());
+        }
+    }
+    count
+}
+
+/// Append the directory to a cpio archive, following its progress with the
+/// file names listed by `cpio -v`.
+fn cpio(dir: &Path, output: &Path) {
+    let script = format!("cd '{dir}' && find . | cpio -v -o -H newc -R 0:0 >> '{output}'");
+    message::status(format_args!("{script}"));
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(script)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to run cpio: {e:?}"));
+
+    let total = count_entries(dir) + 1;
+    let mut progress = message::Progress::new(output.file_name().unwrap(), total, 0);
+    for line in std::io::BufReader::new(child.stderr.take().unwrap()).lines() {
+        let line = line.unwrap_or_default();
+        if line.starts_with('.') {
+            progress.add(1, 0);
+        } else if !line.ends_with(" blocks") {
+            eprintln!("{line}");
+        }
+    }
+    progress.finish();
+    let status = child.wait().unwrap();
+    if !status.success() {
+        panic!("cpio failed: {status}");
+    }
+}
+
+/// Create an initramfs from the rootfs, appending the boot script when a command is specified.
+pub fn make_initramfs(rootfs: &Path, outdir: &Path, command: Option<&str>) -> PathBuf {
+    let image = outdir.join("initramfs.cpio");
+    let _ = std::fs::remove_file(&image);
+    cpio(rootfs, &image);
+
+    if let Some(command) = command {
+        // The kernel accepts concatenated cpio archives: the boot script is
+        // appended in a second archive to leave the rootfs untouched.
+        let overlay = outdir.join("overlay");
+        let _ = std::fs::remove_dir_all(&overlay);
+        std::fs::create_dir_all(&overlay).unwrap();
+        let script = overlay.join(INIT_SCRIPT);
+        std::fs::write(&script, init_script(command)).unwrap();
+        std::fs::set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+        cpio(&overlay, &image);
+    }
+    image
+}
+
+/// Create an ext4 image from the rootfs, writing the boot script with debugfs when a command is specified.
+pub fn make_ext4(rootfs: &Path, outdir: &Path, command: Option<&str>) -> PathBuf {
+    let image = outdir.join("rootfs.ext4");
+    let _ = std::fs::remove_file(&image);
+    let size_kib = (dir_size(rootfs) * 3 / 2) / 1024 + 16 * 1024;
+    message::status(format_args!(
+        "mke2fs -t ext4 -d {:#?} {:#?} {size_kib}k",
+        rootfs, image
+    ));
+    run(Command::new("mke2fs")
+        .arg("-q")
+        .arg("-t")
+        .arg("ext4")
+        .arg("-E")
+        .arg("root_owner=0:0")
+        .arg("-d")
+        .arg(rootfs)
+        .arg(&image)
+        .arg(format!("{size_kib}k")));
+
+    if let Some(command) = command {
+        let script = outdir.join(INIT_SCRIPT);
+        std::fs::write(&script, init_script(command)).unwrap();
+        for request in [
+            format!("write {script} {INIT_SCRIPT}"),
+            format!("sif {INIT_SCRIPT} mode 0100755"),
+        ] {
+            message::status(format_args!("debugfs -w -R {:#?} {:#?}", request, image));
+            run(Command::new("debugfs")
+                .arg("-w")
+                .arg("-R")
+                .arg(request)
+                .arg(&image)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()));
+        }
+    }
+    image
+}
+
+/// Wait for the exit status printed by the boot script on the serial console.
+fn wait_exit_status(qemu: &mut std::process::Child, timeout: Duration) -> i32 {
+    let stdout = qemu.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(line) => {
+                message::status(format_args!("{line}"));
+                if let Some(code) = line.trim().strip_prefix(EXIT_MARKER) {
+                    break code.parse().unwrap_or(1);
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                let _ = qemu.kill();
+                panic!("Boot test timed out after {}s", timeout.as_secs());
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                panic!("qemu exited before the boot command completed");
+            }
+        }
+    };
+    let _ = qemu.kill();
+    let _ = qemu.wait();
+    status
+}
+
+/// Boot the rootfs with qemu-system and return the exit status of the boot command,
+/// if any was specified.
+pub fn boot(rootfs: &Path, outdir: &Path, target: Option<&str>, options: &BootOptions) -> i32 {
+    let arch = crate::exec::qemu_arch(target.unwrap_or(std::env::consts::ARCH));
+    let (machine_args, console) = machine(&arch);
+    std::fs::create_dir_all(outdir).unwrap();
+
+    let command = options.command.as_deref();
+    let mut qemu = Command::new(format!("qemu-system-{arch}"));
+    qemu.args(machine_args)
+        .arg("-m")
+        .arg("512")
+        .arg("-nographic")
+        .arg("-no-reboot")
+        .arg("-kernel")
+        .arg(&options.kernel);
+    if let Some(dtb) = &options.dtb {
+        qemu.arg("-dtb").arg(dtb);
+    }
+
+    let mut cmdline = format!("console={console} panic=-1");
+    match options.image {
+        Image::Initramfs => {
+            let image = make_initramfs(rootfs, outdir, command);
+            qemu.arg("-initrd").arg(image);
+            if command.is_some() {
+                cmdline += &format!(" rdinit=/{INIT_SCRIPT}");
+            }
+        }
+        Image::Ext4 => {
+            let image = make_ext4(rootfs, outdir, command);
+            qemu.arg("-drive")
+                .arg(format!("file={image},format=raw,if=virtio"));
+            cmdline += " root=/dev/vda rw";
+            if command.is_some() {
+                cmdline += &format!(" init=/{INIT_SCRIPT}");
+            }
+        }
+    }
+    qemu.arg("-append").arg(&cmdline);
+    message::status(format_args!(
+        "qemu-system-{arch} -kernel {:#?} -append {:#?}",
+        options.kernel, cmdline
+    ));
+
+    if command.is_none() {
+        let status = qemu
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run qemu-system-{arch}: {e:?}"));
+        return status.code().unwrap_or(1);
+    }
+
+    let mut child = qemu
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to run qemu-system-{arch}: {e:?}"));
+    wait_exit_status(&mut child, options.timeout)
+}
@@ -0,0 +1,134 @@
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+
+/// Name of the configuration file of a workspace, in its `.cargo` directory.
+pub const WORKSPACE_CONFIG: &str = ".cargo/rootfs.toml";
+
+/// Defaults of the options, read from the configuration files and the environment.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub dest: Option<PathBuf>,
+    pub target: Option<String>,
+    pub altsrc: Option<PathBuf>,
+    pub strip: Option<String>,
+    pub message_format: Option<String>,
+}
+
+impl Config {
+    /// Set a key of a configuration file, returning false for the unknown keys.
+    fn set(&mut self, key: &str, value: String) -> bool {
+        match key.replace('-', "_").as_str() {
+            "dest" => self.dest = Some(value.into()),
+            "target" => self.target = Some(value),
+            "altsrc" => self.altsrc = Some(value.into()),
+            "strip" => self.strip = Some(value),
+            "message_format" => self.message_format = Some(value),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Read the `key = "value"` lines of a configuration file, if it exists.
+    fn read(&mut self, file: &Path) {
+        let content = match std::fs::read_to_string(file) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| -> ! { panic!("{file}:{}: {message}", number + 1) };
+            let (key, value) = line
+                .split_once('=')
+                .unwrap_or_else(|| error("expected key = \"value\""));
+            let value = parse_string(value.trim()).unwrap_or_else(|| error("expected a string"));
+            let key = key.trim().trim_matches('"');
+            if !self.set(key, value) {
+                error(&format!("unknown key {key}"));
+            }
+        }
+    }
+}
+
+/// Parse a TOML basic ("...") or literal ('...') string, followed by an optional comment.
+pub fn parse_string(value: &str) -> Option<String> {
+    let quote = value.chars().next()?;
+    let mut chars = value[1..].chars();
+    let mut string = String::new();
+    match quote {
+        '\'' => string = chars.by_ref().take_while(|c| *c != '\'').collect(),
+        '"' => loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => match chars.next()? {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    c @ ('"' | '\\') => string.push(c),
+                    _ => return None,
+                },
+                c => string.push(c),
+            }
+        },
+        _ => return None,
+    }
+    let rest = chars.as_str().trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some(string)
+}
+
+/// Load the defaults: the environment variables `CARGO_ROOTFS_<KEY>` take precedence over
+/// the `.cargo/rootfs.toml` of the current directory or of its parents, which takes
+/// precedence over `~/.config/cargo-rootfs/config.toml`.
+pub fn load() -> Config {
+    let mut config = Config::default();
+    let home = std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Ok(dir) = home {
+        config.read(&dir.join("cargo-rootfs/config.toml"));
+    }
+
+    let cwd = std::env::current_dir()
+        .ok()
+        .and_then(|x| PathBuf::try_from(x).ok());
+    let workspace = cwd.as_deref().and_then(|cwd| {
+        cwd.ancestors()
+            .map(|dir| dir.join(WORKSPACE_CONFIG))
+            .find(|file| file.is_file())
+    });
+    if let Some(file) = workspace {
+        config.read(&file);
+    }
+
+    for key in ["dest", "target", "altsrc", "strip", "message_format"] {
+        let name = format!("CARGO_ROOTFS_{}", key.to_uppercase());
+        if let Ok(value) = std::env::var(name) {
+            config.set(key, value);
+        }
+    }
+    config
+}
+
+/// Return the `build.target` of the cargo configuration, the first one of a list, as
+/// merged by cargo from the `.cargo/config.toml` files: `cargo config get` is unstable,
+/// enabled by `RUSTC_BOOTSTRAP=1`.
+pub fn cargo_build_target() -> Option<String> {
+    let cargo = std::env::var("CARGO").unwrap_or("cargo".into());
+    let output = std::process::Command::new(&cargo)
+        .args(["config", "get", "build.target", "--format", "json-value"])
+        .arg("-Zunstable-options")
+        .env("RUSTC_BOOTSTRAP", "1")
+        .stderr(std::process::Stdio::null())
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run {cargo}: {e:?}"));
+    // Fails when build.target is not set.
+    if !output.status.success() {
+        return None;
+    }
+    match serde_json::from_slice(&output.stdout).ok()? {
+        serde_json::Value::String(target) => Some(target),
+        serde_json::Value::Array(targets) => targets.first()?.as_str().map(String::from),
+        _ => None,
+    }
+}
@@ -0,0 +1,184 @@
+use crate::shell_quote;
+use camino::Utf8PathBuf as PathBuf;
+use serde::Deserialize;
+
+/// First and last ids of the system users created for the daemons.
+const FIRST_ID: u32 = 100;
+const LAST_ID: u32 = 999;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    #[default]
+    No,
+    OnFailure,
+    Always,
+}
+
+impl Restart {
+    fn name(&self) -> &str {
+        match self {
+            Self::No => "no",
+            Self::OnFailure => "on-failure",
+            Self::Always => "always",
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Init {
+    #[default]
+    Sysv,
+    Systemd,
+}
+
+/// A service, expanded into its binary, init script or systemd unit, user and directories.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Daemon {
+    pub name: String,
+    /// Binary in /usr/bin, named after the daemon by default.
+    pub bin: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// System user running the daemon, created if needed. The daemon runs as root by default.
+    pub user: Option<String>,
+    /// Directories owned by the user, such as the state and log directories.
+    #[serde(default)]
+    pub dirs: Vec<PathBuf>,
+    #[serde(default)]
+    pub restart: Restart,
+    #[serde(default)]
+    pub init: Init,
+    pub start: Option<u32>,
+    pub stop: Option<u32>,
+}
+
+/// Return the id of an entry of /etc/passwd or /etc/group.
+pub fn find_id(content: &str, name: &str) -> Option<u32> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        match fields.next() == Some(name) {
+            true => fields.nth(1).and_then(|id| id.parse().ok()),
+            false => None,
+        }
+    })
+}
+
+/// Return the first system id free in both /etc/passwd and /etc/group.
+pub fn free_id(passwd: &str, group: &str) -> u32 {
+    let used = |content: &str, id: u32| {
+        content
+            .lines()
+            .any(|line| line.split(':').nth(2) == Some(id.to_string().as_str()))
+    };
+    (FIRST_ID..=LAST_ID)
+        .find(|id| !used(passwd, *id) && !used(group, *id))
+        .unwrap_or_else(|| panic!("No free system id in {FIRST_ID}..{LAST_ID}"))
+}
+
+impl Daemon {
+    pub fn bin(&self) -> &str {
+        self.bin.as_deref().unwrap_or(&self.name)
+    }
+
+    fn path(&self) -> String {
+        shell_quote(&format!("/usr/bin/{}", self.bin()))
+    }
+
+    fn args(&self) -> String {
+        self.args
+            .iter()
+            .map(|arg| format!(" {}", shell_quote(arg)))
+            .collect()
+    }
+
+    pub fn passwd_line(&self, user: &str, uid: u32, gid: u32) -> String {
+        let home = match self.dirs.first() {
+            Some(dir) => dir.as_str(),
+            None => "/nonexistent",
+        };
+        format!("{user}:x:{uid}:{gid}:{}:{home}:/sbin/nologin", self.name)
+    }
+
+    /// Generate the init script, starting the daemon with start-stop-daemon. With a restart
+    /// policy, the daemon is run by a supervisor loop of the script.
+    pub fn init_script(&self) -> String {
+        let name = &self.name;
+        let (path, args) = (self.path(), self.args());
+        let chuid = match &self.user {
+            Some(user) => format!(" -c {}", shell_quote(user)),
+            None => String::new(),
+        };
+        let mut dirs = String::new();
+        for dir in &self.dirs {
+            dirs += &format!("    mkdir -p {}\n", shell_quote(dir.as_str()));
+            if let Some(user) = &self.user {
+                let owner = shell_quote(&format!("{user}:{user}"));
+                dirs += &format!("    chown {owner} {}\n", shell_quote(dir.as_str()));
+            }
+        }
+        let start = match self.restart {
+            Restart::No => format!("-m -p \"$PIDFILE\"{chuid} -x {path} --{args}"),
+            _ => "-m -p \"$PIDFILE\" -x /bin/sh -- \"$0\" supervise".into(),
+        };
+        let until = match self.restart {
+            Restart::OnFailure => "            [ $? -eq 0 ] && break\n",
+            _ => "",
+        };
+        format!(
+            "#!/bin/sh
+# Init script of {name}, generated by cargo-rootfs.
+PIDFILE=/var/run/{name}.pid
+
+start() {{
+{dirs}    start-stop-daemon -S -b {start}
+}}
+
+stop() {{
+    start-stop-daemon -K -p \"$PIDFILE\"
+    start-stop-daemon -K -x {path}
+    rm -f \"$PIDFILE\"
+}}
+
+case \"$1\" in
+    start) start ;;
+    stop) stop ;;
+    restart) stop; sleep 1; start ;;
+    supervise)
+        while true; do
+            start-stop-daemon -S{chuid} -x {path} --{args}
+{until}            sleep 1
+        done
+        ;;
+    *) echo \"Usage: $0 {{start|stop|restart}}\"; exit 1 ;;
+esac
+"
+        )
+    }
+
+    /// Generate the systemd service unit.
+    pub fn unit(&self) -> String {
+        let mut unit = format!(
+            "[Unit]\nDescription={}\nAfter=network.target\n\n[Service]\nExecStart=/usr/bin/{}{}\nRestart={}\n",
+            self.name,
+            self.bin(),
+            self.args(),
+            self.restart.name()
+        );
+        if let Some(user) = &self.user {
+            unit += &format!("User={user}\nGroup={user}\n");
+        }
+        unit + "\n[Install]\nWantedBy=multi-user.target\n"
+    }
+
+    /// Generate the systemd-tmpfiles configuration creating the directories.
+    pub fn tmpfiles(&self) -> String {
+        let user = self.user.as_deref().unwrap_or("root");
+        self.dirs
+            .iter()
+            .map(|dir| format!("d {dir} 0755 {user} {user} -\n"))
+            .collect()
+    }
+}
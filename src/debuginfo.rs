@@ -0,0 +1,164 @@
+use crate::elf::Elf;
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use colored::Colorize;
+use serde_json::json;
+
+/// Directory of the build-id tree of the debug files, looked up by gdb.
+pub const BUILD_ID_DIR: &str = "usr/lib/debug/.build-id";
+
+/// Compression of the debug sections of the installed binaries.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    Zlib,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "zlib" => Self::Zlib,
+            "zstd" => Self::Zstd,
+            _ => panic!("Unknown debug compression {name:?} (expected zlib or zstd)"),
+        }
+    }
+
+    /// The objcopy option compressing the debug sections.
+    pub fn option(&self) -> &'static str {
+        match self {
+            Self::Zlib => "--compress-debug-sections=zlib",
+            Self::Zstd => "--compress-debug-sections=zstd",
+        }
+    }
+}
+
+/// Return the objcopy program: OBJCOPY, or the objcopy of the toolchain of the strip
+/// program, or llvm-objcopy when it is not installed.
+pub fn objcopy() -> String {
+    if let Ok(program) = std::env::var("OBJCOPY") {
+        return program;
+    }
+    let strip = std::env::var("STRIP").unwrap_or("strip".into());
+    let program = match strip.strip_suffix("strip") {
+        Some(prefix) => format!("{prefix}objcopy"),
+        None => "objcopy".into(),
+    };
+    if crate::exec::find_program(&program).is_none()
+        && crate::exec::find_program("llvm-objcopy").is_some()
+    {
+        return "llvm-objcopy".into();
+    }
+    program
+}
+
+/// Return the debug file of a build-id in a build-id tree: `xx/yyyy.debug`.
+pub fn debug_file(dir: &Path, build_id: &str) -> PathBuf {
+    let (prefix, rest) = build_id.split_at(2);
+    dir.join(BUILD_ID_DIR)
+        .join(prefix)
+        .join(format!("{rest}.debug"))
+}
+
+/// Save the debug information of an ELF file in the build-id tree of a directory, before
+/// it is stripped, and return its build-id. A file without build-id is only warned about.
+pub fn split(file: &Path, dir: &Path) -> Option<String> {
+    let build_id = match Elf::parse(file).and_then(|elf| elf.build_id()) {
+        Some(x) if x.len() > 2 => x,
+        _ => {
+            message::status(format_args!(
+                "{} {file} has no build-id: its debug information is not kept (link with -Wl,--build-id)",
+                "Warning:".yellow().bold()
+            ));
+            return None;
+        }
+    };
+    // The same build-id is the same binary, already split.
+    let debug = debug_file(dir, &build_id);
+    if debug.exists() {
+        return Some(build_id);
+    }
+    let parent = debug.parent().unwrap();
+    std::fs::create_dir_all(parent)
+        .unwrap_or_else(|e| panic!("Failed to create directory {parent}: {e:?}"));
+    let tmp = debug.with_extension(format!("debug.{}", std::process::id()));
+    let program = objcopy();
+    message::operation(
+        format_args!("{program} --only-keep-debug {file} {debug}"),
+        "split-debug",
+        json!({"file": file, "debug": debug, "build_id": build_id}),
+    );
+    let status = std::process::Command::new(&program)
+        .arg("--only-keep-debug")
+        .arg(file)
+        .arg(&tmp)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+    if !status.success() {
+        let _ = std::fs::remove_file(&tmp);
+        panic!("{program} --only-keep-debug {file} failed: {status}");
+    }
+    std::fs::rename(&tmp, &debug)
+        .unwrap_or_else(|e| panic!("Failed to rename {tmp} to {debug}: {e:?}"));
+    Some(build_id)
+}
+
+/// Export a stripped ELF file and its debug file in the layout of a debuginfod server,
+/// `buildid/<ID>/executable` and `buildid/<ID>/debuginfo`, served as is over HTTP.
+pub fn export(file: &Path, debug: &Path, build_id: &str, dir: &Path) {
+    let dst = dir.join("buildid").join(build_id);
+    std::fs::create_dir_all(&dst)
+        .unwrap_or_else(|e| panic!("Failed to create directory {dst}: {e:?}"));
+    message::operation(
+        format_args!("cp {file} {debug} {dst}"),
+        "debuginfod",
+        json!({"file": file, "build_id": build_id, "dir": dst}),
+    );
+    let executable = dst.join("executable");
+    std::fs::copy(file, &executable)
+        .unwrap_or_else(|e| panic!("Failed to copy {file} to {executable}: {e:?}"));
+    // The debug files are never rewritten: they are hard linked when possible.
+    let debuginfo = dst.join("debuginfo");
+    let _ = std::fs::remove_file(&debuginfo);
+    std::fs::hard_link(debug, &debuginfo)
+        .or_else(|_| std::fs::copy(debug, &debuginfo).map(|_| ()))
+        .unwrap_or_else(|e| panic!("Failed to copy {debug} to {debuginfo}: {e:?}"));
+}
+
+/// Compress the debug sections of an installed binary in place, keeping its symbols.
+pub fn compress(file: &Path, compression: Compression) {
+    let program = objcopy();
+    message::operation(
+        format_args!("{program} {} {file}", compression.option()),
+        "compress-debug",
+        json!({"file": file, "option": compression.option()}),
+    );
+    let status = std::process::Command::new(&program)
+        .arg(compression.option())
+        .arg(file)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+    if !status.success() {
+        panic!("{program} {} {file} failed: {status}", compression.option());
+    }
+}
+
+/// Remove sections of an installed binary in place, with objcopy options `-R <SECTION>`,
+/// keeping its symbols.
+pub fn remove_sections(file: &Path, options: &[&str]) {
+    let program = objcopy();
+    let line: String = options.iter().map(|x| format!(" {x}")).collect();
+    message::operation(
+        format_args!("{program}{line} {file}"),
+        "scrub-notes",
+        json!({"file": file, "options": options}),
+    );
+    let status = std::process::Command::new(&program)
+        .args(options)
+        .arg(file)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+    if !status.success() {
+        panic!("{program}{line} {file} failed: {status}");
+    }
+}
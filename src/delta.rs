@@ -0,0 +1,208 @@
+use crate::manifest::Kind;
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::os::unix::fs::PermissionsExt;
+
+/// Name of the index of the image, in the output directory.
+pub const INDEX: &str = "rootfs.manifest";
+
+/// Bounds of the chunks cut in the files: their average size is about 64 KiB.
+const MIN_CHUNK: usize = 16 << 10;
+const MAX_CHUNK: usize = 256 << 10;
+/// Cut a chunk when the rolling hash has its 16 low bits set to zero.
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+
+/// A file, symbolic link or directory of the image, with the chunks of a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    /// sha256 of the chunks of a file, whose concatenation is the file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+/// The index of an image version: its nodes, rebuilt from the chunk store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    pub package: String,
+    pub version: String,
+    pub nodes: Vec<Node>,
+}
+
+impl Index {
+    pub fn load(file: &Path) -> Self {
+        let data = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+        serde_json::from_str(&data).unwrap_or_else(|e| {
+            if serde_json::from_str::<crate::manifest::Manifest>(&data).is_ok() {
+                panic!("{file} is an installed-files manifest, not the {INDEX} index of a delta");
+            }
+            panic!("Failed to parse {file}: {e}")
+        })
+    }
+
+    fn chunks(&self) -> BTreeSet<&str> {
+        let chunks = self.nodes.iter().flat_map(|node| node.chunks.iter());
+        chunks.map(|x| x.as_str()).collect()
+    }
+}
+
+/// The gear table of the rolling hash, generated by splitmix64 to be stable across builds.
+fn gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    for value in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *value = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Cut data in content-defined chunks, so an insertion only changes the chunks around it.
+fn cut<'a>(data: &'a [u8], gear: &[u64; 256]) -> Vec<&'a [u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let end = data.len().min(start + MAX_CHUNK);
+        let mut hash = 0u64;
+        let mut cut = end;
+        for i in (start + MIN_CHUNK).min(end)..end {
+            hash = (hash << 1).wrapping_add(gear[data[i] as usize]);
+            if hash & CHUNK_MASK == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+        chunks.push(&data[start..cut]);
+        start = cut;
+    }
+    chunks
+}
+
+fn sha256(data: &[u8]) -> String {
+    crate::hex(&Sha256::digest(data))
+}
+
+/// Return the path of a chunk in a chunk store, in a directory of its 4 first digits.
+pub fn chunk_path(store: &Path, chunk: &str) -> PathBuf {
+    store.join(&chunk[..4]).join(format!("{chunk}.chunk"))
+}
+
+/// List the nodes of a directory, recursively and sorted, without the files of cargo-rootfs
+/// (the installed-files manifests and the journal), which are not updated by a delta.
+fn walk(rootfs: &Path, dir: &Path, nodes: &mut Vec<(PathBuf, std::fs::Metadata)>) {
+    let mut entries: Vec<PathBuf> = dir
+        .read_dir_utf8()
+        .unwrap_or_else(|e| panic!("Failed to read {dir}: {e:?}"))
+        .flatten()
+        .map(|x| x.into_path())
+        .collect();
+    entries.sort();
+    let excluded = [
+        rootfs.join(crate::manifest::MANIFEST_DIR),
+        rootfs.join(crate::journal::JOURNAL_DIR),
+    ];
+    for path in entries {
+        if excluded.contains(&path) {
+            continue;
+        }
+        let metadata = path.symlink_metadata().unwrap();
+        let is_dir = metadata.is_dir();
+        nodes.push((path.clone(), metadata));
+        if is_dir {
+            walk(rootfs, &path, nodes);
+        }
+    }
+}
+
+/// Write the index of the rootfs and the chunk store of its files in the output directory,
+/// storing only the chunks missing from the previous version. Return the new index.
+pub fn write(
+    rootfs: &Path,
+    output: &Path,
+    from: Option<&Index>,
+    package: &str,
+    version: &str,
+) -> Index {
+    let store = output.join("chunks");
+    std::fs::create_dir_all(&store)
+        .unwrap_or_else(|e| panic!("Failed to create directory {store}: {e:?}"));
+    let known = from.map(|x| x.chunks()).unwrap_or_default();
+    let gear = gear();
+
+    let mut entries = vec![];
+    walk(rootfs, rootfs, &mut entries);
+    let (mut total, mut stored, mut stored_bytes, mut total_bytes) = (0, 0, 0, 0);
+    let mut nodes = vec![];
+    for (path, metadata) in entries {
+        // The staging directory is owned by the user running cargo, the image by root.
+        let mut node = Node {
+            path: PathBuf::from("/").join(path.strip_prefix(rootfs).unwrap()),
+            kind: Kind::File,
+            mode: format!("{:04o}", metadata.permissions().mode() & 0o7777),
+            uid: 0,
+            gid: 0,
+            size: None,
+            target: None,
+            chunks: vec![],
+        };
+        if metadata.is_symlink() {
+            node.kind = Kind::Symlink;
+            node.target = Some(path.read_link_utf8().unwrap());
+        } else if metadata.is_dir() {
+            node.kind = Kind::Dir;
+        } else if metadata.is_file() {
+            let data =
+                std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {path}: {e:?}"));
+            node.size = Some(data.len() as u64);
+            for chunk in cut(&data, &gear) {
+                let hash = sha256(chunk);
+                total += 1;
+                total_bytes += chunk.len();
+                let file = chunk_path(&store, &hash);
+                if !known.contains(hash.as_str()) && !file.exists() {
+                    std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+                    std::fs::write(&file, chunk)
+                        .unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+                    stored += 1;
+                    stored_bytes += chunk.len();
+                }
+                node.chunks.push(hash);
+            }
+        } else {
+            // The device nodes, fifos and sockets are made on the device.
+            continue;
+        }
+        nodes.push(node);
+    }
+
+    let index = Index {
+        package: package.into(),
+        version: version.into(),
+        nodes,
+    };
+    let file = output.join(INDEX);
+    let mut content = serde_json::to_string_pretty(&index).unwrap();
+    content.push('\n');
+    std::fs::write(&file, content).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+    message::status(format_args!(
+        "Stored {stored} of {total} chunks ({stored_bytes} of {total_bytes} bytes) in {store}"
+    ));
+    index
+}
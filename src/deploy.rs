@@ -0,0 +1,199 @@
+use crate::exec::run;
+use crate::{message, shell_quote};
+use camino::Utf8Path as Path;
+use std::process::{Command, Stdio};
+
+/// Remote rootfs location, formatted as `[user@]host[:dir]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshTarget {
+    pub host: String,
+    pub dir: String,
+}
+
+impl SshTarget {
+    pub fn parse(location: &str) -> Self {
+        match location.split_once(':') {
+            Some((host, dir)) if !dir.is_empty() => Self {
+                host: host.into(),
+                dir: dir.into(),
+            },
+            Some((host, _)) => Self {
+                host: host.into(),
+                dir: "/".into(),
+            },
+            None => Self {
+                host: location.into(),
+                dir: "/".into(),
+            },
+        }
+    }
+}
+
+/// Android device reached with adb.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdbTarget {
+    pub serial: Option<String>,
+    pub prefix: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Ssh(SshTarget),
+    Adb(AdbTarget),
+}
+
+impl Backend {
+    /// Parse a deploy backend formatted as `ssh:[user@]host[:dir]` or `adb[:serial]`.
+    pub fn parse(spec: &str, prefix: Option<&str>) -> Self {
+        let prefix = prefix.unwrap_or("/").to_string();
+        if spec == "adb" {
+            return Self::Adb(AdbTarget {
+                serial: None,
+                prefix,
+            });
+        }
+        if let Some(serial) = spec.strip_prefix("adb:") {
+            return Self::Adb(AdbTarget {
+                serial: Some(serial.into()),
+                prefix,
+            });
+        }
+        if let Some(location) = spec.strip_prefix("ssh:") {
+            return Self::Ssh(SshTarget::parse(location));
+        }
+        panic!(
+            "Unknown deploy backend {spec:?} (expected ssh:<[USER@]HOST[:DIR]> or adb[:SERIAL])"
+        );
+    }
+
+    pub fn sync(&self, staging: &Path, delta: bool) {
+        match self {
+            Self::Ssh(target) => ssh_sync(staging, target, delta),
+            Self::Adb(target) => adb_sync(staging, target, delta),
+        }
+    }
+
+    pub fn restart(&self, services: &[String]) {
+        match self {
+            Self::Ssh(target) => ssh_restart(target, services),
+            Self::Adb(target) => adb_restart(target, services),
+        }
+    }
+}
+
+/// Copy the staging directory to the remote target.
+///
+/// By default, the whole tree is streamed with tar over ssh, which only requires
+/// a shell and tar on the board. With `delta`, rsync compares file contents and
+/// only transfers the files which changed since the last deploy.
+pub fn ssh_sync(staging: &Path, target: &SshTarget, delta: bool) {
+    if delta {
+        let src = format!("{staging}/");
+        let dst = format!("{}:{}", target.host, target.dir);
+        message::status(format_args!(
+            "rsync -a --no-owner --no-group --checksum {:#?} {:#?}",
+            src, dst
+        ));
+        run(Command::new("rsync")
+            .arg("-a")
+            .arg("--no-owner")
+            .arg("--no-group")
+            .arg("--checksum")
+            .arg(src)
+            .arg(dst));
+        return;
+    }
+
+    let dir = shell_quote(&target.dir);
+    let remote = format!("mkdir -p {dir} && tar -C {dir} -xf -");
+    message::status(format_args!(
+        "tar -C {:#?} --owner=0 --group=0 -cf - . | ssh {} {:#?}",
+        staging, target.host, remote
+    ));
+    let mut tar = Command::new("tar")
+        .arg("-C")
+        .arg(staging)
+        .arg("--owner=0")
+        .arg("--group=0")
+        .arg("-cf")
+        .arg("-")
+        .arg(".")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to run tar: {e:?}"));
+    let stdout = tar.stdout.take().unwrap();
+    run(Command::new("ssh")
+        .arg(&target.host)
+        .arg(remote)
+        .stdin(stdout));
+    let status = tar.wait().expect("tar error");
+    if !status.success() {
+        panic!("tar failed: {status}");
+    }
+}
+
+/// Restart the init scripts installed by the rules on the remote target.
+pub fn ssh_restart(target: &SshTarget, services: &[String]) {
+    for service in services {
+        let remote = format!("{} restart", shell_quote(service));
+        message::status(format_args!("ssh {} {:#?}", target.host, remote));
+        run(Command::new("ssh").arg(&target.host).arg(remote));
+    }
+}
+
+fn adb(target: &AdbTarget) -> Command {
+    let mut cmd = Command::new("adb");
+    if let Some(serial) = &target.serial {
+        cmd.arg("-s").arg(serial);
+    }
+    cmd
+}
+
+fn adb_display(target: &AdbTarget) -> String {
+    match &target.serial {
+        Some(serial) => format!("adb -s {serial}"),
+        None => "adb".into(),
+    }
+}
+
+/// Push the staging directory to the Android device.
+///
+/// When deploying to the device root, the read-only partitions (/system, /vendor)
+/// are remounted read-write first. With `delta`, only the files newer than their
+/// copy on the device are pushed.
+pub fn adb_sync(staging: &Path, target: &AdbTarget, delta: bool) {
+    if target.prefix == "/" {
+        message::status(format_args!("{} remount", adb_display(target)));
+        run(adb(target).arg("remount"));
+    } else {
+        let remote = format!("mkdir -p {}", shell_quote(&target.prefix));
+        message::status(format_args!("{} shell {:#?}", adb_display(target), remote));
+        run(adb(target).arg("shell").arg(remote));
+    }
+
+    for entry in staging.read_dir_utf8().unwrap() {
+        let entry = entry.unwrap();
+        let mut cmd = adb(target);
+        cmd.arg("push");
+        if delta {
+            cmd.arg("--sync");
+        }
+        message::detail(format_args!(
+            "{} push {}{:#?} {:#?}",
+            adb_display(target),
+            if delta { "--sync " } else { "" },
+            entry.path(),
+            target.prefix
+        ));
+        run(cmd.arg(entry.path()).arg(&target.prefix));
+    }
+}
+
+/// Restart the init scripts installed by the rules on the Android device.
+pub fn adb_restart(target: &AdbTarget, services: &[String]) {
+    for service in services {
+        let remote = format!("{} restart", shell_quote(service));
+        message::status(format_args!("{} shell {:#?}", adb_display(target), remote));
+        run(adb(target).arg("shell").arg(remote));
+    }
+}
@@ -0,0 +1,674 @@
+use crate::message;
+use crate::verity;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::Deserialize;
+use serde_json::value::Value;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::Command;
+
+const SECTOR: u64 = 512;
+/// Alignment of the partitions, and offset of the first one.
+const ALIGN: u64 = 1 << 20;
+/// Number of entries of the GPT partition array, 32 sectors of 128 bytes entries.
+const GPT_ENTRIES: usize = 128;
+
+/// A `[[partition]]` of the disk layout.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub name: String,
+    /// linux, esp, fat32, swap, a GPT type GUID or a MBR type byte (default: esp for vfat,
+    /// linux otherwise).
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// ext2, ext3, ext4, vfat or raw (default: ext4).
+    pub filesystem: Option<String>,
+    /// Size of the partition (default: its content, or the rest of the disk for the last one).
+    pub size: Option<String>,
+    /// Directory of the rootfs copied in the partition (`/` for the whole rootfs), or file
+    /// of the rootfs written to a raw partition. The partition is empty without source.
+    pub source: Option<PathBuf>,
+    /// Filesystem label (default: the name).
+    pub label: Option<String>,
+    #[serde(default)]
+    pub bootable: bool,
+    /// Append a dm-verity hash tree to the filesystem (default: with --verity, for the
+    /// partition of the whole rootfs).
+    pub verity: Option<bool>,
+}
+
+/// A disk layout file, e.g. `disk.toml`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// gpt or mbr (default: gpt).
+    pub table: Option<String>,
+    /// Size of the disk (default: the end of the last partition).
+    pub size: Option<String>,
+    #[serde(rename = "partition")]
+    pub partitions: Vec<Partition>,
+}
+
+/// Parse a scalar value of the layout: a string, an integer or a boolean.
+fn parse_value(value: &str) -> Option<Value> {
+    if let Some(string) = crate::config::parse_string(value) {
+        return Some(Value::String(string));
+    }
+    let value = value.split('#').next().unwrap().trim();
+    match value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => value
+            .parse::<u64>()
+            .ok()
+            .map(|x| Value::String(x.to_string())),
+    }
+}
+
+impl Layout {
+    /// Read a layout file: its `key = value` lines and `[[partition]]` tables.
+    pub fn load(file: &Path) -> Self {
+        let content = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("Failed to read disk layout {file}: {e:?}"));
+        let mut layout = serde_json::Map::new();
+        let mut partitions: Vec<Value> = vec![];
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| -> ! { panic!("{file}:{}: {message}", number + 1) };
+            if let Some(table) = line.strip_prefix("[[") {
+                match table.split("]]").next().unwrap().trim() {
+                    "partition" => partitions.push(Value::Object(Default::default())),
+                    table => error(&format!("unknown table {table}")),
+                }
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .unwrap_or_else(|| error("expected key = value"));
+            let value = parse_value(value.trim()).unwrap_or_else(|| error("invalid value"));
+            let table = match partitions.last_mut() {
+                Some(Value::Object(partition)) => partition,
+                _ => &mut layout,
+            };
+            table.insert(key.trim().trim_matches('"').into(), value);
+        }
+        layout.insert("partition".into(), Value::Array(partitions));
+        serde_json::from_value(Value::Object(layout))
+            .unwrap_or_else(|e| panic!("{file}: {}", crate::did_you_mean(&e.to_string())))
+    }
+}
+
+/// The partition table, and the type of a partition in this table.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Table {
+    Gpt,
+    Mbr,
+}
+
+impl Table {
+    fn parse(name: &str) -> Self {
+        match name {
+            "gpt" => Self::Gpt,
+            "mbr" | "dos" => Self::Mbr,
+            _ => panic!("Unknown partition table {name:?} (expected gpt or mbr)"),
+        }
+    }
+
+    /// Return the GPT type GUID or the MBR type byte of a partition type.
+    fn partition_type(&self, kind: &str) -> String {
+        let known = match kind {
+            "linux" => ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "83"),
+            "esp" | "uefi" => ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "ef"),
+            "fat32" => ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "0c"),
+            "swap" => ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "82"),
+            _ => ("", ""),
+        };
+        match (self, known) {
+            (Self::Gpt, ("", _)) if parse_guid(kind).is_some() => kind.into(),
+            (Self::Mbr, (_, ""))
+                if u8::from_str_radix(kind.trim_start_matches("0x"), 16).is_ok() =>
+            {
+                kind.trim_start_matches("0x").into()
+            }
+            (Self::Gpt, (guid, _)) if !guid.is_empty() => guid.into(),
+            (Self::Mbr, (_, byte)) if !byte.is_empty() => byte.into(),
+            _ => panic!("Unknown {self:?} partition type {kind:?}"),
+        }
+    }
+}
+
+/// Parse a GUID, in the mixed-endian order of the GPT.
+fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let hex: String = guid.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 || guid.len() != 36 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Some(bytes)
+}
+
+/// Return a version 4 GUID derived from a seed, to get reproducible images.
+fn seeded_guid(seed: &str) -> [u8; 16] {
+    let digest = Sha256::digest(seed.as_bytes());
+    let mut guid = [0u8; 16];
+    guid.copy_from_slice(&digest[..16]);
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+/// CRC32 (IEEE 802.3) of the GPT headers and partition arrays.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn align(size: u64) -> u64 {
+    size.div_ceil(ALIGN) * ALIGN
+}
+
+/// A partition placed on the disk, with its image.
+struct Placed {
+    partition: Partition,
+    kind: String,
+    image: Option<PathBuf>,
+    start: u64,
+    size: u64,
+}
+
+/// Return the source of a partition, in the rootfs.
+fn source_path(rootfs: &Path, partition: &Partition) -> Option<PathBuf> {
+    let source = partition.source.as_ref()?;
+    let path = rootfs.join(source.strip_prefix("/").unwrap_or(source));
+    if !path.exists() {
+        panic!("[{}] source {source} not found in {rootfs}", partition.name);
+    }
+    Some(path)
+}
+
+/// Create the filesystem image of a partition, of the given size.
+fn make_filesystem(partition: &Partition, source: Option<&Path>, image: &Path, size: u64) {
+    let filesystem = partition.filesystem.as_deref().unwrap_or("ext4");
+    let label = partition.label.as_deref().unwrap_or(&partition.name);
+    let _ = std::fs::remove_file(image);
+    let size_kib = size / 1024;
+    match filesystem {
+        "ext2" | "ext3" | "ext4" => {
+            message::status(format_args!(
+                "mke2fs -t {filesystem} -L {label} {}{image} {size_kib}k",
+                source.map(|x| format!("-d {x} ")).unwrap_or_default()
+            ));
+            let mut mke2fs = Command::new("mke2fs");
+            mke2fs.args(["-q", "-t", filesystem, "-L", label, "-E", "root_owner=0:0"]);
+            if let Some(source) = source {
+                mke2fs.arg("-d").arg(source);
+            }
+            // An existing image keeps mke2fs quiet.
+            std::fs::File::create(image)
+                .unwrap_or_else(|e| panic!("Failed to create {image}: {e:?}"));
+            crate::exec::run(mke2fs.arg(image).arg(format!("{size_kib}k")));
+        }
+        "vfat" => {
+            // FAT labels are limited to 11 characters.
+            let label: String = label.to_uppercase().chars().take(11).collect();
+            message::status(format_args!("mkfs.vfat -n {label} -C {image} {size_kib}"));
+            crate::exec::run(
+                Command::new("mkfs.vfat")
+                    .args(["-n", &label, "-C"])
+                    .arg(image)
+                    .arg(size_kib.to_string()),
+            );
+            let Some(source) = source else {
+                return;
+            };
+            let mut entries: Vec<PathBuf> = source
+                .read_dir_utf8()
+                .unwrap_or_else(|e| panic!("Failed to read {source}: {e:?}"))
+                .flatten()
+                .map(|x| x.into_path())
+                .collect();
+            entries.sort();
+            if entries.is_empty() {
+                return;
+            }
+            message::status(format_args!("mcopy -i {image} -s {source}/* ::/"));
+            crate::exec::run(
+                Command::new("mcopy")
+                    .arg("-i")
+                    .arg(image)
+                    .args(["-s", "-p", "-m", "-Q"])
+                    .args(&entries)
+                    .arg("::/"),
+            );
+        }
+        _ => panic!(
+            "[{}] unknown filesystem {filesystem:?} (expected ext2, ext3, ext4, vfat or raw)",
+            partition.name
+        ),
+    }
+}
+
+/// Copy an image in the disk at an offset, skipping the zero blocks to keep it sparse.
+fn write_at(disk: &mut std::fs::File, offset: u64, image: &Path, size: u64) {
+    let mut file =
+        std::fs::File::open(image).unwrap_or_else(|e| panic!("Failed to open {image}: {e:?}"));
+    let length = file.metadata().unwrap().len();
+    if length > size {
+        panic!("{image} ({length} bytes) does not fit in its partition ({size} bytes)");
+    }
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut position = offset;
+    loop {
+        let count = file.read(&mut buffer).unwrap();
+        if count == 0 {
+            break;
+        }
+        if buffer[..count].iter().any(|x| *x != 0) {
+            disk.seek(SeekFrom::Start(position)).unwrap();
+            disk.write_all(&buffer[..count]).unwrap();
+        }
+        position += count as u64;
+    }
+}
+
+/// Write a GPT: the protective MBR, the primary header and partition array, and their
+/// backups at the end of the disk.
+fn write_gpt(disk: &mut std::fs::File, placed: &[Placed], disk_size: u64, seed: &str) {
+    let last_lba = disk_size / SECTOR - 1;
+    let array_sectors = (GPT_ENTRIES * 128) as u64 / SECTOR;
+    let mut array = vec![0u8; GPT_ENTRIES * 128];
+    for (i, part) in placed.iter().enumerate() {
+        let entry = &mut array[i * 128..(i + 1) * 128];
+        entry[0..16].copy_from_slice(&parse_guid(&part.kind).unwrap());
+        let guid = seeded_guid(&format!("{seed}:{}", part.partition.name));
+        entry[16..32].copy_from_slice(&guid);
+        entry[32..40].copy_from_slice(&(part.start / SECTOR).to_le_bytes());
+        entry[40..48].copy_from_slice(&((part.start + part.size) / SECTOR - 1).to_le_bytes());
+        // Legacy BIOS bootable attribute, used by U-Boot and syslinux.
+        let attributes: u64 = if part.partition.bootable { 1 << 2 } else { 0 };
+        entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+        let name: Vec<u16> = part.partition.name.encode_utf16().take(36).collect();
+        for (j, c) in name.iter().enumerate() {
+            entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let array_crc = crc32(&array);
+
+    let header = |current: u64, backup: u64, array_lba: u64| {
+        let mut header = vec![0u8; SECTOR as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + array_sectors).to_le_bytes());
+        header[48..56].copy_from_slice(&(last_lba - 1 - array_sectors).to_le_bytes());
+        header[56..72].copy_from_slice(&seeded_guid(seed));
+        header[72..80].copy_from_slice(&array_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&array_crc.to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    };
+
+    let mut mbr = vec![0u8; SECTOR as usize];
+    let sectors = (last_lba).min(u32::MAX as u64) as u32;
+    mbr[446..462].copy_from_slice(&mbr_entry(false, 0xee, 1, sectors));
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+
+    let backup_array = last_lba - array_sectors;
+    let writes = [
+        (0, mbr),
+        (1, header(1, last_lba, 2)),
+        (2, array.clone()),
+        (backup_array, array),
+        (last_lba, header(last_lba, 1, backup_array)),
+    ];
+    for (lba, data) in writes {
+        disk.seek(SeekFrom::Start(lba * SECTOR)).unwrap();
+        disk.write_all(&data).unwrap();
+    }
+}
+
+/// Return a MBR partition entry, with the CHS addresses of the LBA-only disks.
+fn mbr_entry(bootable: bool, kind: u8, start: u32, sectors: u32) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[0] = if bootable { 0x80 } else { 0 };
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    entry
+}
+
+/// Write a MBR partition table, with up to 4 primary partitions.
+fn write_mbr(disk: &mut std::fs::File, placed: &[Placed], seed: &str) {
+    if placed.len() > 4 {
+        panic!("mbr partition tables support at most 4 partitions");
+    }
+    let mut mbr = vec![0u8; SECTOR as usize];
+    mbr[440..444].copy_from_slice(&seeded_guid(seed)[..4]);
+    for (i, part) in placed.iter().enumerate() {
+        let kind = u8::from_str_radix(&part.kind, 16).unwrap();
+        let start = u32::try_from(part.start / SECTOR)
+            .unwrap_or_else(|_| panic!("[{}] starts beyond 2 TiB", part.partition.name));
+        let sectors = u32::try_from(part.size / SECTOR)
+            .unwrap_or_else(|_| panic!("[{}] is larger than 2 TiB", part.partition.name));
+        let entry = mbr_entry(part.partition.bootable, kind, start, sectors);
+        mbr[446 + i * 16..462 + i * 16].copy_from_slice(&entry);
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    disk.seek(SeekFrom::Start(0)).unwrap();
+    disk.write_all(&mbr).unwrap();
+}
+
+/// Build a disk image from a layout, with the partitions made from the rootfs.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    layout: &Layout,
+    verity: Option<&verity::VerityOptions>,
+    output: &Path,
+) {
+    let table_name = layout.table.as_deref().unwrap_or("gpt");
+    let table = Table::parse(table_name);
+    let disk_size = layout.size.as_deref().map(crate::parse_size);
+    if layout.partitions.is_empty() {
+        panic!("The disk layout has no [[partition]]");
+    }
+    std::fs::create_dir_all(outdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+
+    let mut placed: Vec<Placed> = vec![];
+    let mut start = ALIGN;
+    let count = layout.partitions.len();
+    for (i, partition) in layout.partitions.iter().enumerate() {
+        let filesystem = partition.filesystem.as_deref().unwrap_or("ext4");
+        let default_kind = if filesystem == "vfat" { "esp" } else { "linux" };
+        let kind = table.partition_type(partition.kind.as_deref().unwrap_or(default_kind));
+        let source = source_path(rootfs, partition);
+        let content = match (&source, filesystem) {
+            (Some(source), "raw") => source.metadata().unwrap().len(),
+            // Room for the metadata of the filesystem.
+            (Some(source), _) => crate::boot::dir_size(source) * 3 / 2 + 16 * ALIGN,
+            (None, _) => 0,
+        };
+        let size = match (&partition.size, disk_size) {
+            (Some(size), _) => crate::parse_size(size),
+            // The last partition fills the disk, leaving the room of the backup GPT.
+            (None, Some(disk_size)) if i + 1 == count => (disk_size - ALIGN)
+                .checked_sub(start)
+                .unwrap_or_else(|| panic!("[{}] does not fit in the disk size", partition.name)),
+            (None, _) if content > 0 => content,
+            (None, _) => panic!("[{}] requires a size or a source", partition.name),
+        };
+        let size = align(size);
+        if content > size && filesystem == "raw" {
+            panic!(
+                "[{}] {content} bytes do not fit in {size} bytes",
+                partition.name
+            );
+        }
+        let image = match (source, filesystem) {
+            (Some(source), "raw") => Some(source),
+            (None, "raw") => None,
+            (source, _) => {
+                let image = outdir.join(format!("{}.img", partition.name));
+                let whole = partition.source.as_deref() == Some("/".into());
+                let options = match (partition.verity, verity) {
+                    (Some(false), _) | (None, None) => None,
+                    (None, Some(_)) if !whole => None,
+                    (_, options) => Some(options.cloned().unwrap_or_default()),
+                };
+                // The filesystem leaves room for the hash tree appended in the partition.
+                let fs_size = match &options {
+                    Some(options) if options.hash == verity::Hash::Appended => {
+                        (size - verity::hash_size(size)) / 4096 * 4096
+                    }
+                    _ => size,
+                };
+                make_filesystem(partition, source.as_deref(), &image, fs_size);
+                if let Some(options) = options {
+                    verity::format(&image, &options);
+                }
+                Some(image)
+            }
+        };
+        placed.push(Placed {
+            partition: partition.clone(),
+            kind,
+            image,
+            start,
+            size,
+        });
+        start += size;
+    }
+
+    let end = start + ALIGN;
+    let disk_size = disk_size
+        .map(|x| x.div_ceil(SECTOR) * SECTOR)
+        .unwrap_or(end);
+    if disk_size < end {
+        panic!("The partitions ({end} bytes) do not fit in the disk size ({disk_size} bytes)");
+    }
+
+    message::status(format_args!(
+        "Writing {table_name} disk image {output} ({disk_size} bytes)"
+    ));
+    let _ = std::fs::remove_file(output);
+    let mut disk = std::fs::File::create(output)
+        .unwrap_or_else(|e| panic!("Failed to create {output}: {e:?}"));
+    disk.set_len(disk_size).unwrap();
+    for part in &placed {
+        message::status(format_args!(
+            "  {}: {} bytes at {}",
+            part.partition.name, part.size, part.start
+        ));
+        if let Some(image) = &part.image {
+            write_at(&mut disk, part.start, image, part.size);
+        }
+    }
+    let seed = output.file_name().unwrap_or_default();
+    let names: Vec<&str> = placed.iter().map(|x| x.partition.name.as_str()).collect();
+    let seed = format!("{seed}:{}", names.join(","));
+    match table {
+        Table::Gpt => write_gpt(&mut disk, &placed, disk_size, &seed),
+        Table::Mbr => write_mbr(&mut disk, &placed, &seed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!("cargo-rootfs-{}-{name}", std::process::id()));
+        PathBuf::from_path_buf(file).unwrap()
+    }
+
+    fn placed(name: &str, kind: &str, start: u64, size: u64, bootable: bool) -> Placed {
+        Placed {
+            partition: Partition {
+                name: name.into(),
+                kind: None,
+                filesystem: None,
+                size: None,
+                source: None,
+                label: None,
+                bootable,
+                verity: None,
+            },
+            kind: kind.into(),
+            image: None,
+            start,
+            size,
+        }
+    }
+
+    fn le32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guids() {
+        let guid = parse_guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4").unwrap();
+        assert_eq!(
+            guid,
+            [
+                0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47,
+                0x7d, 0xe4
+            ]
+        );
+        assert_eq!(parse_guid("0FC63DAF84834772-8E79-3D69D8477DE4-"), None);
+        assert_eq!(parse_guid("0FC63DAF-8483-4772-8E79-3D69D8477DEX"), None);
+        let guid = seeded_guid("disk");
+        assert_eq!(guid, seeded_guid("disk"));
+        assert_ne!(guid, seeded_guid("other"));
+        assert_eq!(guid[7] >> 4, 4);
+        assert_eq!(guid[8] >> 6, 2);
+    }
+
+    #[test]
+    fn partition_types() {
+        let linux = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+        assert_eq!(Table::Gpt.partition_type("linux"), linux);
+        assert_eq!(Table::Gpt.partition_type(linux), linux);
+        assert_eq!(Table::Mbr.partition_type("esp"), "ef");
+        assert_eq!(Table::Mbr.partition_type("0x8e"), "8e");
+        assert_eq!(Table::parse("dos"), Table::Mbr);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown Gpt partition type \"83\"")]
+    fn mbr_type_in_gpt() {
+        Table::Gpt.partition_type("83");
+    }
+
+    #[test]
+    fn load_layout() {
+        let file = temp_file("disk.toml");
+        std::fs::write(
+            &file,
+            "# A disk\ntable = \"gpt\"\nsize = \"64M\"\n\n\
+             [[partition]]\nname = \"boot\"\nfilesystem = \"vfat\" # comment\nsize = 16777216\nbootable = true\n\n\
+             [[partition]]\nname = \"rootfs\"\nsource = \"/\"\n",
+        )
+        .unwrap();
+        let layout = Layout::load(&file);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(layout.table.as_deref(), Some("gpt"));
+        assert_eq!(layout.size.as_deref(), Some("64M"));
+        let [boot, rootfs] = &layout.partitions[..] else {
+            panic!("{layout:?}");
+        };
+        assert_eq!(boot.filesystem.as_deref(), Some("vfat"));
+        assert_eq!(boot.size.as_deref(), Some("16777216"));
+        assert!(boot.bootable);
+        assert_eq!(rootfs.source.as_deref(), Some(Path::new("/")));
+        assert!(!rootfs.bootable);
+    }
+
+    #[test]
+    fn write_gpt_headers() {
+        let file = temp_file("gpt.img");
+        let disk_size = 8 * ALIGN;
+        let mut disk = std::fs::File::create(&file).unwrap();
+        disk.set_len(disk_size).unwrap();
+        let linux = Table::Gpt.partition_type("linux");
+        let parts = [placed("rootfs", &linux, ALIGN, 4 * ALIGN, true)];
+        write_gpt(&mut disk, &parts, disk_size, "seed");
+        drop(disk);
+        let data = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let sector = SECTOR as usize;
+        let last_lba = disk_size / SECTOR - 1;
+        assert_eq!(&data[510..512], &[0x55, 0xaa]);
+        assert_eq!(data[446 + 4], 0xee);
+        let primary = &data[sector..2 * sector];
+        let backup = &data[last_lba as usize * sector..];
+        for (header, current, other, array) in [
+            (primary, 1, last_lba, 2),
+            (backup, last_lba, 1, last_lba - 32),
+        ] {
+            assert_eq!(&header[0..8], b"EFI PART");
+            let mut copy = header[..92].to_vec();
+            copy[16..20].fill(0);
+            assert_eq!(le32(header, 16), crc32(&copy));
+            assert_eq!(le64(header, 24), current);
+            assert_eq!(le64(header, 32), other);
+            assert_eq!(le64(header, 40), 34);
+            assert_eq!(le64(header, 48), last_lba - 33);
+            assert_eq!(le64(header, 72), array);
+            let array = &data[array as usize * sector..][..GPT_ENTRIES * 128];
+            assert_eq!(le32(header, 88), crc32(array));
+            assert_eq!(&array[0..16], &parse_guid(&linux).unwrap());
+            assert_eq!(le64(array, 32), ALIGN / SECTOR);
+            assert_eq!(le64(array, 40), 5 * ALIGN / SECTOR - 1);
+            assert_eq!(le64(array, 48), 1 << 2);
+            assert_eq!(
+                &array[56..68],
+                "rootfs"
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn write_mbr_entries() {
+        let file = temp_file("mbr.img");
+        let mut disk = std::fs::File::create(&file).unwrap();
+        let parts = [
+            placed("boot", "0c", ALIGN, ALIGN, true),
+            placed("rootfs", "83", 2 * ALIGN, 3 * ALIGN, false),
+        ];
+        write_mbr(&mut disk, &parts, "seed");
+        drop(disk);
+        let data = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(data.len(), SECTOR as usize);
+        assert_eq!(&data[440..444], &seeded_guid("seed")[..4]);
+        let boot = &data[446..462];
+        assert_eq!((boot[0], boot[4]), (0x80, 0x0c));
+        assert_eq!((le32(boot, 8), le32(boot, 12)), (2048, 2048));
+        let rootfs = &data[462..478];
+        assert_eq!((rootfs[0], rootfs[4]), (0, 0x83));
+        assert_eq!((le32(rootfs, 8), le32(rootfs, 12)), (4096, 6144));
+        assert_eq!(&data[478..510], &[0; 32]);
+        assert_eq!(&data[510..512], &[0x55, 0xaa]);
+    }
+}
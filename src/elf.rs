@@ -0,0 +1,366 @@
+use camino::Utf8Path as Path;
+
+const ET_EXEC: u64 = 2;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474e552;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_BIND_NOW: u64 = 24;
+const DT_FLAGS: u64 = 30;
+const DT_FLAGS_1: u64 = 0x6ffffffb;
+const DF_BIND_NOW: u64 = 8;
+const DF_1_NOW: u64 = 1;
+const NT_GNU_BUILD_ID: u64 = 3;
+pub const SHF_COMPRESSED: u64 = 0x800;
+
+/// Convert an offset or a size read from the file, which may not fit in memory.
+fn index(value: u64) -> Option<usize> {
+    usize::try_from(value).ok()
+}
+
+/// A program header of an ELF file.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    align: u64,
+}
+
+/// The dynamic linking information of an ELF file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Elf {
+    data: Vec<u8>,
+    is_64: bool,
+    is_le: bool,
+    segments: Vec<Segment>,
+}
+
+impl Elf {
+    /// Parse an ELF file, returning None for the other files.
+    pub fn parse(file: &Path) -> Option<Self> {
+        let data = std::fs::read(file).ok()?;
+        if data.len() < 0x34 || data[..4] != *b"\x7fELF" {
+            return None;
+        }
+        let mut elf = Self {
+            is_64: data[4] == 2,
+            is_le: data[5] == 1,
+            data,
+            segments: vec![],
+        };
+        let (phoff, phentsize, phnum) = match elf.is_64 {
+            true => (elf.word(0x20, 8)?, elf.word(0x36, 2)?, elf.word(0x38, 2)?),
+            false => (elf.word(0x1c, 4)?, elf.word(0x2a, 2)?, elf.word(0x2c, 2)?),
+        };
+        for i in 0..phnum {
+            let base = phoff.checked_add(i.checked_mul(phentsize)?)?;
+            let field = |offset: u64, size| elf.word(index(base.checked_add(offset)?)?, size);
+            let segment = match elf.is_64 {
+                true => Segment {
+                    kind: field(0, 4)? as u32,
+                    offset: field(8, 8)?,
+                    vaddr: field(16, 8)?,
+                    filesz: field(32, 8)?,
+                    align: field(48, 8)?,
+                },
+                false => Segment {
+                    kind: field(0, 4)? as u32,
+                    offset: field(4, 4)?,
+                    vaddr: field(8, 4)?,
+                    filesz: field(16, 4)?,
+                    align: field(28, 4)?,
+                },
+            };
+            elf.segments.push(segment);
+        }
+        Some(elf)
+    }
+
+    /// Read an integer of `size` bytes at `offset`.
+    fn word(&self, offset: usize, size: usize) -> Option<u64> {
+        let bytes = self.data.get(offset..offset.checked_add(size)?)?;
+        let fold = |value: u64, byte: &u8| (value << 8) | *byte as u64;
+        Some(match self.is_le {
+            true => bytes.iter().rev().fold(0, fold),
+            false => bytes.iter().fold(0, fold),
+        })
+    }
+
+    fn string(&self, offset: usize) -> Option<String> {
+        let bytes = self.data.get(offset..)?;
+        let end = bytes.iter().position(|b| *b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    fn segment(&self, kind: u32) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.kind == kind)
+    }
+
+    /// Convert a virtual address to a file offset, with the loaded segments.
+    fn file_offset(&self, vaddr: u64) -> Option<usize> {
+        let segment = self.segments.iter().find(|segment| {
+            segment.kind == PT_LOAD
+                && segment.vaddr <= vaddr
+                && vaddr < segment.vaddr.saturating_add(segment.filesz)
+        })?;
+        index((vaddr - segment.vaddr).checked_add(segment.offset)?)
+    }
+
+    /// Return the dynamic loader (PT_INTERP), if any.
+    pub fn interpreter(&self) -> Option<String> {
+        let segment = self.segment(PT_INTERP)?;
+        self.string(index(segment.offset)?)
+    }
+
+    /// Return the entries of the dynamic section, as (tag, value).
+    fn dynamic(&self) -> Vec<(u64, u64)> {
+        let segment = match self.segment(PT_DYNAMIC) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let size = if self.is_64 { 8 } else { 4 };
+        let mut entries = vec![];
+        let Some(mut offset) = index(segment.offset) else {
+            return vec![];
+        };
+        let end = segment.offset.saturating_add(segment.filesz);
+        while let (Some(tag), Some(value)) = (
+            self.word(offset, size),
+            self.word(offset.saturating_add(size), size),
+        ) {
+            if tag == DT_NULL || offset as u64 >= end {
+                break;
+            }
+            entries.push((tag, value));
+            offset += 2 * size;
+        }
+        entries
+    }
+
+    /// Return the file offset and size of the dynamic string table.
+    fn strtab(&self, entries: &[(u64, u64)]) -> Option<(usize, usize)> {
+        let value = |wanted| {
+            entries
+                .iter()
+                .find(|(tag, _)| *tag == wanted)
+                .map(|(_, value)| *value)
+        };
+        let offset = self.file_offset(value(DT_STRTAB)?)?;
+        Some((offset, value(DT_STRSZ).unwrap_or(0) as usize))
+    }
+
+    /// Return the shared libraries required by the file (DT_NEEDED).
+    pub fn needed(&self) -> Vec<String> {
+        let entries = self.dynamic();
+        let strtab = match self.strtab(&entries) {
+            Some((offset, _)) => offset,
+            None => return vec![],
+        };
+        entries
+            .iter()
+            .filter(|(tag, _)| *tag == DT_NEEDED)
+            .filter_map(|(_, name)| self.string(strtab.checked_add(index(*name)?)?))
+            .collect()
+    }
+
+    /// An executable not built as a position independent executable (PIE).
+    pub fn is_fixed_executable(&self) -> bool {
+        self.word(0x10, 2) == Some(ET_EXEC)
+    }
+
+    /// The file has a read-only relocations segment (RELRO).
+    pub fn has_relro(&self) -> bool {
+        self.segment(PT_GNU_RELRO).is_some()
+    }
+
+    /// The symbols are resolved at load time, making the whole RELRO segment read-only.
+    pub fn binds_now(&self) -> bool {
+        self.dynamic().iter().any(|(tag, value)| match *tag {
+            DT_BIND_NOW => true,
+            DT_FLAGS => value & DF_BIND_NOW != 0,
+            DT_FLAGS_1 => value & DF_1_NOW != 0,
+            _ => false,
+        })
+    }
+
+    /// Check if a symbol name is in the dynamic string table, e.g. an imported function.
+    pub fn has_dynamic_string(&self, name: &str) -> Option<bool> {
+        let (offset, size) = self.strtab(&self.dynamic())?;
+        let table = self.data.get(offset..offset.checked_add(size)?)?;
+        Some(table.split(|b| *b == 0).any(|x| x == name.as_bytes()))
+    }
+
+    /// Check if the file was built by rustc, which records its version in `.comment`, or
+    /// has the panic locations of the standard library, when `.comment` is removed.
+    pub fn is_rust(&self) -> bool {
+        let needles: [&[u8]; 2] = [b"rustc version ", b"/rustc/"];
+        needles
+            .iter()
+            .any(|needle| self.data.windows(needle.len()).any(|x| x == *needle))
+    }
+
+    /// Return the GNU build-id of the file (NT_GNU_BUILD_ID note), in hex.
+    pub fn build_id(&self) -> Option<String> {
+        let notes = self
+            .segments
+            .iter()
+            .filter(|segment| segment.kind == PT_NOTE);
+        for segment in notes {
+            // The name and the descriptor are padded to the alignment of the segment.
+            let align = if segment.align == 8 { 8 } else { 4 };
+            let mut offset = index(segment.offset)?;
+            let end = index(segment.offset.checked_add(segment.filesz)?)?;
+            while offset.checked_add(12)? <= end {
+                let namesz = index(self.word(offset, 4)?)?;
+                let descsz = index(self.word(offset + 4, 4)?)?;
+                let kind = self.word(offset + 8, 4)?;
+                let name = offset + 12;
+                let desc = name.checked_add(namesz.div_ceil(align) * align)?;
+                if kind == NT_GNU_BUILD_ID
+                    && self.data.get(name..name.checked_add(namesz)?) == Some(&b"GNU\0"[..])
+                {
+                    let id = self.data.get(desc..desc.checked_add(descsz)?)?;
+                    return Some(crate::hex(id));
+                }
+                offset = desc.checked_add(descsz.div_ceil(align) * align)?;
+            }
+        }
+        None
+    }
+
+    /// Return the names and the flags of the sections.
+    pub fn sections(&self) -> Vec<(String, u64)> {
+        self.section_headers().unwrap_or_default()
+    }
+
+    fn section_headers(&self) -> Option<Vec<(String, u64)>> {
+        let (shoff, shentsize, shnum, shstrndx) = match self.is_64 {
+            true => (
+                self.word(0x28, 8)?,
+                self.word(0x3a, 2)?,
+                self.word(0x3c, 2)?,
+                self.word(0x3e, 2)?,
+            ),
+            false => (
+                self.word(0x20, 4)?,
+                self.word(0x2e, 2)?,
+                self.word(0x30, 2)?,
+                self.word(0x32, 2)?,
+            ),
+        };
+        // The name offset, the flags and the file offset of a section.
+        let header = |i: u64| {
+            let base = shoff.checked_add(i.checked_mul(shentsize)?)?;
+            let field = |offset: u64, size| self.word(index(base.checked_add(offset)?)?, size);
+            match self.is_64 {
+                true => Some((field(0, 4)?, field(8, 8)?, field(0x18, 8)?)),
+                false => Some((field(0, 4)?, field(8, 4)?, field(0x10, 4)?)),
+            }
+        };
+        let (_, _, names) = header(shstrndx)?;
+        (0..shnum)
+            .map(|i| {
+                let (name, flags, _) = header(i)?;
+                Some((self.string(index(names.checked_add(name)?)?)?, flags))
+            })
+            .collect()
+    }
+
+    /// Check if the file has a symbol table or debug information, removed by strip.
+    pub fn has_symbols(&self) -> bool {
+        self.sections()
+            .iter()
+            .any(|(name, _)| name == ".symtab" || name.starts_with(".debug_"))
+    }
+
+    /// A statically linked file has no dynamic loader and requires no shared library.
+    pub fn is_static(&self) -> bool {
+        self.interpreter().is_none() && self.needed().is_empty()
+    }
+
+    /// Replace the dynamic loader in place, when the new path fits in the PT_INTERP segment.
+    pub fn set_interpreter(file: &Path, interp: &str) -> bool {
+        let mut elf = match Self::parse(file) {
+            Some(x) => x,
+            None => return false,
+        };
+        let segment = match elf.segment(PT_INTERP) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+        if interp.len() as u64 + 1 > segment.filesz {
+            return false;
+        }
+        // The segment of a truncated file may end after the data.
+        let area = index(segment.offset)
+            .zip(index(segment.filesz))
+            .and_then(|(start, size)| elf.data.get_mut(start..start.checked_add(size)?));
+        let Some(area) = area else {
+            return false;
+        };
+        area.fill(0);
+        area[..interp.len()].copy_from_slice(interp.as_bytes());
+        std::fs::write(file, &elf.data).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little-endian ELF64 header with a PT_INTERP program header.
+    fn header(phoff: u64, interp_offset: u64, interp_size: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 120];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        data[0x20..0x28].copy_from_slice(&phoff.to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        data[64..68].copy_from_slice(&PT_INTERP.to_le_bytes());
+        data[72..80].copy_from_slice(&interp_offset.to_le_bytes());
+        data[96..104].copy_from_slice(&interp_size.to_le_bytes());
+        data
+    }
+
+    fn write(name: &str, data: &[u8]) -> camino::Utf8PathBuf {
+        let file = std::env::temp_dir().join(format!("cargo-rootfs-{}-{name}", std::process::id()));
+        let file = camino::Utf8PathBuf::from_path_buf(file).unwrap();
+        std::fs::write(&file, data).unwrap();
+        file
+    }
+
+    #[test]
+    fn interpreter_past_the_end_of_a_truncated_file() {
+        let file = write("truncated.elf", &header(64, 200, 50));
+        assert_eq!(Elf::parse(&file).unwrap().interpreter(), None);
+        assert!(!Elf::set_interpreter(&file, "/lib/ld.so"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn overflowing_program_header_offset() {
+        let file = write("overflow.elf", &header(u64::MAX - 8, 0, 0));
+        assert_eq!(Elf::parse(&file), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn set_interpreter_in_place() {
+        let mut data = header(64, 120, 16);
+        data.resize(136, 0);
+        data[120..131].copy_from_slice(b"/lib/ld.so\0");
+        let file = write("interp.elf", &data);
+        assert!(Elf::set_interpreter(&file, "/lib/ld2.so"));
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(elf.interpreter().as_deref(), Some("/lib/ld2.so"));
+        assert!(!Elf::set_interpreter(&file, "/lib/a-much-longer-loader.so"));
+        std::fs::remove_file(&file).unwrap();
+    }
+}
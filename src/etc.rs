@@ -0,0 +1,176 @@
+use serde::Deserialize;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+
+/// The header of the generated files, pointing to their source.
+const HEADER: &str = "# Generated by cargo-rootfs from [workspace.metadata.rootfs.etc]\n";
+
+/// A mount of /etc/fstab.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    pub device: String,
+    pub mountpoint: String,
+    #[serde(rename = "type")]
+    pub fstype: String,
+    #[serde(default = "default_options")]
+    pub options: String,
+    #[serde(default)]
+    pub dump: u32,
+    #[serde(default)]
+    pub pass: u32,
+}
+
+fn default_options() -> String {
+    "defaults".into()
+}
+
+/// The name servers of /etc/resolv.conf.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Resolv {
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    #[serde(default)]
+    pub search: Vec<String>,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// The `[workspace.metadata.rootfs.etc]` table: the skeleton of /etc.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Etc {
+    pub hostname: Option<String>,
+    /// The names of the addresses of /etc/hosts, besides localhost.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    /// The services of the databases of /etc/nsswitch.conf.
+    #[serde(default)]
+    pub nsswitch: BTreeMap<String, String>,
+    pub resolv: Option<Resolv>,
+}
+
+fn invalid(field: &str, error: &str) -> ! {
+    panic!("workspace.metadata.rootfs.etc.{field}: {error}")
+}
+
+impl Etc {
+    pub fn load(workspace_metadata: &Value) -> Self {
+        let etc: Self = match &workspace_metadata["rootfs"]["etc"] {
+            Value::Null => Self::default(),
+            table => serde_json::from_value(table.clone()).unwrap_or_else(|e| {
+                panic!(
+                    "workspace.metadata.rootfs.etc: {}",
+                    crate::did_you_mean(&e.to_string())
+                )
+            }),
+        };
+        etc.check();
+        etc
+    }
+
+    fn check(&self) {
+        if let Some(hostname) = &self.hostname {
+            let valid = hostname.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+            if !valid || hostname.len() > 253 {
+                invalid("hostname", &format!("{hostname:?} is not a valid hostname"));
+            }
+        }
+        for mount in &self.mounts {
+            if !mount.mountpoint.starts_with('/') && mount.mountpoint != "none" {
+                let error = format!("{:?} is not an absolute path", mount.mountpoint);
+                invalid("mounts", &error);
+            }
+            let fields = [
+                &mount.device,
+                &mount.mountpoint,
+                &mount.fstype,
+                &mount.options,
+            ];
+            if fields
+                .iter()
+                .any(|x| x.is_empty() || x.contains(char::is_whitespace))
+            {
+                let error = format!("{} has an empty field or spaces", mount.mountpoint);
+                invalid("mounts", &error);
+            }
+        }
+    }
+
+    fn hosts(&self) -> String {
+        let mut content = String::from(HEADER);
+        content += "127.0.0.1\tlocalhost\n";
+        content += "::1\t\tlocalhost ip6-localhost ip6-loopback\n";
+        if let Some(hostname) = &self.hostname {
+            content += &format!("127.0.1.1\t{hostname}\n");
+        }
+        for (address, names) in &self.hosts {
+            content += &format!("{address}\t{}\n", names.join(" "));
+        }
+        content
+    }
+
+    fn fstab(&self) -> String {
+        let mut content = String::from(HEADER);
+        content += "# <file system> <mount point> <type> <options> <dump> <pass>\n";
+        for mount in &self.mounts {
+            content += &format!(
+                "{} {} {} {} {} {}\n",
+                mount.device, mount.mountpoint, mount.fstype, mount.options, mount.dump, mount.pass
+            );
+        }
+        content
+    }
+
+    fn nsswitch(&self) -> String {
+        let mut content = String::from(HEADER);
+        for (database, services) in &self.nsswitch {
+            content += &format!("{database}:\t{services}\n");
+        }
+        content
+    }
+
+    fn resolv(resolv: &Resolv) -> String {
+        let mut content = String::from(HEADER);
+        if !resolv.search.is_empty() {
+            content += &format!("search {}\n", resolv.search.join(" "));
+        }
+        for nameserver in &resolv.nameservers {
+            content += &format!("nameserver {nameserver}\n");
+        }
+        if !resolv.options.is_empty() {
+            content += &format!("options {}\n", resolv.options.join(" "));
+        }
+        content
+    }
+
+    /// Return the files of the skeleton, relative to the rootfs, with their content. Only
+    /// the files described in the table are written.
+    pub fn files(&self) -> Vec<(&'static str, String)> {
+        let mut files = vec![];
+        if let Some(hostname) = &self.hostname {
+            files.push(("etc/hostname", format!("{hostname}\n")));
+        }
+        if self.hostname.is_some() || !self.hosts.is_empty() {
+            files.push(("etc/hosts", self.hosts()));
+        }
+        if !self.mounts.is_empty() {
+            files.push(("etc/fstab", self.fstab()));
+        }
+        if !self.nsswitch.is_empty() {
+            files.push(("etc/nsswitch.conf", self.nsswitch()));
+        }
+        if let Some(resolv) = &self.resolv {
+            files.push(("etc/resolv.conf", Self::resolv(resolv)));
+        }
+        files
+    }
+}
@@ -0,0 +1,207 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::os::unix::fs::MetadataExt;
+
+/// Run a command, and panic when it can not be run or fails.
+pub fn run(cmd: &mut std::process::Command) {
+    let status = cmd
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {:?}: {e:?}", cmd.get_program()));
+    if !status.success() {
+        panic!("{:?} failed: {status}", cmd.get_program());
+    }
+}
+
+/// Return the qemu-user architecture name for a target triple.
+pub fn qemu_arch(triple: &str) -> String {
+    let arch = triple.split('-').next().unwrap_or(triple);
+    let arch = match arch {
+        "x86" | "i386" | "i586" | "i686" => "i386",
+        "powerpc64le" => "ppc64le",
+        "powerpc64" => "ppc64",
+        "powerpc" => "ppc",
+        "riscv64gc" | "riscv64imac" => "riscv64",
+        "riscv32gc" | "riscv32imac" | "riscv32imc" => "riscv32",
+        other if other.starts_with("armeb") => "armeb",
+        other if other.starts_with("arm") || other.starts_with("thumb") => "arm",
+        other => other,
+    };
+    arch.to_string()
+}
+
+fn host_arch() -> String {
+    qemu_arch(std::env::consts::ARCH)
+}
+
+/// Return true if binaries for the target triple can not run natively on the host.
+pub fn is_foreign(target: Option<&str>) -> bool {
+    match target {
+        Some(triple) => qemu_arch(triple) != host_arch(),
+        None => false,
+    }
+}
+
+fn is_root() -> bool {
+    // /proc/self is owned by the effective user of the current process.
+    std::fs::metadata("/proc/self")
+        .map(|m| m.uid() == 0)
+        .unwrap_or(false)
+}
+
+fn has_unprivileged_userns() -> bool {
+    let disabled = |path: &str| {
+        std::fs::read_to_string(path)
+            .map(|value| value.trim() == "0")
+            .unwrap_or(false)
+    };
+    !disabled("/proc/sys/kernel/unprivileged_userns_clone")
+        && !disabled("/proc/sys/user/max_user_namespaces")
+}
+
+/// How the commands are run inside a rootfs.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// chroot as root, unshare with unprivileged user namespaces, and proot when they
+    /// are not available, or without binfmt_misc handler for a foreign target.
+    #[default]
+    Auto,
+    Chroot,
+    Unshare,
+    Proot,
+}
+
+impl Backend {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "auto" => Self::Auto,
+            "chroot" => Self::Chroot,
+            "unshare" => Self::Unshare,
+            "proot" => Self::Proot,
+            _ => panic!("Unknown exec backend {name:?} (expected auto, chroot, unshare or proot)"),
+        }
+    }
+}
+
+/// Return the path of a program found in PATH.
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .filter_map(|dir| PathBuf::from_path_buf(dir).ok())
+        .map(|dir| dir.join(name))
+        .find(|file| file.is_file())
+}
+
+fn proot() -> String {
+    std::env::var("PROOT").unwrap_or("proot".into())
+}
+
+fn binfmt_handler(arch: &str) -> String {
+    format!("/proc/sys/fs/binfmt_misc/qemu-{arch}")
+}
+
+/// Return true if the binaries of the target can run, natively or through binfmt_misc.
+fn can_execute(target: Option<&str>) -> bool {
+    !is_foreign(target) || Path::new(&binfmt_handler(&qemu_arch(target.unwrap()))).exists()
+}
+
+fn check_binfmt(target: Option<&str>) {
+    if can_execute(target) {
+        return;
+    }
+    let arch = qemu_arch(target.unwrap());
+    panic!(
+        "Can not run {arch} binaries on this {} host: binfmt_misc handler {} is not registered (install qemu-user-static, or use --exec-backend proot)",
+        host_arch(),
+        binfmt_handler(&arch)
+    );
+}
+
+/// Select the backend running commands in a rootfs for `--exec-backend auto`.
+fn select(target: Option<&str>) -> Backend {
+    let fallback = |reason: &str| {
+        if find_program(&proot()).is_none() {
+            panic!("Running commands in the rootfs {reason}, or proot");
+        }
+        Backend::Proot
+    };
+    if !can_execute(target) {
+        fallback("of a foreign target requires a binfmt_misc qemu-user handler (install qemu-user-static)")
+    } else if is_root() {
+        Backend::Chroot
+    } else if has_unprivileged_userns() {
+        Backend::Unshare
+    } else {
+        fallback("requires root privileges or unprivileged user namespaces")
+    }
+}
+
+/// Build a command running `program` with `rootfs` as root directory.
+///
+/// With the auto backend, chroot is used when running as root, otherwise an unprivileged user
+/// namespace is created with unshare. Foreign binaries are executed by qemu-user through
+/// binfmt_misc. proot is the fallback, running the foreign binaries with `-q qemu-<ARCH>`.
+pub fn rootfs_command(
+    rootfs: &Path,
+    target: Option<&str>,
+    backend: Backend,
+    program: &str,
+    args: &[String],
+) -> std::process::Command {
+    let mut cmd = match backend {
+        Backend::Auto => return rootfs_command(rootfs, target, select(target), program, args),
+        Backend::Chroot => {
+            check_binfmt(target);
+            message::status(format_args!(
+                "chroot {:#?} {} {}",
+                rootfs,
+                program,
+                args.join(" ")
+            ));
+            let mut cmd = std::process::Command::new("chroot");
+            cmd.arg(rootfs);
+            cmd
+        }
+        Backend::Unshare => {
+            check_binfmt(target);
+            message::status(format_args!(
+                "unshare --map-root-user --root {:#?} {} {}",
+                rootfs,
+                program,
+                args.join(" ")
+            ));
+            let mut cmd = std::process::Command::new("unshare");
+            cmd.arg("--map-root-user").arg("--root").arg(rootfs);
+            cmd
+        }
+        Backend::Proot => {
+            let proot = proot();
+            // The PATH of the command may be the one of the rootfs.
+            let program_path = find_program(&proot).unwrap_or(proot.as_str().into());
+            let mut cmd = std::process::Command::new(program_path);
+            // Fake the root user, like the user namespace of unshare.
+            cmd.arg("-0").arg("-r").arg(rootfs).arg("-w").arg("/");
+            let mut qemu = String::new();
+            if is_foreign(target) {
+                let arch = qemu_arch(target.unwrap());
+                let name = format!("qemu-{arch}");
+                let qemu_path = find_program(&name)
+                    .or_else(|| find_program(&format!("{name}-static")))
+                    .unwrap_or_else(|| {
+                        panic!("Can not run {arch} binaries with proot: {name} not found (install qemu-user)")
+                    });
+                qemu = format!(" -q {qemu_path}");
+                cmd.arg("-q").arg(qemu_path);
+            }
+            message::status(format_args!(
+                "{proot} -0 -r {:#?} -w /{qemu} {} {}",
+                rootfs,
+                program,
+                args.join(" ")
+            ));
+            cmd
+        }
+    };
+    cmd.arg(program).args(args);
+    cmd
+}
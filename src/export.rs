@@ -0,0 +1,155 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::process::Command;
+
+/// Container format of an exported rootfs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// A tarball for `wsl --import`.
+    Wsl,
+    /// A directory for `systemd-nspawn -D`, with its `.nspawn` settings file.
+    Nspawn,
+    /// A LXC container directory: its `config` and its `rootfs`.
+    Lxc,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "wsl" => Self::Wsl,
+            "nspawn" => Self::Nspawn,
+            "lxc" => Self::Lxc,
+            _ => panic!("Unknown export format {name:?} (expected wsl, nspawn or lxc)"),
+        }
+    }
+
+    /// Return the default output of the container of a package.
+    pub fn default_output(&self, name: &str, version: &str) -> PathBuf {
+        match self {
+            Self::Wsl => format!("{name}-{version}.tar.gz").into(),
+            Self::Nspawn | Self::Lxc => name.into(),
+        }
+    }
+}
+
+/// What the container runs: its init system, or the binary of the root package.
+fn entry_point(rootfs: &Path, name: &str) -> (bool, Option<String>) {
+    let boot = rootfs.join("sbin/init").exists() || rootfs.join("usr/sbin/init").exists();
+    let binary = format!("/usr/bin/{name}");
+    let exists = rootfs.join(&binary[1..]).is_file();
+    (boot, exists.then_some(binary))
+}
+
+/// LXC architecture name of a target triple.
+fn lxc_arch(target: &str) -> String {
+    match crate::exec::qemu_arch(target).as_str() {
+        "arm" => "armhf".into(),
+        "i386" => "i686".into(),
+        arch => arch.into(),
+    }
+}
+
+/// Copy the rootfs in a new directory, keeping the owners, modes and links.
+fn copy_rootfs(rootfs: &Path, dir: &Path) {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {dir}: {e:?}"));
+    message::status(format_args!("cp -a {rootfs}/. {dir}"));
+    crate::exec::run(Command::new("cp").arg("-a").arg(rootfs.join(".")).arg(dir));
+}
+
+fn write(file: &Path, content: &str) {
+    message::status(format_args!("Writing {file}"));
+    std::fs::write(file, content).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+}
+
+/// Return the `/etc/wsl.conf` of the rootfs: its hostname, and systemd as init.
+fn wsl_conf(rootfs: &Path, name: &str) -> String {
+    let mut conf = format!("[network]\nhostname = {name}\n");
+    if rootfs.join("usr/lib/systemd/systemd").exists() {
+        conf += "\n[boot]\nsystemd = true\n";
+    }
+    conf
+}
+
+/// Return the `<MACHINE>.nspawn` settings of the container.
+fn nspawn_settings(rootfs: &Path, name: &str) -> String {
+    let (boot, binary) = entry_point(rootfs, name);
+    let mut settings = String::from("[Exec]\n");
+    if boot {
+        settings += "Boot=yes\n";
+    } else if let Some(binary) = binary {
+        settings += &format!("Parameters={binary}\n");
+    }
+    settings += "\n[Network]\nPrivate=yes\n";
+    settings
+}
+
+/// Return the LXC `config` of the container.
+fn lxc_config(rootfs: &Path, name: &str, target: Option<&str>) -> String {
+    let (boot, binary) = entry_point(rootfs, name);
+    let arch = lxc_arch(target.unwrap_or(std::env::consts::ARCH));
+    let mut config = format!(
+        "lxc.include = /usr/share/lxc/config/common.conf\n\
+         lxc.arch = {arch}\n\
+         lxc.uts.name = {name}\n\
+         lxc.rootfs.path = dir:{rootfs}\n\
+         lxc.net.0.type = empty\n"
+    );
+    if let (false, Some(binary)) = (boot, binary) {
+        config += &format!("lxc.init.cmd = {binary}\n");
+    }
+    config
+}
+
+/// Export the rootfs in a container format, named after the root package.
+pub fn export(
+    rootfs: &Path,
+    outdir: &Path,
+    format: Format,
+    name: &str,
+    target: Option<&str>,
+    output: &Path,
+) {
+    match format {
+        Format::Wsl => {
+            // The generated files are added to the tarball, without modifying the rootfs.
+            let _ = std::fs::remove_dir_all(outdir);
+            std::fs::create_dir_all(outdir.join("etc"))
+                .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+            let mut extra = vec![];
+            if !rootfs.join("etc/wsl.conf").exists() {
+                write(&outdir.join("etc/wsl.conf"), &wsl_conf(rootfs, name));
+                extra.push("./etc/wsl.conf");
+            }
+
+            let mut tar = Command::new("tar");
+            tar.args(["--sort=name", "--owner=0", "--group=0", "--numeric-owner"]);
+            if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+                tar.arg(format!("--mtime=@{epoch}")).arg("--clamp-mtime");
+            }
+            tar.arg("-czf").arg(output).arg("-C").arg(rootfs).arg(".");
+            if !extra.is_empty() {
+                tar.arg("-C").arg(outdir).args(&extra);
+            }
+            message::status(format_args!("tar -C {rootfs} -czf {output} ."));
+            crate::exec::run(&mut tar);
+        }
+        Format::Nspawn => {
+            copy_rootfs(rootfs, output);
+            let machine = output.file_name().unwrap_or(name);
+            let settings = output.with_file_name(format!("{machine}.nspawn"));
+            write(&settings, &nspawn_settings(output, name));
+        }
+        Format::Lxc => {
+            let dir = output.join("rootfs");
+            copy_rootfs(rootfs, &dir);
+            let dir = dir
+                .canonicalize_utf8()
+                .unwrap_or_else(|e| panic!("Failed to resolve {dir}: {e:?}"));
+            write(&output.join("config"), &lxc_config(&dir, name, target));
+        }
+    }
+    message::status(format_args!("Exported {name} to {output}"));
+}
@@ -0,0 +1,212 @@
+use crate::boot;
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::fmt::Write;
+use std::process::Command;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitOptions {
+    pub kernel: PathBuf,
+    pub dtbs: Vec<PathBuf>,
+    pub image: boot::Image,
+    /// Load and entry address of the kernel, executed in place when unset.
+    pub load: Option<u64>,
+    pub entry: Option<u64>,
+    /// Directory of the `<name>.key` and `<name>.crt` signing keys.
+    pub key_dir: Option<PathBuf>,
+    pub key_name: String,
+    pub algo: String,
+}
+
+/// Parse a load or entry address, in hexadecimal (`0x...`) or decimal.
+pub fn parse_address(address: &str) -> u64 {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    parsed.unwrap_or_else(|_| panic!("Invalid address {address:?}"))
+}
+
+/// Return the U-Boot name of an architecture.
+fn uboot_arch(arch: &str) -> &'static str {
+    match arch {
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "x86_64" => "x86_64",
+        "i386" => "x86",
+        "riscv64" | "riscv32" => "riscv",
+        "ppc" | "ppc64" | "ppc64le" => "powerpc",
+        "mips" | "mipsel" => "mips",
+        "mips64" | "mips64el" => "mips64",
+        other => panic!("No U-Boot architecture for {other}"),
+    }
+}
+
+/// Return the compression of an image from its magic number, as named by mkimage.
+fn compression(file: &Path) -> &'static str {
+    let mut magic = [0u8; 6];
+    let _ = std::io::Read::read(
+        &mut std::fs::File::open(file).unwrap_or_else(|e| panic!("Failed to open {file}: {e:?}")),
+        &mut magic,
+    );
+    match magic {
+        [0x1f, 0x8b, ..] => "gzip",
+        [0x42, 0x5a, 0x68, ..] => "bzip2",
+        [0x5d, 0x00, 0x00, ..] => "lzma",
+        [0x89, 0x4c, 0x5a, 0x4f, ..] => "lzo",
+        [0x02, 0x21, 0x4c, 0x18, ..] => "lz4",
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => "zstd",
+        _ => "none",
+    }
+}
+
+/// Return an address as a device tree value, with two cells above 4 GiB.
+fn address_cells(address: u64) -> String {
+    match u32::try_from(address) {
+        Ok(address) => format!("<{address:#x}>"),
+        Err(_) => format!("<{:#x} {:#x}>", address >> 32, address & 0xffff_ffff),
+    }
+}
+
+/// Return the image tree source of the FIT: the kernel, the device trees and the ramdisk,
+/// hashed with sha256, and a configuration per device tree, signed with a key name.
+fn image_tree_source(options: &FitOptions, arch: &str, ramdisk: &Path, name: &str) -> String {
+    let wide = options.load.or(options.entry).unwrap_or_default() > u32::MAX as u64;
+    let hash = "\t\t\thash-1 {\n\t\t\t\talgo = \"sha256\";\n\t\t\t};\n";
+    let mut its = String::from("/dts-v1/;\n\n/ {\n");
+    let _ = writeln!(its, "\tdescription = \"{name}\";");
+    let _ = writeln!(its, "\t#address-cells = <{}>;\n", if wide { 2 } else { 1 });
+    its += "\timages {\n";
+
+    let (kind, load, entry) = match options.load {
+        Some(load) => ("kernel", load, options.entry.unwrap_or(load)),
+        None => ("kernel_noload", 0, 0),
+    };
+    let _ = write!(
+        its,
+        "\t\tkernel {{\n\
+         \t\t\tdescription = \"{}\";\n\
+         \t\t\tdata = /incbin/(\"{}\");\n\
+         \t\t\ttype = \"{kind}\";\n\
+         \t\t\tarch = \"{arch}\";\n\
+         \t\t\tos = \"linux\";\n\
+         \t\t\tcompression = \"{}\";\n\
+         \t\t\tload = {};\n\
+         \t\t\tentry = {};\n\
+         {hash}\t\t}};\n",
+        options.kernel.file_name().unwrap_or_default(),
+        options.kernel,
+        compression(&options.kernel),
+        address_cells(load),
+        address_cells(entry),
+    );
+    for (i, dtb) in options.dtbs.iter().enumerate() {
+        let _ = write!(
+            its,
+            "\t\tfdt-{} {{\n\
+             \t\t\tdescription = \"{}\";\n\
+             \t\t\tdata = /incbin/(\"{dtb}\");\n\
+             \t\t\ttype = \"flat_dt\";\n\
+             \t\t\tarch = \"{arch}\";\n\
+             \t\t\tcompression = \"none\";\n\
+             {hash}\t\t}};\n",
+            i + 1,
+            dtb.file_name().unwrap_or_default(),
+        );
+    }
+    let _ = write!(
+        its,
+        "\t\tramdisk {{\n\
+         \t\t\tdescription = \"{name} rootfs\";\n\
+         \t\t\tdata = /incbin/(\"{ramdisk}\");\n\
+         \t\t\ttype = \"ramdisk\";\n\
+         \t\t\tarch = \"{arch}\";\n\
+         \t\t\tos = \"linux\";\n\
+         \t\t\tcompression = \"{}\";\n\
+         {hash}\t\t}};\n\
+         \t}};\n\n",
+        compression(ramdisk),
+    );
+
+    its += "\tconfigurations {\n\t\tdefault = \"conf-1\";\n";
+    let dtbs: Vec<Option<&PathBuf>> = match options.dtbs.is_empty() {
+        true => vec![None],
+        false => options.dtbs.iter().map(Some).collect(),
+    };
+    for (i, dtb) in dtbs.iter().enumerate() {
+        let description = dtb.and_then(|x| x.file_stem()).unwrap_or(name);
+        let _ = writeln!(its, "\t\tconf-{} {{", i + 1);
+        let _ = writeln!(its, "\t\t\tdescription = \"{description}\";");
+        its += "\t\t\tkernel = \"kernel\";\n";
+        if dtb.is_some() {
+            let _ = writeln!(its, "\t\t\tfdt = \"fdt-{}\";", i + 1);
+        }
+        its += "\t\t\tramdisk = \"ramdisk\";\n";
+        if options.key_dir.is_some() {
+            let images = match dtb {
+                Some(_) => "\"kernel\", \"fdt\", \"ramdisk\"",
+                None => "\"kernel\", \"ramdisk\"",
+            };
+            let _ = write!(
+                its,
+                "\t\t\tsignature-1 {{\n\
+                 \t\t\t\talgo = \"{}\";\n\
+                 \t\t\t\tkey-name-hint = \"{}\";\n\
+                 \t\t\t\tsign-images = {images};\n\
+                 \t\t\t}};\n",
+                options.algo, options.key_name,
+            );
+        }
+        its += "\t\t};\n";
+    }
+    its += "\t};\n};\n";
+    its
+}
+
+/// Build a FIT image of the rootfs with mkimage, from its image tree source written
+/// next to it (`<output>.its`). The FIT is signed when a key directory is specified.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    target: Option<&str>,
+    options: &FitOptions,
+    name: &str,
+    output: &Path,
+) {
+    let arch = crate::exec::qemu_arch(target.unwrap_or(std::env::consts::ARCH));
+    let arch = uboot_arch(&arch);
+    std::fs::create_dir_all(outdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+    let ramdisk = match options.image {
+        boot::Image::Initramfs => boot::make_initramfs(rootfs, outdir, None),
+        boot::Image::Ext4 => boot::make_ext4(rootfs, outdir, None),
+    };
+    let absolute = |file: &Path| -> PathBuf {
+        file.canonicalize_utf8()
+            .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"))
+    };
+    let options = FitOptions {
+        kernel: absolute(&options.kernel),
+        dtbs: options.dtbs.iter().map(|x| absolute(x)).collect(),
+        ..options.clone()
+    };
+
+    let its = output.with_extension("its");
+    let source = image_tree_source(&options, arch, &absolute(&ramdisk), name);
+    std::fs::write(&its, source).unwrap_or_else(|e| panic!("Failed to write {its}: {e:?}"));
+
+    let mkimage = std::env::var("MKIMAGE").unwrap_or("mkimage".into());
+    let mut cmd = Command::new(&mkimage);
+    cmd.arg("-f").arg(&its);
+    if let Some(key_dir) = &options.key_dir {
+        cmd.arg("-k").arg(key_dir);
+    }
+    cmd.arg(output);
+    let key = options.key_dir.as_ref().map(|x| format!("-k {x} "));
+    message::status(format_args!(
+        "{mkimage} -f {its} {}{output}",
+        key.unwrap_or_default()
+    ));
+    crate::exec::run(&mut cmd);
+}
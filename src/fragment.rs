@@ -0,0 +1,229 @@
+use crate::message;
+use crate::plan::{Operation, Plan};
+use crate::shell_quote;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Ninja,
+    Make,
+}
+
+pub fn parse(spec: &str) -> (Format, PathBuf) {
+    let (format, path) = spec
+        .split_once(':')
+        .unwrap_or_else(|| panic!("--emit-build {spec} must be formatted as <ninja|make>:<FILE>"));
+    let format = match format {
+        "ninja" => Format::Ninja,
+        "make" => Format::Make,
+        other => panic!("Unknown build format {other:?} (expected ninja or make)"),
+    };
+    (format, PathBuf::from(path))
+}
+
+/// A copy or a symbolic link of the plan, which only depends on its source.
+enum Step<'a> {
+    Copy {
+        src: &'a Path,
+        dst: &'a Path,
+        mode: Option<&'a str>,
+    },
+    Symlink {
+        target: &'a Path,
+        link: &'a Path,
+    },
+}
+
+/// Return the paths of the rootfs modified by an operation.
+fn touched(operation: &Operation) -> Vec<&Path> {
+    match operation {
+        Operation::Comment { .. } | Operation::Hook { .. } => vec![],
+        Operation::Mkdir { path, .. }
+        | Operation::Write { path, .. }
+        | Operation::Edit { path, .. }
+        | Operation::Remove { path, .. }
+        | Operation::Backup { path, .. }
+        | Operation::Patch { path, .. }
+        | Operation::Run { path, .. } => vec![path],
+        Operation::Copy { dst, .. } => vec![dst],
+        Operation::Symlink { link, .. } => vec![link],
+        Operation::Hardlink { original, link } => vec![original, link],
+    }
+}
+
+/// Split the plan in the steps run independently, and the other operations, run in
+/// order by a final step.
+fn split(plan: &Plan) -> (Vec<Step<'_>>, Plan) {
+    let count = |path: &Path| {
+        plan.operations
+            .iter()
+            .flat_map(touched)
+            .filter(|x| *x == path)
+            .count()
+    };
+    let mut steps = vec![];
+    let mut rest = plan.clone();
+    rest.operations.clear();
+    for operation in &plan.operations {
+        match operation {
+            Operation::Copy { src, dst, mode } if count(dst) == 1 => steps.push(Step::Copy {
+                src,
+                dst,
+                mode: mode.as_deref(),
+            }),
+            Operation::Symlink { target, link } if count(link) == 1 => {
+                steps.push(Step::Symlink { target, link })
+            }
+            other => rest.operations.push(other.clone()),
+        }
+    }
+    (steps, rest)
+}
+
+/// Return the host files read by the operations of the final step.
+fn inputs(plan: &Plan) -> Vec<&Path> {
+    plan.operations
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::Copy { src, .. } => Some(src.as_path()),
+            Operation::Patch { patch, .. } => Some(patch.as_path()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ninja_escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "$ ")
+        .replace(':', "$:")
+}
+
+fn make_escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace(':', "\\:")
+}
+
+fn ninja(plan: &Plan, steps: &[Step], script: &Path, stamp: &Path, inputs: &[&Path]) -> String {
+    let rootfs = |path: &Path| format!("$destdir{}", ninja_escape(path.as_str()));
+    let mut ninja = format!(
+        "# Install of {}, generated by cargo-rootfs.
+destdir = {}
+
+rule rootfs_install
+  command = install -D -m $mode \"$in\" \"$out\"
+  description = INSTALL $out
+rule rootfs_copy
+  command = mkdir -p \"$$(dirname \"$out\")\" && rm -f \"$out\" && cp -pPR \"$in\" \"$out\"
+  description = INSTALL $out
+rule rootfs_symlink
+  command = mkdir -p \"$$(dirname \"$out\")\" && ln -sfn $target \"$out\"
+  description = SYMLINK $out
+rule rootfs_finish
+  command = DESTDIR=\"$destdir\" sh \"$in\" && touch \"$out\"
+  description = ROOTFS $destdir
+
+",
+        plan.package,
+        ninja_escape(plan.dst().as_str())
+    );
+    let mut outputs = vec![];
+    for step in steps {
+        match step {
+            Step::Copy { src, dst, mode } => {
+                let rule = if mode.is_some() {
+                    "rootfs_install"
+                } else {
+                    "rootfs_copy"
+                };
+                ninja += &format!(
+                    "build {}: {rule} {}\n",
+                    rootfs(dst),
+                    ninja_escape(src.as_str())
+                );
+                if let Some(mode) = mode {
+                    ninja += &format!("  mode = {mode}\n");
+                }
+                outputs.push(rootfs(dst));
+            }
+            Step::Symlink { target, link } => {
+                ninja += &format!("build {}: rootfs_symlink\n", rootfs(link));
+                ninja += &format!(
+                    "  target = {}\n",
+                    shell_quote(target.as_str()).replace('$', "$$")
+                );
+                outputs.push(rootfs(link));
+            }
+        }
+    }
+    outputs.extend(inputs.iter().map(|x| ninja_escape(x.as_str())));
+    let implicit = match outputs.is_empty() {
+        true => String::new(),
+        false => format!(" | {}", outputs.join(" ")),
+    };
+    ninja += &format!(
+        "build {}: rootfs_finish {}{implicit}\n",
+        ninja_escape(stamp.as_str()),
+        ninja_escape(script.as_str()),
+    );
+    ninja + &format!("build rootfs: phony {}\n", ninja_escape(stamp.as_str()))
+}
+
+fn make(plan: &Plan, steps: &[Step], script: &Path, stamp: &Path, inputs: &[&Path]) -> String {
+    let rootfs = |path: &Path| format!("$(DESTDIR){}", make_escape(path.as_str()));
+    let mut make = format!(
+        "# Install of {}, generated by cargo-rootfs.\nDESTDIR ?= {}\n\n.PHONY: rootfs\nrootfs: {}\n\n",
+        plan.package,
+        make_escape(plan.dst().as_str()),
+        make_escape(stamp.as_str())
+    );
+    let mut outputs = vec![];
+    for step in steps {
+        match step {
+            Step::Copy { src, dst, mode } => {
+                make += &format!("{}: {}\n", rootfs(dst), make_escape(src.as_str()));
+                make += &match mode {
+                    Some(mode) => format!("\tinstall -D -m {mode} \"$<\" \"$@\"\n"),
+                    None => {
+                        "\tmkdir -p \"$(@D)\" && rm -f \"$@\" && cp -pPR \"$<\" \"$@\"\n".into()
+                    }
+                };
+                outputs.push(rootfs(dst));
+            }
+            Step::Symlink { target, link } => {
+                let target = shell_quote(target.as_str()).replace('$', "$$");
+                make += &format!("{}:\n", rootfs(link));
+                make += &format!("\tmkdir -p \"$(@D)\" && ln -sfn {target} \"$@\"\n");
+                outputs.push(rootfs(link));
+            }
+        }
+    }
+    outputs.extend(inputs.iter().map(|x| make_escape(x.as_str())));
+    make += &format!(
+        "{}: {}{}\n\tDESTDIR=\"$(DESTDIR)\" sh \"$<\" && touch \"$@\"\n",
+        make_escape(stamp.as_str()),
+        make_escape(script.as_str()),
+        outputs.iter().map(|x| format!(" {x}")).collect::<String>()
+    );
+    make
+}
+
+/// Write a ninja or make fragment installing the plan: a step for each copy and symbolic
+/// link, depending on its source, then a final step running the other operations in order
+/// with a shell script written next to the fragment.
+pub fn write(plan: &Plan, format: Format, output: &Path) {
+    message::status(format_args!("Writing build fragment to {output}"));
+    let (steps, rest) = split(plan);
+    // The fragment may be included from another directory.
+    let cwd = PathBuf::try_from(std::env::current_dir().unwrap()).unwrap();
+    let script = cwd.join(format!("{output}.sh"));
+    let stamp = cwd.join(format!("{output}.stamp"));
+    rest.save_script(&script);
+    let inputs = inputs(&rest);
+    let data = match format {
+        Format::Ninja => ninja(plan, &steps, &script, &stamp, &inputs),
+        Format::Make => make(plan, &steps, &script, &stamp, &inputs),
+    };
+    std::fs::write(output, data).unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+}
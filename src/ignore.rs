@@ -0,0 +1,214 @@
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+
+/// Name of the file listing the paths to exclude from a copied directory.
+pub const IGNORE_FILE: &str = ".rootfsignore";
+
+/// A pattern of a `.rootfsignore` file, following the gitignore syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Directory containing the `.rootfsignore` file.
+    base: PathBuf,
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    /// The pattern contains a '/': it is matched against the path relative to `base`,
+    /// instead of the file name.
+    anchored: bool,
+}
+
+/// Append the rules of the `.rootfsignore` file of a directory, if any.
+pub fn load(dir: &Path, rules: &mut Vec<Rule>) {
+    let file = dir.join(IGNORE_FILE);
+    let content = match std::fs::read_to_string(&file) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    rules.extend(content.lines().filter_map(|line| parse(dir, line)));
+}
+
+/// Parse a pattern relative to a directory, None for the empty lines and comments.
+pub fn parse(dir: &Path, line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, line) = match line.strip_prefix('!') {
+        Some(line) => (true, line),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let anchored = line.contains('/');
+    Some(Rule {
+        base: dir.to_path_buf(),
+        pattern: line.trim_start_matches('/').to_string(),
+        negate,
+        dir_only,
+        anchored,
+    })
+}
+
+/// Check if a path is excluded by the rules: the last matching rule wins.
+pub fn is_ignored(rules: &[Rule], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for rule in rules {
+        if rule.dir_only && !is_dir {
+            continue;
+        }
+        let relative = match path.strip_prefix(&rule.base) {
+            Ok(x) => x.as_str(),
+            Err(_) => continue,
+        };
+        let text = if rule.anchored {
+            relative
+        } else {
+            path.file_name().unwrap_or(relative)
+        };
+        if glob(rule.pattern.as_bytes(), text.as_bytes()) {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+/// Match a text with a glob pattern: `*` and `?` do not match '/', `**` matches any
+/// number of directories, `[...]` matches a character class.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob(rest, text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == b'/' && glob(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let end = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=end).any(|i| glob(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => glob(rest, text),
+            _ => false,
+        },
+        [b'[', class @ ..] => {
+            let end = match class.iter().skip(1).position(|c| *c == b']') {
+                Some(end) => end + 1,
+                None => return matches!(text, [b'[', text @ ..] if glob(class, text)),
+            };
+            let (negate, set) = match &class[..end] {
+                [b'!' | b'^', set @ ..] => (true, set),
+                set => (false, set),
+            };
+            match text {
+                [c, text @ ..] if *c != b'/' => {
+                    let mut found = false;
+                    let mut i = 0;
+                    while i < set.len() {
+                        if i + 2 < set.len() && set[i + 1] == b'-' {
+                            found |= set[i] <= *c && *c <= set[i + 2];
+                            i += 3;
+                        } else {
+                            found |= set[i] == *c;
+                            i += 1;
+                        }
+                    }
+                    found != negate && glob(&class[end + 1..], text)
+                }
+                _ => false,
+            }
+        }
+        [b'\\', p, rest @ ..] | [p, rest @ ..] => match text {
+            [c, text @ ..] if c == p => glob(rest, text),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        lines
+            .iter()
+            .filter_map(|line| parse(Path::new("/src"), line))
+            .collect()
+    }
+
+    fn ignored(lines: &[&str], path: &str, is_dir: bool) -> bool {
+        is_ignored(&rules(lines), Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn parse_comments_and_escapes() {
+        assert!(rules(&["", "# comment", "   "]).is_empty());
+        let rule = &rules(&["\\#file"])[0];
+        assert_eq!(rule.pattern, "#file");
+        assert!(!rule.negate);
+        let rule = &rules(&["!/build/"])[0];
+        assert_eq!(rule.pattern, "build");
+        assert!(rule.negate && rule.dir_only && rule.anchored);
+    }
+
+    #[test]
+    fn star_and_question_mark() {
+        assert!(ignored(&["*.o"], "/src/a/b/main.o", false));
+        assert!(!ignored(&["*.o"], "/src/main.c", false));
+        assert!(ignored(&["main.?"], "/src/main.c", false));
+        assert!(!ignored(&["main.?"], "/src/main.cc", false));
+        // `*` and `?` do not match '/'.
+        assert!(!ignored(&["a*c"], "/src/a/c", false));
+        assert!(!ignored(&["a?c"], "/src/a/c", false));
+        assert!(!ignored(&["a/*.o"], "/src/a/b/main.o", false));
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(ignored(&["**/cache"], "/src/cache", true));
+        assert!(ignored(&["**/cache"], "/src/a/b/cache", true));
+        assert!(ignored(&["doc/**/*.md"], "/src/doc/README.md", false));
+        assert!(ignored(&["doc/**/*.md"], "/src/doc/a/b/README.md", false));
+        assert!(ignored(&["doc/**"], "/src/doc/a/b", false));
+        assert!(!ignored(&["doc/**/*.md"], "/src/a/doc/README.md", false));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(ignored(&["file[0-9]"], "/src/file7", false));
+        assert!(!ignored(&["file[0-9]"], "/src/filex", false));
+        assert!(ignored(&["file[!0-9]"], "/src/filex", false));
+        assert!(ignored(&["file[abc]"], "/src/fileb", false));
+        // An unterminated class matches a '['.
+        assert!(ignored(&["file[a"], "/src/file[a", false));
+    }
+
+    #[test]
+    fn anchoring() {
+        assert!(ignored(&["/out"], "/src/out", true));
+        assert!(!ignored(&["/out"], "/src/a/out", true));
+        assert!(ignored(&["out"], "/src/a/out", true));
+        assert!(ignored(&["a/out"], "/src/a/out", true));
+        assert!(!ignored(&["a/out"], "/src/b/a/out", true));
+        // The rules apply under the directory of their `.rootfsignore` only.
+        assert!(!ignored(&["*.o"], "/other/main.o", false));
+    }
+
+    #[test]
+    fn directory_only() {
+        assert!(ignored(&["build/"], "/src/a/build", true));
+        assert!(!ignored(&["build/"], "/src/a/build", false));
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        assert!(!ignored(&["*.log", "!keep.log"], "/src/keep.log", false));
+        assert!(ignored(&["*.log", "!keep.log"], "/src/drop.log", false));
+        assert!(ignored(&["!keep.log", "*.log"], "/src/keep.log", false));
+        assert!(!ignored(&["!*.log"], "/src/keep.log", false));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::boot;
+use crate::message;
+use camino::Utf8Path as Path;
+use std::process::Command;
+
+/// Filesystem of a rootfs image.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Filesystem {
+    #[default]
+    Ext4,
+    Squashfs,
+    Erofs,
+    Ubifs,
+    Jffs2,
+}
+
+impl Filesystem {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "ext4" => Self::Ext4,
+            "squashfs" => Self::Squashfs,
+            "erofs" => Self::Erofs,
+            "ubifs" => Self::Ubifs,
+            "jffs2" => Self::Jffs2,
+            _ => panic!(
+                "Unknown image filesystem {name:?} (expected ext4, squashfs, erofs, ubifs or jffs2)"
+            ),
+        }
+    }
+
+    /// Extension of the image: the UBIFS volume is written in a UBI image.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Ext4 => "ext4",
+            Self::Squashfs => "squashfs",
+            Self::Erofs => "erofs",
+            Self::Ubifs => "ubi",
+            Self::Jffs2 => "jffs2",
+        }
+    }
+
+    /// Whether the image is for a block device, rather than a raw flash.
+    pub fn is_block(&self) -> bool {
+        matches!(self, Self::Ext4 | Self::Squashfs | Self::Erofs)
+    }
+}
+
+/// Geometry of a raw NAND or NOR flash, for UBIFS and JFFS2.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Geometry {
+    /// Physical erase block size.
+    pub peb_size: Option<u64>,
+    /// Logical erase block size of UBI: the PEB size without the UBI headers.
+    pub leb_size: Option<u64>,
+    /// Minimum I/O unit of the flash: its page size, or its sub-page size for the UBI headers.
+    pub min_io_size: Option<u64>,
+    /// Maximum number of LEBs of the UBIFS volume, bounding its size.
+    pub max_leb_cnt: Option<u64>,
+}
+
+/// Whether the rootfs may be owned by its real owners. Otherwise it is owned by the user
+/// running cargo, and its files are given to root in the image.
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Run a mkfs tool, printing its command line.
+fn run_mkfs(cmd: &mut Command) {
+    let args: Vec<String> = cmd
+        .get_args()
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect();
+    message::status(format_args!(
+        "{} {}",
+        cmd.get_program().to_string_lossy(),
+        args.join(" ")
+    ));
+    crate::exec::run(cmd);
+}
+
+fn make_ubifs(rootfs: &Path, outdir: &Path, geometry: &Geometry, output: &Path) {
+    let required = |value: Option<u64>, flag: &str| {
+        value.unwrap_or_else(|| panic!("ubifs images require {flag}"))
+    };
+    let peb_size = required(geometry.peb_size, "--peb-size");
+    let leb_size = required(geometry.leb_size, "--leb-size");
+    let min_io_size = required(geometry.min_io_size, "--min-io-size");
+    let max_leb_cnt = required(geometry.max_leb_cnt, "--max-leb-cnt");
+    if leb_size >= peb_size {
+        panic!("--leb-size {leb_size} is not smaller than --peb-size {peb_size}");
+    }
+
+    let volume = outdir.join("rootfs.ubifs");
+    let _ = std::fs::remove_file(&volume);
+    let mut cmd = Command::new("mkfs.ubifs");
+    if !is_root() {
+        cmd.arg("-U");
+    }
+    run_mkfs(
+        cmd.arg("-r")
+            .arg(rootfs)
+            .arg("-m")
+            .arg(min_io_size.to_string())
+            .arg("-e")
+            .arg(leb_size.to_string())
+            .arg("-c")
+            .arg(max_leb_cnt.to_string())
+            .arg("-o")
+            .arg(&volume),
+    );
+
+    let config = outdir.join("ubinize.cfg");
+    let content = format!(
+        "[rootfs]\nmode=ubi\nimage={volume}\nvol_id=0\nvol_type=dynamic\nvol_name=rootfs\nvol_flags=autoresize\n"
+    );
+    std::fs::write(&config, content).unwrap_or_else(|e| panic!("Failed to write {config}: {e:?}"));
+    run_mkfs(
+        Command::new("ubinize")
+            .arg("-o")
+            .arg(output)
+            .arg("-p")
+            .arg(peb_size.to_string())
+            .arg("-m")
+            .arg(min_io_size.to_string())
+            .arg(&config),
+    );
+}
+
+/// Write the image of the rootfs in a filesystem, with the intermediate files in outdir.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    filesystem: Filesystem,
+    geometry: &Geometry,
+    output: &Path,
+) {
+    std::fs::create_dir_all(outdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+    let _ = std::fs::remove_file(output);
+    match filesystem {
+        Filesystem::Ext4 => {
+            let image = boot::make_ext4(rootfs, outdir, None);
+            std::fs::rename(&image, output)
+                .or_else(|_| std::fs::copy(&image, output).map(|_| ()))
+                .unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+        }
+        // mksquashfs and mkfs.erofs honor SOURCE_DATE_EPOCH for reproducible images.
+        Filesystem::Squashfs => {
+            let mut cmd = Command::new("mksquashfs");
+            cmd.arg(rootfs)
+                .arg(output)
+                .args(["-noappend", "-comp", "xz", "-no-progress"]);
+            if !is_root() {
+                cmd.arg("-all-root");
+            }
+            run_mkfs(&mut cmd);
+        }
+        Filesystem::Erofs => {
+            let mut cmd = Command::new("mkfs.erofs");
+            cmd.arg("-zlz4hc");
+            if !is_root() {
+                cmd.arg("--all-root");
+            }
+            run_mkfs(cmd.arg(output).arg(rootfs));
+        }
+        Filesystem::Ubifs => make_ubifs(rootfs, outdir, geometry, output),
+        Filesystem::Jffs2 => {
+            let mut cmd = Command::new("mkfs.jffs2");
+            cmd.arg("-r").arg(rootfs).arg("-o").arg(output).arg("-p");
+            if !is_root() {
+                cmd.arg("-U");
+            }
+            if let Some(peb_size) = geometry.peb_size {
+                cmd.arg("-e").arg(peb_size.to_string());
+            }
+            run_mkfs(&mut cmd);
+        }
+    }
+    let size = std::fs::metadata(output)
+        .unwrap_or_else(|e| panic!("{output} not written: {e:?}"))
+        .len();
+    message::status(format_args!(
+        "Wrote image {output} ({})",
+        message::human_size(size)
+    ));
+}
@@ -0,0 +1,135 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::os::unix::fs::{symlink, MetadataExt};
+
+/// Directory of the journal backups, relative to the rootfs.
+pub const JOURNAL_DIR: &str = ".cargo-rootfs-journal";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Undo {
+    Remove(PathBuf),
+    RemoveDir(PathBuf),
+    Restore { path: PathBuf, backup: PathBuf },
+}
+
+/// Undo journal of the modifications made to the rootfs.
+///
+/// Each destination path is saved before being modified for the first time:
+/// existing files or symbolic links are moved to a backup directory inside the rootfs
+/// (to stay on the same filesystem), new paths are simply removed on rollback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    dir: PathBuf,
+    saved: Vec<PathBuf>,
+    undo: Vec<Undo>,
+}
+
+impl Journal {
+    pub fn new(rootfs: &Path) -> Self {
+        Self {
+            dir: rootfs.join(JOURNAL_DIR),
+            saved: vec![],
+            undo: vec![],
+        }
+    }
+
+    /// Save a directory before it is created.
+    pub fn save_dir(&mut self, dir: &Path) {
+        if dir.exists() || self.saved.iter().any(|x| x == dir) {
+            return;
+        }
+        if let Some(parent) = dir.parent() {
+            self.save_dir(parent);
+        }
+        self.saved.push(dir.to_path_buf());
+        self.undo.push(Undo::RemoveDir(dir.to_path_buf()));
+    }
+
+    /// Save the current state of a path, before it is created, replaced or removed.
+    pub fn save(&mut self, path: &Path) {
+        self.save_path(path, false);
+    }
+
+    /// Save a copy of a file, before it is modified in place.
+    pub fn save_in_place(&mut self, path: &Path) {
+        self.save_path(path, true);
+    }
+
+    fn save_path(&mut self, path: &Path, in_place: bool) {
+        if self.saved.iter().any(|x| x == path) {
+            return;
+        }
+        if let Some(parent) = path.parent() {
+            self.save_dir(parent);
+        }
+        self.saved.push(path.to_path_buf());
+
+        let metadata = match path.symlink_metadata() {
+            Ok(x) => x,
+            Err(_) => {
+                self.undo.push(Undo::Remove(path.to_path_buf()));
+                return;
+            }
+        };
+        if metadata.is_dir() {
+            return;
+        }
+
+        let backup = self.dir.join(self.undo.len().to_string());
+        std::fs::create_dir_all(&self.dir).unwrap();
+        let linked = in_place && metadata.is_file() && metadata.nlink() > 1;
+        if linked && std::fs::rename(path, &backup).is_ok() {
+            // A hard link, e.g. made by --dedupe, is modified as a copy of its own: the other
+            // links keep their content, and are linked again on rollback.
+            std::fs::copy(&backup, path)
+                .unwrap_or_else(|e| panic!("Failed to copy {backup} to {path}: {e:?}"));
+            let _ = std::os::unix::fs::chown(path, Some(metadata.uid()), Some(metadata.gid()));
+        } else if in_place || std::fs::rename(path, &backup).is_err() {
+            // Modified in place, or not on the same filesystem: keep a copy instead.
+            if metadata.is_symlink() {
+                symlink(path.read_link_utf8().unwrap(), &backup).unwrap();
+            } else {
+                std::fs::copy(path, &backup)
+                    .unwrap_or_else(|e| panic!("Failed to backup {path} to {backup}: {e:?}"));
+            }
+        }
+        self.undo.push(Undo::Restore {
+            path: path.to_path_buf(),
+            backup,
+        });
+    }
+
+    /// Revert every modification recorded in the journal.
+    pub fn rollback(&mut self) {
+        for undo in self.undo.drain(..).rev() {
+            match undo {
+                Undo::Remove(path) => {
+                    message::status(format_args!("rm -f {:#?}", path));
+                    let _ = std::fs::remove_file(&path);
+                }
+                Undo::RemoveDir(path) => {
+                    message::status(format_args!("rmdir {:#?}", path));
+                    let _ = std::fs::remove_dir(&path);
+                }
+                Undo::Restore { path, backup } => {
+                    message::status(format_args!("mv {:#?} {:#?}", backup, path));
+                    let _ = std::fs::remove_file(&path);
+                    let _ = std::fs::create_dir_all(path.parent().unwrap());
+                    if std::fs::rename(&backup, &path).is_err() {
+                        eprintln!("Failed to restore {path} from {backup}");
+                    }
+                }
+            }
+        }
+        self.saved.clear();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+
+    /// Drop the backups, once the install succeeded.
+    pub fn commit(&mut self) {
+        self.undo.clear();
+        self.saved.clear();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,121 @@
+use camino::Utf8Path as Path;
+
+/// The position of a rule, or of one of its fields, in a crate manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Find the line of the `index`-th `[[package.metadata.rootfs]]` table of a manifest,
+/// or of one of its keys. The rules are only known from the JSON output of cargo
+/// metadata, so the manifest is read again to locate them, line by line. The line is
+/// unknown unless the manifest has one such header for each of the `rules`: a rule written
+/// otherwise (e.g. `rootfs = [{ ... }]`, or a dotted `metadata.rootfs` key) would shift them.
+pub fn find_rule(
+    manifest: &Path,
+    index: usize,
+    rules: usize,
+    field: Option<&str>,
+) -> Option<Location> {
+    let content = std::fs::read_to_string(manifest).ok()?;
+    // Report the manifests of the current directory with a short path.
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let file = manifest.strip_prefix(&cwd).unwrap_or(manifest).to_string();
+    let mut lines = vec![];
+    let mut multiline = false;
+    for (number, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        // Skip the content of the multi-line strings, like `append = """`.
+        let quotes = trimmed.matches("\"\"\"").count() + trimmed.matches("'''").count();
+        let in_string = multiline;
+        if quotes % 2 == 1 {
+            multiline = !multiline;
+        }
+        if !in_string && !trimmed.starts_with('#') {
+            lines.push((number + 1, trimmed));
+        }
+    }
+    let is_rule = |line: &str| {
+        let header = line.split('#').next().unwrap();
+        let header: String = header.chars().filter(|c| !c.is_whitespace()).collect();
+        header == "[[package.metadata.rootfs]]"
+    };
+    let tables: Vec<usize> = (0..lines.len()).filter(|i| is_rule(lines[*i].1)).collect();
+    if tables.len() != rules {
+        return None;
+    }
+    let start = *tables.get(index)?;
+    let location = |line| Some(Location { file, line });
+    let Some(field) = field else {
+        return location(lines[start].0);
+    };
+    for (number, line) in &lines[start + 1..] {
+        // The next table, or a line of a multi-line array starting with a nested array.
+        if line.starts_with('[') {
+            break;
+        }
+        let key = line.split(['=', '.']).next().unwrap().trim();
+        if key.trim_matches('"') == field {
+            return location(*number);
+        }
+    }
+    location(lines[start].0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(
+        name: &str,
+        manifest: &str,
+        index: usize,
+        rules: usize,
+        field: Option<&str>,
+    ) -> Option<usize> {
+        let file =
+            std::env::temp_dir().join(format!("cargo-rootfs-{}-{name}.toml", std::process::id()));
+        let file = camino::Utf8PathBuf::from_path_buf(file).unwrap();
+        std::fs::write(&file, manifest).unwrap();
+        let location = find_rule(&file, index, rules, field);
+        std::fs::remove_file(&file).unwrap();
+        location.map(|x| x.line)
+    }
+
+    const TABLES: &str = r#"[package]
+name = "app"
+
+[[package.metadata.rootfs]]
+source = "a"
+destination = "/a"
+
+# [[package.metadata.rootfs]]
+[[package.metadata.rootfs]]
+append = """
+[[package.metadata.rootfs]]
+"""
+alternative.name = "b"
+"#;
+
+    #[test]
+    fn find_tables_and_keys() {
+        assert_eq!(find("tables", TABLES, 0, 2, None), Some(4));
+        assert_eq!(find("keys", TABLES, 0, 2, Some("destination")), Some(6));
+        assert_eq!(find("dotted", TABLES, 1, 2, Some("alternative")), Some(13));
+        assert_eq!(find("missing", TABLES, 1, 2, Some("source")), Some(9));
+    }
+
+    #[test]
+    fn unknown_line_of_inline_rules() {
+        let manifest = "[package.metadata]\nrootfs = [{ source = \"a\" }, { source = \"b\" }]\n";
+        assert_eq!(find("inline", manifest, 1, 2, None), None);
+        assert_eq!(find("count", TABLES, 0, 3, None), None);
+    }
+}
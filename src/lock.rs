@@ -0,0 +1,49 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use std::os::fd::AsRawFd;
+
+/// What to do when the destination is locked by another cargo-rootfs.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Mode {
+    /// Fail immediately.
+    #[default]
+    Fail,
+    /// Block until it is unlocked (--wait).
+    Wait,
+    /// Do not lock the destination (--no-lock).
+    Disabled,
+}
+
+/// An advisory lock of a destination directory, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _dir: std::fs::File,
+}
+
+/// Take the exclusive flock of a destination directory, created if missing, so two
+/// cargo-rootfs can not write the same rootfs, its manifests and its state file at once.
+pub fn lock(dst: &Path, mode: Mode) -> Option<Lock> {
+    if mode == Mode::Disabled {
+        return None;
+    }
+    std::fs::create_dir_all(dst)
+        .unwrap_or_else(|e| panic!("Failed to create directory {dst}: {e:?}"));
+    let dir = std::fs::File::open(dst).unwrap_or_else(|e| panic!("Failed to open {dst}: {e:?}"));
+    let fd = dir.as_raw_fd();
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            panic!("Failed to lock {dst}: {error:?}");
+        }
+        if mode == Mode::Fail {
+            panic!("{dst} is locked by another cargo-rootfs (use --wait to wait for it)");
+        }
+        message::status(format_args!("Blocking waiting for the lock of {dst}"));
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            let error = std::io::Error::last_os_error();
+            panic!("Failed to lock {dst}: {error:?}");
+        }
+    }
+    message::debug(format_args!("Locked {dst}"));
+    Some(Lock { _dir: dir })
+}
@@ -2,9 +2,67 @@ use camino::Utf8Path as Path;
 use camino::Utf8PathBuf as PathBuf;
 use colored::Colorize;
 use serde::Deserialize;
+use serde_json::json;
 use serde_json::value::Value;
+use std::cell::RefCell;
 use std::fs::Permissions;
-use std::os::unix::fs::{symlink, PermissionsExt};
+use std::io::IsTerminal;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+mod audit;
+mod boot;
+mod config;
+mod daemon;
+mod debuginfo;
+mod delta;
+mod deploy;
+mod disk;
+mod elf;
+mod etc;
+mod exec;
+mod export;
+mod fit;
+mod fragment;
+mod ignore;
+mod image;
+mod journal;
+mod location;
+mod lock;
+mod manifest;
+mod mender;
+mod message;
+mod netboot;
+mod ostree;
+mod plan;
+mod profile;
+mod rauc;
+mod runner;
+mod sbom;
+mod sign;
+mod state;
+mod swupdate;
+mod verity;
+mod watch;
+mod whiteout;
+
+/// The files written by depmod in `/lib/modules/<VERSION>`.
+const DEPMOD_FILES: [&str; 12] = [
+    "modules.alias",
+    "modules.alias.bin",
+    "modules.builtin.alias.bin",
+    "modules.builtin.bin",
+    "modules.builtin.modinfo.bin",
+    "modules.dep",
+    "modules.dep.bin",
+    "modules.devname",
+    "modules.softdep",
+    "modules.symbols",
+    "modules.symbols.bin",
+    "modules.weakdep",
+];
+
+/// The rootfs of the rules without a `rootfs` field.
+const MAIN_ROOTFS: &str = "main";
 
 #[derive(Default, Debug, Copy, Clone, PartialEq)]
 enum Command {
@@ -12,7 +70,49 @@ enum Command {
     None,
     Install,
     Release,
-    //Info,
+    Deploy,
+    Watch,
+    Run,
+    Shell,
+    Boot,
+    Image,
+    Disk,
+    Fit,
+    Swu,
+    Rauc,
+    Mender,
+    Ostree,
+    Delta,
+    Export,
+    Netboot,
+    Uninstall,
+    Verify,
+    Diff,
+    Audit,
+    Size,
+    Info,
+    Check,
+    Plan,
+    Apply,
+}
+
+impl Command {
+    /// Return the name of a subcommand writing an image of the rootfs, installed in its
+    /// staging directory target/rootfs-<NAME>.
+    fn staging_name(&self) -> Option<&'static str> {
+        match self {
+            Command::Image => Some("image"),
+            Command::Disk => Some("disk"),
+            Command::Fit => Some("fit"),
+            Command::Swu => Some("swu"),
+            Command::Rauc => Some("rauc"),
+            Command::Mender => Some("mender"),
+            Command::Ostree => Some("ostree"),
+            Command::Delta => Some("delta"),
+            Command::Export => Some("export"),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -21,14 +121,135 @@ struct CargoRootfsArgs {
 
     // Options:
     dst: Option<PathBuf>,
-    altsrc: Option<PathBuf>,
+    /// The alternative source directories, by priority.
+    altsrc: Vec<PathBuf>,
+    /// The crates which must have a source in the alternative source directories.
+    require_altsrc: Vec<String>,
+    /// The named rootfs directories of `--dest <NAME>=<DIRECTORY>`.
+    rootfs_dsts: Vec<(String, PathBuf)>,
     target: Option<String>,
     all_bins_only: bool,
     bins_only: Vec<String>,
     lib_only: bool,
+    tests: bool,
+    benches: bool,
+    no_deps: bool,
+    deps_only: bool,
     init_startdir: Option<PathBuf>,
     init_stopdir: Option<PathBuf>,
+    package: Option<String>,
+    sbom: Option<String>,
+    manifest: Option<String>,
+    licenses: bool,
+    embed_manifest: Option<PathBuf>,
+    no_prune: bool,
+    backup: Option<String>,
+    stats: Option<PathBuf>,
+    emit_script: Option<PathBuf>,
+    emit_build: Option<String>,
+    rootfs_profile: Option<String>,
+    kernel_version: Option<String>,
+    kernel_build_dir: Option<PathBuf>,
+    /// The install plan written by `plan -o`, or read by `apply`.
+    plan: Option<PathBuf>,
+    message_format: Option<message::Format>,
+    color: message::Color,
+    force: bool,
+    lock: lock::Mode,
+    split_debug: Option<PathBuf>,
+    debuginfod: Option<PathBuf>,
+    compress_debug: Option<debuginfo::Compression>,
+    scrub_notes: bool,
+    jobs: Option<usize>,
+    checksum: bool,
+    dedupe: bool,
+    symlink_style: SymlinkStyle,
+    strict: bool,
+    keep_going: bool,
+    interp: Option<PathBuf>,
+    sysroot: Option<PathBuf>,
+    ldconfig: bool,
+    build_info: bool,
+    base: Option<PathBuf>,
+    whiteout: Option<whiteout::Format>,
     verbose: u32,
+    quiet: bool,
+
+    // Deploy Options:
+    host: Option<String>,
+    deploy: Option<String>,
+    deploy_prefix: Option<String>,
+    delta: bool,
+    restart: bool,
+
+    // Run Options:
+    run_args: Vec<String>,
+    exec_backend: exec::Backend,
+
+    // Boot Options:
+    kernel: Option<PathBuf>,
+    dtb: Vec<PathBuf>,
+    boot_image: boot::Image,
+    boot_cmd: Option<String>,
+    boot_timeout: Option<u64>,
+
+    // Verity Options:
+    verity: bool,
+    verity_hash: verity::Hash,
+    verity_key: Option<PathBuf>,
+    verity_cert: Option<PathBuf>,
+
+    // Signing Options:
+    sign: Option<sign::Signer>,
+    sign_elfs: bool,
+
+    // Image Options:
+    image: Option<PathBuf>,
+    filesystem: image::Filesystem,
+    peb_size: Option<u64>,
+    leb_size: Option<u64>,
+    min_io_size: Option<u64>,
+    max_leb_cnt: Option<u64>,
+
+    // Disk Options:
+    layout: Option<PathBuf>,
+    disk: Option<PathBuf>,
+
+    // FIT Options:
+    fit: Option<PathBuf>,
+    fit_load: Option<u64>,
+    fit_entry: Option<u64>,
+    fit_key_dir: Option<PathBuf>,
+    fit_key_name: Option<String>,
+    fit_algo: Option<String>,
+
+    // Swupdate Options:
+    swu: Option<PathBuf>,
+
+    // RAUC Options:
+    rauc: Option<PathBuf>,
+    cert: Option<String>,
+    key: Option<String>,
+
+    // Mender Options:
+    mender: Option<PathBuf>,
+
+    // OSTree Options:
+    repo: Option<PathBuf>,
+    branch: Option<String>,
+
+    // Delta Options:
+    delta_dir: Option<PathBuf>,
+    from: Option<PathBuf>,
+
+    // Export Options:
+    export_format: Option<export::Format>,
+    export: Option<PathBuf>,
+
+    // Netboot Options:
+    export_dir: Option<PathBuf>,
+    tftp_dir: Option<PathBuf>,
+    server: Option<String>,
 
     // Feature Selection:
     features: Vec<cargo_metadata::CargoOpt>,
@@ -36,6 +257,8 @@ struct CargoRootfsArgs {
     // Manifest Options:
     manifest_path: Option<PathBuf>,
     lockfile_path: Option<String>,
+    target_dir: Option<PathBuf>,
+    artifact_dir: Option<PathBuf>,
     locked: bool,
     offline: bool,
     frozen: bool,
@@ -45,20 +268,224 @@ struct CargoRootfsArgs {
 struct CargoRootfs {
     command: Command,
     dst: PathBuf,
-    altsrc: Option<PathBuf>,
+    altsrc: Vec<PathBuf>,
+    /// The sources of the rules found in the alternative source directories.
+    overrides: RefCell<Vec<manifest::Override>>,
     metadata: cargo_metadata::Metadata,
+    /// The root package, whose name is used for the manifest of the rootfs.
+    root: cargo_metadata::PackageId,
     outdir: PathBuf,
     init_startdir: PathBuf,
     init_stopdir: PathBuf,
+    target: Option<String>,
+    hooks: RefCell<Vec<Hook>>,
+    /// The candidates of the alternatives, linked once all the rules are installed.
+    alternatives: RefCell<Vec<AlternativeCandidate>>,
+    /// The patterns of the `remove` rules, matched against the whole rootfs.
+    removals: RefCell<Vec<ignore::Rule>>,
+    /// The base image of the rootfs layer, whose removed paths are hidden by whiteouts.
+    base: Option<PathBuf>,
+    whiteout: whiteout::Format,
+    whiteouts: RefCell<Vec<manifest::Whiteout>>,
+    services: RefCell<Vec<String>>,
+    installed: RefCell<Vec<InstalledFile>>,
+    dry_run: bool,
+    exec_backend: exec::Backend,
+    changes: RefCell<Vec<(PathBuf, Change)>>,
+    journal: RefCell<journal::Journal>,
+    backup: Option<Backup>,
+    started: std::time::Instant,
+    jobs: usize,
+    checksum: bool,
+    offline: bool,
+    copies: RefCell<Vec<PendingCopy>>,
+    /// The files which a diff would copy, whose new content is checked by the patch rules.
+    diff_copies: RefCell<Vec<PendingCopy>>,
+    strips: RefCell<Vec<PathBuf>>,
+    /// The release binaries keeping their symbols, whose --scrub-notes sections are removed.
+    scrubs: RefCell<Vec<PathBuf>>,
+    /// The `--version` of the strip program, read once.
+    strip_version: std::cell::OnceCell<String>,
+    /// Whether the ELF files installed by the current rule are stripped (`strip = true`).
+    strip_rule: std::cell::Cell<bool>,
+    /// The build-id tree of the debug information of the stripped binaries, and its
+    /// debuginfod export.
+    debug_dir: Option<PathBuf>,
+    debuginfod: Option<PathBuf>,
+    /// The binaries whose debug sections are compressed with --compress-debug, instead of
+    /// being stripped.
+    compress_debug: Option<debuginfo::Compression>,
+    debug_compressions: RefCell<Vec<PathBuf>>,
+    /// Remove the identifying sections of the release binaries (--scrub-notes).
+    scrub_notes: bool,
+    /// Manifest entries of the locally modified configuration files, kept as is.
+    conffiles: RefCell<Vec<manifest::Entry>>,
+    interp: Option<PathBuf>,
+    sysroot: PathBuf,
+    ldconfig: bool,
+    symlink_style: SymlinkStyle,
+    /// The cargo build command line, run again to find the OUT_DIR of the build scripts.
+    build_command: Vec<String>,
+    build_messages: RefCell<Option<Vec<cargo_metadata::Message>>>,
+    keep_going: bool,
+    /// The errors of the rules, bins and hooks which failed with --keep-going.
+    failures: RefCell<Vec<String>>,
+    /// The operations of the install, recorded for `cargo rootfs plan` and --emit-script.
+    plan: Option<RefCell<plan::Plan>>,
+    /// Only record the install plan: the hooks are not run, the rootfs is restored.
+    planning: bool,
+    /// Skip the rules of the dependencies (--no-deps), or of the workspace (--deps-only).
+    no_deps: bool,
+    deps_only: bool,
+    /// The profile selected with --rootfs-profile.
+    profile: Option<String>,
+    /// The name of the rootfs, selecting its rules: `main`, or the name of a
+    /// `--dest <NAME>=<DIRECTORY>`.
+    rootfs: String,
+    /// The release of the kernel of the modules, and its build directory.
+    kernel_version: Option<String>,
+    kernel_build_dir: Option<PathBuf>,
+    /// The kernel modules installed by the rules, indexed by depmod.
+    kernel_modules: RefCell<Vec<PathBuf>>,
+    /// The inputs of the install, recorded in its state file.
+    inputs: RefCell<state::Inputs>,
+    /// The firmware blobs installed, with their sha256 and package, to link the identical
+    /// blobs of the other packages to them.
+    firmware: RefCell<Vec<(String, PathBuf, cargo_metadata::PackageId)>>,
+}
+
+/// A file copy, run in parallel with the other ones once every rule is interpreted.
+#[derive(Debug, Clone, PartialEq)]
+struct PendingCopy {
+    src: PathBuf,
+    dst: PathBuf,
+    mode: Option<u32>,
+    /// The package and directory of a directory install, whose progress is shown apart.
+    group: Option<String>,
+}
+
+/// How to save the destination files before overwriting them.
+#[derive(Debug, Clone, PartialEq)]
+enum Backup {
+    Suffix(String),
+    Dir(PathBuf),
+}
+
+/// How the targets of the symbolic link rules are written.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SymlinkStyle {
+    /// As specified by the rule.
+    #[default]
+    Keep,
+    /// Relative to the directory of the link, to work wherever the rootfs is mounted.
+    Relative,
+    /// Absolute path in the rootfs image.
+    Absolute,
+}
+
+impl SymlinkStyle {
+    fn parse(name: &str) -> Self {
+        match name {
+            "keep" => Self::Keep,
+            "relative" => Self::Relative,
+            "absolute" => Self::Absolute,
+            other => {
+                panic!("Unknown symlink style {other:?} (expected relative, absolute or keep)")
+            }
+        }
+    }
+
+    /// Convert the target of a link located in `linkdir`, both absolute paths in the image.
+    fn convert(&self, target: &Path, linkdir: &Path) -> PathBuf {
+        match self {
+            Self::Keep => target.to_path_buf(),
+            Self::Relative if target.is_relative() => target.to_path_buf(),
+            Self::Relative => relative_path(&normalize_path(target), &normalize_path(linkdir)),
+            Self::Absolute => normalize_path(&linkdir.join(target)),
+        }
+    }
+}
+
+/// How an install changes a rootfs path, computed by `cargo rootfs diff`.
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Added,
+    Replaced(String),
+    Untouched,
+    Removed,
+}
+
+/// A file, symbolic link or directory created in the rootfs.
+#[derive(Debug, Clone, PartialEq)]
+struct InstalledFile {
+    path: PathBuf,
+    kind: manifest::Kind,
+    package: cargo_metadata::PackageId,
+    rule: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Hook {
+    package: String,
+    manifest_dir: PathBuf,
+    command: String,
+    run_in_rootfs: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct InitScript {
     start: Option<u32>,
     stop: Option<u32>,
 }
 
+/// A generic command provided by several packages, linked through
+/// `/etc/alternatives/<NAME>` to the binary of highest priority.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Alternative {
+    name: String,
+    priority: i32,
+    /// The generic command (default: /usr/bin/<NAME>).
+    link: Option<PathBuf>,
+}
+
+impl Alternative {
+    fn check(&self) -> Option<String> {
+        let name = &self.name;
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Some(format!("alternative name {name:?} is not a file name"));
+        }
+        if self.link.as_ref().is_some_and(|x| !x.is_absolute()) {
+            return Some("alternative link must be an absolute path".into());
+        }
+        None
+    }
+}
+
+/// A candidate of an alternative, installed by a rule.
+#[derive(Debug, Clone, PartialEq)]
+struct AlternativeCandidate {
+    alternative: Alternative,
+    /// The path of the candidate in the image.
+    path: PathBuf,
+    package: cargo_metadata::PackageId,
+}
+
+/// A launcher script setting the environment and the arguments of a binary,
+/// installed as `<NAME>.real`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Wrapper {
+    #[serde(default)]
+    env: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct CargoRootfsRule {
     destination: Option<PathBuf>,
     source: Option<PathBuf>,
@@ -66,6 +493,284 @@ struct CargoRootfsRule {
     symbolic: Option<bool>,
     root_crate_symlink: Option<bool>,
     init: Option<InitScript>,
+    hook: Option<String>,
+    run_in_rootfs: Option<bool>,
+    mirror: Option<bool>,
+    command: Option<String>,
+    patch: Option<PathBuf>,
+    conffile: Option<bool>,
+    strip: Option<bool>,
+    bin: Option<String>,
+    wrapper: Option<Wrapper>,
+    alternative: Option<Alternative>,
+    symlink_style: Option<SymlinkStyle>,
+    daemon: Option<daemon::Daemon>,
+    append: Option<String>,
+    ensure_line: Option<String>,
+    replace: Option<String>,
+    comment: Option<String>,
+    url: Option<String>,
+    sha256: Option<String>,
+    /// Only install the rule with one of these rootfs profiles.
+    profiles: Option<Vec<String>>,
+    /// The named rootfs of the rule (default: main).
+    rootfs: Option<String>,
+    /// An artifact dependency (`artifact = "bin"`), whose binaries are installed.
+    install_artifact: Option<String>,
+    /// The directory of the source in the alternative source directories (default: the
+    /// name of the package).
+    altsrc_key: Option<PathBuf>,
+    /// Install a kernel module, in `/lib/modules/<VERSION>/extra` by default.
+    kernel_module: Option<bool>,
+    /// Install firmware blobs, in `/lib/firmware` by default.
+    firmware: Option<bool>,
+    /// Compress the firmware blobs for the kernel firmware loader: `xz` or `zst`.
+    compress: Option<String>,
+    /// Patterns of the rootfs paths to remove once everything is installed.
+    remove: Option<Vec<String>>,
+}
+
+impl CargoRootfsRule {
+    /// Parse a rule, returning the error with the field it is about, found by parsing
+    /// the fields one by one since serde_json does not report it.
+    fn parse(value: &Value) -> Result<Self, (Option<String>, String)> {
+        let e = match serde_json::from_value(value.clone()) {
+            Ok(rule) => return Ok(rule),
+            Err(e) => did_you_mean(&e.to_string()),
+        };
+        let field = value.as_object().and_then(|fields| {
+            fields.iter().find_map(|(key, value)| {
+                let field = Value::Object([(key.clone(), value.clone())].into_iter().collect());
+                serde_json::from_value::<Self>(field)
+                    .is_err()
+                    .then(|| key.clone())
+            })
+        });
+        Err((field, e))
+    }
+
+    /// Return the errors of the rule, with the field they are about, except the missing
+    /// sources and the destination collisions, checked against the other rules by
+    /// `cargo rootfs check`.
+    fn validate(&self) -> Vec<(Option<&'static str>, String)> {
+        let mut errors = vec![];
+        let kinds = [
+            ("root_crate_symlink", self.root_crate_symlink == Some(true)),
+            ("hook", self.hook.is_some()),
+            ("patch", self.patch.is_some()),
+            (
+                "append",
+                self.append.is_some() || self.ensure_line.is_some(),
+            ),
+            ("daemon", self.daemon.is_some()),
+            ("bin", self.bin.is_some()),
+            ("source", self.source.is_some()),
+            ("url", self.url.is_some()),
+            ("command", self.command.is_some()),
+            ("install_artifact", self.install_artifact.is_some()),
+            ("remove", self.remove.is_some()),
+        ];
+        let kinds: Vec<&str> = kinds
+            .iter()
+            .filter(|(_, present)| *present)
+            .map(|(name, _)| *name)
+            .collect();
+        if kinds.len() > 1 {
+            let error = format!("{} are mutually exclusive", kinds.join(", "));
+            errors.push((Some(kinds[1]), error));
+        }
+        let kind = kinds.first().copied();
+        let installs_file = matches!(kind, Some("source" | "url" | "command"));
+
+        if let Some(permissions) = &self.permissions {
+            if u32::from_str_radix(permissions, 8).is_err() {
+                let error = format!("permissions {permissions:?} is not an octal number");
+                errors.push((Some("permissions"), error));
+            }
+        }
+        if self.destination.is_none()
+            && self.kernel_module != Some(true)
+            && self.firmware != Some(true)
+            && (installs_file || matches!(kind, Some("patch" | "append")))
+        {
+            errors.push((None, "missing destination".into()));
+        }
+        if kind.is_none() {
+            errors.push((None, "missing source".into()));
+        }
+        if self.url.is_some() && self.sha256.is_none() {
+            errors.push((Some("url"), "url requires a sha256".into()));
+        }
+        if let Some(compress) = &self.compress {
+            if !matches!(compress.as_str(), "xz" | "zst") {
+                let error = format!("compress {compress:?} is not xz or zst");
+                errors.push((Some("compress"), error));
+            }
+        }
+        if let Some(error) = self.alternative.as_ref().and_then(|x| x.check()) {
+            errors.push((Some("alternative"), error));
+        }
+        if let Some(sha256) = &self.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                let error = format!("sha256 {sha256:?} is not a sha256 digest");
+                errors.push((Some("sha256"), error));
+            }
+        }
+        let requirements = [
+            (
+                "symbolic",
+                self.symbolic == Some(true),
+                kind == Some("source"),
+            ),
+            ("mirror", self.mirror == Some(true), kind == Some("source")),
+            ("conffile", self.conffile == Some(true), installs_file),
+            (
+                "wrapper",
+                self.wrapper.is_some(),
+                installs_file || kind == Some("bin"),
+            ),
+            (
+                "alternative",
+                self.alternative.is_some(),
+                installs_file || kind == Some("bin"),
+            ),
+            (
+                "strip",
+                self.strip.is_some(),
+                installs_file || matches!(kind, Some("bin" | "install_artifact")),
+            ),
+            ("init", self.init.is_some(), installs_file),
+            (
+                "run_in_rootfs",
+                self.run_in_rootfs.is_some(),
+                kind == Some("hook"),
+            ),
+            (
+                "replace",
+                self.replace.is_some(),
+                self.ensure_line.is_some(),
+            ),
+            ("comment", self.comment.is_some(), self.append.is_some()),
+            (
+                "symlink_style",
+                self.symlink_style.is_some(),
+                self.symbolic == Some(true),
+            ),
+            (
+                "kernel_module",
+                self.kernel_module.is_some(),
+                kind == Some("source") && self.symbolic != Some(true),
+            ),
+            (
+                "firmware",
+                self.firmware.is_some(),
+                installs_file && self.symbolic != Some(true) && self.kernel_module.is_none(),
+            ),
+            (
+                "compress",
+                self.compress.is_some(),
+                self.firmware == Some(true),
+            ),
+            (
+                "altsrc_key",
+                self.altsrc_key.is_some(),
+                matches!(kind, Some("source" | "patch")),
+            ),
+            (
+                "sha256",
+                self.sha256.is_some(),
+                matches!(kind, Some("url" | "source")) && self.symbolic != Some(true),
+            ),
+        ];
+        for (name, present, allowed) in requirements {
+            if present && !allowed {
+                let kind = kind.unwrap_or("this");
+                errors.push((
+                    Some(name),
+                    format!("{name} is not supported by {kind} rules"),
+                ));
+            }
+        }
+        errors
+    }
+
+    /// Describe what the rule installs, for `cargo rootfs info`.
+    fn describe(&self) -> String {
+        let or_none = |path: &Option<PathBuf>| match path {
+            Some(path) => path.to_string(),
+            None => "?".into(),
+        };
+        let dst = match (&self.destination, self.firmware) {
+            (None, Some(true)) => "/lib/firmware/".into(),
+            (destination, _) => or_none(destination),
+        };
+        if self.root_crate_symlink == Some(true) {
+            return format!(
+                "root_crate_symlink in {}",
+                self.destination.as_deref().unwrap_or("/usr/bin".into())
+            );
+        }
+        if let Some(hook) = &self.hook {
+            return format!("hook {hook:?}");
+        }
+        if let Some(remove) = &self.remove {
+            return format!("remove {}", remove.join(" "));
+        }
+        if let Some(patch) = &self.patch {
+            return format!("patch {patch} -> {dst}");
+        }
+        if self.append.is_some() || self.ensure_line.is_some() {
+            return format!("edit {dst}");
+        }
+        if let Some(daemon) = &self.daemon {
+            return format!("daemon {} (/usr/bin/{})", daemon.name, daemon.bin());
+        }
+        if let Some(bin) = &self.bin {
+            return format!("bin {bin}");
+        }
+        if let Some(artifact) = &self.install_artifact {
+            let dir = self.destination.as_deref().unwrap_or("/usr/bin".into());
+            return format!("artifact {artifact} in {dir}");
+        }
+        let src = match (&self.source, &self.url, &self.command) {
+            (Some(source), _, _) => source.to_string(),
+            (None, Some(url), _) => url.clone(),
+            (None, None, Some(command)) => format!("$({command})"),
+            (None, None, None) => "?".into(),
+        };
+        let mut description = match self.symbolic == Some(true) {
+            true => format!("symlink {dst} -> {src}"),
+            false => format!("{src} -> {dst}"),
+        };
+        if let Some(permissions) = &self.permissions {
+            description += &format!(" ({permissions})");
+        }
+        if let Some(init) = &self.init {
+            description += " init";
+            if let Some(start) = init.start {
+                description += &format!(" S{start}");
+            }
+            if let Some(stop) = init.stop {
+                description += &format!(" K{stop}");
+            }
+        }
+        let flags = [
+            ("mirror", self.mirror),
+            ("conffile", self.conffile),
+            ("strip", self.strip),
+            ("kernel_module", self.kernel_module),
+            ("firmware", self.firmware),
+        ];
+        for (name, value) in flags {
+            if value == Some(true) {
+                description += &format!(" {name}");
+            }
+        }
+        if let Some(compress) = &self.compress {
+            description += &format!(" {compress}");
+        }
+        description
+    }
 }
 
 fn strmode(mode: Option<u32>) -> String {
@@ -76,62 +781,640 @@ fn strmode(mode: Option<u32>) -> String {
     }
 }
 
-fn recursive_copy(src: &Path, dst: &Path, mode: Option<u32>, depth: i32) {
-    if depth > 20 {
-        panic!("Recursive copy detected ({src:?})");
+/// Share the data of the source file with the destination (FICLONE), when both are on
+/// the same copy-on-write filesystem (btrfs, XFS).
+fn reflink(src: &Path, dst: &Path) -> bool {
+    use std::os::fd::AsRawFd;
+    let (Ok(src), Ok(dst)) = (std::fs::File::open(src), std::fs::File::create(dst)) else {
+        return false;
+    };
+    unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) == 0 }
+}
+
+/// Copy a sparse file (using less blocks than its size) without filling its holes,
+/// by only copying its data segments found with SEEK_DATA/SEEK_HOLE.
+fn sparse_copy(src: &Path, dst: &Path) -> bool {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::FileExt;
+
+    let metadata = match src.metadata() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    if metadata.blocks() * 512 >= metadata.len() {
+        return false;
     }
+    let (Ok(src_file), Ok(dst_file)) = (std::fs::File::open(src), std::fs::File::create(dst))
+    else {
+        return false;
+    };
+    let fd = src_file.as_raw_fd();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut offset = 0;
+    loop {
+        let data = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if data < 0 {
+            // ENXIO: no data after offset.
+            break;
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        let mut position = data as u64;
+        while position < hole as u64 {
+            let size = buffer.len().min((hole as u64 - position) as usize);
+            let read = src_file
+                .read_at(&mut buffer[..size], position)
+                .unwrap_or_else(|e| panic!("Failed to read {src}: {e:?}"));
+            if read == 0 {
+                break;
+            }
+            dst_file
+                .write_all_at(&buffer[..read], position)
+                .unwrap_or_else(|e| panic!("Failed to write {dst}: {e:?}"));
+            position += read as u64;
+        }
+        offset = hole;
+    }
+    dst_file
+        .set_len(metadata.len())
+        .unwrap_or_else(|e| panic!("Failed to resize {dst}: {e:?}"));
+    true
+}
 
-    if src.is_file() {
-        println!("install -D {} {:#?} {:#?}", strmode(mode), src, dst);
-        let dstdir = dst.parent().unwrap();
+fn copy_file(src: &Path, dst: &Path, mode: Option<u32>) {
+    message::operation(
+        format_args!("install -D {} {:#?} {:#?}", strmode(mode), src, dst),
+        "copy",
+        json!({"src": src, "dst": dst, "mode": mode.map(|mode| format!("{mode:04o}"))}),
+    );
+    let dstdir = dst.parent().unwrap();
 
-        std::fs::create_dir_all(dstdir)
-            .unwrap_or_else(|e| panic!("Failed to create directory {dstdir}: {e:?}"));
+    std::fs::create_dir_all(dstdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {dstdir}: {e:?}"));
 
+    // Replace a symbolic link instead of writing through it.
+    if dst.is_symlink() {
+        let _ = std::fs::remove_file(dst);
+    }
+    if reflink(src, dst) || sparse_copy(src, dst) {
+        if mode.is_none() {
+            let perms = src.metadata().unwrap().permissions();
+            std::fs::set_permissions(dst, perms).unwrap();
+        }
+    } else {
+        // std::fs::copy() uses copy_file_range() when available, copying the data in the kernel.
         std::fs::copy(src, dst).unwrap_or_else(|e| panic!("Failed to copy {src} to {dst}: {e:?}"));
+    }
 
-        if let Some(mode) = mode {
-            let perms = Permissions::from_mode(mode);
-            std::fs::set_permissions(dst, perms).unwrap();
+    // Keep the modification time, to detect the unchanged files on the next install.
+    if let Ok(mtime) = src.metadata().and_then(|m| m.modified()) {
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(dst)
+            .and_then(|file| file.set_modified(mtime));
+    }
+
+    if let Some(mode) = mode {
+        let perms = Permissions::from_mode(mode);
+        std::fs::set_permissions(dst, perms).unwrap();
+    }
+}
+
+/// Check if a destination file is already up to date: same size, modification time
+/// and mode as the source file, or same content with `checksum`.
+fn is_unchanged(src: &Path, dst: &Path, mode: Option<u32>, checksum: bool) -> bool {
+    let (Ok(src_meta), Ok(dst_meta)) = (src.metadata(), dst.symlink_metadata()) else {
+        return false;
+    };
+    let mode = mode.unwrap_or(src_meta.permissions().mode()) & 0o7777;
+    if !dst_meta.is_file()
+        || dst_meta.len() != src_meta.len()
+        || dst_meta.permissions().mode() & 0o7777 != mode
+    {
+        return false;
+    }
+    if checksum {
+        return std::fs::read(src).ok() == std::fs::read(dst).ok();
+    }
+    src_meta.modified().ok() == dst_meta.modified().ok()
+}
+
+/// Resolve a path which may not exist yet, by canonicalizing its nearest existing ancestor.
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(real) = ancestor.canonicalize_utf8() {
+            let rest = path.strip_prefix(ancestor).unwrap();
+            if rest.as_str().is_empty() {
+                return real;
+            }
+            return real.join(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Resolve the symbolic links of a destination as on the target, where an absolute link is
+/// relative to the rootfs, returning the host file written through them. Unlike
+/// resolve_in_rootfs, the file may not exist, and a link leading above the rootfs is an error.
+fn resolve_destination(rootfs: &Path, path: &Path) -> Result<PathBuf, String> {
+    let relative = path
+        .strip_prefix(rootfs)
+        .map_err(|_| "is outside of the rootfs".to_string())?;
+    let mut pending: Vec<String> = relative.components().rev().map(|c| c.to_string()).collect();
+    let mut resolved = rootfs.to_path_buf();
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "/" => resolved = rootfs.to_path_buf(),
+            "." => {}
+            ".." if resolved == rootfs => {
+                return Err("leads above the rootfs through a symbolic link".into());
+            }
+            ".." => {
+                resolved.pop();
+            }
+            name => {
+                let next = resolved.join(name);
+                let Ok(target) = next.read_link_utf8() else {
+                    resolved = next;
+                    continue;
+                };
+                links += 1;
+                if links > 40 {
+                    return Err("has too many levels of symbolic links".into());
+                }
+                pending.extend(target.components().rev().map(|c| c.to_string()));
+            }
         }
+    }
+    Ok(resolved)
+}
+
+/// Remove the files and the directories of a directory, keeping the directory itself
+/// (and its lock).
+fn remove_dir_content(dir: &Path) {
+    let Ok(entries) = dir.read_dir_utf8() else {
+        return;
+    };
+    for entry in entries {
+        let path = entry
+            .unwrap_or_else(|e| panic!("Failed to read directory {dir}: {e:?}"))
+            .into_path();
+        let result = match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+            _ => std::fs::remove_file(&path),
+        };
+        result.unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+    }
+}
+
+/// Call `f` on each item, sharing them between `jobs` threads.
+fn parallel<T: Sync>(jobs: usize, items: &[T], f: impl Fn(&T) + Sync) {
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                match items.get(i) {
+                    Some(item) => f(item),
+                    None => break,
+                }
+            });
+        }
+    });
+}
+
+/// Walk the source file or directory, calling `copy` for each file and directory to install.
+///
+/// `ancestors` holds the (device, inode) of the directories being walked, to detect
+/// the symbolic links looping to one of them, and `ignore` the rules of their
+/// `.rootfsignore` files.
+fn recursive_copy(
+    src: &Path,
+    dst: &Path,
+    ancestors: &mut Vec<(u64, u64, PathBuf)>,
+    ignore: &mut Vec<ignore::Rule>,
+    copy: &mut dyn FnMut(&Path, &Path, manifest::Kind),
+) {
+    if src.is_file() {
+        copy(src, dst, manifest::Kind::File);
     } else if src.is_dir() {
-        std::fs::create_dir_all(dst).unwrap();
+        let metadata = src.metadata().unwrap();
+        let id = (metadata.dev(), metadata.ino());
+        if let Some(i) = ancestors
+            .iter()
+            .position(|(dev, ino, _)| (*dev, *ino) == id)
+        {
+            let chain: Vec<&str> = ancestors[i..].iter().map(|(_, _, x)| x.as_str()).collect();
+            panic!("Recursive copy detected: {} -> {src}", chain.join(" -> "));
+        }
+        ancestors.push((id.0, id.1, src.to_path_buf()));
+        let rules = ignore.len();
+        ignore::load(src, ignore);
+        copy(src, dst, manifest::Kind::Dir);
         for dir in src.read_dir_utf8().unwrap() {
             let dir = dir.unwrap();
             let name = dir.file_name();
             if name.starts_with(".") {
+                message::debug(format_args!("Skipping hidden file {}", src.join(name)));
                 continue;
             }
             let src = src.join(name);
             let dst = dst.join(name);
-            recursive_copy(&src, &dst, mode, depth + 1);
+            if ignore::is_ignored(ignore, &src, src.is_dir()) {
+                message::debug(format_args!("Skipping ignored file {src}"));
+                continue;
+            }
+            recursive_copy(&src, &dst, ancestors, ignore, copy);
         }
+        ignore.truncate(rules);
+        ancestors.pop();
     } else {
         panic!("Artifact {src:?} not found")
     }
 }
 
-fn strip(file: &Path) {
+/// Return the license files (LICENSE*, COPYING*, NOTICE*) of a crate directory.
+fn find_license_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if let Ok(entries) = dir.read_dir_utf8() {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_uppercase();
+            let is_license = ["LICENSE", "LICENCE", "COPYING", "NOTICE"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix));
+            if is_license && entry.path().is_file() {
+                files.push(entry.path().to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Parse a size with an optional unit: 48MiB, 64MB, 512k or a number of bytes.
+fn parse_size(size: &str) -> u64 {
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid size {size:?}"));
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "kb" => 1000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        _ => panic!("Invalid size unit in {size:?} (expected B, KiB, MiB, GiB, kB, MB or GB)"),
+    };
+    (number * multiplier as f64) as u64
+}
+
+/// Resolve a path of the rootfs image, following the symbolic links as if `root` was
+/// the root directory. Return None when a component does not exist.
+fn resolve_in_rootfs(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: Vec<String> = path
+        .components()
+        .rev()
+        .map(|x| x.as_str().to_string())
+        .collect();
+    let mut hops = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "/" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let candidate = resolved.join(&component);
+        let host = root.join(candidate.strip_prefix("/").unwrap());
+        let metadata = host.symlink_metadata().ok()?;
+        if !metadata.is_symlink() {
+            resolved = candidate;
+            continue;
+        }
+        hops += 1;
+        if hops > 40 {
+            return None;
+        }
+        let target = host.read_link_utf8().ok()?;
+        if target.is_absolute() {
+            resolved = PathBuf::from("/");
+        }
+        pending.extend(target.components().rev().map(|x| x.as_str().to_string()));
+    }
+    Some(resolved)
+}
+
+/// Resolve the `.` and `..` components of an absolute path, without following links.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            camino::Utf8Component::ParentDir => {
+                normalized.pop();
+            }
+            camino::Utf8Component::Normal(name) => normalized.push(name),
+            _ => {}
+        }
+    }
+    normalized
+}
+
+/// Return the path of `target` relative to the directory `dir`.
+fn relative_path(target: &Path, dir: &Path) -> PathBuf {
+    let target: Vec<_> = target.components().collect();
+    let dir: Vec<_> = dir.components().collect();
+    let common = target.iter().zip(&dir).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..dir.len() {
+        path.push("..");
+    }
+    for component in &target[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// Find the line of a rule, or of one of its fields, in the manifest of its package.
+fn find_rule(
+    package: &cargo_metadata::Package,
+    i: usize,
+    field: Option<&str>,
+) -> Option<location::Location> {
+    let rules = package.metadata["rootfs"].as_array().map_or(0, Vec::len);
+    location::find_rule(&package.manifest_path, i, rules, field)
+}
+
+/// Format an error of a rule, at its line in the manifest of the package when it is known,
+/// or with the package name and the rule index otherwise (see location::find_rule).
+fn rule_error(
+    package: &cargo_metadata::Package,
+    i: usize,
+    field: Option<&str>,
+    error: &str,
+) -> String {
+    match find_rule(package, i, field) {
+        Some(location) => format!("{location}: {error}"),
+        None => format!("[{}] package.metadata.rootfs.[{i}]: {error}", package.name),
+    }
+}
+
+/// Return the number of single-character edits needed to change a word into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous + usize::from(x != *y);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Replace the list of the expected fields of a serde "unknown field" error by the closest
+/// one, e.g. "unknown field `permisions`, did you mean `permissions`?".
+fn did_you_mean(error: &str) -> String {
+    if !error.starts_with("unknown field `") {
+        return error.to_string();
+    }
+    // The names are quoted with backticks: the unknown field, then the expected ones.
+    let mut names = error.split('`').skip(1).step_by(2);
+    let unknown = names.next().unwrap_or_default();
+    let closest = names
+        .map(|name| (edit_distance(unknown, name), name))
+        .min()
+        .filter(|(distance, _)| *distance <= unknown.len().div_ceil(3));
+    match closest {
+        Some((_, name)) => format!("unknown field `{unknown}`, did you mean `{name}`?"),
+        None => error.to_string(),
+    }
+}
+
+/// Quote a word for sh.
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Encode bytes in lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_elf(file: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    std::fs::File::open(file)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic))
+        .is_ok()
+        && magic == *b"\x7fELF"
+}
+
+/// The sections identifying the toolchain and the build host, removed by --scrub-notes.
+/// The build-id is kept, to find the debug symbols.
+const SCRUBBED_SECTIONS: [&str; 5] = [
+    ".comment",
+    ".note.ABI-tag",
+    ".note.gnu.gold-version",
+    ".note.package",
+    ".GCC.command.line",
+];
+
+fn has_scrubbed_sections(elf: &elf::Elf) -> bool {
+    elf.sections()
+        .iter()
+        .any(|(name, _)| SCRUBBED_SECTIONS.contains(&name.as_str()))
+}
+
+/// Return the file of the strip cache for a binary stripped by a program with options.
+/// The `--version` of the program is part of the key, since a toolchain can be upgraded
+/// behind the same program name.
+fn strip_cache_file(
+    cache: &Path,
+    program: &str,
+    version: &str,
+    options: &[&str],
+    data: &[u8],
+) -> PathBuf {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    let words = [program, version]
+        .into_iter()
+        .chain(options.iter().copied());
+    for word in words {
+        hasher.update(word.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(data);
+    let key = hex(&hasher.finalize());
+    cache.join(key)
+}
+
+/// Return the output of `$STRIP --version`, empty if it can not be run.
+fn strip_version() -> String {
+    let program = std::env::var("STRIP").unwrap_or("strip".into());
+    let output = std::process::Command::new(program)
+        .arg("--version")
+        .output();
+    output
+        .map(|x| String::from_utf8_lossy(&x.stdout).into_owned())
+        .unwrap_or_default()
+}
+
+/// Number of days after which an unused strip of the cache is removed.
+const STRIP_CACHE_DAYS: u64 = 30;
+
+/// Remove the strips of the cache which were not used for STRIP_CACHE_DAYS.
+fn evict_strip_cache(cache: &Path) {
+    let Ok(entries) = cache.read_dir_utf8() else {
+        return;
+    };
+    let max_age = std::time::Duration::from_secs(STRIP_CACHE_DAYS * 24 * 3600);
+    for entry in entries.flatten() {
+        let age = entry.metadata().and_then(|x| x.modified()).ok();
+        let age = age.and_then(|mtime| mtime.elapsed().ok());
+        if age.is_some_and(|age| age > max_age) {
+            message::debug(format_args!("Removing the unused strip {}", entry.path()));
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Strip a binary, reusing the result of a previous strip of the same binary by the same
+/// program and options, cached in target/rootfs-cache/strip.
+fn strip(file: &Path, options: &[&str], cache: &Path, version: &str) {
     let program = std::env::var("STRIP").unwrap_or("strip".into());
-    println!("{} {}", program, file);
+    let data = std::fs::read(file).unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+    let cached = strip_cache_file(cache, &program, version, options, &data);
+    let key = cached.file_name().unwrap().to_string();
+    if let Ok(stripped) = std::fs::read(&cached) {
+        // The modification time of a strip is its last use, for the eviction.
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(&cached)
+            .and_then(|x| x.set_modified(std::time::SystemTime::now()));
+        message::operation(
+            format_args!("cp {cached} {file}"),
+            "strip",
+            json!({"file": file, "cached": true}),
+        );
+        // The file is rewritten in place, keeping its mode.
+        std::fs::write(file, stripped).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+        return;
+    }
+    let line: String = options.iter().map(|x| format!(" {x}")).collect();
+    message::operation(
+        format_args!("{program}{line} {file}"),
+        "strip",
+        json!({"file": file, "options": options}),
+    );
 
-    std::process::Command::new(program)
+    let output = std::process::Command::new(program)
+        .args(options)
         .arg(file)
         .output()
         .expect("strip error");
+    if output.status.success() {
+        // The cache is only a speedup: failing to fill it is not an error. The stripped
+        // binary is renamed in the cache once complete.
+        let tmp = cache.join(format!(".{key}.{}", std::process::id()));
+        let _ = std::fs::create_dir_all(cache)
+            .and_then(|_| std::fs::copy(file, &tmp))
+            .and_then(|_| std::fs::rename(&tmp, &cached));
+    }
+}
+
+/// Check the pinned sha256 digest of the source file of a rule.
+fn check_sha256(src: &Path, sha256: &str) -> Result<(), String> {
+    if !src.is_file() {
+        return Err(format!(
+            "sha256 requires a source file, {src} is not a file"
+        ));
+    }
+    let digest = manifest::sha256sum(src);
+    if digest != sha256.to_lowercase() {
+        return Err(format!("{src} has the sha256 {digest}, expected {sha256}"));
+    }
+    Ok(())
+}
+
+/// Select the root package: the package of -p, or the `root` of
+/// `[workspace.metadata.rootfs]`, or the package of the manifest. A virtual workspace
+/// without any of them uses its first member.
+fn find_root_package(
+    metadata: &cargo_metadata::Metadata,
+    package: Option<&str>,
+) -> cargo_metadata::PackageId {
+    let members = metadata.workspace_packages();
+    let (name, origin) = match (
+        package,
+        metadata.workspace_metadata["rootfs"]["root"].as_str(),
+    ) {
+        (Some(name), _) => (name, "-p"),
+        (None, Some(name)) => (name, "workspace.metadata.rootfs.root"),
+        (None, None) => {
+            if let Some(root) = metadata.root_package() {
+                return root.id.clone();
+            }
+            let first = members.first().expect("The workspace has no member");
+            if members.len() > 1 {
+                message::status(format_args!(
+                    "{} {} is a virtual workspace, using {} as the root package (select it with -p or [workspace.metadata.rootfs] root)",
+                    "Warning:".yellow().bold(),
+                    metadata.workspace_root,
+                    first.name
+                ));
+            }
+            return first.id.clone();
+        }
+    };
+    match members.iter().find(|x| x.name == name) {
+        Some(root) => root.id.clone(),
+        None => panic!("{origin}: {name} is not a member of the workspace"),
+    }
+}
+
+/// Return the target triple of the host, from `rustc -vV`.
+fn host_triple() -> Option<String> {
+    let rustc = std::env::var("RUSTC").unwrap_or("rustc".into());
+    let output = std::process::Command::new(rustc).arg("-vV").output().ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(|host| host.trim().into())
 }
 
 impl CargoRootfs {
     pub fn new(args: &CargoRootfsArgs) -> Self {
-        let metadata = args.metadata();
+        Self::with_metadata(args, args.metadata(), MAIN_ROOTFS)
+    }
 
+    /// Install a named rootfs, sharing the cargo metadata with the other ones.
+    fn with_metadata(
+        args: &CargoRootfsArgs,
+        metadata: cargo_metadata::Metadata,
+        rootfs: &str,
+    ) -> Self {
+        let target = args.get_target();
         let mut outdir = PathBuf::from(&metadata.target_directory);
-        if let Some(toolchain) = &args.target {
-            outdir.push(toolchain);
-        } else if let Ok(toolchain) = std::env::var("CARGO_BUILD_TARGET") {
+        if let Some(toolchain) = &target {
             outdir.push(toolchain);
         }
         outdir.push("release");
+        // The final artifacts copied by cargo build --artifact-dir.
+        if let Some(dir) = &args.artifact_dir {
+            outdir = dir.clone();
+        }
 
         let init_startdir = match &args.init_startdir {
             Some(x) => x.clone(),
@@ -142,53 +1425,2264 @@ impl CargoRootfs {
             None => PathBuf::from("/etc/rc6.d"),
         };
 
+        let dst = if args.is_deploying() {
+            args.dst
+                .clone()
+                .unwrap_or_else(|| metadata.target_directory.join("rootfs-deploy"))
+        } else if let Some(name) = args.command.staging_name() {
+            args.dst
+                .clone()
+                .unwrap_or_else(|| metadata.target_directory.join(format!("rootfs-{name}")))
+        } else if args.command == Command::Netboot {
+            args.export_dir
+                .clone()
+                .unwrap_or_else(|| panic!("netboot requires --export-dir"))
+        } else if args.command == Command::Plan {
+            // A --dest is copied to the staging directory, which is modified instead.
+            metadata.target_directory.join("rootfs-plan")
+        } else {
+            args.dst.clone().unwrap_or("/".into())
+        };
+
+        let journal = journal::Journal::new(&dst);
+        let cmd = args.build_command();
+        let build_command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect();
+        let jobs = args.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1)
+        });
+        let backup = args.backup.as_ref().map(|backup| {
+            if backup.contains('/') {
+                Backup::Dir(PathBuf::from(backup))
+            } else {
+                Backup::Suffix(backup.clone())
+            }
+        });
+        let planning = args.command == Command::Plan;
+        let recording = planning || args.emit_script.is_some() || args.emit_build.is_some();
+        // Uninstall -p selects a manifest, possibly of a package removed from the workspace.
+        let package = args.package.as_deref();
+        let package = package.filter(|_| args.command != Command::Uninstall);
+        let root = find_root_package(&metadata, package);
+        // The release of a kernel build is written in include/config/kernel.release.
+        let kernel_version = args.kernel_version.clone().or_else(|| {
+            let dir = args.kernel_build_dir.as_ref()?;
+            let file = dir.join("include/config/kernel.release");
+            let release = std::fs::read_to_string(&file)
+                .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+            Some(release.trim().into())
+        });
+        let debug_dir = match (&args.split_debug, &args.debuginfod) {
+            (Some(dir), _) if !dir.as_str().is_empty() => Some(dir.clone()),
+            (Some(_), _) | (None, Some(_)) => Some(metadata.target_directory.join("rootfs-debug")),
+            (None, None) => None,
+        };
+        let plan = recording.then(|| {
+            let root = metadata.packages.iter().find(|x| x.id == root).unwrap();
+            RefCell::new(plan::Plan::new(&dst, &root.name, target.as_deref()))
+        });
+
         Self {
             command: args.command,
-            dst: args.dst.clone().unwrap_or("/".into()),
+            dst,
             altsrc: args.altsrc.clone(),
+            overrides: RefCell::new(Vec::new()),
             metadata,
+            root,
             outdir,
             init_startdir,
             init_stopdir,
+            target,
+            hooks: RefCell::new(Vec::new()),
+            alternatives: RefCell::new(Vec::new()),
+            removals: RefCell::new(Vec::new()),
+            base: args.base.clone(),
+            whiteout: args.whiteout.unwrap_or_default(),
+            whiteouts: RefCell::new(Vec::new()),
+            services: RefCell::new(Vec::new()),
+            installed: RefCell::new(Vec::new()),
+            dry_run: args.command == Command::Diff,
+            exec_backend: args.exec_backend,
+            changes: RefCell::new(Vec::new()),
+            journal: RefCell::new(journal),
+            backup,
+            started: std::time::Instant::now(),
+            jobs,
+            checksum: args.checksum,
+            offline: args.offline || args.frozen,
+            copies: RefCell::new(Vec::new()),
+            diff_copies: RefCell::new(Vec::new()),
+            strips: RefCell::new(Vec::new()),
+            scrubs: RefCell::new(Vec::new()),
+            strip_version: std::cell::OnceCell::new(),
+            strip_rule: std::cell::Cell::new(false),
+            debug_dir,
+            debuginfod: args.debuginfod.clone(),
+            compress_debug: args.compress_debug,
+            debug_compressions: RefCell::new(Vec::new()),
+            scrub_notes: args.scrub_notes,
+            conffiles: RefCell::new(Vec::new()),
+            interp: args.interp.clone(),
+            sysroot: args.sysroot.clone().unwrap_or("/".into()),
+            ldconfig: args.ldconfig,
+            symlink_style: args.symlink_style,
+            build_command,
+            build_messages: RefCell::new(None),
+            keep_going: args.keep_going,
+            failures: RefCell::new(Vec::new()),
+            plan,
+            planning,
+            no_deps: args.no_deps,
+            deps_only: args.deps_only,
+            profile: args.rootfs_profile.clone(),
+            rootfs: rootfs.into(),
+            kernel_version,
+            kernel_build_dir: args.kernel_build_dir.clone(),
+            kernel_modules: RefCell::new(Vec::new()),
+            inputs: RefCell::new(state::Inputs::default()),
+            firmware: RefCell::new(Vec::new()),
         }
     }
 
-    fn get_root_package(&self) -> &cargo_metadata::Package {
-        let resolve = self
-            .metadata
-            .resolve
-            .as_ref()
-            .expect("Failed to resolve dependencies graph");
-        let root = resolve.root.as_ref().expect("No root package");
-        self.get_package(root)
-    }
-
-    fn get_package(&self, id: &cargo_metadata::PackageId) -> &cargo_metadata::Package {
+    /// Copy a file or a directory in the rootfs, on behalf of a package.
+    fn install_file(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        src: &Path,
+        dst: &Path,
+        mode: Option<u32>,
+    ) {
+        if src.is_dir() && canonicalize_existing(dst).starts_with(canonicalize_existing(src)) {
+            panic!(
+                "[{}] Cannot install {src} to {dst}: the destination is inside the source directory",
+                package.name
+            );
+        }
+        let group = src
+            .is_dir()
+            .then(|| format!("[{}] {}", package.name, self.get_image_path(dst)));
+        recursive_copy(src, dst, &mut vec![], &mut vec![], &mut |src, dst, kind| {
+            self.track(src, kind);
+            self.check_path(package, dst);
+            if self.dry_run {
+                self.diff_file(src, dst, kind, mode);
+            } else if kind == manifest::Kind::Dir {
+                self.record(|plan| plan.mkdir(dst, None));
+                self.journal.borrow_mut().save_dir(dst);
+                std::fs::create_dir_all(dst).unwrap();
+            } else {
+                if self.strip_rule.get() && kind == manifest::Kind::File && is_elf(src) {
+                    // Queued before comparing the destination with the stripped source.
+                    self.strip_copy(dst.to_path_buf());
+                }
+                if is_unchanged(src, dst, mode, self.checksum)
+                    || self.is_stripped_copy(src, dst, mode)
+                {
+                    self.record(|plan| plan.copy(src, dst, mode));
+                    message::debug(format_args!("Skipping unchanged {dst}"));
+                    self.cancel_copy(dst);
+                } else {
+                    self.record(|plan| plan.copy(src, dst, mode));
+                    // The destination is saved in the journal when the copy overwrites it.
+                    self.backup_file(dst);
+                    self.cancel_copy(dst);
+                    self.copies.borrow_mut().push(PendingCopy {
+                        src: src.to_path_buf(),
+                        dst: dst.to_path_buf(),
+                        mode,
+                        group: group.clone(),
+                    });
+                }
+            }
+            self.installed.borrow_mut().push(InstalledFile {
+                path: dst.to_path_buf(),
+                kind,
+                package: package.id.clone(),
+                rule: rule.into(),
+            });
+        });
+    }
+
+    /// Record a file or a directory read by the install, an input of its state. The
+    /// `.rootfsignore` file of a directory is also an input, even when it does not exist.
+    fn track(&self, path: &Path, kind: manifest::Kind) {
+        let mut inputs = self.inputs.borrow_mut();
+        inputs.files.push(path.to_path_buf());
+        if kind == manifest::Kind::Dir {
+            inputs.files.push(path.join(ignore::IGNORE_FILE));
+        }
+    }
+
+    /// Check if a configuration file was modified in the rootfs since the last install,
+    /// by comparing it with the previous manifest. A modified file is kept in the manifest.
+    fn is_modified_conffile(&self, src: &Path, dst: &Path) -> bool {
+        if !src.is_file() || !dst.is_file() {
+            return false;
+        }
+        let root = self.get_root_package();
+        let previous = match manifest::Manifest::load(&manifest::path(&self.dst, &root.name)) {
+            Some(x) => x,
+            None => return false,
+        };
+        let path = self.get_image_path(dst);
+        let entry = match previous.files.into_iter().find(|x| x.path == path) {
+            Some(x) => x,
+            None => return false,
+        };
+        let sha256 = manifest::sha256sum(dst);
+        if entry.sha256.as_ref() == Some(&sha256) || sha256 == manifest::sha256sum(src) {
+            return false;
+        }
+        self.conffiles.borrow_mut().push(entry);
+        true
+    }
+
+    /// Remove the files of a destination directory which were not installed, to keep
+    /// it in sync with its source directory.
+    fn mirror_dir(&self, dir: &Path) {
+        let entries = match dir.read_dir_utf8() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_installed = self.installed.borrow().iter().any(|x| x.path == path)
+                || whiteout::is_whiteout(path);
+            let is_dir = path.symlink_metadata().is_ok_and(|m| m.is_dir());
+            if is_installed {
+                if is_dir {
+                    self.mirror_dir(path);
+                }
+                continue;
+            }
+            if is_dir {
+                self.mirror_dir(path);
+            }
+            if self.dry_run {
+                self.changes
+                    .borrow_mut()
+                    .push((path.to_path_buf(), Change::Removed));
+                continue;
+            }
+            let command = if is_dir { "rmdir" } else { "rm -f" };
+            self.record(|plan| plan.remove(path, is_dir));
+            message::operation(
+                format_args!("{command} {:#?}", path),
+                "remove",
+                json!({"path": path}),
+            );
+            self.save_file(path);
+            if is_dir {
+                let _ = std::fs::remove_dir(path);
+            } else if path.symlink_metadata().is_ok() {
+                std::fs::remove_file(path)
+                    .unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+            }
+        }
+    }
+
+    /// Remove the paths of a rootfs directory matching the patterns of the `remove` rules,
+    /// including the files installed by the other rules, which are not in the manifest.
+    fn remove_paths(&self, dir: &Path) {
+        let removals = self.removals.borrow();
+        if removals.is_empty() {
+            return;
+        }
+        if self.dry_run && dir == self.dst {
+            // The files to install are not written by a dry run: they are not added.
+            let is_removed = |file: &InstalledFile| {
+                let is_dir = file.kind == manifest::Kind::Dir;
+                file.path
+                    .ancestors()
+                    .take_while(|x| *x != self.dst)
+                    .any(|x| ignore::is_ignored(&removals, x, is_dir || x != file.path))
+            };
+            let removed: Vec<PathBuf> = self
+                .installed
+                .borrow()
+                .iter()
+                .filter(|file| is_removed(file))
+                .map(|file| file.path.clone())
+                .collect();
+            self.installed
+                .borrow_mut()
+                .retain(|file| !removed.contains(&file.path));
+            self.changes.borrow_mut().retain(|(path, change)| {
+                !removed.contains(path) || !matches!(change, Change::Added)
+            });
+        }
+        // The paths of the base image are hidden by whiteouts in the rootfs layer.
+        let base_dir = self.base.as_ref().map(|base| {
+            let path = self.get_image_path(dir);
+            base.join(path.strip_prefix("/").unwrap())
+        });
+        let mut names: Vec<String> = vec![];
+        for dir in [Some(dir), base_dir.as_deref()].into_iter().flatten() {
+            if let Ok(entries) = dir.read_dir_utf8() {
+                names.extend(entries.flatten().map(|x| x.file_name().to_string()));
+            }
+        }
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = dir.join(&name);
+            let image_path = self.get_image_path(&path);
+            if image_path == Path::new("/").join(journal::JOURNAL_DIR)
+                || image_path == Path::new("/").join(manifest::MANIFEST_DIR)
+            {
+                continue;
+            }
+            let base_path = base_dir.as_ref().map(|dir| dir.join(&name));
+            let base_path = base_path.filter(|x| x.symlink_metadata().is_ok());
+            let is_whiteout = whiteout::is_whiteout(&path);
+            if is_whiteout && base_path.is_none() {
+                continue;
+            }
+            let metadata = match is_whiteout {
+                true => None,
+                false => path.symlink_metadata().ok(),
+            };
+            let exists = metadata.is_some();
+            let metadata = metadata.or_else(|| base_path.as_ref()?.symlink_metadata().ok());
+            let is_dir = metadata.is_some_and(|m| m.is_dir());
+            if !ignore::is_ignored(&removals, &path, is_dir) {
+                if is_dir {
+                    self.remove_paths(&path);
+                }
+                continue;
+            }
+            if exists {
+                self.remove_tree(&path, path.is_dir());
+            }
+            if base_path.is_some() {
+                self.hide(&image_path, false);
+            }
+        }
+    }
+
+    /// Hide a path of the base image by a whiteout: removed, or an opaque directory whose
+    /// content is replaced.
+    fn hide(&self, path: &Path, opaque: bool) {
+        let whiteout = manifest::Whiteout {
+            path: path.to_path_buf(),
+            opaque,
+        };
+        if self.dry_run && !opaque {
+            let dst = self.get_destination_file(path);
+            self.changes.borrow_mut().push((dst, Change::Removed));
+        }
+        let mut whiteouts = self.whiteouts.borrow_mut();
+        if !whiteouts.contains(&whiteout) {
+            whiteouts.push(whiteout);
+        }
+    }
+
+    /// Create the whiteouts of the rootfs layer. Those of the previous install which are
+    /// not needed anymore are removed when pruning, and kept otherwise.
+    fn write_whiteouts(&self, prune: bool) {
+        let root = self.get_root_package();
+        let previous = manifest::Manifest::load(&manifest::path(&self.dst, &root.name))
+            .map(|x| x.whiteouts)
+            .unwrap_or_default();
+        let mut whiteouts = self.whiteouts.borrow_mut();
+        for whiteout in previous {
+            if whiteouts.contains(&whiteout) {
+                continue;
+            }
+            if !prune {
+                whiteouts.push(whiteout);
+                continue;
+            }
+            message::operation(
+                format_args!("Removing the whiteout of {}", whiteout.path),
+                "whiteout",
+                json!({"path": whiteout.path, "opaque": whiteout.opaque, "remove": true}),
+            );
+            whiteout::remove(&self.dst, &whiteout, |file| {
+                self.record(|plan| plan.remove(file, false));
+                self.save_file(file);
+            });
+        }
+        for whiteout in whiteouts.iter() {
+            let file = whiteout::file(&self.dst, whiteout, self.whiteout);
+            if whiteout::is_whiteout(&file) {
+                continue;
+            }
+            message::operation(
+                format_args!("Hiding {} with the whiteout {file}", whiteout.path),
+                "whiteout",
+                json!({"path": whiteout.path, "opaque": whiteout.opaque, "file": file}),
+            );
+            if !file.is_dir() {
+                self.save_file(&file);
+            }
+            if self.whiteout == whiteout::Format::Oci {
+                self.record(|plan| plan.write(&file, b"", 0o644));
+            }
+            whiteout::create(&self.dst, whiteout, self.whiteout);
+        }
+    }
+
+    /// Remove a file, or a directory with its content, on behalf of a `remove` rule.
+    fn remove_tree(&self, path: &Path, is_dir: bool) {
+        if is_dir {
+            let mut entries: Vec<PathBuf> = path
+                .read_dir_utf8()
+                .unwrap_or_else(|e| panic!("Failed to read {path}: {e:?}"))
+                .flatten()
+                .map(|x| x.into_path())
+                .collect();
+            entries.sort();
+            for entry in entries {
+                let is_dir = entry.symlink_metadata().is_ok_and(|m| m.is_dir());
+                self.remove_tree(&entry, is_dir);
+            }
+        }
+        self.installed.borrow_mut().retain(|x| x.path != path);
+        if self.dry_run {
+            let mut changes = self.changes.borrow_mut();
+            changes.retain(|(x, _)| x != path);
+            changes.push((path.to_path_buf(), Change::Removed));
+            return;
+        }
+        let command = if is_dir { "rmdir" } else { "rm -f" };
+        self.record(|plan| plan.remove(path, is_dir));
+        message::operation(
+            format_args!("{command} {:#?}", path),
+            "remove",
+            json!({"path": path}),
+        );
+        self.save_file(path);
+        if is_dir {
+            let _ = std::fs::remove_dir(path);
+        } else if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(path).unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+        }
+    }
+
+    /// Forget a pending copy, when its destination is written again by a later rule.
+    fn cancel_copy(&self, dst: &Path) {
+        self.copies.borrow_mut().retain(|copy| copy.dst != dst);
+    }
+
+    /// Strip a binary with `release`, or compress its debug sections with --compress-debug,
+    /// once copied. It is queued before it is installed, so a destination already stripped
+    /// from the same source is not copied again.
+    fn strip_copy(&self, dst: PathBuf) {
+        if self.command == Command::Release {
+            if !self.strips.borrow().contains(&dst) {
+                self.strips.borrow_mut().push(dst);
+            }
+        } else if self.compress_debug.is_some() && !self.debug_compressions.borrow().contains(&dst)
+        {
+            self.debug_compressions.borrow_mut().push(dst);
+        }
+    }
+
+    /// Remove the --scrub-notes sections of a release binary keeping its symbols, once copied.
+    fn scrub_copy(&self, dst: PathBuf) {
+        if self.command == Command::Release
+            && self.scrub_notes
+            && !self.scrubs.borrow().contains(&dst)
+        {
+            self.scrubs.borrow_mut().push(dst);
+        }
+    }
+
+    /// The options of strip: the default ones, and the sections removed by --scrub-notes.
+    fn strip_options(&self) -> Vec<&'static str> {
+        match self.scrub_notes {
+            true => SCRUBBED_SECTIONS.iter().flat_map(|x| ["-R", x]).collect(),
+            false => vec![],
+        }
+    }
+
+    /// Check if the destination of a binary queued for strip is the stripped source, as
+    /// found in the strip cache: it is up to date although it differs from the source.
+    fn is_stripped_copy(&self, src: &Path, dst: &Path, mode: Option<u32>) -> bool {
+        if !self.strips.borrow().iter().any(|x| x == dst) {
+            return false;
+        }
+        let (Ok(src_meta), Ok(dst_meta)) = (src.metadata(), dst.symlink_metadata()) else {
+            return false;
+        };
+        let mode = mode.unwrap_or(src_meta.permissions().mode()) & 0o7777;
+        if !dst_meta.is_file()
+            || dst_meta.len() >= src_meta.len()
+            || dst_meta.permissions().mode() & 0o7777 != mode
+        {
+            return false;
+        }
+        let (Ok(data), Ok(installed)) = (std::fs::read(src), std::fs::read(dst)) else {
+            return false;
+        };
+        let program = std::env::var("STRIP").unwrap_or("strip".into());
+        let cache = self.metadata.target_directory.join("rootfs-cache/strip");
+        let version = self.strip_version.get_or_init(strip_version);
+        let cached = strip_cache_file(&cache, &program, version, &self.strip_options(), &data);
+        std::fs::read(cached).is_ok_and(|stripped| stripped == installed)
+    }
+
+    /// Run the pending copies, then strip the binaries, with --jobs threads.
+    fn flush_copies(&self) {
+        let copies = std::mem::take(&mut *self.copies.borrow_mut());
+        let strips = std::mem::take(&mut *self.strips.borrow_mut());
+        self.run_copies(&copies);
+        let program = std::env::var("STRIP").unwrap_or("strip".into());
+        let options = self.strip_options();
+        for file in &strips {
+            self.record(|plan| plan.run(&program, &options, file));
+        }
+        // A binary which was not copied is stripped in place while it still has symbols,
+        // e.g. when installed by `install` before `release`.
+        let strips = self.select_stale(&copies, strips, |elf| {
+            elf.has_symbols() || (self.scrub_notes && has_scrubbed_sections(elf))
+        });
+        let cache = self.metadata.target_directory.join("rootfs-cache/strip");
+        let (debug_dir, debuginfod) = (self.debug_dir.as_deref(), self.debuginfod.as_deref());
+        if !strips.is_empty() {
+            let version = self.strip_version.get_or_init(strip_version);
+            parallel(self.jobs, &strips, |file| {
+                // The debug information is split before stripping, from the same binary.
+                let build_id = debug_dir.and_then(|dir| debuginfo::split(file, dir));
+                strip(file, &options, &cache, version);
+                if let (Some(dir), Some(build_id)) = (debuginfod, &build_id) {
+                    let debug = debuginfo::debug_file(debug_dir.unwrap(), build_id);
+                    debuginfo::export(file, &debug, build_id, dir);
+                }
+            });
+            evict_strip_cache(&cache);
+        }
+        if let Some(compression) = self.compress_debug {
+            let files = std::mem::take(&mut *self.debug_compressions.borrow_mut());
+            let program = debuginfo::objcopy();
+            for file in &files {
+                self.record(|plan| plan.run(&program, &[compression.option()], file));
+            }
+            // A binary which was not copied is compressed while its debug sections are not.
+            let files = self.select_stale(&copies, files, |elf| {
+                elf.sections().iter().any(|(name, flags)| {
+                    name.starts_with(".debug_") && flags & elf::SHF_COMPRESSED == 0
+                })
+            });
+            parallel(self.jobs, &files, |file| {
+                debuginfo::compress(file, compression)
+            });
+        }
+        let scrubs = std::mem::take(&mut *self.scrubs.borrow_mut());
+        if !scrubs.is_empty() {
+            let program = debuginfo::objcopy();
+            let options = self.strip_options();
+            for file in &scrubs {
+                self.record(|plan| plan.run(&program, &options, file));
+            }
+            let scrubs = self.select_stale(&copies, scrubs, has_scrubbed_sections);
+            parallel(self.jobs, &scrubs, |file| {
+                debuginfo::remove_sections(file, &options)
+            });
+        }
+    }
+
+    /// Run the pending copy of a file before a rule reads or modifies it in place. The file
+    /// is stripped with the other binaries, by flush_copies.
+    fn flush_copy(&self, dst: &Path) {
+        let (copies, pending) = std::mem::take(&mut *self.copies.borrow_mut())
+            .into_iter()
+            .partition(|copy| copy.dst == dst);
+        *self.copies.borrow_mut() = pending;
+        self.run_copies(&copies);
+    }
+
+    /// Save the destinations in the journal, then copy the files with --jobs threads. The
+    /// files of a directory install show their own progress.
+    fn run_copies(&self, copies: &[PendingCopy]) {
+        for copy in copies {
+            self.journal.borrow_mut().save(&copy.dst);
+        }
+        let mut groups: Vec<(Option<&str>, Vec<&PendingCopy>)> = vec![];
+        for copy in copies {
+            let group = copy.group.as_deref();
+            match groups.iter_mut().find(|(x, _)| *x == group) {
+                Some((_, copies)) => copies.push(copy),
+                None => groups.push((group, vec![copy])),
+            }
+        }
+        for (group, copies) in groups {
+            let bytes = copies
+                .iter()
+                .map(|copy| copy.src.metadata().map(|m| m.len()).unwrap_or(0))
+                .sum();
+            let label = group.unwrap_or("Installing");
+            let progress = message::Progress::new(label, copies.len() as u64, bytes);
+            let progress = std::sync::Mutex::new(progress);
+            parallel(self.jobs, &copies, |copy| {
+                copy_file(&copy.src, &copy.dst, copy.mode);
+                let size = copy.src.metadata().map(|m| m.len()).unwrap_or(0);
+                progress.lock().unwrap().add(1, size);
+            });
+            progress.lock().unwrap().finish();
+        }
+    }
+
+    /// Return the queued binaries which were copied, or which are still `stale`: the latter
+    /// are saved, to be modified in place.
+    fn select_stale(
+        &self,
+        copies: &[PendingCopy],
+        files: Vec<PathBuf>,
+        stale: impl Fn(&elf::Elf) -> bool,
+    ) -> Vec<PathBuf> {
+        files
+            .into_iter()
+            .filter(|file| {
+                if copies.iter().any(|copy| copy.dst == *file) {
+                    return true;
+                }
+                let stale = elf::Elf::parse(file).is_some_and(|elf| stale(&elf));
+                if stale {
+                    self.save_file_in_place(file);
+                }
+                stale
+            })
+            .collect()
+    }
+
+    /// Rewrite the dynamic loader (PT_INTERP) of the installed ELF files with --interp,
+    /// and install the loader from the sysroot at this path.
+    fn set_interpreters(&self) {
+        let interp = match &self.interp {
+            Some(x) => x,
+            None => return,
+        };
+        let mut loader = None;
+        // The loader replaced by --interp, and the first file using it.
+        let mut original: Option<(String, PathBuf)> = None;
+        let mut done: Vec<PathBuf> = vec![];
+        let files: Vec<(PathBuf, cargo_metadata::PackageId)> = self
+            .installed
+            .borrow()
+            .iter()
+            .filter(|file| file.kind == manifest::Kind::File)
+            .map(|file| (file.path.clone(), file.package.clone()))
+            .collect();
+        for (file, package) in files {
+            // A file replaced by a symbolic link is patched where it is installed.
+            if done.contains(&file) || file.is_symlink() {
+                continue;
+            }
+            done.push(file.clone());
+            let file = self.check_path(self.get_package(&package), &file);
+            // Statically linked files have no loader.
+            let old = match elf::Elf::parse(&file).and_then(|elf| elf.interpreter()) {
+                Some(x) => x,
+                None => continue,
+            };
+            if old == interp.as_str() {
+                // Patched by a previous install.
+                loader.get_or_insert(old);
+                continue;
+            }
+            match &original {
+                Some((first, other)) if *first != old => panic!(
+                    "--interp {interp} replaces a single loader: {other} uses {first}, {file} uses {old}"
+                ),
+                Some(_) => {}
+                None => original = Some((old.clone(), file.clone())),
+            }
+            let args = ["--set-interpreter", interp.as_str()];
+            self.record(|plan| plan.run("patchelf", &args, &file));
+            message::operation(
+                format_args!("patchelf --set-interpreter {interp} {file}"),
+                "interp",
+                json!({"file": file, "interp": interp}),
+            );
+            self.save_file_in_place(&file);
+            if elf::Elf::set_interpreter(&file, interp.as_str()) {
+                continue;
+            }
+            // The new path is longer than the previous one: the file must be rewritten.
+            let status = std::process::Command::new("patchelf")
+                .arg("--set-interpreter")
+                .arg(interp)
+                .arg(&file)
+                .status()
+                .unwrap_or_else(|e| {
+                    panic!("Failed to run patchelf, required for a loader longer than {old}: {e:?}")
+                });
+            if !status.success() {
+                panic!("patchelf --set-interpreter {interp} {file} failed: {status}");
+            }
+        }
+
+        if let Some(old) = original.map(|(old, _)| old).or(loader) {
+            let src = self.sysroot.join(old.trim_start_matches('/'));
+            let dst = self.get_destination_file(interp);
+            let root = self.get_root_package();
+            message::status(format_args!("[{}] interp: {src} -> {interp}", root.name));
+            self.install_file(root, "interp", &src, &dst, Some(0o755));
+            self.flush_copy(&dst);
+        }
+    }
+
+    /// Run depmod on the rootfs, once kernel modules are installed, so they can be loaded
+    /// by modprobe.
+    fn run_depmod(&self) {
+        if self.kernel_modules.borrow().is_empty() || self.dry_run {
+            return;
+        }
+        let version = self.kernel_version.as_ref().unwrap();
+        let program = std::env::var("DEPMOD").unwrap_or("depmod".into());
+        // The rootfs is the last argument, for the install plan.
+        let mut args = vec!["-a".to_string()];
+        if let Some(dir) = &self.kernel_build_dir {
+            let map = dir.join("System.map");
+            if map.is_file() {
+                args.extend(["-F".into(), map.to_string()]);
+            }
+        }
+        args.extend([version.clone(), "-b".into()]);
+        let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+        self.record(|plan| plan.run(&program, &args, &self.dst));
+        message::operation(
+            format_args!("{program} {} {}", args.join(" "), self.dst),
+            "depmod",
+            json!({"dst": self.dst, "version": version}),
+        );
+        let modules = self.dst.join("lib/modules").join(version);
+        for name in DEPMOD_FILES {
+            self.save_file(&modules.join(name));
+        }
+        let status = std::process::Command::new(&program)
+            .args(&args)
+            .arg(&self.dst)
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+        if !status.success() {
+            panic!("{program} -b {} failed: {status}", self.dst);
+        }
+    }
+
+    /// Configure the dynamic linker for the shared libraries installed outside of the
+    /// default directories: in /etc/ld.so.conf.d/<root-crate>.conf and the ldconfig cache,
+    /// or in /etc/ld-musl-<arch>.path for musl targets.
+    fn configure_ldconfig(&self) {
+        const DEFAULT_DIRS: [&str; 4] = ["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+        let mut dirs: Vec<PathBuf> = vec![];
+        for file in self.installed.borrow().iter() {
+            let name = file.path.file_name().unwrap_or_default();
+            let is_library = name.ends_with(".so") || name.contains(".so.");
+            if file.kind != manifest::Kind::File || !is_library {
+                continue;
+            }
+            if elf::Elf::parse(&file.path).is_none() {
+                continue;
+            }
+            let dir = self.get_image_path(file.path.parent().unwrap());
+            if !DEFAULT_DIRS.contains(&dir.as_str()) && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        if dirs.is_empty() {
+            return;
+        }
+        dirs.sort();
+        let root = self.get_root_package();
+        let mut content: String = dirs.iter().map(|dir| format!("{dir}\n")).collect();
+        message::status(format_args!(
+            "[{}] ldconfig: {}",
+            root.name,
+            dirs.iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+
+        let target = self.target.as_deref().unwrap_or(std::env::consts::ARCH);
+        if target.contains("musl") {
+            // musl reads the whole library path from a single file.
+            let arch = match target.split('-').next().unwrap() {
+                arch if arch.starts_with("arm") && target.ends_with("hf") => "armhf",
+                arch if arch.starts_with("arm") => "arm",
+                "i586" | "i686" => "i386",
+                arch => arch,
+            };
+            for dir in DEFAULT_DIRS {
+                content += &format!("{dir}\n");
+            }
+            let path = self.dst.join(format!("etc/ld-musl-{arch}.path"));
+            return self.install_content(root, "ldconfig", &path, content.as_bytes(), 0o644);
+        }
+
+        let conf = self
+            .dst
+            .join("etc/ld.so.conf.d")
+            .join(format!("{}.conf", root.name));
+        self.install_content(root, "ldconfig", &conf, content.as_bytes(), 0o644);
+        let edit = plan::Edit::EnsureLine {
+            line: "include /etc/ld.so.conf.d/*.conf".into(),
+            replace: None,
+        };
+        self.edit_file(root, &self.dst.join("etc/ld.so.conf"), &[edit]);
+        if self.dry_run {
+            return;
+        }
+
+        let program = std::env::var("LDCONFIG").unwrap_or("ldconfig".into());
+        let cache = self.dst.join("etc/ld.so.cache");
+        self.record(|plan| plan.run(&program, &["-r"], &self.dst));
+        message::operation(
+            format_args!("{program} -r {}", self.dst),
+            "ldconfig",
+            json!({"dst": self.dst}),
+        );
+        self.save_file_in_place(&cache);
+        let status = std::process::Command::new(&program)
+            .arg("-r")
+            .arg(&self.dst)
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+        if !status.success() {
+            panic!("{program} -r {} failed: {status}", self.dst);
+        }
+        self.installed.borrow_mut().push(InstalledFile {
+            path: cache,
+            kind: manifest::Kind::File,
+            package: root.id.clone(),
+            rule: "ldconfig".into(),
+        });
+    }
+
+    /// Write /etc/os-release, naming the root crate, its version and the build, and
+    /// /etc/buildinfo.json, with the hash of the lockfile and the enabled features.
+    /// Return the build information of the root package: its name and version, the
+    /// `git describe` of the workspace, the target, the rootfs profile, the sha256 of
+    /// `Cargo.lock`, the enabled features and the build time.
+    fn get_build_info(&self) -> Value {
+        let root = self.get_root_package();
+        let workspace_root = &self.metadata.workspace_root;
+        let build_id = std::process::Command::new("git")
+            .args(["describe", "--always", "--dirty", "--tags"])
+            .current_dir(workspace_root)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        let lockfile = workspace_root.join("Cargo.lock");
+        json!({
+            "name": root.name,
+            "version": root.version.to_string(),
+            "build_id": build_id,
+            "target": self.target.clone().or_else(host_triple),
+            "rootfs_profile": self.profile,
+            "lockfile_sha256": lockfile.is_file().then(|| manifest::sha256sum(&lockfile)),
+            "features": self.get_enabled_features(&root.id),
+            "build_time": sbom::timestamp(),
+        })
+    }
+
+    fn write_build_info(&self) {
+        let root = self.get_root_package();
+        let buildinfo = self.get_build_info();
+        let build_id = buildinfo["build_id"].as_str();
+        let target = buildinfo["target"].as_str();
+        // ID only allows lowercase letters, digits, '.', '_' and '-'.
+        let id: String = root
+            .name
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        let mut os_release = format!(
+            "NAME=\"{}\"\nID={id}\nVERSION_ID={}\nPRETTY_NAME=\"{} {}\"\n",
+            root.name, root.version, root.name, root.version
+        );
+        if let Some(build_id) = build_id {
+            os_release += &format!("BUILD_ID={build_id}\n");
+        }
+        if let Some(target) = target {
+            os_release += &format!("TARGET={target}\n");
+        }
+        let build = build_id.map(|x| format!(" ({x})"));
+        message::status(format_args!(
+            "[{}] os-release: {}{}",
+            root.name,
+            root.version,
+            build.unwrap_or_default()
+        ));
+        let path = self.dst.join("etc/os-release");
+        self.install_content(root, "build-info", &path, os_release.as_bytes(), 0o644);
+
+        let mut content = serde_json::to_string_pretty(&buildinfo).unwrap();
+        content.push('\n');
+        let path = self.dst.join("etc/buildinfo.json");
+        self.install_content(root, "build-info", &path, content.as_bytes(), 0o644);
+    }
+
+    /// Write the /etc files described by `[workspace.metadata.rootfs.etc]`.
+    fn install_etc(&self) {
+        let root = self.get_root_package();
+        let etc = etc::Etc::load(&self.metadata.workspace_metadata);
+        for (path, content) in etc.files() {
+            message::status(format_args!("[{}] etc: /{path}", root.name));
+            let dst = self.dst.join(path);
+            let rule = "workspace.metadata.rootfs.etc";
+            self.install_content(root, rule, &dst, content.as_bytes(), 0o644);
+        }
+    }
+
+    /// Replace the root filesystem entry of /etc/fstab by the NFS root, and warn when the
+    /// files are not owned by root, as exported by the NFS server.
+    fn configure_nfs_root(&self) {
+        let root = self.get_root_package();
+        let path = self.dst.join("etc/fstab");
+        if let Some(content) = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|x| netboot::nfs_fstab(&x))
+        {
+            message::status(format_args!("[{}] fstab: NFS root", root.name));
+            self.install_content(root, "netboot", &path, content.as_bytes(), 0o644);
+        }
+        let owner = self.dst.metadata().map(|x| x.uid()).unwrap_or(0);
+        if owner != 0 {
+            message::status(format_args!(
+                "{} {} is not owned by root: install as root for the owners of the NFS root",
+                "Warning:".yellow().bold(),
+                self.dst
+            ));
+        }
+    }
+
+    /// Write the detached signatures of the installed ELF files in /usr/share/signatures,
+    /// at their path in the image with a `.sig` extension.
+    fn sign_elfs(&self, signer: &sign::Signer) {
+        let mut files: Vec<(PathBuf, cargo_metadata::PackageId)> = vec![];
+        for file in self.installed.borrow().iter() {
+            let seen = files.iter().any(|(path, _)| *path == file.path);
+            if file.kind == manifest::Kind::File && !seen && is_elf(&file.path) {
+                files.push((file.path.clone(), file.package.clone()));
+            }
+        }
+        let tmpdir = self.metadata.target_directory.join("rootfs-signatures");
+        std::fs::create_dir_all(&tmpdir)
+            .unwrap_or_else(|e| panic!("Failed to create directory {tmpdir}: {e:?}"));
+        let signature = tmpdir.join("signature");
+        for (path, package) in &files {
+            let image_path = self.get_image_path(path);
+            let dst = self
+                .dst
+                .join(sign::SIGNATURES_DIR)
+                .join(format!("{}.sig", image_path.strip_prefix("/").unwrap()));
+            signer.sign(path, &signature);
+            let content = std::fs::read(&signature)
+                .unwrap_or_else(|e| panic!("Failed to read {signature}: {e:?}"));
+            self.install_content(self.get_package(package), "sign", &dst, &content, 0o644);
+        }
+        let _ = std::fs::remove_file(&signature);
+    }
+
+    /// Replace the installed files having the same content, mode and owner by hard links.
+    fn dedupe(&self) {
+        let mut seen: Vec<(_, PathBuf)> = vec![];
+        let (mut count, mut saved) = (0, 0);
+        let mut paths: Vec<PathBuf> = vec![];
+        for file in self.installed.borrow().iter() {
+            if file.kind == manifest::Kind::File && !paths.contains(&file.path) {
+                paths.push(file.path.clone());
+            }
+        }
+        for path in paths {
+            let metadata = match path.symlink_metadata() {
+                Ok(x) if x.is_file() => x,
+                _ => continue,
+            };
+            let key = (
+                metadata.len(),
+                manifest::sha256sum(&path),
+                metadata.permissions().mode(),
+                metadata.uid(),
+                metadata.gid(),
+            );
+            let original = match seen.iter().find(|(x, _)| *x == key) {
+                Some((_, original)) => original.clone(),
+                None => {
+                    seen.push((key, path));
+                    continue;
+                }
+            };
+            if original.metadata().map(|m| m.ino()).ok() == Some(metadata.ino()) {
+                continue;
+            }
+            self.record(|plan| plan.hardlink(&original, &path));
+            message::operation(
+                format_args!("ln -f {:#?} {:#?}", original, path),
+                "hardlink",
+                json!({"original": original, "link": path}),
+            );
+            self.save_file(&path);
+            let _ = std::fs::remove_file(&path);
+            std::fs::hard_link(&original, &path).unwrap_or_else(|e| {
+                panic!("Failed to link {path} to {original}: {e:?}");
+            });
+            count += 1;
+            saved += metadata.len();
+        }
+        if count > 0 {
+            message::status(format_args!("Deduplicated {count} files ({saved} bytes)"));
+        }
+    }
+
+    /// Check that a destination path stays inside the rootfs: it must not contain `..`
+    /// components, nor be located below a symbolic link pointing outside the rootfs.
+    /// Return the file written by the rules modifying it in place: the target of a
+    /// symbolic link, resolved in the rootfs.
+    fn check_path(&self, package: &cargo_metadata::Package, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(&self.dst).unwrap_or_else(|_| {
+            panic!(
+                "[{}] Destination {path} is outside of the rootfs",
+                package.name
+            )
+        });
+        if relative
+            .components()
+            .any(|c| c == camino::Utf8Component::ParentDir)
+        {
+            panic!("[{}] Destination {path} escapes the rootfs", package.name);
+        }
+        let rootfs = canonicalize_existing(&self.dst);
+        let parent = canonicalize_existing(path.parent().unwrap());
+        if !parent.starts_with(&rootfs) {
+            panic!(
+                "[{}] Destination {path} resolves to {parent}, outside of the rootfs",
+                package.name
+            );
+        }
+        if !path.is_symlink() {
+            return path.to_path_buf();
+        }
+        resolve_destination(&self.dst, path)
+            .unwrap_or_else(|e| panic!("[{}] Destination {path} {e}", package.name))
+    }
+
+    /// Save a destination file before it is overwritten: in the journal, and as
+    /// a backup when requested with --backup. The journal moves the file to its
+    /// backup directory.
+    fn save_file(&self, path: &Path) {
+        self.backup_file(path);
+        self.journal.borrow_mut().save(path);
+    }
+
+    /// Save a destination file before it is modified in place.
+    fn save_file_in_place(&self, path: &Path) {
+        self.backup_file(path);
+        self.journal.borrow_mut().save_in_place(path);
+    }
+
+    /// Copy a destination file to its backup path, when requested with --backup.
+    fn backup_file(&self, path: &Path) {
+        let mut journal = self.journal.borrow_mut();
+        if let Some(backup) = &self.backup {
+            let metadata = path.symlink_metadata();
+            if metadata.is_ok_and(|metadata| !metadata.is_dir()) {
+                let backup = match backup {
+                    Backup::Suffix(suffix) => PathBuf::from(format!("{path}{suffix}")),
+                    Backup::Dir(dir) => {
+                        let path = path.strip_prefix(&self.dst).unwrap_or(path);
+                        dir.join(path)
+                    }
+                };
+                self.record(|plan| plan.backup(path, &backup));
+                message::operation(
+                    format_args!("cp -a {:#?} {:#?}", path, backup),
+                    "backup",
+                    json!({"path": path, "backup": backup}),
+                );
+                journal.save(&backup);
+                std::fs::create_dir_all(backup.parent().unwrap()).unwrap();
+                let _ = std::fs::remove_file(&backup);
+                if path.is_symlink() {
+                    symlink(path.read_link_utf8().unwrap(), &backup).unwrap();
+                } else {
+                    std::fs::copy(path, &backup)
+                        .unwrap_or_else(|e| panic!("Failed to backup {path} to {backup}: {e:?}"));
+                }
+            }
+        }
+    }
+
+    /// Write a generated file in the rootfs, on behalf of a package.
+    fn install_content(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        dst: &Path,
+        content: &[u8],
+        mode: u32,
+    ) {
+        self.check_path(package, dst);
+        if self.dry_run {
+            let change = match std::fs::read(dst) {
+                Err(_) => Change::Added,
+                Ok(data) if data != content => Change::Replaced("content".into()),
+                Ok(_) => Change::Untouched,
+            };
+            self.changes.borrow_mut().push((dst.to_path_buf(), change));
+        } else {
+            self.record(|plan| plan.write(dst, content, mode));
+            message::operation(
+                format_args!("install -D -m 0{mode:0o} {:#?}", dst),
+                "write",
+                json!({"dst": dst, "mode": format!("{mode:04o}")}),
+            );
+            self.save_file(dst);
+            self.cancel_copy(dst);
+            std::fs::create_dir_all(dst.parent().unwrap()).unwrap();
+            if dst.is_symlink() {
+                let _ = std::fs::remove_file(dst);
+            }
+            std::fs::write(dst, content).unwrap_or_else(|e| panic!("Failed to write {dst}: {e:?}"));
+            std::fs::set_permissions(dst, Permissions::from_mode(mode)).unwrap();
+        }
+        self.installed.borrow_mut().push(InstalledFile {
+            path: dst.to_path_buf(),
+            kind: manifest::Kind::File,
+            package: package.id.clone(),
+            rule: rule.into(),
+        });
+    }
+
+    /// Compare a file to install with the rootfs content.
+    fn diff_file(&self, src: &Path, dst: &Path, kind: manifest::Kind, mode: Option<u32>) {
+        let change = match dst.symlink_metadata() {
+            Err(_) => Change::Added,
+            Ok(metadata) if kind == manifest::Kind::Dir => {
+                if metadata.is_dir() {
+                    Change::Untouched
+                } else {
+                    Change::Replaced("not a directory".into())
+                }
+            }
+            Ok(metadata) => {
+                let mut changes = vec![];
+                let actual = metadata.permissions().mode() & 0o7777;
+                let expected = match mode {
+                    Some(mode) => mode,
+                    None => src.metadata().unwrap().permissions().mode() & 0o7777,
+                };
+                if !metadata.is_file() {
+                    changes.push("not a file".to_string());
+                } else if std::fs::read(src).ok() != std::fs::read(dst).ok() {
+                    changes.push("content".to_string());
+                }
+                if actual != expected {
+                    changes.push(format!("mode 0{actual:o} -> 0{expected:o}"));
+                }
+                if changes.is_empty() {
+                    Change::Untouched
+                } else {
+                    Change::Replaced(changes.join(", "))
+                }
+            }
+        };
+        if kind == manifest::Kind::File && change != Change::Untouched {
+            self.diff_copies.borrow_mut().push(PendingCopy {
+                src: src.to_path_buf(),
+                dst: dst.to_path_buf(),
+                mode,
+                group: None,
+            });
+        }
+        if kind == manifest::Kind::File || change != Change::Untouched {
+            self.changes.borrow_mut().push((dst.to_path_buf(), change));
+        }
+    }
+
+    /// Create a symbolic link in the rootfs, on behalf of a package.
+    fn install_symlink(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        original: &Path,
+        link: &Path,
+    ) {
+        self.check_path(package, link);
+        if self.dry_run {
+            let change = match link.read_link_utf8() {
+                Ok(target) if target == original => Change::Untouched,
+                Ok(target) => Change::Replaced(format!("target {target} -> {original}")),
+                Err(_) if link.symlink_metadata().is_ok() => {
+                    Change::Replaced("not a symbolic link".into())
+                }
+                Err(_) => Change::Added,
+            };
+            self.changes.borrow_mut().push((link.to_path_buf(), change));
+        } else {
+            self.record(|plan| plan.symlink(original, link));
+            message::operation(
+                format_args!("ln -sf {:#?} {:#?}", original, link),
+                "symlink",
+                json!({"target": original, "link": link}),
+            );
+            self.save_file(link);
+            self.cancel_copy(link);
+            if let Some(linkdir) = link.parent() {
+                std::fs::create_dir_all(linkdir).unwrap();
+            }
+            let _ = std::fs::remove_file(link);
+            symlink(original, link).unwrap();
+        }
+        self.installed.borrow_mut().push(InstalledFile {
+            path: link.to_path_buf(),
+            kind: manifest::Kind::Symlink,
+            package: package.id.clone(),
+            rule: rule.into(),
+        });
+    }
+
+    /// Return the absolute path of a destination file in the rootfs image.
+    fn get_image_path(&self, path: &Path) -> PathBuf {
+        let path = path.strip_prefix(&self.dst).unwrap_or(path);
+        PathBuf::from("/").join(path)
+    }
+
+    fn write_sbom(&self, spec: &str) {
+        let (format, output) = sbom::parse(spec);
+        let mut entries: Vec<sbom::Entry> = vec![];
+        for file in self.installed.borrow().iter() {
+            if file.kind != manifest::Kind::File {
+                continue;
+            }
+            // A file installed several times is attributed to the last package.
+            entries.retain(|entry| entry.file != file.path);
+            entries.push(sbom::Entry {
+                path: self.get_image_path(&file.path),
+                file: file.path.clone(),
+                package: self.get_package(&file.package),
+            });
+        }
+        sbom::write(format, &output, self.get_root_package(), &entries);
+    }
+
+    /// Print a summary of the install, and write it as JSON when `output` is specified.
+    fn write_stats(&self, output: Option<&Path>) {
+        let mut seen: Vec<&Path> = vec![];
+        let mut packages: Vec<(String, u64, u64)> = vec![];
+        let (mut files, mut bytes, mut symlinks) = (0, 0, 0);
+        let installed = self.installed.borrow();
+        // A file installed several times is attributed to the last package.
+        for file in installed.iter().rev() {
+            if file.kind == manifest::Kind::Dir || seen.contains(&file.path.as_path()) {
+                continue;
+            }
+            seen.push(&file.path);
+            let package = self.get_package(&file.package);
+            let name = format!("{}@{}", package.name, package.version);
+            let index = match packages.iter().position(|(x, _, _)| *x == name) {
+                Some(index) => index,
+                None => {
+                    packages.push((name, 0, 0));
+                    packages.len() - 1
+                }
+            };
+            if file.kind == manifest::Kind::Symlink {
+                symlinks += 1;
+                continue;
+            }
+            let size = file.path.symlink_metadata().map(|m| m.len()).unwrap_or(0);
+            files += 1;
+            bytes += size;
+            packages[index].1 += 1;
+            packages[index].2 += size;
+        }
+        packages.sort();
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        let line = format!(
+            "Installed {files} files ({bytes} bytes) and {symlinks} symbolic links in {elapsed:.2}s"
+        );
+        message::status(line.green().bold());
+        for (name, files, bytes) in &packages {
+            message::status(format_args!("  {name}: {files} files ({bytes} bytes)"));
+        }
+
+        let stats = json!({
+            "files": files,
+            "bytes": bytes,
+            "symlinks": symlinks,
+            "seconds": elapsed,
+            "packages": packages
+                .iter()
+                .map(|(name, files, bytes)| {
+                    json!({"package": name, "files": files, "bytes": bytes})
+                })
+                .collect::<Vec<Value>>(),
+        });
+        message::emit("summary", stats.clone());
+        if let Some(output) = output {
+            message::status(format_args!("Writing stats to {:#?}", output));
+            let data = serde_json::to_string_pretty(&stats).unwrap();
+            std::fs::write(output, data)
+                .unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+        }
+    }
+
+    /// Record the installed files, symbolic links and directories in the rootfs,
+    /// in var/lib/cargo-rootfs/<root-crate>.json.
+    ///
+    /// With `prune`, the entries of the previous manifest which were not installed
+    /// again are removed from the rootfs. Otherwise, they are kept in the manifest.
+    fn write_manifest(&self, prune: bool) -> manifest::Manifest {
+        let root = self.get_root_package();
+        let mut files: Vec<manifest::Entry> = vec![];
+        let mut add = |path: PathBuf, kind, package: &cargo_metadata::Package, rule: &str| {
+            if files.iter().any(|entry| entry.path == path) {
+                return;
+            }
+            let package = format!("{}@{}", package.name, package.version);
+            let entry = manifest::Entry::new(&self.dst, &path, kind, package, rule.into());
+            files.push(entry);
+        };
+
+        // A file installed several times is attributed to the last rule.
+        for file in self.installed.borrow().iter().rev() {
+            let package = self.get_package(&file.package);
+            let path = self.get_image_path(&file.path);
+            for dir in path.ancestors().skip(1) {
+                if dir != "/" {
+                    add(dir.into(), manifest::Kind::Dir, package, &file.rule);
+                }
+            }
+            add(path, file.kind, package, &file.rule);
+        }
+        // The directories of the whiteouts, which are not in the base image.
+        for whiteout in self.whiteouts.borrow().iter() {
+            for dir in whiteout.path.ancestors().skip(1) {
+                if dir != "/" {
+                    add(dir.into(), manifest::Kind::Dir, root, "whiteout");
+                }
+            }
+        }
+        for entry in self.conffiles.borrow().iter() {
+            if !files.iter().any(|x| x.path == entry.path) {
+                files.push(entry.clone());
+            }
+        }
+
+        let path = manifest::path(&self.dst, &root.name);
+        if let Some(previous) = manifest::Manifest::load(&path) {
+            let stale: Vec<manifest::Entry> = previous
+                .files
+                .into_iter()
+                .filter(|old| !files.iter().any(|entry| entry.path == old.path))
+                .collect();
+            if prune {
+                let stale: Vec<&manifest::Entry> = stale.iter().collect();
+                for entry in &stale {
+                    let path = self.get_destination_file(&entry.path);
+                    let is_dir = entry.kind == manifest::Kind::Dir;
+                    self.record(|plan| plan.remove(&path, is_dir));
+                    if entry.kind != manifest::Kind::Dir {
+                        let path = self.get_destination_file(&entry.path);
+                        self.journal.borrow_mut().save(&path);
+                    }
+                }
+                manifest::remove_entries(&self.dst, &stale);
+            } else {
+                let exists = |entry: &manifest::Entry| {
+                    let path = entry.path.strip_prefix("/").unwrap_or(&entry.path);
+                    self.dst.join(path).symlink_metadata().is_ok()
+                };
+                files.extend(stale.into_iter().filter(exists));
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let manifest = manifest::Manifest {
+            package: root.name.clone(),
+            version: root.version.to_string(),
+            files,
+            altsrc: self.overrides.borrow().clone(),
+            whiteouts: self.whiteouts.borrow().clone(),
+        };
+        self.journal.borrow_mut().save(&path);
+        manifest.save(&path);
+        self.record_file(&path);
+        manifest
+    }
+
+    /// Report the sources of the rules found in the alternative source directories, failing
+    /// if a crate required by --require-altsrc has none.
+    fn check_altsrc(&self, required: &[String]) {
+        let overrides = self.overrides.borrow();
+        for entry in overrides.iter() {
+            let line = format!(
+                "[{}] {}: {} from {}",
+                entry.package, entry.rule, entry.source, entry.altsrc
+            );
+            message::result(line, "altsrc", json!(entry));
+        }
+        for name in required {
+            let prefix = format!("{name}@");
+            if !overrides.iter().any(|x| x.package.starts_with(&prefix)) {
+                panic!("[{name}] No source found in the alternative source directories (--require-altsrc)");
+            }
+        }
+    }
+
+    /// Record an operation of the install in the plan.
+    fn record(&self, operation: impl FnOnce(&mut plan::Plan)) {
+        if let Some(plan) = &self.plan {
+            operation(&mut plan.borrow_mut());
+        }
+    }
+
+    /// Record the whole content of a file of the rootfs, e.g. the manifest.
+    fn record_file(&self, file: &Path) {
+        if self.plan.is_none() {
+            return;
+        }
+        let content =
+            std::fs::read(file).unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+        let mode = file
+            .metadata()
+            .map(|m| m.permissions().mode())
+            .unwrap_or(0o644);
+        self.record(|plan| plan.write(file, &content, mode & 0o7777));
+    }
+
+    /// Record the files of the previous manifest which would be pruned by the install.
+    fn diff_stale(&self) {
+        let root = self.get_root_package();
+        let previous = match manifest::Manifest::load(&manifest::path(&self.dst, &root.name)) {
+            Some(x) => x,
+            None => return,
+        };
+        let installed: Vec<PathBuf> = self
+            .installed
+            .borrow()
+            .iter()
+            .map(|file| self.get_image_path(&file.path))
+            .collect();
+        for entry in previous.files {
+            if entry.kind == manifest::Kind::Dir || installed.contains(&entry.path) {
+                continue;
+            }
+            let path = self.get_destination_file(&entry.path);
+            let mut changes = self.changes.borrow_mut();
+            // Already removed by a remove rule.
+            if changes.iter().any(|(x, _)| *x == path) {
+                continue;
+            }
+            if path.symlink_metadata().is_ok() {
+                changes.push((path, Change::Removed));
+            }
+        }
+    }
+
+    /// Record the change of a file whose content is only known by running the install.
+    fn diff_unknown(&self, dst: &Path, details: &str) {
+        let change = match dst.symlink_metadata() {
+            Ok(_) => Change::Replaced(details.into()),
+            Err(_) => Change::Added,
+        };
+        message::detail(format_args!("{dst}: {details}"));
+        self.changes.borrow_mut().push((dst.to_path_buf(), change));
+    }
+
+    fn print_diff(&self) {
+        let mut changes: Vec<(PathBuf, Change)> = vec![];
+        for (path, change) in self.changes.borrow().iter() {
+            let path = self.get_image_path(path);
+            changes.retain(|(other, _)| *other != path);
+            changes.push((path, change.clone()));
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let (mut added, mut replaced, mut untouched, mut removed) = (0, 0, 0, 0);
+        for (path, change) in &changes {
+            match change {
+                Change::Added => {
+                    added += 1;
+                    message::result(
+                        format_args!("{} {path}", "added:".green().bold()),
+                        "diff",
+                        json!({"path": path, "change": "added"}),
+                    );
+                }
+                Change::Replaced(details) => {
+                    replaced += 1;
+                    message::result(
+                        format_args!("{} {path} ({details})", "replaced:".yellow().bold()),
+                        "diff",
+                        json!({"path": path, "change": "replaced", "details": details}),
+                    );
+                }
+                Change::Untouched => {
+                    untouched += 1;
+                    message::result(
+                        format_args!("{} {path}", "untouched:".bold()),
+                        "diff",
+                        json!({"path": path, "change": "untouched"}),
+                    );
+                }
+                Change::Removed => {
+                    removed += 1;
+                    message::result(
+                        format_args!("{} {path}", "removed:".red().bold()),
+                        "diff",
+                        json!({"path": path, "change": "removed"}),
+                    );
+                }
+            }
+        }
+        message::status(format_args!(
+            "{added} added, {replaced} replaced, {untouched} untouched, {removed} removed"
+        ));
+    }
+
+    /// Lock the destination, unless it is only compared.
+    fn lock(&self, mode: lock::Mode) -> Option<lock::Lock> {
+        if self.dry_run {
+            return None;
+        }
+        lock::lock(&self.dst, mode)
+    }
+
+    /// Return the state file of the last install in the destination.
+    fn state_path(&self) -> PathBuf {
+        state::path(
+            &self.metadata.target_directory,
+            &canonicalize_existing(&self.dst),
+        )
+    }
+
+    /// Return the digest of the version, the options and the cargo metadata of the install.
+    fn fingerprint(&self, args: &CargoRootfsArgs) -> String {
+        // Only the options changing the installed files: not the output, nor the jobs.
+        let options: [&dyn std::fmt::Debug; 43] = [
+            &args.command,
+            &args.dst,
+            &args.altsrc,
+            &args.require_altsrc,
+            &args.rootfs_dsts,
+            &args.target,
+            &args.all_bins_only,
+            &args.bins_only,
+            &args.lib_only,
+            &args.tests,
+            &args.benches,
+            &args.no_deps,
+            &args.deps_only,
+            &args.init_startdir,
+            &args.init_stopdir,
+            &args.licenses,
+            &args.embed_manifest,
+            &args.no_prune,
+            &args.backup,
+            &args.rootfs_profile,
+            &args.kernel_version,
+            &args.kernel_build_dir,
+            &args.split_debug,
+            &args.debuginfod,
+            &args.compress_debug,
+            &args.scrub_notes,
+            &args.dedupe,
+            &args.symlink_style,
+            &args.strict,
+            &args.keep_going,
+            &args.interp,
+            &args.sysroot,
+            &args.ldconfig,
+            &args.build_info,
+            &args.base,
+            &args.whiteout,
+            &args.sign,
+            &args.sign_elfs,
+            &args.features,
+            &args.manifest_path,
+            &args.lockfile_path,
+            &args.target_dir,
+            &args.artifact_dir,
+        ];
+        let metadata = serde_json::to_string(&self.metadata).unwrap();
+        let version = env!("CARGO_PKG_VERSION");
+        state::digest(format!("{version}\n{options:?}\n{metadata}").as_bytes())
+    }
+
+    /// Save the state of the install, unless it has untracked inputs (commands and hooks)
+    /// or writes reports out of the rootfs, always run again.
+    fn save_state(&self, file: &Path, fingerprint: String, args: &CargoRootfsArgs) {
+        let reports = [
+            args.sbom.is_some(),
+            args.manifest.is_some(),
+            args.stats.is_some(),
+            args.emit_script.is_some(),
+            args.emit_build.is_some(),
+        ];
+        let inputs = self.inputs.borrow();
+        let reason = if inputs.untracked {
+            Some("it runs commands or builds tests")
+        } else if reports.contains(&true) {
+            Some("it writes reports")
+        } else if !self.hooks.borrow().is_empty() {
+            Some("it runs hooks")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            message::detail(format_args!(
+                "The state of the install is not saved, {reason}: it is always run again"
+            ));
+            let _ = std::fs::remove_file(file);
+            return;
+        }
+        let manifest = manifest::path(&self.dst, &self.get_root_package().name);
+        let installed = self.installed.borrow();
+        let paths = installed.iter().map(|x| x.path.as_path());
+        state::State::new(fingerprint, &inputs, paths.chain([manifest.as_path()])).save(file);
+    }
+
+    fn get_root_package(&self) -> &cargo_metadata::Package {
+        self.get_package(&self.root)
+    }
+
+    fn get_package(&self, id: &cargo_metadata::PackageId) -> &cargo_metadata::Package {
         for package in &self.metadata.packages {
             if package.id == *id {
                 return package;
             }
         }
-        panic!("Could not find {id}");
+        panic!("Could not find {id}");
+    }
+
+    fn get_manifest_dir(&self, package: &cargo_metadata::Package) -> PathBuf {
+        let manifest_dir = package
+            .manifest_path
+            .parent()
+            .unwrap_or_else(|| panic!("[{}] Failed to get manifest directory", package.name));
+        PathBuf::from(manifest_dir)
+    }
+
+    /// Return the directories of the local packages (workspace members and path dependencies).
+    fn get_local_dirs(&self) -> Vec<PathBuf> {
+        self.metadata
+            .packages
+            .iter()
+            .filter(|package| package.source.is_none())
+            .map(|package| self.get_manifest_dir(package))
+            .collect()
+    }
+
+    fn get_source_file(
+        &self,
+        package: &cargo_metadata::Package,
+        i: usize,
+        rule: &CargoRootfsRule,
+        source: &Path,
+    ) -> PathBuf {
+        let source = &self.expand(package, source);
+        let key = rule
+            .altsrc_key
+            .as_deref()
+            .unwrap_or(package.name.as_str().into());
+        for altsrc in &self.altsrc {
+            let altsrc = altsrc.join(key).join(source);
+            if altsrc.exists() {
+                let entry = manifest::Override {
+                    package: format!("{}@{}", package.name, package.version),
+                    rule: format!("package.metadata.rootfs.[{i}]"),
+                    source: source.clone(),
+                    altsrc: altsrc.clone(),
+                };
+                let mut overrides = self.overrides.borrow_mut();
+                if !overrides.contains(&entry) {
+                    overrides.push(entry);
+                }
+                return altsrc;
+            }
+            // An alternative source added later takes precedence.
+            self.track(&altsrc, manifest::Kind::File);
+        }
+
+        self.get_manifest_dir(package).join(source)
+    }
+
+    /// Expand the `${OUT_DIR}` and `${ENV_VAR}` variables of a rule source.
+    fn expand(&self, package: &cargo_metadata::Package, source: &Path) -> PathBuf {
+        let mut expanded = String::new();
+        let mut rest = source.as_str();
+        while let Some(start) = rest.find("${") {
+            expanded += &rest[..start];
+            let end = rest[start..]
+                .find('}')
+                .unwrap_or_else(|| panic!("[{}] Unterminated variable in {source}", package.name));
+            let name = &rest[start + 2..start + end];
+            if name == "OUT_DIR" {
+                expanded += self.get_out_dir(package).as_str();
+            } else {
+                self.inputs.borrow_mut().env.push(name.into());
+                expanded += &std::env::var(name)
+                    .unwrap_or_else(|_| panic!("[{}] {source}: {name} is not set", package.name));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        expanded += rest;
+        PathBuf::from(expanded)
+    }
+
+    /// Run the cargo build again, with extra arguments, and return its JSON messages.
+    fn cargo_messages(&self, extra_args: &[&str]) -> Vec<cargo_metadata::Message> {
+        let (program, args) = self.build_command.split_first().unwrap();
+        let mut command = self.build_command.clone();
+        command.extend(extra_args.iter().map(|x| x.to_string()));
+        message::status(format_args!(
+            "{} --message-format=json-render-diagnostics",
+            command.join(" ")
+        ));
+        let output = std::process::Command::new(program)
+            .args(args)
+            .args(extra_args)
+            .arg("--message-format=json-render-diagnostics")
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+        if !output.status.success() {
+            panic!("{} failed: {}", command.join(" "), output.status);
+        }
+        cargo_metadata::Message::parse_stream(output.stdout.as_slice())
+            .flatten()
+            .collect()
+    }
+
+    /// Return the JSON messages of the cargo build, run again the first time.
+    fn get_build_messages(&self) -> std::cell::RefMut<'_, Vec<cargo_metadata::Message>> {
+        std::cell::RefMut::map(self.build_messages.borrow_mut(), |messages| {
+            messages.get_or_insert_with(|| self.cargo_messages(&[]))
+        })
+    }
+
+    /// Return the OUT_DIR of the build script of a package, reported by cargo.
+    fn get_out_dir(&self, package: &cargo_metadata::Package) -> PathBuf {
+        let out_dir = self
+            .get_build_messages()
+            .iter()
+            .find_map(|message| match message {
+                cargo_metadata::Message::BuildScriptExecuted(script)
+                    if script.package_id == package.id =>
+                {
+                    Some(script.out_dir.clone())
+                }
+                _ => None,
+            });
+        out_dir
+            .unwrap_or_else(|| panic!("[{}] OUT_DIR is not defined: no build script", package.name))
+    }
+
+    /// Return the file of target/rootfs-cache downloaded with this sha256 digest, if any.
+    fn downloaded(&self, sha256: &str) -> Option<PathBuf> {
+        let sha256 = sha256.to_lowercase();
+        let file = self
+            .metadata
+            .target_directory
+            .join("rootfs-cache")
+            .join(&sha256);
+        (file.exists() && manifest::sha256sum(&file) == sha256).then_some(file)
+    }
+
+    /// Download a file to target/rootfs-cache, unless it is already cached, and
+    /// check its sha256 digest.
+    fn download(&self, package: &cargo_metadata::Package, url: &str, sha256: &str) -> PathBuf {
+        if let Some(file) = self.downloaded(sha256) {
+            return file;
+        }
+        let sha256 = sha256.to_lowercase();
+        let cache = self.metadata.target_directory.join("rootfs-cache");
+        let file = cache.join(&sha256);
+        if self.offline {
+            panic!(
+                "[{}] {url} is not in {cache} and can not be downloaded offline",
+                package.name
+            );
+        }
+
+        std::fs::create_dir_all(&cache)
+            .unwrap_or_else(|e| panic!("Failed to create directory {cache}: {e:?}"));
+        let partial = cache.join(format!("{sha256}.part"));
+        message::status(format_args!("curl -fsSL -o {:#?} {url}", partial));
+        let status = std::process::Command::new("curl")
+            .arg("-fsSL")
+            .arg("-o")
+            .arg(&partial)
+            .arg(url)
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run curl: {e:?}"));
+        if !status.success() {
+            let _ = std::fs::remove_file(&partial);
+            panic!("[{}] Failed to download {url}: {status}", package.name);
+        }
+        let digest = manifest::sha256sum(&partial);
+        if digest != sha256 {
+            let _ = std::fs::remove_file(&partial);
+            panic!(
+                "[{}] {url} sha256 mismatch: expected {sha256}, got {digest}",
+                package.name
+            );
+        }
+        std::fs::rename(&partial, &file).unwrap();
+        file
+    }
+
+    /// Add lines to a file of the rootfs, creating it if needed, without duplicating them
+    /// on the next installs:
+    /// - `ensure_line` is appended unless present, replacing the line starting with `replace`;
+    /// - `append` is written between marker comments, replacing the previous block.
+    fn edit_lines(
+        &self,
+        package: &cargo_metadata::Package,
+        i: usize,
+        rule: &CargoRootfsRule,
+        dst: &Path,
+    ) {
+        let mut edits = vec![];
+        if let Some(line) = &rule.ensure_line {
+            edits.push(plan::Edit::EnsureLine {
+                line: line.clone(),
+                replace: rule.replace.clone(),
+            });
+        }
+        if let Some(block) = &rule.append {
+            let comment = rule.comment.as_deref().unwrap_or("#");
+            let id = format!("cargo-rootfs {} [{i}]", package.name);
+            edits.push(plan::Edit::AppendBlock {
+                begin: format!("{comment} BEGIN {id}"),
+                end: format!("{comment} END {id}"),
+                lines: block.lines().map(|x| x.to_string()).collect(),
+            });
+        }
+        self.edit_file(package, dst, &edits);
+    }
+
+    /// Edit the lines of a file of the rootfs, creating it if needed. The edits are
+    /// recorded in the plan, to be replayed on the content of the file in the target.
+    fn edit_file(&self, package: &cargo_metadata::Package, dst: &Path, edits: &[plan::Edit]) {
+        if !self.dry_run {
+            self.flush_copy(dst);
+        }
+        // The file is written through a symbolic link, which must stay in the rootfs.
+        let file = self.check_path(package, dst);
+        self.track(dst, manifest::Kind::File);
+        if !self.dry_run {
+            self.record(|plan| edits.iter().for_each(|edit| plan.edit(dst, edit)));
+        }
+        let old = std::fs::read_to_string(&file).unwrap_or_default();
+        let content = match plan::edit_content(&old, edits) {
+            Some(x) => x,
+            None => return,
+        };
+        message::status(format_args!("[{}] Editing {dst}", package.name));
+        if self.dry_run {
+            let change = match file.exists() {
+                true => Change::Replaced("lines".into()),
+                false => Change::Added,
+            };
+            self.changes.borrow_mut().push((dst.to_path_buf(), change));
+            return;
+        }
+        message::operation(
+            format_args!("echo >> {:#?}", dst),
+            "edit",
+            json!({"file": dst}),
+        );
+        self.save_file_in_place(&file);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, content).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+    }
+
+    /// Create a directory of the rootfs, owned by uid:gid when running as root.
+    fn install_dir(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        dst: &Path,
+        owner: Option<(u32, u32)>,
+    ) {
+        let dir = self.check_path(package, dst);
+        if self.dry_run {
+            if !dst.is_dir() {
+                self.changes
+                    .borrow_mut()
+                    .push((dst.to_path_buf(), Change::Added));
+            }
+        } else {
+            self.record(|plan| plan.mkdir(dst, owner));
+            message::operation(
+                format_args!("install -d {:#?}", dst),
+                "mkdir",
+                json!({"dst": dst}),
+            );
+            self.journal.borrow_mut().save_dir(dst);
+            std::fs::create_dir_all(dst)
+                .unwrap_or_else(|e| panic!("Failed to create directory {dst}: {e:?}"));
+            if let Some((uid, gid)) = owner {
+                // Only root can give the files to another user.
+                if unsafe { libc::geteuid() } == 0 {
+                    std::os::unix::fs::chown(&dir, Some(uid), Some(gid))
+                        .unwrap_or_else(|e| panic!("Failed to chown {dir}: {e:?}"));
+                } else {
+                    message::debug(format_args!("Not running as root: {dst} is not chowned"));
+                }
+            }
+        }
+        self.installed.borrow_mut().push(InstalledFile {
+            path: dst.to_path_buf(),
+            kind: manifest::Kind::Dir,
+            package: package.id.clone(),
+            rule: rule.into(),
+        });
+    }
+
+    /// Install a daemon: its binary, the system user running it, its directories, and
+    /// its init script or systemd unit.
+    fn install_daemon(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        daemon: &daemon::Daemon,
+    ) {
+        let name = &daemon.name;
+        message::status(format_args!("[{}] {rule}: daemon {name}", package.name));
+
+        let bin = self.dst.join("usr/bin").join(daemon.bin());
+        let installed = self.installed.borrow().iter().any(|x| x.path == bin);
+        let is_bin = |target: &cargo_metadata::Target| {
+            target.name == daemon.bin() && target.kind.contains(&cargo_metadata::TargetKind::Bin)
+        };
+        if !installed {
+            if !package.targets.iter().any(is_bin) {
+                panic!(
+                    "[{}] {rule}.daemon: {} is not a binary of the crate",
+                    package.name,
+                    daemon.bin()
+                );
+            }
+            self.install_bin(package, daemon.bin());
+        }
+
+        let mut owner = None;
+        if let Some(user) = &daemon.user {
+            let passwd = self.dst.join("etc/passwd");
+            let group = self.dst.join("etc/group");
+            let passwd_content = std::fs::read_to_string(&passwd).unwrap_or_default();
+            let group_content = std::fs::read_to_string(&group).unwrap_or_default();
+            let free = daemon::free_id(&passwd_content, &group_content);
+            let gid = daemon::find_id(&group_content, user).unwrap_or(free);
+            let uid = daemon::find_id(&passwd_content, user).unwrap_or(free);
+            let edit = plan::Edit::AddUser {
+                name: user.clone(),
+                entry: daemon.passwd_line(user, uid, gid),
+            };
+            self.edit_file(package, &passwd, &[edit]);
+            let edit = plan::Edit::AddUser {
+                name: user.clone(),
+                entry: format!("{user}:x:{gid}:"),
+            };
+            self.edit_file(package, &group, &[edit]);
+            owner = Some((uid, gid));
+        }
+        for dir in &daemon.dirs {
+            let dst = self.get_destination_file(dir);
+            self.install_dir(package, rule, &dst, owner);
+        }
+
+        match daemon.init {
+            daemon::Init::Sysv => {
+                let script = self.dst.join("etc/init.d").join(name);
+                let content = daemon.init_script();
+                self.install_content(package, rule, &script, content.as_bytes(), 0o755);
+                self.services
+                    .borrow_mut()
+                    .push(format!("/etc/init.d/{name}"));
+                let original = PathBuf::from("../init.d").join(name);
+                let start = daemon.start.unwrap_or(90);
+                let link = self.get_dst_startdir().join(format!("S{start}{name}"));
+                self.install_symlink(package, rule, &original, &link);
+                let stop = daemon.stop.unwrap_or(10);
+                let link = self.get_dst_stopdir().join(format!("K{stop}{name}"));
+                self.install_symlink(package, rule, &original, &link);
+            }
+            daemon::Init::Systemd => {
+                let unit = format!("{name}.service");
+                let dst = self.dst.join("usr/lib/systemd/system").join(&unit);
+                let content = daemon.unit();
+                self.install_content(package, rule, &dst, content.as_bytes(), 0o644);
+                let original = PathBuf::from("/usr/lib/systemd/system").join(&unit);
+                let wants = self.dst.join("etc/systemd/system/multi-user.target.wants");
+                self.install_symlink(package, rule, &original, &wants.join(&unit));
+                if !daemon.dirs.is_empty() {
+                    let dst = self
+                        .dst
+                        .join("usr/lib/tmpfiles.d")
+                        .join(format!("{name}.conf"));
+                    let content = daemon.tmpfiles();
+                    self.install_content(package, rule, &dst, content.as_bytes(), 0o644);
+                }
+            }
+        }
+    }
+
+    /// Apply a patch to a file of the rootfs, installed by a previous rule or already
+    /// present in the rootfs. A patch already applied is skipped.
+    fn apply_patch(&self, package: &cargo_metadata::Package, patch: &Path, dst: &Path) {
+        if !self.dry_run {
+            self.flush_copy(dst);
+        }
+        // The file is patched through a symbolic link, which must stay in the rootfs.
+        let target = self.check_path(package, dst);
+        self.track(patch, manifest::Kind::File);
+        // A diff patches a copy of the new content of the file, when it would be copied.
+        let pending = match self.dry_run {
+            true => self.diff_patch_file(dst),
+            false => None,
+        };
+        let file = pending.as_ref().map_or(&target, |copy| &copy.src);
+        if !file.is_file() {
+            panic!("[{}] Can not apply {patch}: {dst} not found", package.name);
+        }
+        let patch_cmd = |args: &[&str]| {
+            std::process::Command::new("patch")
+                .args(args)
+                .arg("-s")
+                .arg("-f")
+                .arg("--no-backup-if-mismatch")
+                .arg("-r")
+                .arg("-")
+                .arg(file)
+                .arg("-i")
+                .arg(patch)
+                .stdout(std::process::Stdio::null())
+                .status()
+                .unwrap_or_else(|e| panic!("Failed to run patch: {e:?}"))
+                .success()
+        };
+        message::status(format_args!("[{}] patch {dst} < {patch}", package.name));
+        if patch_cmd(&["-R", "--dry-run"]) {
+            message::detail(format_args!("{patch} is already applied to {dst}"));
+            return;
+        }
+        if self.dry_run {
+            if let Some(copy) = &pending {
+                // The patched copy is the new content of the file, for the next patches.
+                if !patch_cmd(&["-N"]) {
+                    panic!("[{}] {patch} does not apply to {dst}", package.name);
+                }
+                return self.diff_file(&copy.src, dst, manifest::Kind::File, copy.mode);
+            }
+            if !patch_cmd(&["--dry-run"]) {
+                panic!("[{}] {patch} does not apply to {dst}", package.name);
+            }
+            let change = Change::Replaced(format!("patched by {patch}"));
+            self.changes.borrow_mut().push((dst.to_path_buf(), change));
+            return;
+        }
+        self.record(|plan| plan.patch(dst, patch));
+        message::operation(
+            format_args!("patch {:#?} -i {:#?}", dst, patch),
+            "patch",
+            json!({"file": dst, "patch": patch}),
+        );
+        self.save_file_in_place(&target);
+        if !patch_cmd(&["-N"]) {
+            panic!("[{}] Failed to apply {patch} to {dst}", package.name);
+        }
+    }
+
+    /// Copy the new content of a file which a diff would copy to target/rootfs-diff, to be
+    /// patched instead of the file of the rootfs. The copy replaces its source in the diff.
+    fn diff_patch_file(&self, dst: &Path) -> Option<PendingCopy> {
+        let mut diff_copies = self.diff_copies.borrow_mut();
+        let copy = diff_copies.iter_mut().rev().find(|copy| copy.dst == dst)?;
+        let image = self.get_image_path(dst);
+        let file = self
+            .metadata
+            .target_directory
+            .join("rootfs-diff")
+            .join(image.as_str().trim_start_matches('/'));
+        if copy.src != file {
+            let dir = file.parent().unwrap();
+            std::fs::create_dir_all(dir)
+                .unwrap_or_else(|e| panic!("Failed to create directory {dir}: {e:?}"));
+            std::fs::copy(&copy.src, &file)
+                .unwrap_or_else(|e| panic!("Failed to copy {} to {file}: {e:?}", copy.src));
+            copy.src = file;
+        }
+        Some(copy.clone())
+    }
+
+    /// Run the command of a rule from the crate directory, returning the file it
+    /// generated: `$OUT` if it was written, its standard output otherwise.
+    fn generate(&self, package: &cargo_metadata::Package, i: usize, command: &str) -> PathBuf {
+        self.inputs.borrow_mut().untracked = true;
+        // The command may use the files previously installed, e.g. the binaries.
+        self.flush_copies();
+        let outdir = self
+            .metadata
+            .target_directory
+            .join("rootfs-gen")
+            .join(&package.name)
+            .join(i.to_string());
+        let _ = std::fs::remove_dir_all(&outdir);
+        std::fs::create_dir_all(&outdir)
+            .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+        let out = outdir.join("out");
+
+        message::status(format_args!("sh -c {:#?}", command));
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(self.get_manifest_dir(package))
+            .env("OUT", &out)
+            .env("DESTDIR", &self.dst)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .unwrap_or_else(|e| panic!("[{}] Failed to run {command:?}: {e:?}", package.name));
+        if !output.status.success() {
+            panic!(
+                "[{}] Command {command:?} failed: {}",
+                package.name, output.status
+            );
+        }
+        if !out.exists() {
+            std::fs::write(&out, &output.stdout)
+                .unwrap_or_else(|e| panic!("Failed to write {out}: {e:?}"));
+        }
+        out
     }
 
-    fn get_manifest_dir(&self, package: &cargo_metadata::Package) -> PathBuf {
-        let manifest_dir = package
-            .manifest_path
-            .parent()
-            .unwrap_or_else(|| panic!("[{}] Failed to get manifest directory", package.name));
-        PathBuf::from(manifest_dir)
+    /// Compress a firmware file, or the files of a firmware directory, as expected by the
+    /// kernel firmware loader: xz with CRC32 checks, or zstd. Return the compressed file or
+    /// directory.
+    fn compress_firmware(
+        &self,
+        package: &cargo_metadata::Package,
+        i: usize,
+        src: &Path,
+        compress: &str,
+    ) -> PathBuf {
+        let (program, args) = match compress {
+            "xz" => (
+                std::env::var("XZ").unwrap_or("xz".into()),
+                vec![
+                    "--compress",
+                    "--stdout",
+                    "--check=crc32",
+                    "--lzma2=dict=1MiB",
+                ],
+            ),
+            _ => (
+                std::env::var("ZSTD").unwrap_or("zstd".into()),
+                vec!["--compress", "--stdout", "--quiet"],
+            ),
+        };
+        let outdir = self
+            .metadata
+            .target_directory
+            .join("rootfs-firmware")
+            .join(&package.name)
+            .join(i.to_string());
+        let _ = std::fs::remove_dir_all(&outdir);
+        std::fs::create_dir_all(&outdir)
+            .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+        let out = outdir.join(src.file_name().unwrap_or("firmware"));
+        recursive_copy(
+            src,
+            &out,
+            &mut vec![],
+            &mut vec![],
+            &mut |src, dst, kind| {
+                if kind == manifest::Kind::Dir {
+                    std::fs::create_dir_all(dst)
+                        .unwrap_or_else(|e| panic!("Failed to create directory {dst}: {e:?}"));
+                    return;
+                }
+                let dst = PathBuf::from(format!("{dst}.{compress}"));
+                message::debug(format_args!("{program} {} {src} > {dst}", args.join(" ")));
+                let file = std::fs::File::create(&dst)
+                    .unwrap_or_else(|e| panic!("Failed to create {dst}: {e:?}"));
+                let status = std::process::Command::new(&program)
+                    .args(&args)
+                    .arg(src)
+                    .stdout(file.try_clone().unwrap())
+                    .status()
+                    .unwrap_or_else(|e| {
+                        panic!("[{}] Failed to run {program}: {e:?}", package.name)
+                    });
+                if !status.success() {
+                    panic!("[{}] {program} {src} failed: {status}", package.name);
+                }
+                // Keep the mode and modification time, to detect the unchanged files.
+                let metadata = src.metadata().unwrap();
+                std::fs::set_permissions(&dst, metadata.permissions()).unwrap();
+                if let Ok(mtime) = metadata.modified() {
+                    let _ = file.set_modified(mtime);
+                }
+            },
+        );
+        match src.is_file() {
+            true => PathBuf::from(format!("{out}.{compress}")),
+            false => out,
+        }
     }
 
-    fn get_source_file(&self, package: &cargo_metadata::Package, source: &Path) -> PathBuf {
-        if let Some(altsrc) = &self.altsrc {
-            let altsrc = altsrc.join(&package.name).join(source);
-            if altsrc.exists() {
-                return altsrc;
+    /// Install firmware blobs. A blob identical to a blob installed by another package is
+    /// replaced by a symbolic link to it, or skipped when installed at the same path.
+    fn install_firmware(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        src: &Path,
+        dst: &Path,
+        mode: Option<u32>,
+    ) {
+        let mut files = vec![];
+        recursive_copy(src, dst, &mut vec![], &mut vec![], &mut |src, dst, kind| {
+            self.track(src, kind);
+            if kind == manifest::Kind::File {
+                files.push((src.to_path_buf(), dst.to_path_buf()));
+            }
+        });
+        for (src, dst) in files {
+            let sha256 = manifest::sha256sum(&src);
+            let originals: Vec<PathBuf> = self
+                .firmware
+                .borrow()
+                .iter()
+                .filter(|(x, _, id)| *x == sha256 && *id != package.id)
+                .map(|(_, path, _)| path.clone())
+                .collect();
+            match originals.first() {
+                Some(_) if originals.contains(&dst) => {
+                    message::debug(format_args!("Skipping {dst}, installed by another crate"));
+                }
+                Some(original) => {
+                    let linkdir = self.get_image_path(dst.parent().unwrap());
+                    let original = relative_path(&self.get_image_path(original), &linkdir);
+                    self.install_symlink(package, rule, &original, &dst);
+                }
+                None => {
+                    self.install_file(package, rule, &src, &dst, mode);
+                    let id = package.id.clone();
+                    self.firmware.borrow_mut().push((sha256, dst, id));
+                }
             }
         }
-
-        self.get_manifest_dir(package).join(source)
     }
 
     fn get_destination_file(&self, destination: &Path) -> PathBuf {
@@ -213,17 +3707,22 @@ impl CargoRootfs {
         self.dst.join(dir)
     }
 
-    fn root_crate_symlink_bin(&self, package: &cargo_metadata::Package) {
+    fn get_root_bin(&self) -> Option<&cargo_metadata::Target> {
+        self.get_root_package()
+            .targets
+            .iter()
+            .find(|target| target.kind.contains(&cargo_metadata::TargetKind::Bin))
+    }
+
+    /// Link the binaries of a package to the root crate binary, in /usr/bin or in the
+    /// directory of the rule.
+    fn root_crate_symlink_bin(&self, package: &cargo_metadata::Package, linkdir: Option<&Path>) {
         let root_package = self.get_root_package();
         if root_package.name == package.name {
             return;
         }
 
-        let root_bin = root_package
-            .targets
-            .iter()
-            .find(|target| target.kind.contains(&cargo_metadata::TargetKind::Bin));
-        let root_bin = match root_bin {
+        let root_bin = match self.get_root_bin() {
             Some(x) => x,
             None => return,
         };
@@ -233,15 +3732,34 @@ impl CargoRootfs {
                 continue;
             }
             if !self.is_target_enabled(&package.id, target) {
+                message::debug(format_args!("{} is not enabled", target.name));
                 continue;
             }
 
-            let original = &root_bin.name;
-            let link = self.dst.join("usr/bin").join(&target.name);
-
-            println!("ln -sf {:#?} {:#?}", original, link);
-            let _ = std::fs::remove_file(&link);
-            symlink(original, &link).unwrap();
+            let root_path = self.dst.join("usr/bin").join(&root_bin.name);
+            let linkdir = self.get_destination_file(linkdir.unwrap_or("/usr/bin".into()));
+            let link = linkdir.join(&target.name);
+            let original = relative_path(&root_path, &linkdir);
+            message::status(format_args!(
+                "[{}] root_crate_symlink: {} -> {original}",
+                package.name, target.name
+            ));
+            let installed = self.installed.borrow().iter().any(|x| x.path == root_path);
+            if !installed && !root_path.exists() {
+                let line = format!(
+                    "{} [{}] root_crate_symlink: {} is not installed",
+                    "Warning:".yellow().bold(),
+                    package.name,
+                    self.get_image_path(&root_path)
+                );
+                message::result(
+                    line,
+                    "warning",
+                    json!({"package": package.name, "file": root_path}),
+                );
+                continue;
+            }
+            self.install_symlink(package, "root_crate_symlink", &original, &link);
         }
     }
 
@@ -252,102 +3770,967 @@ impl CargoRootfs {
         rule: &CargoRootfsRule,
     ) {
         if rule.root_crate_symlink == Some(true) {
-            self.root_crate_symlink_bin(package);
+            self.root_crate_symlink_bin(package, rule.destination.as_deref());
             return;
         }
 
-        let rule_src = rule.source.as_ref().unwrap_or_else(|| {
-            panic!(
-                "[{}] Missing package.metadata.rootfs.[{i}].src",
-                package.name
-            )
-        });
-        let rule_dst = rule.destination.as_ref().unwrap_or_else(|| {
-            panic!(
-                "[{}] Missing package.metadata.rootfs.[{i}].dst",
-                package.name
-            )
+        if let Some(command) = &rule.hook {
+            self.hooks.borrow_mut().push(Hook {
+                package: package.name.clone(),
+                manifest_dir: self.get_manifest_dir(package),
+                command: command.clone(),
+                run_in_rootfs: rule.run_in_rootfs == Some(true),
+            });
+            return;
+        }
+
+        if let Some(remove) = &rule.remove {
+            // The patterns match any file of the destination, not only the installed ones.
+            if canonicalize_existing(&self.dst) == "/" {
+                let error = "remove is refused when the destination is /";
+                panic!("{}", rule_error(package, i, Some("remove"), error));
+            }
+            let patterns = remove.iter().filter_map(|x| ignore::parse(&self.dst, x));
+            self.removals.borrow_mut().extend(patterns);
+            return;
+        }
+
+        if let Some(patch) = &rule.patch {
+            let destination = rule.destination.as_ref().unwrap_or_else(|| {
+                panic!("{}", rule_error(package, i, None, "missing destination"))
+            });
+            let patch = self.get_source_file(package, i, rule, patch);
+            let dst = self.get_destination_file(destination);
+            return self.apply_patch(package, &patch, &dst);
+        }
+
+        // Handled by install_bin.
+        if rule.bin.is_some() {
+            return;
+        }
+
+        if let Some(artifact) = &rule.install_artifact {
+            return self.install_artifact(package, i, artifact, rule);
+        }
+
+        if let Some(daemon) = &rule.daemon {
+            let rule_name = format!("package.metadata.rootfs.[{i}]");
+            return self.install_daemon(package, &rule_name, daemon);
+        }
+
+        if rule.append.is_some() || rule.ensure_line.is_some() {
+            let destination = rule.destination.as_ref().unwrap_or_else(|| {
+                panic!("{}", rule_error(package, i, None, "missing destination"))
+            });
+            let dst = self.get_destination_file(destination);
+            return self.edit_lines(package, i, rule, &dst);
+        }
+
+        // A downloaded file is named after the last component of its URL.
+        let url_name = rule
+            .url
+            .as_ref()
+            .map(|url| PathBuf::from(url.rsplit('/').next().unwrap()));
+        let command_name = rule
+            .command
+            .as_ref()
+            .map(|command| PathBuf::from(format!("$({command})")));
+        let rule_src = rule
+            .source
+            .as_ref()
+            .or(url_name.as_ref())
+            .or(command_name.as_ref())
+            .unwrap_or_else(|| panic!("{}", rule_error(package, i, None, "missing source")));
+        let module_dir = (rule.kernel_module == Some(true)).then(|| {
+            let version = self.kernel_version.as_ref().unwrap_or_else(|| {
+                let error = "kernel_module requires --kernel-version or --kernel-build-dir";
+                panic!("{}", rule_error(package, i, Some("kernel_module"), error))
+            });
+            PathBuf::from(format!("/lib/modules/{version}/extra/"))
         });
+        let firmware_dir = (rule.firmware == Some(true)).then(|| PathBuf::from("/lib/firmware/"));
+        let rule_dst = rule
+            .destination
+            .as_ref()
+            .or(module_dir.as_ref())
+            .or(firmware_dir.as_ref())
+            .unwrap_or_else(|| panic!("{}", rule_error(package, i, None, "missing destination")));
+        // A destination ending with '/' is a directory in which the source is installed
+        // with its own name, instead of the path of the installed file or directory.
+        let rule_dst = &if rule_dst.as_str().ends_with('/') {
+            if rule.command.is_some() {
+                let error = "command requires a destination file";
+                panic!("{}", rule_error(package, i, Some("destination"), error));
+            }
+            let name = rule_src.file_name().unwrap_or_else(|| {
+                let error = "source has no file name";
+                panic!("{}", rule_error(package, i, Some("source"), error))
+            });
+            rule_dst.join(name)
+        } else {
+            rule_dst.clone()
+        };
         let mode = rule.permissions.as_ref().map(|mode| {
             u32::from_str_radix(mode, 8).unwrap_or_else(|_| {
-                panic!(
-                    "[{}] package.metadata.rootfs.[{i}].mode is not an octal number",
-                    package.name
-                )
+                let error = format!("permissions {mode:?} is not an octal number");
+                panic!("{}", rule_error(package, i, Some("permissions"), &error))
             })
         });
 
+        let rule_name = format!("package.metadata.rootfs.[{i}]");
+        message::status(format_args!(
+            "[{}] {rule_name}: {rule_src} -> {rule_dst}",
+            package.name
+        ));
         if rule.symbolic == Some(true) {
-            let original = rule_src;
             let link = self.get_destination_file(rule_dst);
-            println!("ln -sf {:#?} {:#?}", original, link);
-            if let Some(linkdir) = link.parent() {
-                std::fs::create_dir_all(linkdir).unwrap();
-            }
-            let _ = std::fs::remove_file(&link);
-            return symlink(original, &link).unwrap();
+            let style = rule.symlink_style.unwrap_or(self.symlink_style);
+            let linkdir = self.get_image_path(link.parent().unwrap());
+            let original = style.convert(rule_src, &linkdir);
+            return self.install_symlink(package, &rule_name, &original, &link);
         } else {
-            let src = self.get_source_file(package, rule_src);
-            let dst = self.get_destination_file(rule_dst);
-            recursive_copy(&src, &dst, mode, 0);
+            let mut src = match &rule.url {
+                Some(url) => {
+                    let sha256 = rule.sha256.as_ref().unwrap_or_else(|| {
+                        let error = "url requires a sha256";
+                        panic!("{}", rule_error(package, i, Some("url"), error))
+                    });
+                    if self.dry_run && self.downloaded(sha256).is_none() {
+                        // A diff does not download the files which are not cached.
+                        let dst = self.get_destination_file(rule_dst);
+                        return self.diff_unknown(&dst, "would download");
+                    }
+                    self.download(package, url, sha256)
+                }
+                None => match &rule.command {
+                    Some(_) if self.dry_run => {
+                        // A diff does not run the commands of the rules.
+                        let dst = self.get_destination_file(rule_dst);
+                        return self.diff_unknown(&dst, "would generate");
+                    }
+                    Some(command) => self.generate(package, i, command),
+                    None => {
+                        let src = self.get_source_file(package, i, rule, rule_src);
+                        if let Some(sha256) = &rule.sha256 {
+                            check_sha256(&src, sha256).unwrap_or_else(|error| {
+                                panic!("{}", rule_error(package, i, Some("sha256"), &error))
+                            });
+                        }
+                        src
+                    }
+                },
+            };
+            let mut dst = self.get_destination_file(rule_dst);
+            if let Some(compress) = &rule.compress {
+                if src.is_file() {
+                    dst = PathBuf::from(format!("{dst}.{compress}"));
+                }
+                if self.dry_run {
+                    // A diff does not compress the firmware files.
+                    return self.diff_unknown(&dst, &format!("would compress with {compress}"));
+                }
+                src = self.compress_firmware(package, i, &src, compress);
+            }
+            if let Some(alternative) = &rule.alternative {
+                if !src.is_file() {
+                    let error = "alternative requires a source file";
+                    panic!("{}", rule_error(package, i, Some("alternative"), error));
+                }
+                self.add_alternative(package, alternative, &dst);
+            }
+            if let Some(wrapper) = &rule.wrapper {
+                if !src.is_file() {
+                    let error = "wrapper requires a source file";
+                    panic!("{}", rule_error(package, i, Some("wrapper"), error));
+                }
+                dst = self.install_wrapper(package, &rule_name, &dst, wrapper);
+            }
+            if rule.conffile == Some(true) && self.is_modified_conffile(&src, &dst) {
+                let new = PathBuf::from(format!("{dst}.new"));
+                let line = format!(
+                    "{} {dst} was modified since the last install: installing {new}",
+                    "Warning:".yellow().bold()
+                );
+                message::result(line, "conffile", json!({"file": dst, "new": new}));
+                dst = new;
+            }
+            if rule.firmware == Some(true) {
+                self.install_firmware(package, &rule_name, &src, &dst, mode);
+            } else {
+                // The ELF files of the rule are stripped, even when they are up to date.
+                self.strip_rule.set(rule.strip == Some(true));
+                self.install_file(package, &rule_name, &src, &dst, mode);
+                self.strip_rule.set(false);
+            }
+            if rule.kernel_module == Some(true) {
+                self.kernel_modules.borrow_mut().push(dst.clone());
+            }
+            if rule.mirror == Some(true) {
+                if !src.is_dir() || rule_dst == "/" {
+                    let error =
+                        "mirror requires a source directory, and a destination other than /";
+                    panic!("{}", rule_error(package, i, Some("mirror"), error));
+                }
+                self.mirror_dir(&dst);
+                // The content of the directory in the base image is replaced.
+                let is_base_dir = |base: &PathBuf| {
+                    base.join(rule_dst.strip_prefix("/").unwrap_or(rule_dst))
+                        .is_dir()
+                };
+                if self.base.as_ref().is_some_and(is_base_dir) {
+                    self.hide(&self.get_image_path(&dst), true);
+                }
+            }
         }
 
         if let Some(init) = &rule.init {
+            self.services.borrow_mut().push(rule_dst.to_string());
             let name = rule_dst.file_name().unwrap();
             let original = PathBuf::from("../init.d").join(name);
             if let Some(order) = &init.start {
                 let rcdir = self.get_dst_startdir();
                 let link = rcdir.join(format!("S{order}{name}"));
-                println!("ln -sf {:#?} {:#?}", original, link);
-                std::fs::create_dir_all(&rcdir).unwrap();
-                let _ = std::fs::remove_file(&link);
-                symlink(&original, &link).unwrap();
+                self.install_symlink(package, &rule_name, &original, &link);
             }
             if let Some(order) = &init.stop {
                 let rcdir = self.get_dst_stopdir();
                 let link = rcdir.join(format!("K{order}{name}"));
-                println!("ln -sf {:#?} {:#?}", original, link);
-                std::fs::create_dir_all(&rcdir).unwrap();
-                let _ = std::fs::remove_file(&link);
-                symlink(&original, &link).unwrap();
+                self.install_symlink(package, &rule_name, &original, &link);
             }
         }
     }
 
+    /// Parse the `[[package.metadata.rootfs]]` rules of a package.
+    fn get_rules(&self, package: &cargo_metadata::Package) -> Vec<CargoRootfsRule> {
+        let rules = match &package.metadata["rootfs"] {
+            Value::Array(rules) => rules,
+            _ => return vec![],
+        };
+        rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                CargoRootfsRule::parse(rule).unwrap_or_else(|(field, e)| {
+                    panic!("{}", rule_error(package, i, field.as_deref(), &e))
+                })
+            })
+            .collect()
+    }
+
     fn install_dependency(&self, package: &cargo_metadata::Package) {
-        if let Value::Array(dep_metadata) = &package.metadata["rootfs"] {
-            let name = &package.name;
-            for (i, rule) in dep_metadata.iter().enumerate() {
-                let rule: CargoRootfsRule =
-                    serde_json::from_value(rule.clone()).unwrap_or_else(|e| {
-                        panic!("[{name}] Failed to parse package.metadata.rootfs.[{i}]: {e:?}")
-                    });
-                self.interpret_metadata_rule(package, i, &rule);
+        for (i, rule) in self.get_rules(package).iter().enumerate() {
+            if !self.is_rule_enabled(rule) {
+                message::debug(format_args!(
+                    "Skipping [{}] package.metadata.rootfs.[{i}]: not in the {} rootfs or profile",
+                    package.name, self.rootfs
+                ));
+                continue;
             }
+            let comment = format!("[{}] package.metadata.rootfs.[{i}]", package.name);
+            self.record(|plan| plan.comment(&comment));
+            self.keep_going(|| self.interpret_metadata_rule(package, i, rule));
         }
     }
 
-    fn install_dependencies(&self) {
+    /// Check if a rule is installed with the selected rootfs profile.
+    fn is_rule_enabled(&self, rule: &CargoRootfsRule) -> bool {
+        if rule.rootfs.as_deref().unwrap_or(MAIN_ROOTFS) != self.rootfs {
+            return false;
+        }
+        match (&rule.profiles, &self.profile) {
+            (None, _) => true,
+            (Some(profiles), Some(profile)) => profiles.contains(profile),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Run a step of the install. With --keep-going, its failure is recorded and the
+    /// install continues with the next steps.
+    fn keep_going(&self, step: impl FnOnce()) {
+        if !self.keep_going {
+            return step();
+        }
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(step));
+        if let Err(e) = result {
+            self.failures
+                .borrow_mut()
+                .push(message::panic_message(e.as_ref()));
+        }
+    }
+
+    /// Report the failures recorded with --keep-going, failing the install if any.
+    fn report_failures(&self) {
+        let failures = self.failures.borrow();
+        if failures.is_empty() {
+            return;
+        }
+        for failure in failures.iter() {
+            let line = format!("{} {failure}", "error:".red().bold());
+            message::result(line, "failure", json!({"message": failure}));
+        }
+        panic!("{} steps of the install failed", failures.len());
+    }
+
+    /// Return the nodes of the resolve graph installed in the rootfs: the workspace members
+    /// and their normal dependencies, for the platform selected by cargo metadata.
+    /// The dev-dependencies and the build-dependencies only run on the host.
+    fn get_rootfs_nodes(&self) -> Vec<&cargo_metadata::Node> {
         let resolve = self
             .metadata
             .resolve
             .as_ref()
             .expect("Failed to resolve dependencies graph");
+        let mut active: Vec<&cargo_metadata::PackageId> =
+            self.metadata.workspace_members.iter().collect();
+        let mut i = 0;
+        while i < active.len() {
+            let node = &resolve[active[i]];
+            for dep in &node.deps {
+                let normal = dep
+                    .dep_kinds
+                    .iter()
+                    .any(|x| x.kind == cargo_metadata::DependencyKind::Normal);
+                if normal && !active.contains(&&dep.pkg) {
+                    active.push(&dep.pkg);
+                }
+            }
+            i += 1;
+        }
+        resolve
+            .nodes
+            .iter()
+            .filter(|node| active.contains(&&node.id))
+            .collect()
+    }
 
-        for node in &resolve.nodes {
+    fn install_dependencies(&self) {
+        for node in self.get_rootfs_nodes() {
             let package = self.get_package(&node.id);
-            self.install_dependency(package);
+            let member = self.metadata.workspace_members.contains(&node.id);
+            if (self.no_deps && !member) || (self.deps_only && member) {
+                message::debug(format_args!("Skipping the rules of {}", package.name));
+                continue;
+            }
+            self.keep_going(|| self.install_dependency(package));
+        }
+    }
+
+    /// Register a file installed at `dst` as a candidate of an alternative.
+    fn add_alternative(
+        &self,
+        package: &cargo_metadata::Package,
+        alternative: &Alternative,
+        dst: &Path,
+    ) {
+        // The rules are only validated by `check`: a bad name would leave /etc/alternatives.
+        if let Some(error) = alternative.check() {
+            panic!("[{}] {}: {error}", package.name, self.get_image_path(dst));
+        }
+        self.alternatives.borrow_mut().push(AlternativeCandidate {
+            alternative: alternative.clone(),
+            path: self.get_image_path(dst),
+            package: package.id.clone(),
+        });
+    }
+
+    /// Link each alternative to its candidate of highest priority, through
+    /// `/etc/alternatives/<NAME>`. Equal priorities are resolved by package name, then
+    /// by path, so the same image is built whatever the order of the rules.
+    fn install_alternatives(&self) {
+        let mut candidates = self.alternatives.borrow().clone();
+        candidates.sort_by(|a, b| {
+            let package_a = &self.get_package(&a.package).name;
+            let package_b = &self.get_package(&b.package).name;
+            (
+                &a.alternative.name,
+                b.alternative.priority,
+                package_a,
+                &a.path,
+            )
+                .cmp(&(
+                    &b.alternative.name,
+                    a.alternative.priority,
+                    package_b,
+                    &b.path,
+                ))
+        });
+        for group in candidates.chunk_by(|a, b| a.alternative.name == b.alternative.name) {
+            let best = &group[0];
+            let name = &best.alternative.name;
+            let links: std::collections::BTreeSet<&PathBuf> = group
+                .iter()
+                .filter_map(|x| x.alternative.link.as_ref())
+                .collect();
+            if links.len() > 1 {
+                panic!("Alternative {name} has conflicting links: {links:?}");
+            }
+            let link = match links.first() {
+                Some(link) => link.to_path_buf(),
+                None => PathBuf::from("/usr/bin").join(name),
+            };
+            if let Some(x) = group.iter().find(|x| x.path == link) {
+                let package = &self.get_package(&x.package).name;
+                panic!("[{package}] {link} is both a candidate and the link of alternative {name}");
+            }
+            let package = self.get_package(&best.package);
+            message::status(format_args!(
+                "[{}] alternative: {name} -> {} (priority {}, {} candidates)",
+                package.name,
+                best.path,
+                best.alternative.priority,
+                group.len()
+            ));
+            let alternative = PathBuf::from("/etc/alternatives").join(name);
+            let dst = self.get_destination_file(&alternative);
+            self.install_symlink(package, "alternative", &best.path, &dst);
+            let dst = self.get_destination_file(&link);
+            self.install_symlink(package, "alternative", &alternative, &dst);
+        }
+    }
+
+    /// Install the license files of every package in usr/share/licenses/<crate>/,
+    /// with a consolidated THIRD-PARTY-NOTICES file.
+    fn install_licenses(&self) {
+        let licenses_dir = self.dst.join("usr/share/licenses");
+        message::status("Installing licenses in /usr/share/licenses");
+        let mut notices = String::new();
+
+        let mut packages: Vec<&cargo_metadata::Package> = self
+            .get_rootfs_nodes()
+            .into_iter()
+            .map(|node| self.get_package(&node.id))
+            .collect();
+        packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+        let versions = |name: &str| packages.iter().filter(|x| x.name == name).count();
+
+        for &package in &packages {
+            let manifest_dir = self.get_manifest_dir(package);
+            // A license file added later changes the directory.
+            self.track(&manifest_dir, manifest::Kind::File);
+            let mut files = find_license_files(&manifest_dir);
+            if let Some(license_file) = &package.license_file {
+                let license_file = manifest_dir.join(license_file);
+                if !license_file.is_file() {
+                    panic!("[{}] license-file {license_file} not found", package.name);
+                }
+                if !files.contains(&license_file) {
+                    files.push(license_file);
+                }
+            }
+
+            let license = package.license.as_deref().unwrap_or("UNKNOWN");
+            notices += &format!("{} {} ({license})\n", package.name, package.version);
+            if let Some(repository) = &package.repository {
+                notices += &format!("{repository}\n");
+            }
+            notices += "\n";
+
+            // The licenses of several versions of a crate may differ.
+            let dir = match versions(&package.name) {
+                1 => licenses_dir.join(&package.name),
+                _ => licenses_dir.join(format!("{}-{}", package.name, package.version)),
+            };
+            for file in files {
+                let name = file.file_name().unwrap();
+                let dst = dir.join(name);
+                self.install_file(package, "licenses", &file, &dst, Some(0o0644));
+                let text = std::fs::read_to_string(&file).unwrap_or_default();
+                notices += &format!("{name}:\n{}\n\n", text.trim_end());
+            }
+            notices += &"-".repeat(80);
+            notices += "\n\n";
+        }
+
+        let dst = licenses_dir.join("THIRD-PARTY-NOTICES");
+        let root = self.get_root_package();
+        self.install_content(root, "licenses", &dst, notices.as_bytes(), 0o0644);
+    }
+
+    /// Install the Cargo.lock of the workspace and a DEPENDENCIES file, listing the crates
+    /// installed in the rootfs with their version, license and source, in a directory of
+    /// the rootfs, so the device can be audited offline.
+    fn embed_manifest(&self, dir: &Path) {
+        let dir = self.get_destination_file(dir);
+        let root = self.get_root_package();
+        message::status(format_args!(
+            "[{}] Embedding the crate list in {}",
+            root.name,
+            self.get_image_path(&dir)
+        ));
+        let lockfile = self.metadata.workspace_root.join("Cargo.lock");
+        if lockfile.is_file() {
+            let dst = dir.join("Cargo.lock");
+            self.install_file(root, "embed-manifest", &lockfile, &dst, Some(0o0644));
+        }
+
+        let mut packages: Vec<&cargo_metadata::Package> = self
+            .get_rootfs_nodes()
+            .into_iter()
+            .map(|node| self.get_package(&node.id))
+            .collect();
+        packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+        let mut content = format!("# Crates of {} {}\n", root.name, root.version);
+        for package in packages {
+            let license = package.license.as_deref().unwrap_or("UNKNOWN");
+            let source = match &package.source {
+                Some(source) => source.to_string(),
+                None => "local".into(),
+            };
+            content += &format!(
+                "{} {} ({license}) {source}\n",
+                package.name, package.version
+            );
+        }
+        let dst = dir.join("DEPENDENCIES");
+        self.install_content(root, "embed-manifest", &dst, content.as_bytes(), 0o0644);
+    }
+
+    /// Report the symbolic links of the rootfs whose target does not exist in the image.
+    /// Only the installed links are checked when the rootfs is the host root directory.
+    fn audit_symlinks(&self, strict: bool) {
+        // Filesystems mounted at runtime.
+        const RUNTIME_DIRS: [&str; 5] = ["/proc", "/sys", "/dev", "/run", "/tmp"];
+        let mut links: Vec<PathBuf> = vec![];
+        if self.dst == "/" {
+            for file in self.installed.borrow().iter() {
+                if file.kind == manifest::Kind::Symlink && !links.contains(&file.path) {
+                    links.push(file.path.clone());
+                }
+            }
+        } else {
+            links = audit::walk(&self.dst);
+            links.retain(|path| path.is_symlink());
+        }
+
+        let mut count = 0;
+        for link in links {
+            let path = self.get_image_path(&link);
+            let target = match link.read_link_utf8() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let absolute = normalize_path(&path.parent().unwrap().join(&target));
+            if RUNTIME_DIRS.iter().any(|dir| absolute.starts_with(dir)) {
+                continue;
+            }
+            if resolve_in_rootfs(&self.dst, &path).is_some() {
+                continue;
+            }
+            count += 1;
+            let line = format!(
+                "{} dangling symbolic link {path} -> {target}",
+                "Warning:".yellow().bold()
+            );
+            message::result(line, "dangling", json!({"link": path, "target": target}));
+        }
+        if strict && count > 0 {
+            panic!("{count} dangling symbolic links found in {}", self.dst);
+        }
+    }
+
+    /// Check the size of the rootfs, and of its directories, against the budgets of
+    /// `[workspace.metadata.rootfs]`, failing with the biggest files when exceeded.
+    fn check_budgets(&self) {
+        let config = &self.metadata.workspace_metadata["rootfs"];
+        let mut budgets: Vec<(PathBuf, String)> = vec![];
+        if let Some(size) = config["max_size"].as_str() {
+            budgets.push(("/".into(), size.into()));
+        }
+        if let Value::Object(dirs) = &config["budgets"] {
+            for (dir, size) in dirs {
+                let size = size.as_str().unwrap_or_else(|| {
+                    panic!("workspace.metadata.rootfs.budgets.{dir:?} is not a size")
+                });
+                budgets.push((dir.into(), size.into()));
+            }
+        }
+        if budgets.is_empty() {
+            return;
+        }
+
+        // The files of the rootfs, or the installed ones for the host root directory.
+        let paths = if self.dst == "/" {
+            let mut paths: Vec<PathBuf> = manifest::load_all(&self.dst)
+                .iter()
+                .flat_map(|manifest| manifest.files.iter())
+                .map(|entry| self.get_destination_file(&entry.path))
+                .collect();
+            paths.sort();
+            paths.dedup();
+            paths
+        } else {
+            audit::walk(&self.dst)
+        };
+        let mut inodes: Vec<(u64, u64)> = vec![];
+        let mut files: Vec<(PathBuf, u64)> = vec![];
+        for path in paths {
+            let metadata = match path.symlink_metadata() {
+                Ok(x) if x.is_file() => x,
+                _ => continue,
+            };
+            // Hard links are only counted once.
+            if inodes.contains(&(metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            inodes.push((metadata.dev(), metadata.ino()));
+            files.push((self.get_image_path(&path), metadata.len()));
+        }
+        files.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+        let mut exceeded = 0;
+        for (dir, size) in &budgets {
+            let budget = parse_size(size);
+            let used: u64 = files
+                .iter()
+                .filter(|(path, _)| path.starts_with(dir))
+                .map(|(_, size)| size)
+                .sum();
+            if used <= budget {
+                message::detail(format_args!(
+                    "{dir}: {} of {size}",
+                    message::human_size(used)
+                ));
+                continue;
+            }
+            exceeded += 1;
+            let line = format!(
+                "{} {dir} uses {}, exceeding its budget of {size} by {}",
+                "error:".red().bold(),
+                message::human_size(used),
+                message::human_size(used - budget),
+            );
+            message::result(
+                line,
+                "budget",
+                json!({"dir": dir, "bytes": used, "budget": budget}),
+            );
+            let biggest = files.iter().filter(|(path, _)| path.starts_with(dir));
+            for (path, size) in biggest.take(10) {
+                let rule = self
+                    .installed
+                    .borrow()
+                    .iter()
+                    .rev()
+                    .find(|file| self.get_image_path(&file.path) == *path)
+                    .map(|file| format!(" ({})", self.get_package(&file.package).name))
+                    .unwrap_or_default();
+                message::status(format_args!(
+                    "  {:>10}  {path}{rule}",
+                    message::human_size(*size)
+                ));
+            }
+        }
+        if exceeded > 0 {
+            panic!("{exceeded} size budgets exceeded");
+        }
+    }
+
+    /// Check the size of the image written by a subcommand against the `max_size` of the
+    /// rootfs, removing the image when it is exceeded.
+    fn check_image_budget(&self, image: &Path) {
+        let config = &self.metadata.workspace_metadata["rootfs"];
+        let Some(size) = config["max_size"].as_str() else {
+            return;
+        };
+        let budget = parse_size(size);
+        let used = std::fs::metadata(image)
+            .unwrap_or_else(|e| panic!("{image} not written: {e:?}"))
+            .len();
+        if used <= budget {
+            message::detail(format_args!(
+                "{image}: {} of {size}",
+                message::human_size(used)
+            ));
+            return;
+        }
+        let _ = std::fs::remove_file(image);
+        panic!(
+            "{image} is {}, exceeding the max_size of {size} by {}",
+            message::human_size(used),
+            message::human_size(used - budget),
+        );
+    }
+
+    /// Run the post-install hooks, once every file is installed.
+    fn run_hooks(&self) {
+        for hook in self.hooks.borrow().iter() {
+            self.keep_going(|| self.run_hook(hook));
+        }
+    }
+
+    fn run_hook(&self, hook: &Hook) {
+        if self.dry_run {
+            message::status(format_args!(
+                "[{}] Skipping hook {:?}",
+                hook.package, hook.command
+            ));
+            return;
+        }
+        self.record(|plan| plan.hook(&hook.command, &hook.manifest_dir, hook.run_in_rootfs));
+        if self.planning {
+            return;
+        }
+        message::emit(
+            "hook",
+            json!({"package": hook.package, "command": hook.command, "run_in_rootfs": hook.run_in_rootfs}),
+        );
+        let mut cmd = if hook.run_in_rootfs {
+            let args = vec!["-c".to_string(), hook.command.clone()];
+            exec::rootfs_command(
+                &self.dst,
+                self.target.as_deref(),
+                self.exec_backend,
+                "/bin/sh",
+                &args,
+            )
+        } else {
+            message::status(format_args!("sh -c {:#?}", hook.command));
+            let mut cmd = std::process::Command::new("sh");
+            cmd.arg("-c")
+                .arg(&hook.command)
+                .current_dir(&hook.manifest_dir)
+                .env("DESTDIR", &self.dst);
+            cmd
+        };
+        if message::is_json() {
+            // Keep stdout for the JSON events.
+            cmd.stdout(std::io::stderr());
+        }
+        let status = cmd.status().unwrap_or_else(|e| {
+            panic!(
+                "[{}] Failed to run hook {:?}: {e:?}",
+                hook.package, hook.command
+            )
+        });
+        if !status.success() {
+            panic!(
+                "[{}] Hook {:?} failed: {status}",
+                hook.package, hook.command
+            );
+        }
+    }
+
+    fn install_bin(&self, package: &cargo_metadata::Package, filename: &str) {
+        let src = self.outdir.join(filename);
+        let mut dst = self.dst.join("usr/bin").join(filename);
+        message::status(format_args!("[{}] bin: {filename}", package.name));
+        let rule = self.get_bin_rule(package, filename);
+        let keep_symbols = rule.as_ref().is_some_and(|rule| rule.strip == Some(false));
+        if let Some(alternative) = rule.as_ref().and_then(|rule| rule.alternative.as_ref()) {
+            self.add_alternative(package, alternative, &dst);
+        }
+        let wrapper = rule.as_ref().and_then(|rule| rule.wrapper.as_ref());
+        if let Some(wrapper) = wrapper {
+            dst = self.install_wrapper(package, "bin", &dst, wrapper);
+        }
+        match keep_symbols {
+            true => self.scrub_copy(dst.clone()),
+            false => self.strip_copy(dst.clone()),
+        }
+        self.install_file(package, "bin", &src, &dst, Some(0o0755));
+        self.check_static(package, filename, &src);
+    }
+
+    /// Return the test executables of the workspace packages, built again by cargo with a
+    /// target selection option.
+    fn get_test_artifacts(&self, selection: &str) -> Vec<cargo_metadata::Artifact> {
+        // The tests are built by the install, from their sources.
+        self.inputs.borrow_mut().untracked = true;
+        self.cargo_messages(&[selection])
+            .into_iter()
+            .filter_map(|message| match message {
+                cargo_metadata::Message::CompilerArtifact(artifact)
+                    if artifact.profile.test
+                        && artifact.executable.is_some()
+                        && self
+                            .metadata
+                            .workspace_members
+                            .contains(&artifact.package_id) =>
+                {
+                    Some(artifact)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Install the test executables of the workspace of a kind, `tests` or `benches`, in
+    /// /usr/lib/<NAME>/<KIND>, with a `run-<KIND>` script running them all.
+    fn install_tests(&self, kind: &str) {
+        let root = self.get_root_package();
+        let dir = self.dst.join("usr/lib").join(&root.name).join(kind);
+        if self.dry_run {
+            // A diff does not build the tests.
+            return self.diff_unknown(&dir, &format!("would build the {kind}"));
+        }
+        let (mut names, mut custom) = (vec![], vec![]);
+        for artifact in self.get_test_artifacts(&format!("--{kind}")) {
+            let package = self.get_package(&artifact.package_id);
+            let name = runner::test_name(&package.name, &artifact.target);
+            if names.contains(&name) || custom.contains(&name) {
+                continue;
+            }
+            message::status(format_args!("[{}] {kind}: {name}", package.name));
+            let src = artifact.executable.as_ref().unwrap();
+            self.install_file(package, kind, src, &dir.join(&name), Some(0o0755));
+            match runner::is_libtest(src) {
+                true => names.push(name),
+                false => custom.push(name),
+            }
+        }
+        names.sort();
+        custom.sort();
+        // The benches run in bench mode, measuring them instead of running them once.
+        let options = if kind == "benches" { "--bench" } else { "" };
+        let script = runner::script(&root.name, kind, &names, &custom, options);
+        let path = dir.join(format!("run-{kind}"));
+        self.install_content(root, kind, &path, script.as_bytes(), 0o755);
+    }
+
+    /// Install the binaries of an artifact dependency of a package, built by cargo in
+    /// `deps/artifact` and located from the messages of the build.
+    fn install_artifact(
+        &self,
+        package: &cargo_metadata::Package,
+        i: usize,
+        name: &str,
+        rule: &CargoRootfsRule,
+    ) {
+        // The artifact dependency may be renamed.
+        let dependency = package
+            .dependencies
+            .iter()
+            .find(|x| x.rename.as_deref().unwrap_or(&x.name) == name)
+            .unwrap_or_else(|| {
+                let error = format!("{name} is not a dependency of {}", package.name);
+                panic!(
+                    "{}",
+                    rule_error(package, i, Some("install_artifact"), &error)
+                )
+            });
+        let id = self
+            .get_medatadata_node(&package.id)
+            .dependencies
+            .iter()
+            .find(|id| self.get_package(id).name == dependency.name)
+            .unwrap_or_else(|| panic!("[{}] {name} is not resolved", package.name))
+            .clone();
+        let executables: Vec<(String, PathBuf)> = self
+            .get_build_messages()
+            .iter()
+            .filter_map(|message| match message {
+                cargo_metadata::Message::CompilerArtifact(artifact)
+                    if artifact.package_id == id
+                        && artifact
+                            .target
+                            .kind
+                            .contains(&cargo_metadata::TargetKind::Bin) =>
+                {
+                    let executable = artifact.executable.clone()?;
+                    Some((artifact.target.name.clone(), executable))
+                }
+                _ => None,
+            })
+            .collect();
+        if executables.is_empty() {
+            let error =
+                format!("no binary of {name} was built: is it an artifact = \"bin\" dependency, built by a nightly cargo with bindeps enabled?");
+            panic!(
+                "{}",
+                rule_error(package, i, Some("install_artifact"), &error)
+            );
+        }
+        let dir =
+            self.get_destination_file(rule.destination.as_deref().unwrap_or("/usr/bin".into()));
+        let rule_name = format!("package.metadata.rootfs.[{i}]");
+        // The executables of the artifacts are named with a hash.
+        for (filename, src) in executables {
+            let dst = dir.join(&filename);
+            message::status(format_args!(
+                "[{}] artifact: {name} {filename}",
+                package.name
+            ));
+            match rule.strip {
+                Some(false) => self.scrub_copy(dst.clone()),
+                _ => self.strip_copy(dst.clone()),
+            }
+            self.install_file(package, &rule_name, &src, &dst, Some(0o0755));
+        }
+    }
+
+    /// Check that a binary is statically linked when built for musl or with crt-static.
+    fn check_static(&self, package: &cargo_metadata::Package, filename: &str, src: &Path) {
+        let elf = match elf::Elf::parse(src) {
+            Some(x) => x,
+            None => return,
+        };
+        if elf.is_static() {
+            message::debug(format_args!(
+                "[{}] {filename} is statically linked",
+                package.name
+            ));
+            return;
         }
+        let rustflags = ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .collect::<String>();
+        let musl = self.target.as_ref().is_some_and(|x| x.contains("musl"));
+        if !musl && !rustflags.contains("+crt-static") {
+            return;
+        }
+        let mut dependencies = elf.needed();
+        dependencies.extend(elf.interpreter());
+        let line = format!(
+            "{} [{}] {filename} is expected to be statically linked, but requires {}",
+            "Warning:".yellow().bold(),
+            package.name,
+            dependencies.join(", ")
+        );
+        message::result(
+            line,
+            "warning",
+            json!({"package": package.name, "file": src, "dependencies": dependencies}),
+        );
     }
 
-    fn install_bin(&self, filename: &str) {
-        let src = self.outdir.join(filename);
-        let dst = self.dst.join("usr/bin").join(filename);
-        recursive_copy(&src, &dst, Some(0o0755), 0);
+    /// Return the rule of the package configuring a binary built by cargo, if any.
+    fn get_bin_rule(
+        &self,
+        package: &cargo_metadata::Package,
+        filename: &str,
+    ) -> Option<CargoRootfsRule> {
+        let rules = match &package.metadata["rootfs"] {
+            Value::Array(rules) => rules,
+            _ => return None,
+        };
+        rules
+            .iter()
+            .filter(|rule| rule["bin"].as_str() == Some(filename))
+            .filter_map(|rule| serde_json::from_value(rule.clone()).ok())
+            .find(|rule| self.is_rule_enabled(rule))
+    }
 
-        if self.command == Command::Release {
-            strip(&dst);
+    /// Install a launcher script for a binary, returning the path of the real binary.
+    fn install_wrapper(
+        &self,
+        package: &cargo_metadata::Package,
+        rule: &str,
+        dst: &Path,
+        wrapper: &Wrapper,
+    ) -> PathBuf {
+        let real = PathBuf::from(format!("{dst}.real"));
+        let mut script = String::from("#!/bin/sh\n");
+        for (name, value) in &wrapper.env {
+            script += &format!("export {name}={}\n", shell_quote(value));
         }
+        script += &format!("exec {}", shell_quote(self.get_image_path(&real).as_str()));
+        for arg in &wrapper.args {
+            script += &format!(" {}", shell_quote(arg));
+        }
+        script += " \"$@\"\n";
+        self.install_content(package, rule, dst, script.as_bytes(), 0o755);
+        real
     }
 
     fn get_medatadata_node(&self, package: &cargo_metadata::PackageId) -> &cargo_metadata::Node {
@@ -379,38 +4762,62 @@ impl CargoRootfs {
         true
     }
 
+    fn get_bin_package(&self, name: &str) -> &cargo_metadata::Package {
+        self.metadata
+            .workspace_packages()
+            .into_iter()
+            .find(|package| {
+                package.targets.iter().any(|target| {
+                    target.name == name && target.kind.contains(&cargo_metadata::TargetKind::Bin)
+                })
+            })
+            .unwrap_or_else(|| panic!("No binary target named {name} in the workspace"))
+    }
+
     fn install_bins(&self) {
         for package in self.metadata.workspace_packages() {
             for target in &package.targets {
                 if target.kind.contains(&cargo_metadata::TargetKind::Bin) {
                     if !self.is_target_enabled(&package.id, target) {
-                        println!("{} is not enabled", target.name);
+                        message::debug(format_args!("{} is not enabled", target.name));
                         continue;
                     }
-                    self.install_bin(&target.name);
+                    // The other rootfs only get the binaries selected by a bin rule.
+                    if self.rootfs != MAIN_ROOTFS
+                        && self.get_bin_rule(package, &target.name).is_none()
+                    {
+                        continue;
+                    }
+                    let comment = format!("[{}] bin: {}", package.name, target.name);
+                    self.record(|plan| plan.comment(&comment));
+                    self.keep_going(|| self.install_bin(package, &target.name));
                 }
             }
         }
     }
 
-    fn install_lib(&self, name: &str) {
+    fn install_lib(&self, package: &cargo_metadata::Package, name: &str) {
         let filename = format!("lib{name}.so");
         let src = self.outdir.join(&filename);
         let dst = self.dst.join("usr/lib").join(&filename);
-        recursive_copy(&src, &dst, Some(0o0755), 0);
+        message::status(format_args!("[{}] lib: {filename}", package.name));
+        self.install_file(package, "lib", &src, &dst, Some(0o0755));
     }
 
     fn install_libs(&self) {
+        if self.rootfs != MAIN_ROOTFS {
+            return;
+        }
         for package in self.metadata.workspace_packages() {
             for target in &package.targets {
                 if target.kind.contains(&cargo_metadata::TargetKind::DyLib)
                     || target.kind.contains(&cargo_metadata::TargetKind::CDyLib)
                 {
                     if !self.is_target_enabled(&package.id, target) {
-                        println!("{} is not enabled", target.name);
+                        message::debug(format_args!("{} is not enabled", target.name));
                         continue;
                     }
-                    self.install_lib(&target.name);
+                    self.install_lib(package, &target.name);
                 }
             }
         }
@@ -430,64 +4837,609 @@ fn help() {
         "[[package.metadata.rootfs]]".cyan().bold(),
         "Cargo.toml".cyan().bold());
     println!();
-    printusage("cargo rootfs install [OPTIONS]");
-    println!("Install package in the rootfs, keeping debug symbols.");
+    printusage("cargo rootfs install [OPTIONS]");
+    println!("Install package in the rootfs, keeping debug symbols.");
+    println!();
+    printusage("cargo rootfs release [OPTIONS]");
+    println!("Install package in the rootfs, keeping debug symbols.");
+    println!();
+    printusage("cargo rootfs uninstall [-p <NAME>] [OPTIONS]");
+    println!(
+        "Remove the files previously installed in the rootfs for the root package (or -p <NAME>)."
+    );
+    println!();
+    printusage("cargo rootfs verify [-p <NAME>] [OPTIONS]");
+    println!("Check the installed files against the manifests, reporting modified, missing or extra files.");
+    println!();
+    printusage("cargo rootfs info [OPTIONS]");
+    println!("List the rootfs rules of the root package and of all its dependencies, without installing them.");
+    println!();
+    printusage("cargo rootfs check [OPTIONS]");
+    println!("Validate the rootfs rules of the root package and of all its dependencies, without installing them.");
+    println!();
+    printusage("cargo rootfs audit [OPTIONS]");
+    println!("Report the world-writable, setuid and setgid files, the files owned by unknown users, and the executables built without PIE, RELRO or stack protector.");
+    println!();
+    printusage("cargo rootfs size [-p <NAME>] [OPTIONS]");
+    println!("Print the size of the installed files for each package and rule, biggest first.");
+    println!();
+    printusage("cargo rootfs plan [-o <FILE>] [OPTIONS]");
+    println!("Record the operations of an install in a JSON plan (default: plan.json), without modifying the rootfs.");
+    println!();
+    printusage("cargo rootfs apply <FILE> [OPTIONS]");
+    println!("Run the operations of a plan in the rootfs, without cargo.");
+    println!();
+    printusage("cargo rootfs diff [OPTIONS]");
+    println!("Show the files which would be added, replaced, left untouched or removed by an install, without modifying the rootfs.");
+    println!();
+    printusage("cargo rootfs run [OPTIONS] [-- <ARGS>...]");
+    println!("Run the root package binary (or --bin <NAME>) inside the rootfs, with qemu-user when cross-compiled.");
+    println!();
+    printusage("cargo rootfs shell --dest <DIRECTORY> [OPTIONS] [-- <ARGS>...]");
+    println!("Run an interactive shell (/bin/sh of the rootfs) inside the rootfs, with qemu-user when cross-compiled.");
+    println!();
+    printusage("cargo rootfs boot --dest <DIRECTORY> --kernel <IMAGE> [OPTIONS]");
+    println!("Boot the rootfs with qemu-system, optionally running a command and reporting its exit status.");
+    println!();
+    printusage("cargo rootfs image [--filesystem <TYPE>] [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-image) and write its filesystem image (default: rootfs.<TYPE>): ext4, squashfs, erofs, or ubifs and jffs2 for raw flashes.");
+    println!();
+    printusage("cargo rootfs disk [--layout <FILE>] [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-disk) and assemble a GPT or MBR disk image (default: disk.img) from its partitions layout (default: disk.toml).");
+    println!();
+    printusage("cargo rootfs fit --kernel <IMAGE> [--dtb <FILE>]... [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-fit) and combine the kernel, the device trees and the rootfs ramdisk in a U-Boot FIT image (default: image.itb), optionally signed.");
+    println!();
+    printusage("cargo rootfs swu [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-swu) and write a swupdate package (default: <NAME>-<VERSION>.swu) of its ext4 image, described by [workspace.metadata.rootfs.swupdate].");
+    println!();
+    printusage("cargo rootfs rauc --cert <FILE> --key <FILE> [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-rauc) and write a signed RAUC bundle (default: <NAME>-<VERSION>.raucb) of its slot images, described by [workspace.metadata.rootfs.rauc].");
+    println!();
+    printusage("cargo rootfs mender [--key <FILE>] [-o <FILE>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-mender) and write a Mender artifact (default: <NAME>-<VERSION>.mender) of its ext4 image, described by [workspace.metadata.rootfs.mender].");
+    println!();
+    printusage("cargo rootfs ostree --repo <DIRECTORY> [--branch <NAME>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-ostree) and commit it in an OSTree repository, with the build information as commit metadata.");
+    println!();
+    printusage("cargo rootfs delta [--from <FILE>] [-o <DIRECTORY>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-delta) and write its index and a content-addressed store of its chunks (default: <NAME>-<VERSION>.delta), without the chunks of a previous index.");
+    println!();
+    printusage("cargo rootfs export --format <wsl|nspawn|lxc> [-o <PATH>] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-export) and export it as a WSL tarball (default: <NAME>-<VERSION>.tar.gz), or a systemd-nspawn or LXC container directory (default: <NAME>).");
+    println!();
+    printusage("cargo rootfs netboot --export-dir <DIRECTORY> [--tftp-dir <DIRECTORY>] [OPTIONS]");
+    println!("Install package in a NFS exported directory for a NFS root, copy the kernel and the device tree in a TFTP directory, and print the boot arguments.");
+    println!();
+    printusage("cargo rootfs watch [OPTIONS]");
+    println!("Build and install package each time a source file changes, deploying it when --host or --deploy is specified.");
+    println!();
+    printusage("cargo rootfs deploy --host <[USER@]HOST[:DIR]> [OPTIONS]");
+    printusage("cargo rootfs deploy --deploy adb[:SERIAL] [OPTIONS]");
+    println!("Install package in a staging directory (default: target/rootfs-deploy) and copy it to a remote target over ssh or adb.");
+    println!();
+    println!("{}", "Options:".green().bold());
+    printopt("-d, --dest <DIRECTORY>", "Rootfs directory (default: /)");
+    printopt(
+        "-d, --dest <NAME>=<DIRECTORY>",
+        "Directory of a named rootfs, e.g. main or recovery (repeatable)",
+    );
+    printopt(
+        "-s, --altsrc <DIRECTORY>",
+        "Use an an alternative sources for files to install (repeatable, by priority).",
+    );
+    printopt(
+        "    --require-altsrc <CRATE>",
+        "Fail if no source of a crate is found in the altsrc directories",
+    );
+    printopt("    --target <TRIPLE>", "Install for target triple");
+    printopt(
+        "-S, --init-start-dir <DIRECTORY>",
+        "Init start script directory (default: /etc/rc1.d)",
+    );
+    printopt(
+        "-K, --init-stop-dir <DIRECTORY>",
+        "Init stop script directory (default: /etc/rc6.d)",
+    );
+    printopt(
+        "-p, --package <NAME>",
+        "Root package to install, uninstall or verify (default: root package, all for verify)",
+    );
+    printopt(
+        "    --backup[=SUFFIX|DIR/]",
+        "Backup the overwritten files (default suffix: ~)",
+    );
+    printopt(
+        "-f, --force",
+        "Install in / or in a non-empty directory not created by cargo-rootfs",
+    );
+    printopt(
+        "    --wait",
+        "Wait for the destination locked by another cargo-rootfs, instead of failing",
+    );
+    printopt("    --no-lock", "Do not lock the destination");
+    printopt(
+        "    --no-prune",
+        "Keep the files installed previously and not anymore",
+    );
+    printopt(
+        "    --licenses",
+        "Install the license files of all crates in /usr/share/licenses",
+    );
+    printopt(
+        "    --embed-manifest <DIRECTORY>",
+        "Install the Cargo.lock and the list of the installed crates in this directory",
+    );
+    printopt(
+        "    --manifest <FORMAT:FILE>",
+        "Write a mtree or sha256 manifest of the installed files",
+    );
+    printopt(
+        "    --sbom <FORMAT:FILE>",
+        "Write a cyclonedx or spdx SBOM of the installed files",
+    );
+    printopt(
+        "    --dedupe",
+        "Replace the installed files with identical content by hard links",
+    );
+    printopt(
+        "    --strict",
+        "Fail when the rootfs contains dangling symbolic links",
+    );
+    printopt(
+        "    --keep-going",
+        "Continue after a rule, bin or hook fails, and report all the failures at the end",
+    );
+    printopt(
+        "    --symlink-style <STYLE>",
+        "Targets of the symbolic link rules: relative, absolute or keep (default: keep)",
+    );
+    printopt(
+        "    --interp <PATH>",
+        "Install the dynamic loader at this path, and use it in the installed binaries",
+    );
+    printopt(
+        "    --sysroot <DIRECTORY>",
+        "Directory containing the dynamic loader of the target (default: /)",
+    );
+    printopt(
+        "    --ldconfig",
+        "Configure the dynamic linker for the libraries installed in other directories",
+    );
+    printopt(
+        "    --build-info",
+        "Write /etc/os-release and /etc/buildinfo.json describing the build",
+    );
+    printopt(
+        "    --base <DIRECTORY>",
+        "Base image of the rootfs layer, whose removed paths are hidden by whiteouts",
+    );
+    printopt(
+        "    --whiteout <FORMAT>",
+        "Whiteouts of the rootfs layer: oci or overlay (default: oci)",
+    );
+    printopt(
+        "    --checksum",
+        "Compare the content of the installed files instead of their size and mtime",
+    );
+    printopt(
+        "-j, --jobs <N>",
+        "Number of parallel copies (default: number of CPUs)",
+    );
+    printopt(
+        "    --stats <FILE>",
+        "Write the install summary statistics as JSON",
+    );
+    printopt(
+        "    --emit-script <FILE>",
+        "Write a shell script replaying the operations of the install",
+    );
+    printopt(
+        "    --emit-build <FORMAT:FILE>",
+        "Write a ninja or make fragment running the install steps incrementally",
+    );
+    printopt(
+        "    --rootfs-profile <NAME>",
+        "Use a profile of [workspace.metadata.rootfs.profiles]",
+    );
+    printopt(
+        "    --kernel-version <VERSION>",
+        "Kernel release of the kernel_module rules",
+    );
+    printopt(
+        "    --kernel-build-dir <DIRECTORY>",
+        "Kernel build directory, for the release and System.map",
+    );
+    printopt(
+        "-o, --output <FILE>",
+        "Install plan written by plan (default: plan.json)",
+    );
+    printopt(
+        "    --color <WHEN>",
+        "Coloring: auto, always or never (default: auto, honoring NO_COLOR)",
+    );
+    printopt(
+        "    --message-format <FMT>",
+        "Output format: human or json (default: human)",
+    );
+    printopt(
+        "-v, --verbose",
+        "Print each installed file (-vv: skipped items too)",
+    );
+    printopt("-q, --quiet", "Only print errors");
+    printopt("-h, --help", "Print help");
+    println!();
+    println!("{}", "Target Selection:".green().bold());
+    printopt("    --lib", "Install only this package's library");
+    printopt("    --bins", "Install all binaries");
+    printopt("    --bin [<NAME>]", "Install only the specified binary");
+    printopt(
+        "    --tests",
+        "Also install the test binaries and their runner in /usr/lib/<NAME>/tests",
+    );
+    printopt(
+        "    --benches",
+        "Also install the bench binaries and their runner in /usr/lib/<NAME>/benches",
+    );
+    printopt(
+        "    --no-deps",
+        "Install only the workspace packages, without the rules of the dependencies",
+    );
+    printopt(
+        "    --deps-only",
+        "Install only the rules of the dependencies, without the workspace packages",
+    );
+    println!();
+    println!("{}", "Feature Selection:".green().bold());
+    printopt(
+        "-F, --features <FEATURES>",
+        "Space or comma separated list of features to activate",
+    );
+    printopt("    --all-features", "Activate all available features");
+    printopt(
+        "    --no-default-features",
+        "Do not activate the `default` feature",
+    );
+    println!();
+    println!("{}", "Manifest Options:".green().bold());
+    printopt("    --manifest-path <PATH>", "Path to Cargo.toml");
+    printopt(
+        "    --lockfile-path <PATH>",
+        "Path to Cargo.lock (unstable)",
+    );
+    printopt(
+        "    --target-dir <DIRECTORY>",
+        "Directory of the build artifacts (default: CARGO_TARGET_DIR or target)",
+    );
+    printopt(
+        "    --artifact-dir <DIRECTORY>",
+        "Directory of the binaries copied by cargo build --artifact-dir (unstable)",
+    );
+    printopt(
+        "    --locked",
+        "Assert that `Cargo.lock` will remain unchanged",
+    );
+    printopt("    --offline", "Run without accessing the network");
+    printopt(
+        "    --frozen",
+        "Equivalent to specifying both --locked and --offline",
+    );
+    println!();
+    println!("{}", "Deploy Options:".green().bold());
+    printopt(
+        "    --host <[USER@]HOST[:DIR]>",
+        "Remote target to deploy to (default DIR: /)",
+    );
+    printopt(
+        "    --deploy <BACKEND>",
+        "Deploy backend: ssh:<[USER@]HOST[:DIR]> or adb[:SERIAL]",
+    );
+    printopt(
+        "    --deploy-prefix <DIR>",
+        "Remote directory for adb (default: /, remounting /system)",
+    );
+    printopt(
+        "    --delta",
+        "Only transfer modified files with rsync (default: tar over ssh)",
+    );
+    printopt("    --restart", "Restart the installed init scripts");
+    println!();
+    println!("{}", "Debug Options:".green().bold());
+    printopt(
+        "    --split-debug[=DIR]",
+        "Keep the debug information of the stripped binaries (default: target/rootfs-debug)",
+    );
+    printopt(
+        "    --debuginfod <DIR>",
+        "Also export the binaries and their debug information for debuginfod",
+    );
+    printopt(
+        "    --compress-debug <TYPE>",
+        "Compress the debug sections of the binaries installed by install: zlib or zstd",
+    );
+    printopt(
+        "    --scrub-notes",
+        "Remove .comment, .note.ABI-tag and the like from the release binaries",
+    );
+    println!();
+    println!("{}", "Run Options:".green().bold());
+    printopt(
+        "    --exec-backend <BACKEND>",
+        "Run, shell and hooks backend: auto, chroot, unshare or proot (default: auto)",
+    );
+    println!();
+    println!("{}", "Boot Options:".green().bold());
+    printopt("    --kernel <IMAGE>", "Kernel image to boot");
+    printopt(
+        "    --dtb <FILE>",
+        "Device tree blob (may be repeated for fit)",
+    );
+    printopt(
+        "    --boot-image <TYPE>",
+        "Rootfs image: initramfs or ext4 (default: initramfs)",
+    );
+    printopt(
+        "    --boot-cmd <COMMAND>",
+        "Run a command at boot and exit with its status",
+    );
+    printopt(
+        "    --boot-timeout <SECONDS>",
+        "Boot command timeout (default: 300)",
+    );
+    println!();
+    println!("{}", "Verity Options:".green().bold());
+    printopt(
+        "    --verity",
+        "Add a dm-verity hash tree to the images of image, disk, swu, rauc and mender",
+    );
+    printopt(
+        "    --verity-hash <WHERE>",
+        "Hash tree location: appended or separate (default: appended)",
+    );
+    printopt(
+        "    --verity-key <FILE>",
+        "Sign the root hash with this key, in a .roothash.p7s file",
+    );
+    printopt(
+        "    --verity-cert <FILE>",
+        "Certificate of the root hash signing key",
+    );
+    println!();
+    println!("{}", "Signing Options:".green().bold());
+    printopt(
+        "    --sign gpg:<KEYID>",
+        "Write detached signatures of the images and archives with gpg",
+    );
+    printopt(
+        "    --sign-cmd <COMMAND>",
+        "Sign with a shell command instead, signing $1 in $2",
+    );
+    printopt(
+        "    --sign-elfs",
+        "Also sign the ELF files, in /usr/share/signatures",
+    );
+    println!();
+    println!("{}", "Image Options:".green().bold());
+    printopt(
+        "    --filesystem <TYPE>",
+        "ext4, squashfs, erofs, ubifs or jffs2 (default: ext4)",
+    );
+    printopt(
+        "-o, --output <FILE>",
+        "Image written by image (default: rootfs.<TYPE>, rootfs.ubi for ubifs)",
+    );
+    printopt(
+        "    --peb-size <SIZE>",
+        "Physical erase block size of the flash (ubifs, jffs2)",
+    );
+    printopt(
+        "    --leb-size <SIZE>",
+        "Logical erase block size of UBI (ubifs)",
+    );
+    printopt(
+        "    --min-io-size <SIZE>",
+        "Minimum I/O unit of the flash, e.g. its page size (ubifs)",
+    );
+    printopt(
+        "    --max-leb-cnt <COUNT>",
+        "Maximum number of LEBs of the UBIFS volume (ubifs)",
+    );
+    println!();
+    println!("{}", "Disk Options:".green().bold());
+    printopt(
+        "    --layout <FILE>",
+        "Partitions layout of the disk image (default: disk.toml)",
+    );
+    printopt(
+        "-o, --output <FILE>",
+        "Disk image written by disk (default: disk.img)",
+    );
+    println!();
+    println!("{}", "FIT Options:".green().bold());
+    printopt(
+        "-o, --output <FILE>",
+        "FIT image written by fit (default: image.itb)",
+    );
+    printopt(
+        "    --fit-load <ADDRESS>",
+        "Kernel load address (default: executed in place)",
+    );
+    printopt(
+        "    --fit-entry <ADDRESS>",
+        "Kernel entry address (default: the load address)",
+    );
+    printopt(
+        "    --fit-key-dir <DIRECTORY>",
+        "Sign the configurations with the keys of this directory",
+    );
+    printopt(
+        "    --fit-key-name <NAME>",
+        "Name of the signing key (default: dev)",
+    );
+    printopt(
+        "    --fit-algo <ALGO>",
+        "Signature algorithm (default: sha256,rsa2048)",
+    );
+    println!();
+    println!("{}", "Swupdate Options:".green().bold());
+    printopt(
+        "-o, --output <FILE>",
+        "Package written by swu (default: <NAME>-<VERSION>.swu)",
+    );
     println!();
-    printusage("cargo rootfs release [OPTIONS]");
-    println!("Install package in the rootfs, keeping debug symbols.");
+    println!("{}", "RAUC Options:".green().bold());
+    printopt(
+        "-o, --output <FILE>",
+        "Bundle written by rauc (default: <NAME>-<VERSION>.raucb)",
+    );
+    printopt(
+        "    --cert <FILE>",
+        "Signing certificate of the bundle (file or PKCS#11 URI)",
+    );
+    printopt(
+        "    --key <FILE>",
+        "Signing key of the bundle (file or PKCS#11 URI)",
+    );
     println!();
-    println!("{}", "Options:".green().bold());
-    printopt("-d, --dest <DIRECTORY>", "Rootfs directory (default: /)");
+    println!("{}", "Mender Options:".green().bold());
     printopt(
-        "-s, --altsrc <DIRECTORY>",
-        "Use an an alternative sources for files to install.",
+        "-o, --output <FILE>",
+        "Artifact written by mender (default: <NAME>-<VERSION>.mender)",
     );
-    printopt("    --target <TRIPLE>", "Install for target triple");
+    printopt("    --key <FILE>", "Signing key of the artifact");
+    println!();
+    println!("{}", "OSTree Options:".green().bold());
     printopt(
-        "-S, --init-start-dir <DIRECTORY>",
-        "Init start script directory (default: /etc/rc1.d)",
+        "    --repo <DIRECTORY>",
+        "OSTree repository, created in archive mode if needed",
     );
     printopt(
-        "-K, --init-stop-dir <DIRECTORY>",
-        "Init stop script directory (default: /etc/rc6.d)",
+        "    --branch <NAME>",
+        "Branch of the commit (default: <NAME>/<TARGET>)",
     );
-    printopt("-v, --verbose", "Use verbose output");
-    printopt("-h, --help", "Print help");
     println!();
-    println!("{}", "Target Selection:".green().bold());
-    printopt("    --lib", "Install only this package's library");
-    printopt("    --bins", "Install all binaries");
-    printopt("    --bin [<NAME>]", "Install only the specified binary");
+    println!("{}", "Delta Options:".green().bold());
+    printopt(
+        "-o, --output <DIRECTORY>",
+        "Index and chunk store written by delta (default: <NAME>-<VERSION>.delta)",
+    );
+    printopt(
+        "    --from <FILE>",
+        "Index of the previous version, whose chunks are not stored",
+    );
     println!();
-    println!("{}", "Feature Selection:".green().bold());
+    println!("{}", "Export Options:".green().bold());
     printopt(
-        "-F, --features <FEATURES>",
-        "Space or comma separated list of features to activate",
+        "    --format <FORMAT>",
+        "Container format: wsl, nspawn or lxc",
     );
-    printopt("    --all-features", "Activate all available features");
     printopt(
-        "    --no-default-features",
-        "Do not activate the `default` feature",
+        "-o, --output <PATH>",
+        "Tarball or container directory written by export",
     );
     println!();
-    println!("{}", "Manifest Options:".green().bold());
-    printopt("    --manifest-path <PATH>", "Path to Cargo.toml");
+    println!("{}", "Netboot Options:".green().bold());
     printopt(
-        "    --lockfile-path <PATH>",
-        "Path to Cargo.lock (unstable)",
+        "    --export-dir <DIRECTORY>",
+        "NFS exported directory of the rootfs",
     );
     printopt(
-        "    --locked",
-        "Assert that `Cargo.lock` will remain unchanged",
+        "    --tftp-dir <DIRECTORY>",
+        "TFTP directory of the kernel and device tree (default: /srv/tftp)",
     );
-    printopt("    --offline", "Run without accessing the network");
     printopt(
-        "    --frozen",
-        "Equivalent to specifying both --locked and --offline",
+        "    --server <ADDRESS>",
+        "Address of the NFS and TFTP server (default: this host)",
     );
 }
 
 impl CargoRootfsArgs {
+    /// Return the dm-verity options of the filesystem images, with --verity.
+    fn verity_options(&self) -> Option<verity::VerityOptions> {
+        self.verity.then(|| verity::VerityOptions {
+            hash: self.verity_hash,
+            key: self.verity_key.clone(),
+            cert: self.verity_cert.clone(),
+        })
+    }
+
+    fn is_deploying(&self) -> bool {
+        match self.command {
+            Command::Deploy => true,
+            Command::Watch => self.host.is_some() || self.deploy.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Build the selected targets in release mode with cargo.
+    fn build(&self) -> bool {
+        let cargo = std::env::var("CARGO").unwrap_or("cargo".into());
+        message::status(format_args!("{cargo} build --release"));
+        self.build_command()
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run {cargo}: {e:?}"))
+            .success()
+    }
+
+    /// Return the target triple of the build, as selected by cargo: --target, then
+    /// CARGO_BUILD_TARGET, then the `build.target` of the cargo configuration.
+    fn get_target(&self) -> Option<String> {
+        self.target
+            .clone()
+            .or_else(|| std::env::var("CARGO_BUILD_TARGET").ok())
+            .or_else(config::cargo_build_target)
+    }
+
+    fn build_command(&self) -> std::process::Command {
+        let cargo = std::env::var("CARGO").unwrap_or("cargo".into());
+        let mut cmd = std::process::Command::new(&cargo);
+        cmd.arg("build").arg("--release");
+        if let Some(target) = &self.target {
+            cmd.arg("--target").arg(target);
+        }
+        if self.all_bins_only {
+            cmd.arg("--bins");
+        }
+        for bin in &self.bins_only {
+            cmd.arg("--bin").arg(bin);
+        }
+        if self.lib_only {
+            cmd.arg("--lib");
+        }
+        for feature in &self.features {
+            match feature {
+                cargo_metadata::CargoOpt::SomeFeatures(features) => {
+                    cmd.arg("--features").arg(features.join(","));
+                }
+                cargo_metadata::CargoOpt::AllFeatures => {
+                    cmd.arg("--all-features");
+                }
+                cargo_metadata::CargoOpt::NoDefaultFeatures => {
+                    cmd.arg("--no-default-features");
+                }
+            }
+        }
+        if let Some(path) = &self.manifest_path {
+            cmd.arg("--manifest-path").arg(path);
+        }
+        if let Some(dir) = &self.target_dir {
+            cmd.arg("--target-dir").arg(dir);
+        }
+        if let Some(dir) = &self.artifact_dir {
+            cmd.arg("-Zunstable-options").arg("--artifact-dir").arg(dir);
+        }
+        if self.locked {
+            cmd.arg("--locked");
+        }
+        if self.offline {
+            cmd.arg("--offline");
+        }
+        if self.frozen {
+            cmd.arg("--frozen");
+        }
+        cmd
+    }
+
     fn metadata(&self) -> cargo_metadata::Metadata {
         let mut cmd = cargo_metadata::MetadataCommand::new();
         let mut other_options = vec![];
@@ -497,6 +5449,11 @@ impl CargoRootfsArgs {
         if let Some(path) = &self.manifest_path {
             cmd.manifest_path(path);
         }
+        // Only keep the dependencies enabled for the platform of the rootfs.
+        if let Some(target) = self.get_target().or_else(host_triple) {
+            other_options.push("--filter-platform".into());
+            other_options.push(target);
+        }
         if let Some(path) = &self.lockfile_path {
             other_options.push("--lockfile-path".into());
             other_options.push(path.into());
@@ -511,7 +5468,66 @@ impl CargoRootfsArgs {
             other_options.push("--frozen".into());
         }
         cmd.other_options(other_options);
-        cmd.exec().unwrap_or_else(|e| panic!("{e}"))
+        let mut metadata = cmd.exec().unwrap_or_else(|e| panic!("{e}"));
+        // cargo metadata has no --target-dir option, but honors CARGO_TARGET_DIR and
+        // build.target-dir.
+        if let Some(dir) = &self.target_dir {
+            let cwd = PathBuf::try_from(std::env::current_dir().unwrap()).unwrap();
+            metadata.target_directory = cwd.join(dir);
+        }
+        metadata
+    }
+
+    /// Use the options of the profile selected with --rootfs-profile, which are not
+    /// specified on the command line.
+    fn profile(&mut self) {
+        let Some(name) = &self.rootfs_profile else {
+            return;
+        };
+        let mut cmd = cargo_metadata::MetadataCommand::new();
+        cmd.no_deps();
+        if let Some(path) = &self.manifest_path {
+            cmd.manifest_path(path);
+        }
+        let metadata = cmd.exec().unwrap_or_else(|e| panic!("{e}"));
+        let profile = profile::find(&metadata.workspace_metadata, name);
+        self.dst = self.dst.take().or(profile.dest);
+        if self.altsrc.is_empty() {
+            self.altsrc.extend(profile.altsrc);
+        }
+        self.target = self.target.take().or(profile.target);
+        if let (None, Some(format)) = (self.message_format, profile.message_format) {
+            self.message_format = Some(message::Format::parse(&format));
+        }
+        if !profile.features.is_empty() {
+            self.features
+                .push(cargo_metadata::CargoOpt::SomeFeatures(profile.features));
+        }
+        if profile.all_features {
+            self.features.push(cargo_metadata::CargoOpt::AllFeatures);
+        }
+        if profile.no_default_features {
+            self.features
+                .push(cargo_metadata::CargoOpt::NoDefaultFeatures);
+        }
+    }
+
+    /// Use the defaults of the configuration files and of the environment, for the options
+    /// which are not specified on the command line or by the rootfs profile.
+    fn defaults(&mut self, config: config::Config) {
+        self.dst = self.dst.take().or(config.dest);
+        if self.altsrc.is_empty() {
+            self.altsrc.extend(config.altsrc);
+        }
+        self.target = self.target.take().or(config.target);
+        if let (None, Some(format)) = (self.message_format, config.message_format) {
+            self.message_format = Some(message::Format::parse(&format));
+        }
+        if let Some(strip) = config.strip {
+            if std::env::var_os("STRIP").is_none() {
+                std::env::set_var("STRIP", strip);
+            }
+        }
     }
 
     fn parse(&mut self) {
@@ -532,7 +5548,106 @@ impl CargoRootfsArgs {
                     self.command = Command::Release;
                     break;
                 }
-                "--help" | "-h" => return help(),
+                "deploy" => {
+                    self.command = Command::Deploy;
+                    break;
+                }
+                "watch" => {
+                    self.command = Command::Watch;
+                    break;
+                }
+                "run" => {
+                    self.command = Command::Run;
+                    break;
+                }
+                "shell" => {
+                    self.command = Command::Shell;
+                    break;
+                }
+                "boot" => {
+                    self.command = Command::Boot;
+                    break;
+                }
+                "image" => {
+                    self.command = Command::Image;
+                    break;
+                }
+                "disk" => {
+                    self.command = Command::Disk;
+                    break;
+                }
+                "fit" => {
+                    self.command = Command::Fit;
+                    break;
+                }
+                "swu" => {
+                    self.command = Command::Swu;
+                    break;
+                }
+                "rauc" => {
+                    self.command = Command::Rauc;
+                    break;
+                }
+                "mender" => {
+                    self.command = Command::Mender;
+                    break;
+                }
+                "ostree" => {
+                    self.command = Command::Ostree;
+                    break;
+                }
+                "delta" => {
+                    self.command = Command::Delta;
+                    break;
+                }
+                "export" => {
+                    self.command = Command::Export;
+                    break;
+                }
+                "netboot" => {
+                    self.command = Command::Netboot;
+                    break;
+                }
+                "uninstall" => {
+                    self.command = Command::Uninstall;
+                    break;
+                }
+                "verify" => {
+                    self.command = Command::Verify;
+                    break;
+                }
+                "diff" => {
+                    self.command = Command::Diff;
+                    break;
+                }
+                "audit" => {
+                    self.command = Command::Audit;
+                    break;
+                }
+                "size" => {
+                    self.command = Command::Size;
+                    break;
+                }
+                "info" => {
+                    self.command = Command::Info;
+                    break;
+                }
+                "check" => {
+                    self.command = Command::Check;
+                    break;
+                }
+                "plan" => {
+                    self.command = Command::Plan;
+                    break;
+                }
+                "apply" => {
+                    self.command = Command::Apply;
+                    break;
+                }
+                "--help" | "-h" => {
+                    help();
+                    std::process::exit(0);
+                }
                 other => panic!("Unknown argument {}", other),
             }
         }
@@ -547,27 +5662,358 @@ impl CargoRootfsArgs {
             match arg.as_str() {
                 // options
                 "-d" | "--dest" => {
-                    self.dst = Some(PathBuf::from(args.next().unwrap()));
+                    let dst = args.next().unwrap();
+                    match dst.split_once('=') {
+                        Some((name, dir)) if !name.is_empty() && !name.contains('/') => {
+                            self.rootfs_dsts.push((name.into(), dir.into()))
+                        }
+                        _ => self.dst = Some(PathBuf::from(dst)),
+                    }
+                }
+                "--require-altsrc" => {
+                    self.require_altsrc.push(args.next().unwrap());
+                }
+                "-s" | "--altsrc" => {
+                    self.altsrc.push(PathBuf::from(args.next().unwrap()));
+                }
+                "--target" => {
+                    self.target = Some(args.next().unwrap());
+                }
+                "-S" | "--init-start-dir" => {
+                    self.init_startdir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "-K" | "--init-stop-dir" => {
+                    self.init_stopdir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "-p" | "--package" => {
+                    self.package = Some(args.next().unwrap());
+                }
+                "--backup" => {
+                    self.backup = Some("~".into());
+                }
+                backup if backup.starts_with("--backup=") => {
+                    self.backup = Some(backup["--backup=".len()..].into());
+                }
+                "--color" => {
+                    self.color = message::Color::parse(&args.next().unwrap());
+                    message::set_color(self.color);
+                }
+                "--message-format" => {
+                    self.message_format = Some(message::Format::parse(&args.next().unwrap()));
+                }
+                "--kernel-version" => {
+                    self.kernel_version = Some(args.next().unwrap());
+                }
+                "--kernel-build-dir" => {
+                    self.kernel_build_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--rootfs-profile" => {
+                    self.rootfs_profile = Some(args.next().unwrap());
+                }
+                "--interp" => {
+                    self.interp = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--sysroot" => {
+                    self.sysroot = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--symlink-style" => {
+                    self.symlink_style = SymlinkStyle::parse(&args.next().unwrap());
+                }
+                "--strict" => {
+                    self.strict = true;
+                }
+                "--keep-going" => {
+                    self.keep_going = true;
+                }
+                "--ldconfig" => {
+                    self.ldconfig = true;
+                }
+                "--build-info" => {
+                    self.build_info = true;
+                }
+                "--base" => {
+                    self.base = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--whiteout" => {
+                    self.whiteout = Some(whiteout::Format::parse(&args.next().unwrap()));
+                }
+                "--stats" => {
+                    self.stats = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--emit-script" => {
+                    self.emit_script = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--emit-build" => {
+                    self.emit_build = Some(args.next().unwrap());
+                }
+                "-j" | "--jobs" => {
+                    let jobs = args.next().unwrap();
+                    let jobs = jobs
+                        .parse()
+                        .unwrap_or_else(|_| panic!("--jobs {jobs} is not a number"));
+                    self.jobs = Some(jobs);
+                }
+                "--dedupe" => {
+                    self.dedupe = true;
+                }
+                "--checksum" => {
+                    self.checksum = true;
+                }
+                "-f" | "--force" => {
+                    self.force = true;
+                }
+                "--wait" => {
+                    self.lock = lock::Mode::Wait;
+                }
+                "--no-lock" => {
+                    self.lock = lock::Mode::Disabled;
+                }
+                "--split-debug" => {
+                    self.split_debug = Some(PathBuf::new());
+                }
+                split if split.starts_with("--split-debug=") => {
+                    self.split_debug = Some(split["--split-debug=".len()..].into());
+                }
+                "--debuginfod" => {
+                    self.debuginfod = Some(args.next().unwrap().into());
+                }
+                "--scrub-notes" => {
+                    self.scrub_notes = true;
+                }
+                "--compress-debug" => {
+                    let compression = debuginfo::Compression::parse(&args.next().unwrap());
+                    self.compress_debug = Some(compression);
+                }
+                "--no-prune" => {
+                    self.no_prune = true;
+                }
+                "--licenses" => {
+                    self.licenses = true;
+                }
+                "--embed-manifest" => {
+                    self.embed_manifest = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--manifest" => {
+                    self.manifest = Some(args.next().unwrap());
+                }
+                "--sbom" => {
+                    self.sbom = Some(args.next().unwrap());
+                }
+                "--help" | "-h" => {
+                    help();
+                    std::process::exit(0);
+                }
+                "--verbose" | "-v" => self.verbose += 1,
+                "-vv" => self.verbose += 2,
+                "--quiet" | "-q" => self.quiet = true,
+
+                // deploy options:
+                "--host" => {
+                    self.host = Some(args.next().unwrap());
+                }
+                "--deploy" => {
+                    self.deploy = Some(args.next().unwrap());
+                }
+                "--deploy-prefix" => {
+                    self.deploy_prefix = Some(args.next().unwrap());
+                }
+                "--delta" => {
+                    self.delta = true;
+                }
+                "--restart" => {
+                    self.restart = true;
+                }
+
+                // run options:
+                "--exec-backend" => {
+                    self.exec_backend = exec::Backend::parse(&args.next().unwrap());
+                }
+
+                // boot options:
+                "--kernel" => {
+                    self.kernel = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--dtb" => {
+                    self.dtb.push(PathBuf::from(args.next().unwrap()));
+                }
+                "--boot-image" => {
+                    self.boot_image = boot::Image::parse(&args.next().unwrap());
+                }
+                "--boot-cmd" => {
+                    self.boot_cmd = Some(args.next().unwrap());
+                }
+                "--boot-timeout" => {
+                    let timeout = args.next().unwrap();
+                    let timeout = timeout
+                        .parse()
+                        .unwrap_or_else(|_| panic!("--boot-timeout {timeout} is not a number"));
+                    self.boot_timeout = Some(timeout);
+                }
+
+                // verity options:
+                "--verity" => {
+                    self.verity = true;
+                }
+                "--verity-hash" => {
+                    self.verity_hash = verity::Hash::parse(&args.next().unwrap());
+                }
+                "--verity-key" => {
+                    self.verity_key = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--verity-cert" => {
+                    self.verity_cert = Some(PathBuf::from(args.next().unwrap()));
+                }
+
+                // signing options:
+                "--sign" => {
+                    self.sign = Some(sign::Signer::parse(&args.next().unwrap()));
+                }
+                "--sign-cmd" => {
+                    self.sign = Some(sign::Signer::Command(args.next().unwrap()));
+                }
+                "--sign-elfs" => {
+                    self.sign_elfs = true;
+                }
+
+                // image options:
+                "--filesystem" => {
+                    self.filesystem = image::Filesystem::parse(&args.next().unwrap());
+                }
+                "-o" | "--output" if self.command == Command::Image => {
+                    self.image = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--peb-size" => {
+                    self.peb_size = Some(parse_size(&args.next().unwrap()));
+                }
+                "--leb-size" => {
+                    self.leb_size = Some(parse_size(&args.next().unwrap()));
+                }
+                "--min-io-size" => {
+                    self.min_io_size = Some(parse_size(&args.next().unwrap()));
+                }
+                "--max-leb-cnt" => {
+                    let count = args.next().unwrap();
+                    let count = count
+                        .parse()
+                        .unwrap_or_else(|_| panic!("--max-leb-cnt {count} is not a number"));
+                    self.max_leb_cnt = Some(count);
+                }
+
+                // disk options:
+                "--layout" => {
+                    self.layout = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "-o" | "--output" if self.command == Command::Disk => {
+                    self.disk = Some(PathBuf::from(args.next().unwrap()));
+                }
+
+                // FIT options:
+                "-o" | "--output" if self.command == Command::Fit => {
+                    self.fit = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--fit-load" => {
+                    self.fit_load = Some(fit::parse_address(&args.next().unwrap()));
+                }
+                "--fit-entry" => {
+                    self.fit_entry = Some(fit::parse_address(&args.next().unwrap()));
+                }
+                "--fit-key-dir" => {
+                    self.fit_key_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--fit-key-name" => {
+                    self.fit_key_name = Some(args.next().unwrap());
+                }
+                "--fit-algo" => {
+                    self.fit_algo = Some(args.next().unwrap());
+                }
+
+                // swupdate options:
+                "-o" | "--output" if self.command == Command::Swu => {
+                    self.swu = Some(PathBuf::from(args.next().unwrap()));
+                }
+
+                // RAUC options:
+                "-o" | "--output" if self.command == Command::Rauc => {
+                    self.rauc = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--cert" => {
+                    self.cert = Some(args.next().unwrap());
+                }
+                "--key" => {
+                    self.key = Some(args.next().unwrap());
+                }
+
+                // Mender options:
+                "-o" | "--output" if self.command == Command::Mender => {
+                    self.mender = Some(PathBuf::from(args.next().unwrap()));
+                }
+
+                // OSTree options:
+                "--repo" => {
+                    self.repo = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--branch" => {
+                    self.branch = Some(args.next().unwrap());
+                }
+
+                // delta options:
+                "-o" | "--output" if self.command == Command::Delta => {
+                    self.delta_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--from" => {
+                    self.from = Some(PathBuf::from(args.next().unwrap()));
+                }
+
+                // netboot options:
+                "--export-dir" => {
+                    self.export_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--tftp-dir" => {
+                    self.tftp_dir = Some(PathBuf::from(args.next().unwrap()));
                 }
-                "-s" | "--altsrc" => {
-                    self.altsrc = Some(PathBuf::from(args.next().unwrap()));
+                "--server" => {
+                    self.server = Some(args.next().unwrap());
                 }
-                "--target" => {
-                    self.target = Some(args.next().unwrap());
+
+                // export options:
+                "--format" => {
+                    self.export_format = Some(export::Format::parse(&args.next().unwrap()));
                 }
-                "-S" | "--init-start-dir" => {
-                    self.init_startdir = Some(PathBuf::from(args.next().unwrap()));
+                "-o" | "--output" if self.command == Command::Export => {
+                    self.export = Some(PathBuf::from(args.next().unwrap()));
                 }
-                "-K" | "--init-stop-dir" => {
-                    self.init_stopdir = Some(PathBuf::from(args.next().unwrap()));
+
+                "--" => {
+                    self.run_args = args.by_ref().collect();
+                }
+
+                // plan options:
+                "-o" | "--output" => {
+                    self.plan = Some(PathBuf::from(args.next().unwrap()));
+                }
+                plan if self.command == Command::Apply
+                    && self.plan.is_none()
+                    && !plan.starts_with('-') =>
+                {
+                    self.plan = Some(PathBuf::from(plan));
                 }
-                "--help" | "-h" => help(),
-                "--verbose" | "-v" => self.verbose += 1,
 
                 // target selections:
                 "--lib" => {
                     self.lib_only = true;
                 }
+                "--tests" => {
+                    self.tests = true;
+                }
+                "--benches" => {
+                    self.benches = true;
+                }
+                "--no-deps" => {
+                    self.no_deps = true;
+                }
+                "--deps-only" => {
+                    self.deps_only = true;
+                }
                 "--bins" => {
                     self.all_bins_only = true;
                 }
@@ -602,6 +6048,12 @@ impl CargoRootfsArgs {
                 "--lockfile-path" => {
                     self.lockfile_path = Some(args.next().unwrap());
                 }
+                "--target-dir" => {
+                    self.target_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
+                "--artifact-dir" => {
+                    self.artifact_dir = Some(PathBuf::from(args.next().unwrap()));
+                }
                 "--locked" => {
                     self.locked = true;
                 }
@@ -618,17 +6070,135 @@ impl CargoRootfsArgs {
     }
 }
 
-fn main() {
-    let mut args = CargoRootfsArgs::default();
-    args.parse();
+/// Refuse to install in the host root filesystem, or in a directory which was not
+/// created by cargo-rootfs, unless --force is specified or the user confirms.
+fn check_destination(dst: &Path, force: bool) {
+    if force {
+        return;
+    }
+    let reason = if canonicalize_existing(dst) == "/" {
+        "is the host root filesystem"
+    } else if dst.join(manifest::MANIFEST_DIR).exists() {
+        return;
+    } else if dst
+        .read_dir_utf8()
+        .map(|mut dir| dir.next().is_some())
+        .unwrap_or(false)
+    {
+        "is not empty and was not created by cargo-rootfs"
+    } else {
+        return;
+    };
 
-    let cargo_rootfs = CargoRootfs::new(&args);
+    if !std::io::stdin().is_terminal() {
+        panic!("Destination {dst} {reason}: use --force to install anyway");
+    }
+    eprint!(
+        "{} {dst} {reason}. Install anyway? [y/N] ",
+        "Warning:".yellow().bold()
+    );
+    let mut answer = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        panic!("Install to {dst} aborted");
+    }
+}
+
+/// Install the package, rolling back the rootfs modifications if any step fails.
+fn install(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let _lock = cargo_rootfs.lock(args.lock);
+    check_destination(&cargo_rootfs.dst, args.force || cargo_rootfs.dry_run);
+    let state = cargo_rootfs.state_path();
+    let fingerprint = cargo_rootfs.fingerprint(args);
+    let up_to_date = state::State::load(&state).is_some_and(|x| x.is_up_to_date(&fingerprint));
+    if up_to_date && !cargo_rootfs.dry_run {
+        let line = format!("{} is up to date", cargo_rootfs.dst);
+        message::status(line.green().bold());
+        return;
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        install_package(cargo_rootfs, args);
+    }));
+    let mut journal = cargo_rootfs.journal.borrow_mut();
+    if let Err(e) = result {
+        if !cargo_rootfs.dry_run {
+            eprintln!("{}", "Install failed: rolling back".red().bold());
+            journal.rollback();
+        }
+        std::panic::resume_unwind(e);
+    }
+    journal.commit();
+    if !cargo_rootfs.dry_run {
+        cargo_rootfs.save_state(&state, fingerprint, args);
+    }
+}
+
+/// Record the operations of an install in a JSON plan, restoring the rootfs afterwards.
+fn plan(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let output = args.plan.clone().unwrap_or("plan.json".into());
+    let _lock = cargo_rootfs.lock(args.lock);
+    if let Some(rootfs) = &args.dst {
+        stage_plan(rootfs, &cargo_rootfs.dst);
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        install_package(cargo_rootfs, args);
+    }));
+    cargo_rootfs.journal.borrow_mut().rollback();
+    if let Err(e) = result {
+        std::panic::resume_unwind(e);
+    }
+    if let Some(plan) = &cargo_rootfs.plan {
+        plan.borrow().save(&output);
+    }
+}
+
+/// Copy the content of a rootfs to the staging directory of a plan, so the install is
+/// recorded from its files without modifying it.
+fn stage_plan(rootfs: &Path, staging: &Path) {
+    if !rootfs.is_dir() {
+        panic!("Destination {rootfs} is not a directory");
+    }
+    remove_dir_content(staging);
+    std::fs::create_dir_all(staging)
+        .unwrap_or_else(|e| panic!("Failed to create directory {staging}: {e:?}"));
+    message::operation(
+        format_args!("cp -a {:#?} {:#?}", rootfs.join("."), staging),
+        "copy",
+        json!({"src": rootfs, "dst": staging}),
+    );
+    let status = std::process::Command::new("cp")
+        .arg("-a")
+        .arg(rootfs.join("."))
+        .arg(staging)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run cp: {e:?}"));
+    if !status.success() {
+        panic!("cp -a {rootfs} {staging} failed: {status}");
+    }
+}
+
+/// Run the operations of a plan written by `cargo rootfs plan`, without cargo.
+fn apply(args: &CargoRootfsArgs) {
+    let file = args
+        .plan
+        .as_ref()
+        .unwrap_or_else(|| panic!("apply requires a plan file"));
+    let plan = plan::Plan::load(file);
+    let dst = args.dst.clone().unwrap_or("/".into());
+    check_destination(&dst, args.force);
+    plan.apply(&dst, args.exec_backend);
+    let line = format!("{} operations applied to {dst}", plan.operations.len());
+    message::status(line.green().bold());
+}
 
+/// Install the binaries and libraries of the workspace selected by the target options.
+fn install_targets(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
     if args.all_bins_only {
         cargo_rootfs.install_bins();
     }
     for bin in &args.bins_only {
-        cargo_rootfs.install_bin(bin);
+        let package = cargo_rootfs.get_bin_package(bin);
+        cargo_rootfs.install_bin(package, bin);
     }
     if args.lib_only {
         cargo_rootfs.install_libs();
@@ -639,6 +6209,963 @@ fn main() {
         cargo_rootfs.install_bins();
         cargo_rootfs.install_libs();
     }
+}
+
+/// Install the named rootfs of `--dest <NAME>=<DIRECTORY>` from the same metadata. They
+/// are all restored if one of them fails.
+fn install_all(args: &CargoRootfsArgs) {
+    let single = [
+        ("--manifest", args.manifest.is_some()),
+        ("--sbom", args.sbom.is_some()),
+        ("--stats", args.stats.is_some()),
+        ("--emit-script", args.emit_script.is_some()),
+        ("--emit-build", args.emit_build.is_some()),
+    ];
+    if let Some((option, _)) = single.iter().find(|(_, present)| *present) {
+        panic!("{option} is not supported with several named --dest");
+    }
+    if !matches!(
+        args.command,
+        Command::Install | Command::Release | Command::Diff
+    ) {
+        panic!("Several named --dest are only supported by install, release and diff");
+    }
+    let metadata = args.metadata();
+    let rootfs: Vec<CargoRootfs> = args
+        .rootfs_dsts
+        .iter()
+        .map(|(name, dst)| {
+            let mut args = args.clone();
+            args.dst = Some(dst.clone());
+            CargoRootfs::with_metadata(&args, metadata.clone(), name)
+        })
+        .collect();
+    let _locks: Vec<_> = rootfs.iter().map(|x| x.lock(args.lock)).collect();
+    for cargo_rootfs in &rootfs {
+        check_destination(&cargo_rootfs.dst, args.force || cargo_rootfs.dry_run);
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        for cargo_rootfs in &rootfs {
+            let line = format!(
+                "Installing the {} rootfs in {}",
+                cargo_rootfs.rootfs, cargo_rootfs.dst
+            );
+            message::status(line.green().bold());
+            install_package(cargo_rootfs, args);
+        }
+    }));
+    if let Err(e) = result {
+        if args.command != Command::Diff {
+            eprintln!("{}", "Install failed: rolling back".red().bold());
+            for cargo_rootfs in &rootfs {
+                cargo_rootfs.journal.borrow_mut().rollback();
+            }
+        }
+        std::panic::resume_unwind(e);
+    }
+    for cargo_rootfs in &rootfs {
+        cargo_rootfs.journal.borrow_mut().commit();
+    }
+}
+
+fn install_package(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    if args.no_deps && args.deps_only {
+        panic!("--no-deps and --deps-only are mutually exclusive");
+    }
+    if !args.deps_only {
+        // The skeleton comes first, so the rules can edit its files.
+        cargo_rootfs.install_etc();
+        install_targets(cargo_rootfs, args);
+        if args.tests {
+            cargo_rootfs.install_tests("tests");
+        }
+        if args.benches {
+            cargo_rootfs.install_tests("benches");
+        }
+    }
 
     cargo_rootfs.install_dependencies();
+    cargo_rootfs.install_alternatives();
+    cargo_rootfs.check_altsrc(&args.require_altsrc);
+    if args.licenses {
+        cargo_rootfs.install_licenses();
+    }
+    if let Some(dir) = &args.embed_manifest {
+        cargo_rootfs.embed_manifest(dir);
+    }
+    cargo_rootfs.flush_copies();
+    if !cargo_rootfs.dry_run {
+        cargo_rootfs.set_interpreters();
+    }
+    cargo_rootfs.run_depmod();
+    if args.ldconfig {
+        cargo_rootfs.configure_ldconfig();
+    }
+    if args.build_info {
+        cargo_rootfs.write_build_info();
+    }
+    cargo_rootfs.run_hooks();
+    cargo_rootfs.remove_paths(&cargo_rootfs.dst);
+    if args.command == Command::Netboot && !cargo_rootfs.dry_run {
+        cargo_rootfs.configure_nfs_root();
+    }
+    cargo_rootfs.report_failures();
+    if args.sign_elfs && !cargo_rootfs.dry_run {
+        let signer = args
+            .sign
+            .as_ref()
+            .unwrap_or_else(|| panic!("--sign-elfs requires --sign or --sign-cmd"));
+        cargo_rootfs.sign_elfs(signer);
+    }
+    // The files are linked once modified: the next steps only write the metadata files.
+    if args.dedupe && !cargo_rootfs.dry_run {
+        cargo_rootfs.dedupe();
+    }
+    if !cargo_rootfs.dry_run {
+        cargo_rootfs.audit_symlinks(args.strict);
+    }
+    // Stale files are only pruned when the whole package is installed.
+    let partial = args.all_bins_only
+        || !args.bins_only.is_empty()
+        || args.lib_only
+        || args.no_deps
+        || args.deps_only;
+    let prune = !args.no_prune && !partial;
+    if cargo_rootfs.dry_run {
+        if prune {
+            cargo_rootfs.diff_stale();
+        }
+        return cargo_rootfs.print_diff();
+    }
+    cargo_rootfs.write_whiteouts(prune);
+    let manifest = cargo_rootfs.write_manifest(prune);
+    cargo_rootfs.check_budgets();
+
+    if let Some(spec) = &args.manifest {
+        let (format, output) = manifest::parse_format(spec);
+        manifest::write_format(&manifest, format, &output);
+    }
+
+    if let Some(spec) = &args.sbom {
+        cargo_rootfs.write_sbom(spec);
+    }
+    cargo_rootfs.write_stats(args.stats.as_deref());
+    if let (Some(output), Some(plan)) = (&args.emit_script, &cargo_rootfs.plan) {
+        plan.borrow().save_script(output);
+    }
+    if let (Some(spec), Some(plan)) = (&args.emit_build, &cargo_rootfs.plan) {
+        let (format, output) = fragment::parse(spec);
+        fragment::write(&plan.borrow(), format, &output);
+    }
+}
+
+/// Install the package in the staging directory of an image subcommand, from a clean
+/// directory so the removed files are not in the image, and return the directory of the
+/// intermediate files of the image (target/rootfs-<NAME>-images).
+fn stage_image(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) -> PathBuf {
+    let name = args.command.staging_name().unwrap();
+    // The staging directory stays locked while it is cleaned and installed.
+    let _lock = cargo_rootfs.lock(args.lock);
+    if args.dst.is_none() {
+        remove_dir_content(&cargo_rootfs.dst);
+    }
+    let args = &CargoRootfsArgs {
+        lock: lock::Mode::Disabled,
+        ..args.clone()
+    };
+    install(cargo_rootfs, args);
+    let target_dir = &cargo_rootfs.metadata.target_directory;
+    target_dir.join(format!("rootfs-{name}-images"))
+}
+
+fn deploy(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let backend = match (&args.deploy, &args.host) {
+        (Some(spec), _) => deploy::Backend::parse(spec, args.deploy_prefix.as_deref()),
+        (None, Some(host)) => deploy::Backend::Ssh(deploy::SshTarget::parse(host)),
+        (None, None) => panic!("deploy requires --host or --deploy"),
+    };
+
+    // Start from a clean staging directory so removed files are not deployed again.
+    if args.dst.is_none() {
+        let _ = std::fs::remove_dir_all(&cargo_rootfs.dst);
+    }
+    install(cargo_rootfs, args);
+
+    backend.sync(&cargo_rootfs.dst, args.delta);
+    if args.restart {
+        backend.restart(&cargo_rootfs.services.borrow());
+    }
+}
+
+fn uninstall(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let name = match &args.package {
+        Some(name) => name.clone(),
+        None => cargo_rootfs.get_root_package().name.clone(),
+    };
+    let _lock = cargo_rootfs.lock(args.lock);
+    let path = manifest::path(&cargo_rootfs.dst, &name);
+    let manifest = manifest::Manifest::load(&path).unwrap_or_else(|| {
+        panic!("[{name}] No manifest found in {path}: package is not installed")
+    });
+
+    for whiteout in &manifest.whiteouts {
+        whiteout::remove(&cargo_rootfs.dst, whiteout, |_| {});
+    }
+    let entries: Vec<&manifest::Entry> = manifest.files.iter().collect();
+    manifest::remove_entries(&cargo_rootfs.dst, &entries);
+    message::operation(
+        format_args!("rm -f {:#?}", path),
+        "remove",
+        json!({"path": path}),
+    );
+    std::fs::remove_file(&path).unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+    // The directories of the manifest are removed up to the rootfs, while they are empty.
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(&cargo_rootfs.dst) || dir == cargo_rootfs.dst {
+            break;
+        }
+        if std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+fn verify(args: &CargoRootfsArgs) {
+    let dst = args.dst.clone().unwrap_or("/".into());
+    let mut manifests = manifest::load_all(&dst);
+    if let Some(name) = &args.package {
+        manifests.retain(|manifest| &manifest.package == name);
+    }
+    if manifests.is_empty() {
+        panic!("No manifest found in {}", dst.join(manifest::MANIFEST_DIR));
+    }
+
+    let issues = manifest::verify(&dst, &manifests);
+    for (path, issue) in &issues {
+        let (line, fields) = match issue {
+            manifest::Issue::Missing => (
+                format!("{} {path}", "missing:".red().bold()),
+                json!({"path": path, "issue": "missing"}),
+            ),
+            manifest::Issue::Modified(changes) => (
+                format!("{} {path} ({changes})", "modified:".yellow().bold()),
+                json!({"path": path, "issue": "modified", "details": changes}),
+            ),
+            manifest::Issue::Extra => (
+                format!("{} {path}", "extra:".cyan().bold()),
+                json!({"path": path, "issue": "extra"}),
+            ),
+        };
+        message::result(line, "verify", fields);
+    }
+    let count: usize = manifests.iter().map(|m| m.files.len()).sum();
+    if !issues.is_empty() {
+        let line = format!("{} issues found in {count} entries", issues.len());
+        message::status(line.red().bold());
+        std::process::exit(1);
+    }
+    message::status(format!("{count} entries verified").green().bold());
+}
+
+/// Report the security issues of the rootfs: the whole directory, or the installed
+/// files for the host root directory.
+fn audit(args: &CargoRootfsArgs) {
+    let dst = args.dst.clone().unwrap_or("/".into());
+    let paths = if dst == "/" {
+        let manifests = manifest::load_all(&dst);
+        if manifests.is_empty() {
+            panic!("No manifest found in {}", dst.join(manifest::MANIFEST_DIR));
+        }
+        let mut paths: Vec<PathBuf> = manifests
+            .iter()
+            .flat_map(|manifest| manifest.files.iter())
+            .map(|entry| entry.path.clone())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    } else {
+        audit::walk(&dst)
+    };
+
+    let issues = audit::audit(&dst, &paths);
+    for (path, issue) in &issues {
+        let line = format!("{} {path}", format!("{}:", issue.name()).yellow().bold());
+        let fields = json!({"path": path, "issue": issue.name()});
+        message::result(line, "audit", fields);
+    }
+    if !issues.is_empty() {
+        let line = format!("{} issues found in {} entries", issues.len(), paths.len());
+        message::status(line.red().bold());
+        std::process::exit(1);
+    }
+    message::status(format!("{} entries audited", paths.len()).green().bold());
+}
+
+/// Validate the rootfs rules of the packages of the dependency graph: their fields,
+/// their sources, and the destinations installed by several rules.
+fn check(cargo_rootfs: &CargoRootfs) {
+    let mut errors = 0;
+    let mut report = |package: &cargo_metadata::Package, i, field: Option<&str>, error: &str| {
+        errors += 1;
+        // The same line as rule_error, with the same limitations.
+        let location = find_rule(package, i, field);
+        let line = format!(
+            "{} {}",
+            "error:".red().bold(),
+            rule_error(package, i, field, error)
+        );
+        let fields = json!({
+            "package": package.name,
+            "index": i,
+            "error": error,
+            "file": location.as_ref().map(|x| &x.file),
+            "line": location.as_ref().map(|x| x.line),
+        });
+        message::result(line, "check", fields);
+    };
+    // The destinations, with the rule, package, index and the sha256 of firmware blobs.
+    let mut destinations: Vec<(PathBuf, CargoRootfsRule, String, usize, Option<String>)> = vec![];
+    let mut rules = 0;
+
+    for node in cargo_rootfs.get_rootfs_nodes() {
+        let package = cargo_rootfs.get_package(&node.id);
+        let name = &package.name;
+        let values = match &package.metadata["rootfs"] {
+            Value::Array(values) => values.clone(),
+            Value::Null => vec![],
+            _ => {
+                report(
+                    package,
+                    0,
+                    None,
+                    "package.metadata.rootfs is not an array of tables",
+                );
+                continue;
+            }
+        };
+        for (i, value) in values.into_iter().enumerate() {
+            rules += 1;
+            let rule = match CargoRootfsRule::parse(&value) {
+                Ok(x) => x,
+                Err((field, e)) => {
+                    report(package, i, field.as_deref(), &e);
+                    continue;
+                }
+            };
+            for (field, error) in rule.validate() {
+                report(package, i, field, &error);
+            }
+
+            let mut firmware = None;
+            let sources = [("source", &rule.source), ("patch", &rule.patch)];
+            let sources = sources
+                .into_iter()
+                .filter_map(|(field, x)| Some((field, x.as_ref()?)));
+            for (field, source) in sources {
+                // The variables are only known once the package is built.
+                if rule.symbolic == Some(true) || source.as_str().contains("${") {
+                    continue;
+                }
+                let path = cargo_rootfs.get_source_file(package, i, &rule, source);
+                if !path.exists() {
+                    report(
+                        package,
+                        i,
+                        Some(field),
+                        &format!("source {path} does not exist"),
+                    );
+                } else if let (Some(sha256), "source") = (&rule.sha256, field) {
+                    // The digest of the source file, as verified by the install, not of the patch.
+                    if let Err(error) = check_sha256(&path, sha256) {
+                        report(package, i, Some("sha256"), &error);
+                    }
+                }
+                if rule.firmware == Some(true) && path.is_file() {
+                    firmware = Some(manifest::sha256sum(&path));
+                }
+            }
+
+            let mut dsts: Vec<PathBuf> = vec![];
+            let firmware_dir =
+                (rule.firmware == Some(true)).then(|| PathBuf::from("/lib/firmware/"));
+            let destination = rule.destination.as_ref().or(firmware_dir.as_ref());
+            if let (Some(dst), None, None) = (destination, &rule.patch, &rule.append) {
+                let mut dst = match (dst.as_str().ends_with('/'), &rule.source) {
+                    (true, Some(source)) => dst.join(source.file_name().unwrap_or_default()),
+                    _ => dst.clone(),
+                };
+                if let (Some(compress), Some(_)) = (&rule.compress, &firmware) {
+                    dst = PathBuf::from(format!("{dst}.{compress}"));
+                }
+                if rule.ensure_line.is_none() && rule.root_crate_symlink.is_none() {
+                    if let Some(init) = &rule.init {
+                        let name = dst.file_name().unwrap_or_default();
+                        if let Some(start) = init.start {
+                            dsts.push(cargo_rootfs.init_startdir.join(format!("S{start}{name}")));
+                        }
+                        if let Some(stop) = init.stop {
+                            dsts.push(cargo_rootfs.init_stopdir.join(format!("K{stop}{name}")));
+                        }
+                    }
+                    dsts.push(dst);
+                }
+            }
+            for dst in dsts {
+                // The rules of different rootfs, or of disjoint profiles, are never
+                // installed together, and the identical firmware blobs are installed once.
+                let other = destinations.iter().find(|(x, other, _, _, sha256)| {
+                    *x == dst
+                        && (firmware.is_none() || *sha256 != firmware)
+                        && other.rootfs.as_deref().unwrap_or(MAIN_ROOTFS)
+                            == rule.rootfs.as_deref().unwrap_or(MAIN_ROOTFS)
+                        && match (&other.profiles, &rule.profiles) {
+                            (Some(a), Some(b)) => a.iter().any(|x| b.contains(x)),
+                            _ => true,
+                        }
+                });
+                if let Some((_, _, other, j, _)) = other {
+                    let error = format!(
+                        "{dst} is also installed by [{other}] package.metadata.rootfs.[{j}]"
+                    );
+                    report(package, i, Some("destination"), &error);
+                }
+                destinations.push((dst, rule.clone(), name.clone(), i, firmware.clone()));
+            }
+        }
+    }
+
+    if errors > 0 {
+        let line = format!("{errors} errors found in {rules} rules");
+        message::status(line.red().bold());
+        std::process::exit(1);
+    }
+    message::status(format!("{rules} rules checked").green().bold());
+}
+
+/// List the rootfs rules of the packages of the dependency graph.
+fn info(cargo_rootfs: &CargoRootfs) {
+    let nodes = cargo_rootfs.get_rootfs_nodes();
+    let mut count = 0;
+    for node in &nodes {
+        let package = cargo_rootfs.get_package(&node.id);
+        let id = format!("{}@{}", package.name, package.version);
+        for (i, rule) in cargo_rootfs.get_rules(package).iter().enumerate() {
+            count += 1;
+            let line = format!("[{id}] package.metadata.rootfs.[{i}]: {}", rule.describe());
+            let fields = json!({"package": id, "index": i, "rule": package.metadata["rootfs"][i]});
+            message::result(line, "rule", fields);
+        }
+    }
+    let packages = nodes.len();
+    message::status(
+        format!("{count} rules in {packages} packages")
+            .green()
+            .bold(),
+    );
+}
+
+/// Print the size of the installed files for each package and rule, from the manifests.
+fn size(args: &CargoRootfsArgs) {
+    let dst = args.dst.clone().unwrap_or("/".into());
+    let mut manifests = manifest::load_all(&dst);
+    if let Some(name) = &args.package {
+        manifests.retain(|manifest| &manifest.package == name);
+    }
+    if manifests.is_empty() {
+        panic!("No manifest found in {}", dst.join(manifest::MANIFEST_DIR));
+    }
+
+    let usage = manifest::usage(&manifests);
+    let total: u64 = usage.iter().map(|x| x.bytes).sum();
+    let files: u64 = usage.iter().map(|x| x.files).sum();
+    let width = usage.iter().map(|x| x.package.len()).max().unwrap_or(0);
+    message::status(
+        format!(
+            "{:>10} {:>6} {:>6}  {:width$}  RULE",
+            "SIZE", "%", "FILES", "PACKAGE"
+        )
+        .bold(),
+    );
+    for x in &usage {
+        let percent = match total {
+            0 => 0.0,
+            total => 100.0 * x.bytes as f64 / total as f64,
+        };
+        let line = format!(
+            "{:>10} {percent:>5.1}% {:>6}  {:width$}  {}",
+            message::human_size(x.bytes),
+            x.files,
+            x.package,
+            x.rule
+        );
+        let fields =
+            json!({"package": x.package, "rule": x.rule, "files": x.files, "bytes": x.bytes});
+        message::result(line, "size", fields);
+    }
+    let line = format!(
+        "{:>10} {:>6} {files:>6}  total",
+        message::human_size(total),
+        ""
+    );
+    message::result(
+        line.bold(),
+        "total",
+        json!({"files": files, "bytes": total}),
+    );
+}
+
+fn run(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let bin = match args.bins_only.first() {
+        Some(bin) => bin.clone(),
+        None => cargo_rootfs
+            .get_root_bin()
+            .expect("The root package has no binary to run")
+            .name
+            .clone(),
+    };
+    let program = format!("/usr/bin/{bin}");
+    let status = exec::rootfs_command(
+        &cargo_rootfs.dst,
+        cargo_rootfs.target.as_deref(),
+        args.exec_backend,
+        &program,
+        &args.run_args,
+    )
+    .status()
+    .unwrap_or_else(|e| panic!("Failed to run {program}: {e:?}"));
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn shell(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    if args.dst.is_none() {
+        panic!("shell requires --dest");
+    }
+    let rootfs = &cargo_rootfs.dst;
+    // /bin/sh is often an absolute symbolic link to busybox, resolved inside the rootfs.
+    if rootfs.join("bin/sh").symlink_metadata().is_err() {
+        panic!("{rootfs} has no /bin/sh: install a shell, e.g. busybox, in the rootfs");
+    }
+    let mut cmd = exec::rootfs_command(
+        rootfs,
+        cargo_rootfs.target.as_deref(),
+        args.exec_backend,
+        "/bin/sh",
+        &args.run_args,
+    );
+    cmd.env(
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    )
+    .env("HOME", "/root")
+    .env("PS1", "[rootfs] \\w # ");
+    let status = cmd
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run /bin/sh: {e:?}"));
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn boot(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    if args.dst.is_none() {
+        panic!("boot requires --dest");
+    }
+    let options = boot::BootOptions {
+        kernel: args.kernel.clone().expect("boot requires --kernel"),
+        dtb: match args.dtb.as_slice() {
+            [] => None,
+            [dtb] => Some(dtb.clone()),
+            _ => panic!("boot accepts a single --dtb"),
+        },
+        image: args.boot_image,
+        command: args.boot_cmd.clone(),
+        timeout: std::time::Duration::from_secs(args.boot_timeout.unwrap_or(300)),
+    };
+    let outdir = cargo_rootfs.metadata.target_directory.join("rootfs-boot");
+    let status = boot::boot(
+        &cargo_rootfs.dst,
+        &outdir,
+        cargo_rootfs.target.as_deref(),
+        &options,
+    );
+    if options.command.is_some() {
+        let line = format!("Boot command exited with status {status}");
+        if status == 0 {
+            message::status(line.green().bold());
+        } else {
+            message::status(line.red().bold());
+        }
+    }
+    std::process::exit(status);
+}
+
+fn image(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let filesystem = args.filesystem;
+    let geometry = image::Geometry {
+        peb_size: args.peb_size,
+        leb_size: args.leb_size,
+        min_io_size: args.min_io_size,
+        max_leb_cnt: args.max_leb_cnt,
+    };
+    let verity = args.verity_options();
+    if verity.is_some() && !filesystem.is_block() {
+        panic!("--verity requires a block filesystem image (ext4, squashfs or erofs)");
+    }
+    let output = args
+        .image
+        .clone()
+        .unwrap_or_else(|| format!("rootfs.{}", filesystem.extension()).into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    image::build(&cargo_rootfs.dst, &outdir, filesystem, &geometry, &output);
+    if let Some(options) = &verity {
+        verity::format(&output, options);
+    }
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn disk(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let layout = disk::Layout::load(args.layout.as_deref().unwrap_or("disk.toml".into()));
+    let output = args.disk.clone().unwrap_or("disk.img".into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    let verity = args.verity_options();
+    disk::build(
+        &cargo_rootfs.dst,
+        &outdir,
+        &layout,
+        verity.as_ref(),
+        &output,
+    );
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn fit(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let options = fit::FitOptions {
+        kernel: args.kernel.clone().expect("fit requires --kernel"),
+        dtbs: args.dtb.clone(),
+        image: args.boot_image,
+        load: args.fit_load,
+        entry: args.fit_entry,
+        key_dir: args.fit_key_dir.clone(),
+        key_name: args.fit_key_name.clone().unwrap_or("dev".into()),
+        algo: args.fit_algo.clone().unwrap_or("sha256,rsa2048".into()),
+    };
+    let output = args.fit.clone().unwrap_or("image.itb".into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    let name = &cargo_rootfs.get_root_package().name;
+    fit::build(
+        &cargo_rootfs.dst,
+        &outdir,
+        cargo_rootfs.target.as_deref(),
+        &options,
+        name,
+        &output,
+    );
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn swu(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let config = swupdate::Swupdate::load(&cargo_rootfs.metadata.workspace_metadata);
+    let root = cargo_rootfs.get_root_package();
+    let output = args
+        .swu
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}.swu", root.name, root.version).into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    swupdate::build(
+        &cargo_rootfs.dst,
+        &outdir,
+        &config,
+        &cargo_rootfs.metadata.workspace_root,
+        root,
+        args.verity_options().as_ref(),
+        &output,
+    );
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn rauc(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let config = rauc::Rauc::load(&cargo_rootfs.metadata.workspace_metadata);
+    let signer = rauc::Signer {
+        cert: args.cert.clone().expect("rauc requires --cert"),
+        key: args.key.clone().expect("rauc requires --key"),
+    };
+    let root = cargo_rootfs.get_root_package();
+    let output = args
+        .rauc
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}.raucb", root.name, root.version).into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    rauc::build(
+        &cargo_rootfs.dst,
+        &outdir,
+        &config,
+        &signer,
+        root,
+        args.verity_options().as_ref(),
+        &output,
+    );
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn mender(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let config = mender::Mender::load(&cargo_rootfs.metadata.workspace_metadata);
+    let root = cargo_rootfs.get_root_package();
+    let name = format!("{}-{}", root.name, root.version);
+    let output = args
+        .mender
+        .clone()
+        .unwrap_or_else(|| format!("{name}.mender").into());
+
+    let outdir = stage_image(cargo_rootfs, args);
+    mender::build(
+        &cargo_rootfs.dst,
+        &outdir,
+        &config,
+        args.key.as_deref(),
+        &name,
+        args.verity_options().as_ref(),
+        &output,
+    );
+    cargo_rootfs.check_image_budget(&output);
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output);
+    }
+}
+
+fn ostree(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let repo = args.repo.clone().expect("ostree requires --repo");
+
+    stage_image(cargo_rootfs, args);
+
+    let root = cargo_rootfs.get_root_package();
+    let buildinfo = cargo_rootfs.get_build_info();
+    let branch = args.branch.clone().unwrap_or_else(|| {
+        let target = buildinfo["target"]
+            .as_str()
+            .unwrap_or(std::env::consts::ARCH);
+        format!("{}/{target}", root.name)
+    });
+    let subject = format!("{} {}", root.name, root.version);
+    let checksum = ostree::commit(&cargo_rootfs.dst, &repo, &branch, &subject, &buildinfo);
+    message::status(format_args!("Committed {branch}: {checksum}"));
+}
+
+fn delta(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let from = args.from.as_deref().map(delta::Index::load);
+    let root = cargo_rootfs.get_root_package();
+    let output = args
+        .delta_dir
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}.delta", root.name, root.version).into());
+
+    stage_image(cargo_rootfs, args);
+
+    let version = root.version.to_string();
+    delta::write(
+        &cargo_rootfs.dst,
+        &output,
+        from.as_ref(),
+        &root.name,
+        &version,
+    );
+    if let Some(signer) = &args.sign {
+        signer.sign_output(&output.join(delta::INDEX));
+    }
+}
+
+fn export(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    let format = args.export_format.expect("export requires --format");
+    let root = cargo_rootfs.get_root_package();
+    let output = args
+        .export
+        .clone()
+        .unwrap_or_else(|| format.default_output(&root.name, &root.version.to_string()));
+
+    // systemd-nspawn requires an os-release file.
+    let args = &CargoRootfsArgs {
+        build_info: true,
+        ..args.clone()
+    };
+    let outdir = stage_image(cargo_rootfs, args);
+    export::export(
+        &cargo_rootfs.dst,
+        &outdir,
+        format,
+        &root.name,
+        cargo_rootfs.target.as_deref(),
+        &output,
+    );
+    if let (Some(signer), export::Format::Wsl) = (&args.sign, format) {
+        signer.sign_output(&output);
+    }
+}
+
+fn netboot(cargo_rootfs: &CargoRootfs, args: &CargoRootfsArgs) {
+    install(cargo_rootfs, args);
+    if !cargo_rootfs.dry_run {
+        let export_dir = cargo_rootfs
+            .dst
+            .canonicalize_utf8()
+            .unwrap_or_else(|e| panic!("Failed to resolve {}: {e:?}", cargo_rootfs.dst));
+        netboot::export(&netboot::NetbootOptions {
+            export_dir,
+            tftp_dir: args.tftp_dir.clone().unwrap_or("/srv/tftp".into()),
+            kernel: args.kernel.clone(),
+            dtbs: args.dtb.clone(),
+            server: args.server.clone(),
+        });
+    }
+}
+
+fn watch(args: &CargoRootfsArgs) {
+    let mut watched = None;
+    loop {
+        let result = std::panic::catch_unwind(|| {
+            let cargo_rootfs = CargoRootfs::new(args);
+            let watched = (
+                cargo_rootfs.get_local_dirs(),
+                cargo_rootfs.metadata.target_directory.clone(),
+            );
+            if !args.build() {
+                eprintln!("{}", "Build failed".red().bold());
+            } else if args.is_deploying() {
+                deploy(&cargo_rootfs, args);
+            } else {
+                install(&cargo_rootfs, args);
+            }
+            watched
+        });
+        if let Ok(x) = result {
+            watched = Some(x);
+        }
+        let (dirs, target_dir) = watched.as_ref().expect("Nothing to watch");
+        message::status("Watching for changes...".green().bold());
+        watch::wait_for_change(dirs, target_dir);
+    }
+}
+
+fn main() {
+    let mut args = CargoRootfsArgs::default();
+    message::set_color(args.color);
+    args.parse();
+    args.profile();
+    args.defaults(config::load());
+    message::init(
+        args.message_format.unwrap_or_default(),
+        message::Verbosity::new(args.quiet, args.verbose),
+    );
+
+    if args.command == Command::Watch {
+        return watch(&args);
+    }
+    if args.command == Command::Verify {
+        return verify(&args);
+    }
+    if args.command == Command::Audit {
+        return audit(&args);
+    }
+    if args.command == Command::Size {
+        return size(&args);
+    }
+    if args.command == Command::Apply {
+        return apply(&args);
+    }
+    if !args.rootfs_dsts.is_empty() {
+        return install_all(&args);
+    }
+
+    let cargo_rootfs = CargoRootfs::new(&args);
+
+    match args.command {
+        Command::Deploy => deploy(&cargo_rootfs, &args),
+        Command::Run => run(&cargo_rootfs, &args),
+        Command::Shell => shell(&cargo_rootfs, &args),
+        Command::Boot => boot(&cargo_rootfs, &args),
+        Command::Image => image(&cargo_rootfs, &args),
+        Command::Disk => disk(&cargo_rootfs, &args),
+        Command::Fit => fit(&cargo_rootfs, &args),
+        Command::Swu => swu(&cargo_rootfs, &args),
+        Command::Rauc => rauc(&cargo_rootfs, &args),
+        Command::Mender => mender(&cargo_rootfs, &args),
+        Command::Ostree => ostree(&cargo_rootfs, &args),
+        Command::Delta => delta(&cargo_rootfs, &args),
+        Command::Export => export(&cargo_rootfs, &args),
+        Command::Netboot => netboot(&cargo_rootfs, &args),
+        Command::Uninstall => uninstall(&cargo_rootfs, &args),
+        Command::Info => info(&cargo_rootfs),
+        Command::Check => check(&cargo_rootfs),
+        Command::Plan => plan(&cargo_rootfs, &args),
+        _ => install(&cargo_rootfs, &args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty rootfs in the temporary directory.
+    fn rootfs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cargo-rootfs-{}-{name}", std::process::id()));
+        let dir = PathBuf::from_path_buf(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("etc")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolve_absolute_symlink() {
+        let rootfs = rootfs("absolute");
+        symlink("/tmp/outside.txt", rootfs.join("etc/x.conf")).unwrap();
+        let resolved = resolve_destination(&rootfs, &rootfs.join("etc/x.conf"));
+        assert_eq!(resolved, Ok(rootfs.join("tmp/outside.txt")));
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn resolve_relative_symlink() {
+        let rootfs = rootfs("relative");
+        std::fs::create_dir(rootfs.join("run")).unwrap();
+        symlink("../run/x.conf", rootfs.join("etc/x.conf")).unwrap();
+        let resolved = resolve_destination(&rootfs, &rootfs.join("etc/x.conf"));
+        assert_eq!(resolved, Ok(rootfs.join("run/x.conf")));
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn refuse_symlink_above_rootfs() {
+        let rootfs = rootfs("above");
+        symlink("../../outside.txt", rootfs.join("etc/x.conf")).unwrap();
+        let resolved = resolve_destination(&rootfs, &rootfs.join("etc/x.conf"));
+        assert!(resolved.is_err());
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn refuse_symlink_loop() {
+        let rootfs = rootfs("loop");
+        symlink("y.conf", rootfs.join("etc/x.conf")).unwrap();
+        symlink("x.conf", rootfs.join("etc/y.conf")).unwrap();
+        let resolved = resolve_destination(&rootfs, &rootfs.join("etc/x.conf"));
+        assert!(resolved.is_err());
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
 }
@@ -0,0 +1,392 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+/// Directory of the installed-files manifests, relative to the rootfs.
+pub const MANIFEST_DIR: &str = "var/lib/cargo-rootfs";
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Symlink,
+    Dir,
+}
+
+/// A file, symbolic link or directory installed in the rootfs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Absolute path in the rootfs image.
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    /// Package which provided the entry, as `name@version`.
+    pub package: String,
+    /// Rule which installed the entry.
+    pub rule: String,
+}
+
+/// A source of a rule replaced by a file of an alternative source directory (--altsrc).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Override {
+    /// Package of the rule, as `name@version`.
+    pub package: String,
+    pub rule: String,
+    pub source: PathBuf,
+    /// The file or directory installed instead, on the host.
+    pub altsrc: PathBuf,
+}
+
+/// A path of the base image (--base) hidden by a whiteout of the rootfs layer: removed by
+/// a rule, or an opaque directory whose content is replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Whiteout {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub opaque: bool,
+}
+
+/// The files installed in a rootfs for a root crate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub package: String,
+    pub version: String,
+    pub files: Vec<Entry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub altsrc: Vec<Override>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub whiteouts: Vec<Whiteout>,
+}
+
+pub fn sha256sum(file: &Path) -> String {
+    let data = std::fs::read(file).unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+    crate::hex(&Sha256::digest(&data))
+}
+
+/// Return the path of the manifest of a root crate in the rootfs.
+pub fn path(rootfs: &Path, package: &str) -> PathBuf {
+    rootfs.join(MANIFEST_DIR).join(format!("{package}.json"))
+}
+
+impl Entry {
+    /// Describe an installed path, reading its mode, owner and content from the rootfs.
+    pub fn new(rootfs: &Path, path: &Path, kind: Kind, package: String, rule: String) -> Self {
+        let file = rootfs.join(path.strip_prefix("/").unwrap_or(path));
+        let metadata = file
+            .symlink_metadata()
+            .unwrap_or_else(|e| panic!("Failed to stat {file}: {e:?}"));
+        let (size, sha256) = match kind {
+            Kind::File => (Some(metadata.len()), Some(sha256sum(&file))),
+            _ => (None, None),
+        };
+        let target = match kind {
+            Kind::Symlink => Some(PathBuf::from_path_buf(file.read_link().unwrap()).unwrap()),
+            _ => None,
+        };
+        Self {
+            path: path.to_path_buf(),
+            kind,
+            mode: format!("{:04o}", metadata.permissions().mode() & 0o7777),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size,
+            sha256,
+            target,
+            package,
+            rule,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = std::fs::read_to_string(path).ok()?;
+        let manifest = serde_json::from_str(&data)
+            .unwrap_or_else(|e| panic!("Failed to parse manifest {path}: {e:?}"));
+        Some(manifest)
+    }
+
+    pub fn save(&self, path: &Path) {
+        message::status(format_args!("Writing manifest to {:#?}", path));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let data = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, data).unwrap_or_else(|e| panic!("Failed to write {path}: {e:?}"));
+    }
+}
+
+/// Remove the entries from the rootfs: files and symbolic links first, then the
+/// directories which are left empty.
+pub fn remove_entries(rootfs: &Path, entries: &[&Entry]) {
+    let host_path = |entry: &Entry| {
+        let path = entry.path.strip_prefix("/").unwrap_or(&entry.path);
+        rootfs.join(path)
+    };
+
+    for entry in entries {
+        if entry.kind == Kind::Dir {
+            continue;
+        }
+        let path = host_path(entry);
+        if path.symlink_metadata().is_ok() {
+            message::operation(
+                format_args!("rm -f {:#?}", path),
+                "remove",
+                serde_json::json!({"path": path}),
+            );
+            std::fs::remove_file(&path)
+                .unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+        }
+    }
+
+    let mut dirs: Vec<&&Entry> = entries.iter().filter(|e| e.kind == Kind::Dir).collect();
+    dirs.sort_by_key(|entry| std::cmp::Reverse(entry.path.components().count()));
+    for entry in dirs {
+        let path = host_path(entry);
+        let is_empty = path
+            .read_dir_utf8()
+            .map(|mut dir| dir.next().is_none())
+            .unwrap_or(false);
+        if is_empty {
+            message::status(format_args!("rmdir {:#?}", path));
+            let _ = std::fs::remove_dir(&path);
+        }
+    }
+}
+
+/// Load the manifests of all the root crates installed in the rootfs.
+pub fn load_all(rootfs: &Path) -> Vec<Manifest> {
+    let mut manifests = vec![];
+    if let Ok(entries) = rootfs.join(MANIFEST_DIR).read_dir_utf8() {
+        for entry in entries.flatten() {
+            if entry.path().extension() != Some("json") {
+                continue;
+            }
+            if let Some(manifest) = Manifest::load(entry.path()) {
+                manifests.push(manifest);
+            }
+        }
+    }
+    manifests.sort_by(|a, b| a.package.cmp(&b.package));
+    manifests
+}
+
+/// The size of the files installed by a rule of a package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub package: String,
+    pub rule: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Attribute the size of the installed files to the package rules, biggest first.
+/// A file recorded by several manifests is only counted once.
+pub fn usage(manifests: &[Manifest]) -> Vec<Usage> {
+    let mut seen: Vec<&Path> = vec![];
+    let mut usage: Vec<Usage> = vec![];
+    for entry in manifests.iter().flat_map(|manifest| manifest.files.iter()) {
+        if entry.kind != Kind::File || seen.contains(&entry.path.as_path()) {
+            continue;
+        }
+        seen.push(&entry.path);
+        let index = usage
+            .iter()
+            .position(|x| x.package == entry.package && x.rule == entry.rule)
+            .unwrap_or_else(|| {
+                usage.push(Usage {
+                    package: entry.package.clone(),
+                    rule: entry.rule.clone(),
+                    files: 0,
+                    bytes: 0,
+                });
+                usage.len() - 1
+            });
+        usage[index].files += 1;
+        usage[index].bytes += entry.size.unwrap_or(0);
+    }
+    usage.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.package.cmp(&b.package)));
+    usage
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    Missing,
+    Modified(String),
+    Extra,
+}
+
+fn check_entry(rootfs: &Path, entry: &Entry) -> Option<Issue> {
+    let file = rootfs.join(entry.path.strip_prefix("/").unwrap_or(&entry.path));
+    let metadata = match file.symlink_metadata() {
+        Ok(x) => x,
+        Err(_) => return Some(Issue::Missing),
+    };
+    let kind = if metadata.is_symlink() {
+        Kind::Symlink
+    } else if metadata.is_dir() {
+        Kind::Dir
+    } else {
+        Kind::File
+    };
+    if kind != entry.kind {
+        return Some(Issue::Modified(format!(
+            "{:?} replaced by {kind:?}",
+            entry.kind
+        )));
+    }
+
+    let mut changes = vec![];
+    if kind != Kind::Symlink {
+        let mode = format!("{:04o}", metadata.permissions().mode() & 0o7777);
+        if mode != entry.mode {
+            changes.push(format!("mode {} -> {mode}", entry.mode));
+        }
+    }
+    if metadata.uid() != entry.uid || metadata.gid() != entry.gid {
+        changes.push(format!(
+            "owner {}:{} -> {}:{}",
+            entry.uid,
+            entry.gid,
+            metadata.uid(),
+            metadata.gid()
+        ));
+    }
+    if kind == Kind::File && Some(sha256sum(&file)) != entry.sha256 {
+        changes.push("content".into());
+    }
+    if kind == Kind::Symlink {
+        let target = file
+            .read_link()
+            .ok()
+            .and_then(|x| PathBuf::from_path_buf(x).ok());
+        if target != entry.target {
+            changes.push(format!("target {:?} -> {target:?}", entry.target));
+        }
+    }
+    if changes.is_empty() {
+        None
+    } else {
+        Some(Issue::Modified(changes.join(", ")))
+    }
+}
+
+/// Compare the rootfs with the manifests.
+///
+/// Extra files are only searched in the directories recorded in the manifests.
+pub fn verify(rootfs: &Path, manifests: &[Manifest]) -> Vec<(PathBuf, Issue)> {
+    let mut issues = vec![];
+    let entries: Vec<&Entry> = manifests.iter().flat_map(|m| m.files.iter()).collect();
+    let is_known = |path: &Path| entries.iter().any(|entry| entry.path == path);
+
+    for entry in &entries {
+        if let Some(issue) = check_entry(rootfs, entry) {
+            issues.push((entry.path.clone(), issue));
+        }
+    }
+
+    for dir in entries.iter().filter(|entry| entry.kind == Kind::Dir) {
+        let host_dir = rootfs.join(dir.path.strip_prefix("/").unwrap_or(&dir.path));
+        let children = match host_dir.read_dir_utf8() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for child in children.flatten() {
+            let path = dir.path.join(child.file_name());
+            let is_whiteout = crate::whiteout::is_whiteout(&host_dir.join(child.file_name()));
+            if !is_known(&path) && !is_whiteout && path != Path::new("/").join(MANIFEST_DIR) {
+                issues.push((path, Issue::Extra));
+            }
+        }
+    }
+    issues.sort_by(|a, b| a.0.cmp(&b.0));
+    issues.dedup();
+    issues
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Mtree,
+    Sha256,
+}
+
+/// Parse a `--manifest` value formatted as `<mtree|sha256>:<FILE>`.
+pub fn parse_format(spec: &str) -> (Format, PathBuf) {
+    let (format, path) = spec
+        .split_once(':')
+        .unwrap_or_else(|| panic!("--manifest {spec} must be formatted as <mtree|sha256>:<FILE>"));
+    let format = match format {
+        "mtree" => Format::Mtree,
+        "sha256" => Format::Sha256,
+        other => panic!("Unknown manifest format {other:?} (expected mtree or sha256)"),
+    };
+    (format, PathBuf::from(path))
+}
+
+/// Encode a path as mtree(5) does, with octal escapes for whitespace and special characters.
+fn mtree_escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' && byte != b'#' {
+            escaped.push(byte as char);
+        } else {
+            escaped += &format!("\\{byte:03o}");
+        }
+    }
+    escaped
+}
+
+fn mtree(manifest: &Manifest) -> String {
+    let mut mtree = "#mtree\n".to_string();
+    for entry in &manifest.files {
+        let path = mtree_escape(&format!(".{}", entry.path));
+        let common = format!("uid={} gid={} mode={}", entry.uid, entry.gid, entry.mode);
+        let line = match entry.kind {
+            Kind::Dir => format!("{path} type=dir {common}"),
+            Kind::Symlink => {
+                let target = entry.target.as_ref().map(|x| x.as_str()).unwrap_or("");
+                format!("{path} type=link {common} link={}", mtree_escape(target))
+            }
+            Kind::File => format!(
+                "{path} type=file {common} size={} sha256digest={}",
+                entry.size.unwrap_or(0),
+                entry.sha256.as_deref().unwrap_or("")
+            ),
+        };
+        mtree += &line;
+        mtree += "\n";
+    }
+    mtree
+}
+
+fn sha256sums(manifest: &Manifest) -> String {
+    let mut sums = String::new();
+    for entry in &manifest.files {
+        if let Some(sha256) = &entry.sha256 {
+            let path = entry.path.strip_prefix("/").unwrap_or(&entry.path);
+            sums += &format!("{sha256}  {path}\n");
+        }
+    }
+    sums
+}
+
+/// Write a description of the installed files as mtree or sha256sum file.
+pub fn write_format(manifest: &Manifest, format: Format, output: &Path) {
+    let data = match format {
+        Format::Mtree => mtree(manifest),
+        Format::Sha256 => sha256sums(manifest),
+    };
+    message::status(format_args!("Writing {format:?} manifest to {:#?}", output));
+    std::fs::write(output, data).unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+}
@@ -0,0 +1,124 @@
+use crate::boot;
+use crate::message;
+use crate::verity;
+use camino::Utf8Path as Path;
+use serde::Deserialize;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+/// The states of the Mender client whose scripts are shipped in the artifacts.
+const ARTIFACT_STATES: &[&str] = &[
+    "ArtifactInstall",
+    "ArtifactReboot",
+    "ArtifactCommit",
+    "ArtifactRollback",
+    "ArtifactRollbackReboot",
+    "ArtifactFailure",
+];
+
+/// The `[workspace.metadata.rootfs.mender]` table.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mender {
+    /// Name of the artifact (default: `<NAME>-<VERSION>` of the root package).
+    pub artifact_name: Option<String>,
+    /// Device types compatible with the artifact.
+    #[serde(default)]
+    pub device_types: Vec<String>,
+    /// Commands of the state scripts, by script name, e.g. `ArtifactCommit_Enter_50`.
+    #[serde(default)]
+    pub state_scripts: BTreeMap<String, String>,
+}
+
+impl Mender {
+    pub fn load(workspace_metadata: &Value) -> Self {
+        match &workspace_metadata["rootfs"]["mender"] {
+            Value::Null => Self::default(),
+            table => serde_json::from_value(table.clone()).unwrap_or_else(|e| {
+                panic!(
+                    "workspace.metadata.rootfs.mender: {}",
+                    crate::did_you_mean(&e.to_string())
+                )
+            }),
+        }
+    }
+}
+
+/// Check the name of a state script: `<STATE>_<Enter|Leave|Error>_<NN>[_<DESCRIPTION>]`.
+fn check_script_name(name: &str) {
+    let mut parts = name.splitn(4, '_');
+    let state = parts.next().unwrap_or_default();
+    let action = parts.next().unwrap_or_default();
+    let order = parts.next().unwrap_or_default();
+    let valid = ARTIFACT_STATES.contains(&state)
+        && ["Enter", "Leave", "Error"].contains(&action)
+        && order.len() == 2
+        && order.chars().all(|c| c.is_ascii_digit());
+    if !valid {
+        panic!(
+            "workspace.metadata.rootfs.mender.state_scripts: invalid script name {name:?} \
+             (expected <STATE>_<Enter|Leave|Error>_<NN>, with STATE one of {})",
+            ARTIFACT_STATES.join(", ")
+        );
+    }
+}
+
+/// Build a Mender artifact of the ext4 image of the rootfs with `mender-artifact write
+/// rootfs-image`, with the state scripts generated from their commands, signed when a
+/// key is specified.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    config: &Mender,
+    key: Option<&str>,
+    name: &str,
+    verity: Option<&verity::VerityOptions>,
+    output: &Path,
+) {
+    if config.device_types.is_empty() {
+        panic!("workspace.metadata.rootfs.mender.device_types is required");
+    }
+    for script in config.state_scripts.keys() {
+        check_script_name(script);
+    }
+    std::fs::create_dir_all(outdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+    let image = boot::make_ext4(rootfs, outdir, None);
+    if let Some(options) = verity {
+        verity::format(&image, options);
+    }
+
+    let scripts = outdir.join("scripts");
+    let _ = std::fs::remove_dir_all(&scripts);
+    std::fs::create_dir_all(&scripts)
+        .unwrap_or_else(|e| panic!("Failed to create directory {scripts}: {e:?}"));
+
+    let artifact_name = config.artifact_name.as_deref().unwrap_or(name);
+    let mender_artifact = std::env::var("MENDER_ARTIFACT").unwrap_or("mender-artifact".into());
+    let mut cmd = Command::new(&mender_artifact);
+    cmd.args(["write", "rootfs-image", "--artifact-name", artifact_name]);
+    for device_type in &config.device_types {
+        cmd.arg("--device-type").arg(device_type);
+    }
+    for (script, command) in &config.state_scripts {
+        let file = scripts.join(script);
+        std::fs::write(&file, format!("#!/bin/sh\nset -e\n{command}\n"))
+            .unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+        std::fs::set_permissions(&file, Permissions::from_mode(0o755)).unwrap();
+        cmd.arg("--script").arg(file);
+    }
+    if let Some(key) = key {
+        cmd.arg("--key").arg(key);
+    }
+    cmd.arg("--file")
+        .arg(&image)
+        .arg("--output-path")
+        .arg(output);
+    message::status(format_args!(
+        "{mender_artifact} write rootfs-image --artifact-name {artifact_name} --file {image} --output-path {output}"
+    ));
+    crate::exec::run(&mut cmd);
+}
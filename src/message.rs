@@ -0,0 +1,271 @@
+use serde_json::value::Value;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    #[default]
+    Human,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "human" => Self::Human,
+            "json" => Self::Json,
+            other => panic!("Unknown message format {other:?} (expected human or json)"),
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "auto" => Self::Auto,
+            "always" => Self::Always,
+            "never" => Self::Never,
+            other => panic!("Unknown color mode {other:?} (expected auto, always or never)"),
+        }
+    }
+}
+
+/// Enable or disable the colored output. With `auto`, colors are only used on a
+/// terminal, and when NO_COLOR is not set.
+pub fn set_color(color: Color) {
+    let enabled = match color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => {
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+            !no_color && std::io::stdout().is_terminal()
+        }
+    };
+    colored::control::set_override(enabled);
+}
+
+/// Amount of human readable output.
+#[derive(Default, Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Errors only.
+    Quiet,
+    /// Summary and one line per rule.
+    #[default]
+    Normal,
+    /// One line per installed file (-v).
+    Verbose,
+    /// Skipped and filtered items too (-vv).
+    Debug,
+}
+
+impl Verbosity {
+    pub fn new(quiet: bool, verbose: u32) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Debug,
+        }
+    }
+}
+
+fn enabled(verbosity: Verbosity) -> bool {
+    !is_json() && verbosity as u8 <= VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Select the output format and verbosity, reporting the panics as error events in JSON.
+pub fn init(format: Format, verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+    if format != Format::Json {
+        return;
+    }
+    JSON.store(true, Ordering::Relaxed);
+    std::panic::set_hook(Box::new(|info| {
+        let message = panic_message(info.payload());
+        emit("error", serde_json::json!({"message": message}));
+    }));
+}
+
+/// Return the message of a panic.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "panic".into()),
+    }
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print a JSON event on a single line, when JSON messages are requested.
+pub fn emit(event: &str, fields: Value) {
+    if !is_json() {
+        return;
+    }
+    let mut object = serde_json::json!({"event": event});
+    if let Value::Object(fields) = fields {
+        object.as_object_mut().unwrap().extend(fields);
+    }
+    println!("{object}");
+}
+
+/// Report a file operation: as a shell-like line for humans with -v, or as a JSON event.
+pub fn operation(line: impl Display, event: &str, fields: Value) {
+    emit(event, fields);
+    if enabled(Verbosity::Verbose) {
+        println!("{line}");
+    }
+}
+
+/// Report the result of a command: as a line for humans, or as a JSON event.
+pub fn result(line: impl Display, event: &str, fields: Value) {
+    emit(event, fields);
+    if enabled(Verbosity::Normal) {
+        println!("{line}");
+    }
+}
+
+/// Print a line for humans, omitted with --quiet or JSON messages.
+pub fn status(line: impl Display) {
+    if enabled(Verbosity::Normal) {
+        println!("{line}");
+    }
+}
+
+/// Print a line for humans with -v.
+pub fn detail(line: impl Display) {
+    if enabled(Verbosity::Verbose) {
+        println!("{line}");
+    }
+}
+
+/// Print a skipped or filtered item for humans with -vv.
+pub fn debug(line: impl Display) {
+    if enabled(Verbosity::Debug) {
+        println!("{line}");
+    }
+}
+
+/// Format a size in bytes with a binary unit, e.g. 1.5 MiB.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Progress of a long operation, refreshed on stderr on a terminal, or logged
+/// periodically otherwise. Nothing is shown for operations completing quickly.
+pub struct Progress {
+    label: String,
+    total_files: u64,
+    total_bytes: u64,
+    files: u64,
+    bytes: u64,
+    started: Instant,
+    shown: Option<Instant>,
+    terminal: bool,
+}
+
+impl Progress {
+    const DELAY: Duration = Duration::from_millis(500);
+    const REFRESH: Duration = Duration::from_millis(100);
+    const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(label: impl Display, total_files: u64, total_bytes: u64) -> Self {
+        Self {
+            label: label.to_string(),
+            total_files,
+            total_bytes,
+            files: 0,
+            bytes: 0,
+            started: Instant::now(),
+            shown: None,
+            terminal: std::io::stderr().is_terminal(),
+        }
+    }
+
+    pub fn add(&mut self, files: u64, bytes: u64) {
+        self.files += files;
+        self.bytes += bytes;
+        if !enabled(Verbosity::Normal) || enabled(Verbosity::Verbose) {
+            return;
+        }
+        let now = Instant::now();
+        let interval = if self.terminal {
+            Self::REFRESH
+        } else {
+            Self::LOG_INTERVAL
+        };
+        let due = match self.shown {
+            Some(shown) => now - shown >= interval,
+            None => now - self.started >= Self::DELAY,
+        };
+        if !due {
+            return;
+        }
+        self.shown = Some(now);
+
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let mut line = format!(
+            "{}: {}/{} files, {:.1}/{:.1} MiB",
+            self.label,
+            self.files,
+            self.total_files,
+            mib(self.bytes),
+            mib(self.total_bytes)
+        );
+        let elapsed = (now - self.started).as_secs_f64();
+        let (done, total) = if self.total_bytes > 0 {
+            (self.bytes, self.total_bytes)
+        } else {
+            (self.files, self.total_files)
+        };
+        if done > 0 && total >= done {
+            let eta = elapsed * (total - done) as f64 / done as f64;
+            line += &format!(", ETA {eta:.0}s");
+        }
+        if self.terminal {
+            eprint!("\r\x1b[K{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    }
+
+    /// Clear the progress line.
+    pub fn finish(&mut self) {
+        if self.terminal && self.shown.take().is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
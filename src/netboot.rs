@@ -0,0 +1,146 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use colored::Colorize;
+
+/// The kernel images looked for in /boot, with the U-Boot command booting them.
+const KERNELS: [(&str, &str); 5] = [
+    ("Image", "booti"),
+    ("zImage", "bootz"),
+    ("uImage", "bootm"),
+    ("bzImage", "zboot"),
+    ("vmlinuz", "zboot"),
+];
+
+/// The fstab entry of the NFS root filesystem, mounted by the kernel.
+const NFS_ROOT: &str = "/dev/nfs / nfs defaults,noatime 0 0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetbootOptions {
+    /// The NFS exported directory of the rootfs.
+    pub export_dir: PathBuf,
+    pub tftp_dir: PathBuf,
+    /// Kernel and device trees (default: the ones of /boot in the rootfs).
+    pub kernel: Option<PathBuf>,
+    pub dtbs: Vec<PathBuf>,
+    /// Address of the NFS and TFTP server (default: the address of this host).
+    pub server: Option<String>,
+}
+
+/// Return the fstab with its root filesystem entry replaced by the NFS root, or None when
+/// it has no root entry.
+pub fn nfs_fstab(fstab: &str) -> Option<String> {
+    let mut replaced = false;
+    let mut content = String::new();
+    for line in fstab.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !line.trim_start().starts_with('#') && fields.get(1) == Some(&"/") {
+            if fields[0] != "/dev/nfs" {
+                content += &format!("# {line}\n");
+            }
+            if !replaced {
+                content += NFS_ROOT;
+                content.push('\n');
+            }
+            replaced = true;
+        } else {
+            content += line;
+            content.push('\n');
+        }
+    }
+    replaced.then_some(content)
+}
+
+/// Return the address of the interface of this host routing to the network.
+fn host_address() -> Option<String> {
+    // Connecting a UDP socket selects the source address without sending anything.
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+fn copy(src: &Path, dir: &Path) -> String {
+    let name = src
+        .file_name()
+        .unwrap_or_else(|| panic!("{src} is not a file"));
+    let dst = dir.join(name);
+    message::status(format_args!("cp {src} {dst}"));
+    std::fs::copy(src, &dst).unwrap_or_else(|e| panic!("Failed to copy {src} to {dst}: {e:?}"));
+    name.to_string()
+}
+
+/// Copy the kernel and the device trees in the TFTP directory, and print the server
+/// configuration and the boot commands of the board.
+pub fn export(options: &NetbootOptions) {
+    let rootfs = &options.export_dir;
+    let boot = rootfs.join("boot");
+    let kernel = options.kernel.clone().or_else(|| {
+        KERNELS
+            .iter()
+            .map(|(name, _)| boot.join(name))
+            .find(|file| file.is_file())
+    });
+    let mut dtbs = options.dtbs.clone();
+    if dtbs.is_empty() && options.kernel.is_none() {
+        if let Ok(entries) = boot.read_dir_utf8() {
+            let mut found: Vec<PathBuf> = entries
+                .flatten()
+                .map(|x| x.into_path())
+                .filter(|x| x.extension() == Some("dtb"))
+                .collect();
+            found.sort();
+            dtbs = found;
+        }
+    }
+
+    let server = options
+        .server
+        .clone()
+        .or_else(host_address)
+        .unwrap_or("<SERVER>".into());
+    let bootargs = format!("root=/dev/nfs nfsroot={server}:{rootfs},v3,tcp rw ip=dhcp");
+    let mut commands = vec![format!("setenv serverip {server}")];
+    match &kernel {
+        Some(kernel) => {
+            std::fs::create_dir_all(&options.tftp_dir).unwrap_or_else(|e| {
+                panic!("Failed to create directory {}: {e:?}", options.tftp_dir)
+            });
+            let name = copy(kernel, &options.tftp_dir);
+            let dtbs: Vec<String> = dtbs.iter().map(|x| copy(x, &options.tftp_dir)).collect();
+            commands.push(format!("tftp ${{kernel_addr_r}} {name}"));
+            let fdt = match dtbs.first() {
+                Some(dtb) => {
+                    commands.push(format!("tftp ${{fdt_addr_r}} {dtb}"));
+                    "${fdt_addr_r}"
+                }
+                None => "${fdtcontroladdr}",
+            };
+            commands.push(format!("setenv bootargs {bootargs}"));
+            let bootcmd = KERNELS
+                .iter()
+                .find(|(x, _)| name.starts_with(x))
+                .map(|(_, bootcmd)| *bootcmd)
+                .unwrap_or("booti");
+            commands.push(format!("{bootcmd} ${{kernel_addr_r}} - {fdt}"));
+        }
+        None => {
+            message::status(format_args!(
+                "{} No kernel in --kernel or {boot}: only the rootfs is exported",
+                "Warning:".yellow().bold()
+            ));
+            commands.push(format!("setenv bootargs {bootargs}"));
+        }
+    }
+
+    println!();
+    println!("{}", "/etc/exports:".green().bold());
+    println!("{rootfs} *(rw,sync,no_root_squash,no_subtree_check)");
+    println!();
+    println!("{}", "Kernel command line:".green().bold());
+    println!("{bootargs}");
+    println!();
+    println!("{}", "U-Boot:".green().bold());
+    for command in commands {
+        println!("{command}");
+    }
+}
@@ -0,0 +1,82 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use serde_json::value::Value;
+use std::process::Command;
+
+fn ostree() -> String {
+    std::env::var("OSTREE").unwrap_or("ostree".into())
+}
+
+/// Return the metadata of a commit from the build information: `version`, the OSTree
+/// standard key, and `cargo-rootfs.<KEY>` strings for the others.
+fn metadata(buildinfo: &Value) -> Vec<(String, String)> {
+    let mut metadata = vec![];
+    let Value::Object(buildinfo) = buildinfo else {
+        return metadata;
+    };
+    for (key, value) in buildinfo {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(value) => value.clone(),
+            Value::Array(values) => {
+                let values: Vec<&str> = values.iter().filter_map(|x| x.as_str()).collect();
+                values.join(",")
+            }
+            value => value.to_string(),
+        };
+        let key = match key.as_str() {
+            "version" => key.clone(),
+            key => format!("cargo-rootfs.{}", key.replace('_', "-")),
+        };
+        metadata.push((key, value));
+    }
+    metadata
+}
+
+/// Commit the rootfs in a branch of an OSTree repository, creating an archive repository
+/// if it does not exist, and return the checksum of the commit.
+pub fn commit(
+    rootfs: &Path,
+    repo: &Path,
+    branch: &str,
+    subject: &str,
+    buildinfo: &Value,
+) -> String {
+    let ostree = ostree();
+    if !repo.join("config").is_file() {
+        message::status(format_args!("{ostree} --repo={repo} init --mode=archive"));
+        crate::exec::run(
+            Command::new(&ostree)
+                .arg(format!("--repo={repo}"))
+                .args(["init", "--mode=archive"]),
+        );
+    }
+
+    let mut cmd = Command::new(&ostree);
+    cmd.arg(format!("--repo={repo}"))
+        .arg("commit")
+        .arg(format!("--branch={branch}"))
+        .arg(format!("--tree=dir={rootfs}"))
+        .arg(format!("--subject={subject}"))
+        .args(["--owner-uid=0", "--owner-gid=0"]);
+    if std::env::var_os("SOURCE_DATE_EPOCH").is_some() {
+        cmd.arg(format!("--timestamp={}", crate::sbom::timestamp()));
+    }
+    for (key, value) in metadata(buildinfo) {
+        cmd.arg(format!("--add-metadata-string={key}={value}"));
+    }
+    message::status(format_args!(
+        "{ostree} --repo={repo} commit --branch={branch} --tree=dir={rootfs}"
+    ));
+    let output = cmd
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run {ostree}: {e:?}"));
+    if !output.status.success() {
+        panic!(
+            "{ostree} commit failed: {}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
@@ -0,0 +1,829 @@
+use crate::exec::run;
+use crate::journal::Journal;
+use crate::{message, shell_quote};
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+
+/// An edit of the lines of a file, applied to its current content: replaying it on a rootfs
+/// keeps the other lines of the file, and applying it twice does not duplicate them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "edit", rename_all = "kebab-case")]
+pub enum Edit {
+    /// Append a line unless present, or replace the line starting with a prefix.
+    EnsureLine {
+        line: String,
+        replace: Option<String>,
+    },
+    /// Write lines between marker lines, replacing the previous block.
+    AppendBlock {
+        begin: String,
+        end: String,
+        lines: Vec<String>,
+    },
+    /// Append the entry of a user or a group to /etc/passwd or /etc/group, unless its name
+    /// is already there.
+    AddUser { name: String, entry: String },
+}
+
+impl Edit {
+    pub fn apply(&self, lines: &mut Vec<String>) {
+        match self {
+            Edit::EnsureLine { line, replace } => {
+                let replaced = replace
+                    .as_ref()
+                    .and_then(|prefix| lines.iter().position(|x| x.starts_with(prefix.as_str())));
+                match replaced {
+                    Some(index) => lines[index] = line.clone(),
+                    None if !lines.iter().any(|x| x == line) => lines.push(line.clone()),
+                    None => {}
+                }
+            }
+            Edit::AppendBlock {
+                begin,
+                end,
+                lines: block,
+            } => {
+                let mut new = vec![begin.clone()];
+                new.extend(block.iter().cloned());
+                new.push(end.clone());
+                let start = lines.iter().position(|x| x == begin);
+                let stop = lines.iter().position(|x| x == end);
+                match (start, stop) {
+                    (Some(start), Some(stop)) if start < stop => {
+                        lines.splice(start..=stop, new);
+                    }
+                    _ => lines.extend(new),
+                }
+            }
+            Edit::AddUser { name, entry } => {
+                let prefix = format!("{name}:");
+                if !lines.iter().any(|x| x.starts_with(&prefix)) {
+                    lines.push(entry.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Apply edits to the content of a file, returning None when it is unchanged.
+pub fn edit_content(old: &str, edits: &[Edit]) -> Option<String> {
+    let mut lines: Vec<String> = old.lines().map(|x| x.to_string()).collect();
+    for edit in edits {
+        edit.apply(&mut lines);
+    }
+    let content = lines.join("\n") + "\n";
+    (content != old).then_some(content)
+}
+
+/// An operation of an install. The paths of the rootfs are absolute paths of the image,
+/// the other ones (sources, patches, backups out of the rootfs) are paths of the build
+/// machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Comment {
+        text: String,
+    },
+    Mkdir {
+        path: PathBuf,
+        owner: Option<(u32, u32)>,
+    },
+    Copy {
+        src: PathBuf,
+        dst: PathBuf,
+        /// Octal mode, e.g. "0755". The mode of the source is kept by default.
+        mode: Option<String>,
+    },
+    /// A generated file, with its whole content: the text, or the hexadecimal digits of the
+    /// binary files.
+    Write {
+        path: PathBuf,
+        mode: String,
+        content: String,
+        #[serde(default)]
+        hex: bool,
+    },
+    Symlink {
+        target: PathBuf,
+        link: PathBuf,
+    },
+    Hardlink {
+        original: PathBuf,
+        link: PathBuf,
+    },
+    Remove {
+        path: PathBuf,
+        dir: bool,
+    },
+    Edit {
+        path: PathBuf,
+        #[serde(flatten)]
+        edit: Edit,
+    },
+    Backup {
+        path: PathBuf,
+        backup: PathBuf,
+        /// Whether the backup is a path of the rootfs, e.g. with a --backup suffix.
+        #[serde(default)]
+        in_rootfs: bool,
+    },
+    Patch {
+        path: PathBuf,
+        patch: PathBuf,
+    },
+    /// A program run on a path of the rootfs, e.g. strip or ldconfig.
+    Run {
+        program: String,
+        args: Vec<String>,
+        path: PathBuf,
+    },
+    Hook {
+        command: String,
+        dir: PathBuf,
+        run_in_rootfs: bool,
+    },
+}
+
+/// The operations of an install, recorded for `cargo rootfs plan` and --emit-script,
+/// and replayed by `cargo rootfs apply` or by the generated shell script.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub package: String,
+    pub target: Option<String>,
+    /// Destination of the recording install, the default `$DESTDIR` of the script.
+    #[serde(skip)]
+    dst: PathBuf,
+    pub operations: Vec<Operation>,
+}
+
+impl Plan {
+    pub fn new(dst: &Path, package: &str, target: Option<&str>) -> Self {
+        Self {
+            package: package.into(),
+            target: target.map(|x| x.into()),
+            dst: dst.to_path_buf(),
+            operations: vec![],
+        }
+    }
+
+    pub fn dst(&self) -> &Path {
+        &self.dst
+    }
+
+    pub fn load(file: &Path) -> Self {
+        let data = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+        serde_json::from_str(&data).unwrap_or_else(|e| panic!("Failed to parse {file}: {e}"))
+    }
+
+    pub fn save(&self, file: &Path) {
+        message::status(format_args!("Writing install plan to {file}"));
+        let data = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(file, data).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+    }
+
+    /// Return the path of the image of a path of the rootfs.
+    fn image(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.dst) {
+            Ok(relative) => PathBuf::from("/").join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    fn push(&mut self, operation: Operation) {
+        self.operations.push(operation);
+    }
+
+    pub fn comment(&mut self, text: &str) {
+        self.push(Operation::Comment { text: text.into() });
+    }
+
+    pub fn mkdir(&mut self, dir: &Path, owner: Option<(u32, u32)>) {
+        let path = self.image(dir);
+        self.push(Operation::Mkdir { path, owner });
+    }
+
+    pub fn copy(&mut self, src: &Path, dst: &Path, mode: Option<u32>) {
+        let (src, dst) = (src.to_path_buf(), self.image(dst));
+        let mode = mode.map(|mode| format!("{mode:04o}"));
+        self.push(Operation::Copy { src, dst, mode });
+    }
+
+    pub fn write(&mut self, dst: &Path, content: &[u8], mode: u32) {
+        let path = self.image(dst);
+        let (content, hex) = match std::str::from_utf8(content) {
+            Ok(text) if !text.contains('\0') => (text.to_string(), false),
+            _ => (crate::hex(content), true),
+        };
+        self.push(Operation::Write {
+            path,
+            mode: format!("{mode:04o}"),
+            content,
+            hex,
+        });
+    }
+
+    pub fn symlink(&mut self, target: &Path, link: &Path) {
+        let (target, link) = (target.to_path_buf(), self.image(link));
+        self.push(Operation::Symlink { target, link });
+    }
+
+    pub fn hardlink(&mut self, original: &Path, link: &Path) {
+        let (original, link) = (self.image(original), self.image(link));
+        self.push(Operation::Hardlink { original, link });
+    }
+
+    pub fn remove(&mut self, path: &Path, dir: bool) {
+        let path = self.image(path);
+        self.push(Operation::Remove { path, dir });
+    }
+
+    pub fn edit(&mut self, dst: &Path, edit: &Edit) {
+        let (path, edit) = (self.image(dst), edit.clone());
+        self.push(Operation::Edit { path, edit });
+    }
+
+    pub fn backup(&mut self, path: &Path, backup: &Path) {
+        let in_rootfs = backup.starts_with(&self.dst);
+        let (path, backup) = (self.image(path), self.image(backup));
+        self.push(Operation::Backup {
+            path,
+            backup,
+            in_rootfs,
+        });
+    }
+
+    pub fn patch(&mut self, dst: &Path, patch: &Path) {
+        let (path, patch) = (self.image(dst), patch.to_path_buf());
+        self.push(Operation::Patch { path, patch });
+    }
+
+    pub fn run(&mut self, program: &str, args: &[&str], file: &Path) {
+        self.push(Operation::Run {
+            program: program.into(),
+            args: args.iter().map(|x| x.to_string()).collect(),
+            path: self.image(file),
+        });
+    }
+
+    pub fn hook(&mut self, command: &str, dir: &Path, run_in_rootfs: bool) {
+        self.push(Operation::Hook {
+            command: command.into(),
+            dir: dir.to_path_buf(),
+            run_in_rootfs,
+        });
+    }
+
+    /// Write the operations as a POSIX shell script, installing in `$DESTDIR`.
+    pub fn save_script(&self, file: &Path) {
+        message::status(format_args!("Writing install script to {file}"));
+        let mut script = format!(
+            "#!/bin/sh\n# Install of {}, generated by cargo-rootfs.\nset -e\n[ -n \"$DESTDIR\" ] || DESTDIR={}\nexport DESTDIR\n",
+            self.package,
+            shell_quote(self.dst.as_str())
+        );
+        for operation in &self.operations {
+            script += &script_lines(operation);
+            script += "\n";
+        }
+        std::fs::write(file, script).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+        let _ = std::fs::set_permissions(file, std::fs::Permissions::from_mode(0o755));
+    }
+
+    /// Run the operations in a rootfs, rolling back its modifications if one fails.
+    pub fn apply(&self, rootfs: &Path, backend: crate::exec::Backend) {
+        let mut journal = Journal::new(rootfs);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for operation in &self.operations {
+                self.apply_operation(rootfs, operation, backend, &mut journal);
+            }
+        }));
+        if let Err(e) = result {
+            eprintln!("Apply failed: rolling back");
+            journal.rollback();
+            std::panic::resume_unwind(e);
+        }
+        journal.commit();
+    }
+
+    fn apply_operation(
+        &self,
+        rootfs: &Path,
+        operation: &Operation,
+        backend: crate::exec::Backend,
+        journal: &mut Journal,
+    ) {
+        let host = |path: &Path| rootfs.join(path.strip_prefix("/").unwrap_or(path));
+        let create_parent = |path: &Path, journal: &mut Journal| {
+            let parent = path.parent().unwrap();
+            journal.save_dir(parent);
+            std::fs::create_dir_all(parent)
+                .unwrap_or_else(|e| panic!("Failed to create directory {parent}: {e:?}"));
+        };
+        if let Operation::Comment { text } = operation {
+            return message::status(text);
+        }
+        let fields = serde_json::to_value(operation).unwrap();
+        message::operation(script_lines(operation), "apply", fields);
+
+        match operation {
+            Operation::Comment { .. } => {}
+            Operation::Mkdir { path, owner } => {
+                let path = host(path);
+                journal.save_dir(&path);
+                std::fs::create_dir_all(&path)
+                    .unwrap_or_else(|e| panic!("Failed to create directory {path}: {e:?}"));
+                if let (Some((uid, gid)), 0) = (owner, unsafe { libc::geteuid() }) {
+                    std::os::unix::fs::chown(&path, Some(*uid), Some(*gid))
+                        .unwrap_or_else(|e| panic!("Failed to chown {path}: {e:?}"));
+                }
+            }
+            Operation::Copy { src, dst, mode } => {
+                let dst = host(dst);
+                create_parent(&dst, journal);
+                journal.save(&dst);
+                let _ = std::fs::remove_file(&dst);
+                if src.is_symlink() {
+                    let target = src.read_link_utf8().unwrap();
+                    std::os::unix::fs::symlink(target, &dst)
+                        .unwrap_or_else(|e| panic!("Failed to create {dst}: {e:?}"));
+                    return;
+                }
+                std::fs::copy(src, &dst)
+                    .unwrap_or_else(|e| panic!("Failed to copy {src} to {dst}: {e:?}"));
+                if let Some(mode) = mode {
+                    let _ = std::fs::set_permissions(&dst, parse_mode(mode));
+                }
+            }
+            Operation::Write {
+                path,
+                mode,
+                content,
+                hex,
+            } => {
+                let path = host(path);
+                let data = match hex {
+                    true => decode_hex(content)
+                        .unwrap_or_else(|e| panic!("Invalid content of {path}: {e}")),
+                    false => content.as_bytes().to_vec(),
+                };
+                create_parent(&path, journal);
+                journal.save(&path);
+                let _ = std::fs::remove_file(&path);
+                std::fs::write(&path, data)
+                    .unwrap_or_else(|e| panic!("Failed to write {path}: {e:?}"));
+                let _ = std::fs::set_permissions(&path, parse_mode(mode));
+            }
+            Operation::Symlink { target, link } => {
+                let link = host(link);
+                create_parent(&link, journal);
+                journal.save(&link);
+                let _ = std::fs::remove_file(&link);
+                std::os::unix::fs::symlink(target, &link)
+                    .unwrap_or_else(|e| panic!("Failed to create {link}: {e:?}"));
+            }
+            Operation::Hardlink { original, link } => {
+                let link = host(link);
+                journal.save(&link);
+                let _ = std::fs::remove_file(&link);
+                std::fs::hard_link(host(original), &link)
+                    .unwrap_or_else(|e| panic!("Failed to link {link} to {original}: {e:?}"));
+            }
+            Operation::Remove { path, dir } => {
+                let path = host(path);
+                journal.save(&path);
+                if *dir {
+                    let _ = std::fs::remove_dir(&path);
+                } else if path.symlink_metadata().is_ok() {
+                    std::fs::remove_file(&path)
+                        .unwrap_or_else(|e| panic!("Failed to remove {path}: {e:?}"));
+                }
+            }
+            Operation::Edit { path, edit } => {
+                let path = host(path);
+                let old = std::fs::read_to_string(&path).unwrap_or_default();
+                if let Some(content) = edit_content(&old, std::slice::from_ref(edit)) {
+                    create_parent(&path, journal);
+                    journal.save_in_place(&path);
+                    std::fs::write(&path, content)
+                        .unwrap_or_else(|e| panic!("Failed to write {path}: {e:?}"));
+                }
+            }
+            Operation::Backup {
+                path,
+                backup,
+                in_rootfs,
+            } => {
+                let path = host(path);
+                // Only the files of the target are saved.
+                if path.symlink_metadata().is_err() {
+                    return;
+                }
+                let backup = match in_rootfs {
+                    true => host(backup),
+                    false => backup.clone(),
+                };
+                create_parent(&backup, journal);
+                journal.save(&backup);
+                let _ = std::fs::remove_file(&backup);
+                std::fs::copy(&path, &backup)
+                    .unwrap_or_else(|e| panic!("Failed to backup {path} to {backup}: {e:?}"));
+            }
+            Operation::Patch { path, patch } => {
+                let path = host(path);
+                journal.save_in_place(&path);
+                run(std::process::Command::new("patch")
+                    .arg("-N")
+                    .arg(&path)
+                    .arg("-i")
+                    .arg(patch));
+            }
+            Operation::Run {
+                program,
+                args,
+                path,
+            } => {
+                let path = host(path);
+                if path.is_file() {
+                    journal.save_in_place(&path);
+                }
+                run(std::process::Command::new(program).args(args).arg(&path));
+            }
+            Operation::Hook {
+                command,
+                dir,
+                run_in_rootfs,
+            } => {
+                let args = vec!["-c".to_string(), command.clone()];
+                let mut cmd = match run_in_rootfs {
+                    true => crate::exec::rootfs_command(
+                        rootfs,
+                        self.target.as_deref(),
+                        backend,
+                        "/bin/sh",
+                        &args,
+                    ),
+                    false => {
+                        let mut cmd = std::process::Command::new("sh");
+                        cmd.args(&args).current_dir(dir).env("DESTDIR", rootfs);
+                        cmd
+                    }
+                };
+                if message::is_json() {
+                    // Keep stdout for the JSON events.
+                    cmd.stdout(std::io::stderr());
+                }
+                run(&mut cmd);
+            }
+        }
+    }
+}
+
+fn parse_mode(mode: &str) -> std::fs::Permissions {
+    let mode = u32::from_str_radix(mode, 8)
+        .unwrap_or_else(|_| panic!("Mode {mode:?} is not an octal number"));
+    std::fs::Permissions::from_mode(mode)
+}
+
+/// Decode the hexadecimal digits of the content of a binary file.
+fn decode_hex(digits: &str) -> Result<Vec<u8>, String> {
+    if digits.len() % 2 == 1 {
+        return Err(format!(
+            "odd number of hexadecimal digits ({})",
+            digits.len()
+        ));
+    }
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(byte) if pair.iter().all(u8::is_ascii_hexdigit) => {
+                Ok(u8::from_str_radix(byte, 16).unwrap())
+            }
+            _ => Err(format!(
+                "invalid hexadecimal digits {:?}",
+                String::from_utf8_lossy(pair)
+            )),
+        })
+        .collect()
+}
+
+/// The awk programs of the edits of the install script which replace lines, reading their
+/// strings from the environment, which awk does not unescape.
+const ENSURE_LINE_AWK: &str = r#"!d && index($0, ENVIRON["P"]) == 1 { print ENVIRON["L"]; d = 1; next }
+{ print; if ($0 == ENVIRON["L"]) d = 1 }
+END { if (!d) print ENVIRON["L"] }"#;
+const APPEND_BLOCK_AWK: &str = r#"!s && $0 == ENVIRON["B"] { s = 1; buf = $0 "\n"; next }
+s == 1 && $0 == ENVIRON["E"] { printf "%s", ENVIRON["N"]; s = 2; next }
+s == 1 { buf = buf $0 "\n"; next }
+{ print }
+END { if (s == 1) printf "%s", buf; if (s != 2) printf "%s", ENVIRON["N"] }"#;
+
+/// Quote a path of the rootfs, relative to `$DESTDIR`.
+fn rootfs(path: &Path) -> String {
+    format!("\"$DESTDIR\"{}", shell_quote(path.as_str()))
+}
+
+/// Return the shell commands of an operation.
+fn script_lines(operation: &Operation) -> String {
+    let mkdir_parent = |path: &Path| format!("mkdir -p \"$(dirname {})\"", rootfs(path));
+    match operation {
+        Operation::Comment { text } => format!("\n# {text}"),
+        Operation::Mkdir { path, owner } => {
+            let mut lines = format!("install -d {}", rootfs(path));
+            if let Some((uid, gid)) = owner {
+                // Only root can give the files to another user.
+                lines += &format!(
+                    "\n[ \"$(id -u)\" != 0 ] || chown {uid}:{gid} {}",
+                    rootfs(path)
+                );
+            }
+            lines
+        }
+        Operation::Copy { src, dst, mode } => {
+            let (src, dst_path) = (shell_quote(src.as_str()), rootfs(dst));
+            match mode {
+                Some(mode) => format!("install -D -m {mode} {src} {dst_path}"),
+                // Keep the mode of the source, and copy the symbolic links as is.
+                None => format!(
+                    "{} && rm -f {dst_path} && cp -pPR {src} {dst_path}",
+                    mkdir_parent(dst)
+                ),
+            }
+        }
+        Operation::Write {
+            path,
+            mode,
+            content,
+            hex,
+        } => {
+            let dst = rootfs(path);
+            let mut lines = mkdir_parent(path) + "\n";
+            if *hex {
+                lines += &format!(": > {dst}\n");
+                let data = decode_hex(content)
+                    .unwrap_or_else(|e| panic!("Invalid content of {path}: {e}"));
+                for chunk in data.chunks(64) {
+                    let escaped: String = chunk.iter().map(|b| format!("\\{b:03o}")).collect();
+                    lines += &format!("printf '{escaped}' >> {dst}\n");
+                }
+            } else if content.ends_with('\n') {
+                // A here-document always ends with a new line.
+                let delimiter = (0..)
+                    .map(|i| format!("CARGO_ROOTFS_EOF{i}"))
+                    .find(|x| !content.lines().any(|line| line == x))
+                    .unwrap();
+                lines += &format!("cat > {dst} <<'{delimiter}'\n{content}{delimiter}\n");
+            } else {
+                lines += &format!("printf '%s' {} > {dst}\n", shell_quote(content));
+            }
+            lines + &format!("chmod {mode} {dst}")
+        }
+        Operation::Symlink { target, link } => format!(
+            "{}\nln -sfn {} {}",
+            mkdir_parent(link),
+            shell_quote(target.as_str()),
+            rootfs(link)
+        ),
+        Operation::Hardlink { original, link } => {
+            format!("ln -f {} {}", rootfs(original), rootfs(link))
+        }
+        Operation::Remove { path, dir: true } => {
+            format!("rmdir {} 2>/dev/null || true", rootfs(path))
+        }
+        Operation::Remove { path, dir: false } => format!("rm -f {}", rootfs(path)),
+        Operation::Edit { path, edit } => {
+            let dst = rootfs(path);
+            // Terminate the last line before appending a line.
+            let append = |line: &str| {
+                format!(
+                    "{{ [ -z \"$(tail -c 1 {dst})\" ] || echo >> {dst}; printf '%s\\n' {} >> {dst}; }}",
+                    shell_quote(line)
+                )
+            };
+            let env = |name: &str, value: &str| format!("{name}={} ", shell_quote(value));
+            let (vars, program) = match edit {
+                Edit::EnsureLine {
+                    line,
+                    replace: None,
+                } => {
+                    return format!(
+                        "{}\ntouch {dst}\ngrep -qxF -- {} {dst} || {}",
+                        mkdir_parent(path),
+                        shell_quote(line),
+                        append(line)
+                    );
+                }
+                Edit::AddUser { name, entry } => {
+                    return format!(
+                        "{}\ntouch {dst}\ncut -d: -f1 {dst} | grep -qxF -- {} || {}",
+                        mkdir_parent(path),
+                        shell_quote(name),
+                        append(entry)
+                    );
+                }
+                Edit::EnsureLine {
+                    line,
+                    replace: Some(prefix),
+                } => (env("L", line) + &env("P", prefix), ENSURE_LINE_AWK),
+                Edit::AppendBlock { begin, end, lines } => {
+                    let mut block = format!("{begin}\n");
+                    for line in lines {
+                        block += &format!("{line}\n");
+                    }
+                    block += &format!("{end}\n");
+                    (
+                        env("B", begin) + &env("E", end) + &env("N", &block),
+                        APPEND_BLOCK_AWK,
+                    )
+                }
+            };
+            // The file is rewritten through cat to keep its mode and owner.
+            format!(
+                "{}\ntouch {dst}\n{vars}awk {} {dst} > {dst}.tmp && cat {dst}.tmp > {dst} && rm -f {dst}.tmp",
+                mkdir_parent(path),
+                shell_quote(program)
+            )
+        }
+        Operation::Backup {
+            path,
+            backup,
+            in_rootfs,
+        } => {
+            let backup = match in_rootfs {
+                true => rootfs(backup),
+                false => shell_quote(backup.as_str()),
+            };
+            let path = rootfs(path);
+            format!(
+                "if [ -e {path} ] || [ -L {path} ]; then\n  mkdir -p \"$(dirname {backup})\"\n  cp -a {path} {backup}\nfi"
+            )
+        }
+        Operation::Patch { path, patch } => format!(
+            "patch -N {} -i {}",
+            rootfs(path),
+            shell_quote(patch.as_str())
+        ),
+        Operation::Run {
+            program,
+            args,
+            path,
+        } => {
+            let args: String = args
+                .iter()
+                .map(|x| format!(" {}", shell_quote(x)))
+                .collect();
+            format!("{}{args} {}", shell_quote(program), rootfs(path))
+        }
+        Operation::Hook {
+            command,
+            run_in_rootfs: true,
+            ..
+        } => format!("chroot \"$DESTDIR\" /bin/sh -c {}", shell_quote(command)),
+        Operation::Hook { command, dir, .. } => format!(
+            "(cd {} && sh -c {})",
+            shell_quote(dir.as_str()),
+            shell_quote(command)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a rootfs with an /etc/old.conf file in the temporary directory.
+    fn rootfs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cargo-rootfs-{}-{name}", std::process::id()));
+        let dir = PathBuf::from_path_buf(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("etc")).unwrap();
+        std::fs::write(dir.join("etc/old.conf"), "a\n").unwrap();
+        dir
+    }
+
+    fn write(path: &str, content: &str, hex: bool) -> Operation {
+        Operation::Write {
+            path: path.into(),
+            mode: "0640".into(),
+            content: content.into(),
+            hex,
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn decode_hex_digits() {
+        assert_eq!(decode_hex(""), Ok(vec![]));
+        assert_eq!(decode_hex("00ff10Ab"), Ok(vec![0x00, 0xff, 0x10, 0xab]));
+        assert_eq!(
+            decode_hex("abc"),
+            Err("odd number of hexadecimal digits (3)".into())
+        );
+        assert_eq!(
+            decode_hex("0g"),
+            Err("invalid hexadecimal digits \"0g\"".into())
+        );
+        assert!(decode_hex("+f").is_err());
+        assert!(decode_hex("é").is_err());
+    }
+
+    #[test]
+    fn apply_operations() {
+        let rootfs = rootfs("apply");
+        let src = rootfs.with_extension("src");
+        std::fs::write(&src, "data").unwrap();
+        std::fs::write(rootfs.join("etc/gone"), "").unwrap();
+        let mut plan = Plan::new(&rootfs, "app", None);
+        plan.operations = vec![
+            Operation::Comment {
+                text: "Installing app".into(),
+            },
+            Operation::Mkdir {
+                path: "/var/lib/app".into(),
+                owner: None,
+            },
+            write("/etc/app.conf", "key = value\n", false),
+            write("/bin/blob", "00ff10", true),
+            Operation::Symlink {
+                target: "app.conf".into(),
+                link: "/etc/link.conf".into(),
+            },
+            Operation::Hardlink {
+                original: "/etc/app.conf".into(),
+                link: "/etc/hard.conf".into(),
+            },
+            Operation::Copy {
+                src: src.clone(),
+                dst: "/usr/share/app/data".into(),
+                mode: Some("0600".into()),
+            },
+            Operation::Backup {
+                path: "/etc/old.conf".into(),
+                backup: "/etc/old.conf.orig".into(),
+                in_rootfs: true,
+            },
+            Operation::Edit {
+                path: "/etc/old.conf".into(),
+                edit: Edit::EnsureLine {
+                    line: "b".into(),
+                    replace: None,
+                },
+            },
+            Operation::Remove {
+                path: "/etc/gone".into(),
+                dir: false,
+            },
+        ];
+        plan.apply(&rootfs, crate::exec::Backend::default());
+        let read = |path: &str| std::fs::read(rootfs.join(path)).unwrap();
+
+        assert!(rootfs.join("var/lib/app").is_dir());
+        assert_eq!(read("etc/app.conf"), b"key = value\n");
+        assert_eq!(mode(&rootfs.join("etc/app.conf")), 0o640);
+        assert_eq!(read("bin/blob"), [0x00, 0xff, 0x10]);
+        assert_eq!(
+            rootfs.join("etc/link.conf").read_link_utf8().unwrap(),
+            "app.conf"
+        );
+        let ino = |path: &str| {
+            std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(rootfs.join(path)).unwrap())
+        };
+        assert_eq!(ino("etc/hard.conf"), ino("etc/app.conf"));
+        assert_eq!(read("usr/share/app/data"), b"data");
+        assert_eq!(mode(&rootfs.join("usr/share/app/data")), 0o600);
+        assert_eq!(read("etc/old.conf.orig"), b"a\n");
+        assert_eq!(read("etc/old.conf"), b"a\nb\n");
+        assert!(!rootfs.join("etc/gone").exists());
+        assert!(!rootfs.join(crate::journal::JOURNAL_DIR).exists());
+        std::fs::remove_dir_all(&rootfs).unwrap();
+        std::fs::remove_file(&src).unwrap();
+    }
+
+    #[test]
+    fn rollback_failed_apply() {
+        let rootfs = rootfs("rollback");
+        let mut plan = Plan::new(&rootfs, "app", None);
+        plan.operations = vec![
+            write("/etc/old.conf", "new\n", false),
+            Operation::Mkdir {
+                path: "/var/lib/app".into(),
+                owner: None,
+            },
+            write("/etc/bad.bin", "abc", true),
+        ];
+        let result =
+            std::panic::catch_unwind(|| plan.apply(&rootfs, crate::exec::Backend::default()));
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(rootfs.join("etc/old.conf")).unwrap(), b"a\n");
+        assert!(!rootfs.join("var").exists());
+        assert!(!rootfs.join("etc/bad.bin").exists());
+        assert!(!rootfs.join(crate::journal::JOURNAL_DIR).exists());
+        std::fs::remove_dir_all(&rootfs).unwrap();
+    }
+}
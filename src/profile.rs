@@ -0,0 +1,47 @@
+use camino::Utf8PathBuf as PathBuf;
+use serde::Deserialize;
+use serde_json::value::Value;
+
+/// A `[workspace.metadata.rootfs.profiles.<NAME>]` table, selected with --rootfs-profile.
+/// Its options are used when they are not specified on the command line.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub dest: Option<PathBuf>,
+    pub target: Option<String>,
+    pub altsrc: Option<PathBuf>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub all_features: bool,
+    #[serde(default)]
+    pub no_default_features: bool,
+    pub message_format: Option<String>,
+}
+
+/// Find a profile in the workspace metadata.
+pub fn find(workspace_metadata: &Value, name: &str) -> Profile {
+    let profiles = &workspace_metadata["rootfs"]["profiles"];
+    let profile = match &profiles[name] {
+        Value::Null => {
+            let names: Vec<&str> = match profiles {
+                Value::Object(profiles) => profiles.keys().map(|x| x.as_str()).collect(),
+                _ => vec![],
+            };
+            panic!(
+                "Unknown rootfs profile {name:?} (available: {})",
+                match names.is_empty() {
+                    true => "none".into(),
+                    false => names.join(", "),
+                }
+            );
+        }
+        profile => profile,
+    };
+    serde_json::from_value(profile.clone()).unwrap_or_else(|e| {
+        panic!(
+            "workspace.metadata.rootfs.profiles.{name}: {}",
+            crate::did_you_mean(&e.to_string())
+        )
+    })
+}
@@ -0,0 +1,147 @@
+use crate::boot;
+use crate::message;
+use crate::verity;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::Deserialize;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process::Command;
+
+/// A `[workspace.metadata.rootfs.rauc.slots.<CLASS>]` table: the image of a slot class.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Slot {
+    /// Directory of the rootfs made into an ext4 image, or file of the rootfs used as the
+    /// image (default: `/`, the whole rootfs).
+    pub source: Option<PathBuf>,
+}
+
+/// The `[workspace.metadata.rootfs.rauc]` table.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rauc {
+    /// Compatible string of the system, checked by RAUC before installing the bundle.
+    pub compatible: Option<String>,
+    /// Version of the update (default: the version of the root package).
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// Bundle format: plain, verity or crypt (default: verity).
+    pub format: Option<String>,
+    /// Slot classes updated by the bundle (default: a `rootfs` slot with the whole rootfs).
+    #[serde(default)]
+    pub slots: BTreeMap<String, Slot>,
+}
+
+impl Rauc {
+    pub fn load(workspace_metadata: &Value) -> Self {
+        match &workspace_metadata["rootfs"]["rauc"] {
+            Value::Null => Self::default(),
+            table => serde_json::from_value(table.clone()).unwrap_or_else(|e| {
+                panic!(
+                    "workspace.metadata.rootfs.rauc: {}",
+                    crate::did_you_mean(&e.to_string())
+                )
+            }),
+        }
+    }
+}
+
+/// Signing certificate and key of the bundle: files or PKCS#11 URIs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signer {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Build a RAUC bundle of the rootfs: the manifest and the slot images are written in a
+/// bundle directory, signed and packed by `rauc bundle`. The version and description default
+/// to the ones of the package.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    config: &Rauc,
+    signer: &Signer,
+    package: &cargo_metadata::Package,
+    verity: Option<&verity::VerityOptions>,
+    output: &Path,
+) {
+    let version = package.version.to_string();
+    let compatible = config
+        .compatible
+        .as_deref()
+        .unwrap_or_else(|| panic!("workspace.metadata.rootfs.rauc.compatible is required"));
+    let format = config.format.as_deref().unwrap_or("verity");
+    if !["plain", "verity", "crypt"].contains(&format) {
+        panic!("Unknown RAUC bundle format {format:?} (expected plain, verity or crypt)");
+    }
+    let mut slots = config.slots.clone();
+    if slots.is_empty() {
+        slots.insert("rootfs".into(), Slot::default());
+    }
+
+    let bundle = outdir.join("bundle");
+    let _ = std::fs::remove_dir_all(&bundle);
+    std::fs::create_dir_all(&bundle)
+        .unwrap_or_else(|e| panic!("Failed to create directory {bundle}: {e:?}"));
+
+    let mut manifest = String::from("[update]\n");
+    let _ = writeln!(manifest, "compatible={compatible}");
+    let _ = writeln!(
+        manifest,
+        "version={}",
+        config.version.as_deref().unwrap_or(&version)
+    );
+    let _ = writeln!(
+        manifest,
+        "description={}",
+        config.description.as_deref().unwrap_or(&package.name)
+    );
+    let _ = writeln!(manifest, "build={}", crate::sbom::timestamp());
+    let _ = write!(manifest, "\n[bundle]\nformat={format}\n");
+
+    for (class, slot) in &slots {
+        let source = slot.source.as_deref().unwrap_or("/".into());
+        let path = rootfs.join(source.strip_prefix("/").unwrap_or(source));
+        let filename = if path.is_dir() {
+            let dir = outdir.join(class);
+            std::fs::create_dir_all(&dir)
+                .unwrap_or_else(|e| panic!("Failed to create directory {dir}: {e:?}"));
+            let image = boot::make_ext4(&path, &dir, None);
+            if let Some(options) = verity {
+                verity::format(&image, options);
+            }
+            let filename = format!("{class}.ext4");
+            std::fs::rename(&image, bundle.join(&filename)).unwrap();
+            filename
+        } else if path.is_file() {
+            let filename = format!("{class}.img");
+            std::fs::copy(&path, bundle.join(&filename))
+                .unwrap_or_else(|e| panic!("Failed to copy {path}: {e:?}"));
+            filename
+        } else {
+            panic!("[{class}] source {source} not found in {rootfs}");
+        };
+        let _ = write!(manifest, "\n[image.{class}]\nfilename={filename}\n");
+    }
+    let file = bundle.join("manifest.raucm");
+    std::fs::write(&file, manifest).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+
+    let rauc = std::env::var("RAUC").unwrap_or("rauc".into());
+    let _ = std::fs::remove_file(output);
+    message::status(format_args!(
+        "{rauc} bundle --cert {} --key {} {bundle} {output}",
+        signer.cert, signer.key
+    ));
+    crate::exec::run(
+        Command::new(&rauc)
+            .arg("bundle")
+            .arg("--cert")
+            .arg(&signer.cert)
+            .arg("--key")
+            .arg(&signer.key)
+            .arg(&bundle)
+            .arg(output),
+    );
+}
@@ -0,0 +1,84 @@
+use camino::Utf8Path as Path;
+use cargo_metadata::TargetKind;
+
+/// Return the name of an installed test executable: `<PACKAGE>-lib` for the unit tests of
+/// the library, and `<PACKAGE>-<KIND>-<TARGET>` for the other targets.
+pub fn test_name(package: &str, target: &cargo_metadata::Target) -> String {
+    let kind = |kind: &TargetKind| target.kind.contains(kind);
+    if kind(&TargetKind::Bin) {
+        format!("{package}-bin-{}", target.name)
+    } else if kind(&TargetKind::Test) {
+        format!("{package}-test-{}", target.name)
+    } else if kind(&TargetKind::Bench) {
+        format!("{package}-bench-{}", target.name)
+    } else if kind(&TargetKind::Example) {
+        format!("{package}-example-{}", target.name)
+    } else {
+        format!("{package}-lib")
+    }
+}
+
+/// Check if a test executable was built with the libtest harness, which reads
+/// RUST_TEST_THREADS. A `harness = false` target (e.g. criterion) has its own options.
+pub fn is_libtest(executable: &Path) -> bool {
+    let needle = b"RUST_TEST_THREADS";
+    std::fs::read(executable).is_ok_and(|data| data.windows(needle.len()).any(|x| x == needle))
+}
+
+/// Return the runner script of the test executables of a kind (tests or benches), run with
+/// the `options` and the arguments of the script.
+///
+/// The libtest JSON events of each libtest executable are printed as they are reported,
+/// followed by a suite event aggregating their counts. libtest only accepts `--format json`
+/// with `RUSTC_BOOTSTRAP=1`, as an unstable option. The other executables (`harness = false`)
+/// only get the options, their output is printed on stderr and their failure fails the suite.
+pub fn script(
+    name: &str,
+    kind: &str,
+    executables: &[String],
+    custom: &[String],
+    options: &str,
+) -> String {
+    let quote = |list: &[String]| {
+        let list: Vec<String> = list.iter().map(|x| crate::shell_quote(x)).collect();
+        list.join(" ")
+    };
+    format!(
+        r#"#!/bin/sh
+# Run the {name} {kind} of this directory, reporting in libtest JSON.
+cd "$(dirname "$0")" || exit 1
+output=${{TMPDIR:-/tmp}}/{name}-{kind}.$$
+trap 'rm -f "$output"' EXIT
+passed=0 failed=0 ignored=0 measured=0 filtered_out=0 status=0
+for test in {list}; do
+    if ! RUSTC_BOOTSTRAP=1 "./$test" -Z unstable-options --format json {options} "$@" >"$output"; then
+        status=1
+    fi
+    cat "$output"
+    suite=$(grep -E '"type": *"suite", *"event": *"(ok|failed)"' "$output" | tail -n 1)
+    if [ -z "$suite" ]; then
+        echo "$test did not report its results" >&2
+        status=1
+        continue
+    fi
+    for field in passed failed ignored measured filtered_out; do
+        value=$(echo "$suite" | sed -n "s/.*\"$field\": *\([0-9]*\).*/\1/p")
+        eval "$field=\$(($field + ${{value:-0}}))"
+    done
+done
+for test in {custom}; do
+    if ! "./$test" {options} "$@" >&2; then
+        echo "$test failed" >&2
+        status=1
+    fi
+done
+event=ok
+[ "$status" -eq 0 ] || event=failed
+printf '{{ "type": "suite", "event": "%s", "passed": %d, "failed": %d, "ignored": %d, "measured": %d, "filtered_out": %d }}\n' \
+    "$event" "$passed" "$failed" "$ignored" "$measured" "$filtered_out"
+exit "$status"
+"#,
+        list = quote(executables),
+        custom = quote(custom),
+    )
+}
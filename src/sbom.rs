@@ -0,0 +1,357 @@
+use crate::{hex, message};
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde_json::json;
+use serde_json::value::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    CycloneDx,
+    Spdx,
+}
+
+/// Parse a `--sbom` value formatted as `<cyclonedx|spdx>:<FILE>`.
+pub fn parse(spec: &str) -> (Format, PathBuf) {
+    let (format, path) = spec
+        .split_once(':')
+        .unwrap_or_else(|| panic!("--sbom {spec} must be formatted as <cyclonedx|spdx>:<FILE>"));
+    let format = match format {
+        "cyclonedx" => Format::CycloneDx,
+        "spdx" => Format::Spdx,
+        other => panic!("Unknown SBOM format {other:?} (expected cyclonedx or spdx)"),
+    };
+    (format, PathBuf::from(path))
+}
+
+/// A regular file installed in the rootfs.
+pub struct Entry<'a> {
+    /// Absolute path of the file in the rootfs image.
+    pub path: PathBuf,
+    /// Path of the file on the host.
+    pub file: PathBuf,
+    pub package: &'a cargo_metadata::Package,
+}
+
+/// Return the SHA-1 and the SHA-256 of a file, read once by blocks.
+fn digests(file: &Path) -> (String, String) {
+    let mut reader =
+        std::fs::File::open(file).unwrap_or_else(|e| panic!("Failed to open {file}: {e:?}"));
+    let (mut sha1, mut sha256) = (Sha1::new(), Sha256::new());
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = std::io::Read::read(&mut reader, &mut buffer)
+            .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+        if n == 0 {
+            break;
+        }
+        sha1.update(&buffer[..n]);
+        sha256.update(&buffer[..n]);
+    }
+    (hex(&sha1.finalize()), hex(&sha256.finalize()))
+}
+
+/// Return the creation time as RFC 3339, honoring SOURCE_DATE_EPOCH for reproducible builds.
+pub fn timestamp() -> String {
+    let secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .unwrap_or_else(|_| panic!("SOURCE_DATE_EPOCH={epoch} is not a number")),
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+
+    // Convert days since epoch to a civil date (Howard Hinnant's algorithm).
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = secs % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn purl(package: &cargo_metadata::Package) -> String {
+    format!("pkg:cargo/{}@{}", package.name, package.version)
+}
+
+fn source(package: &cargo_metadata::Package) -> String {
+    match &package.source {
+        Some(source) => source.to_string(),
+        None => "path+".to_string() + package.manifest_path.parent().unwrap().as_str(),
+    }
+}
+
+/// The SPDX download location of a package: the download URL of a crate of crates.io, or
+/// NOASSERTION, a cargo source not being a SPDX download location.
+fn download_location(package: &cargo_metadata::Package) -> String {
+    match &package.source {
+        Some(source) if source.is_crates_io() => format!(
+            "https://crates.io/api/v1/crates/{}/{}/download",
+            package.name, package.version
+        ),
+        _ => "NOASSERTION".into(),
+    }
+}
+
+/// Convert the license of a package to a SPDX license expression, the `/` of the legacy
+/// cargo licenses being `OR`. A license which is not a valid expression is NOASSERTION.
+fn license_expression(license: Option<&str>) -> String {
+    let Some(license) = license else {
+        return "NOASSERTION".into();
+    };
+    let expression = license
+        .split('/')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+    let is_id = |token: &str| {
+        !token.is_empty()
+            && token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'))
+    };
+    // The expected tokens: a license (or a parenthesis), then an operator. A license after
+    // WITH is an exception, which is not followed by another WITH.
+    let (mut depth, mut operand, mut with, mut exception) = (0, true, false, false);
+    for token in spaced.split_whitespace() {
+        match (operand, token) {
+            (true, "(") if !with => depth += 1,
+            (true, token) if is_id(token) && !matches!(token, "AND" | "OR" | "WITH") => {
+                operand = false;
+                (with, exception) = (false, with);
+            }
+            (false, ")") if depth > 0 => {
+                depth -= 1;
+                exception = false;
+            }
+            (false, "AND" | "OR") => {
+                operand = true;
+                exception = false;
+            }
+            (false, "WITH") if !exception => (operand, with) = (true, true),
+            _ => return "NOASSERTION".into(),
+        }
+    }
+    match operand || depth != 0 {
+        true => "NOASSERTION".into(),
+        false => expression,
+    }
+}
+
+fn packages<'a>(entries: &[Entry<'a>]) -> Vec<&'a cargo_metadata::Package> {
+    let mut packages: Vec<&cargo_metadata::Package> = vec![];
+    for entry in entries {
+        if !packages.iter().any(|p| p.id == entry.package.id) {
+            packages.push(entry.package);
+        }
+    }
+    packages
+}
+
+fn cyclonedx(root: &cargo_metadata::Package, entries: &[Entry]) -> Value {
+    let component = |package: &cargo_metadata::Package, kind: &str| {
+        let mut component = json!({
+            "type": kind,
+            "bom-ref": package.id.repr,
+            "name": package.name,
+            "version": package.version.to_string(),
+            "purl": purl(package),
+            "properties": [{"name": "cargo:source", "value": source(package)}],
+        });
+        if package.license.is_some() {
+            let license = license_expression(package.license.as_deref());
+            if license != "NOASSERTION" {
+                component["licenses"] = json!([{"expression": license}]);
+            }
+        }
+        component
+    };
+
+    let mut components = vec![];
+    let mut dependencies = vec![];
+    for package in packages(entries) {
+        if package.id != root.id {
+            components.push(component(package, "library"));
+        }
+        let files: Vec<Value> = entries
+            .iter()
+            .filter(|entry| entry.package.id == package.id)
+            .map(|entry| json!(format!("file:{}", entry.path)))
+            .collect();
+        dependencies.push(json!({"ref": package.id.repr, "dependsOn": files}));
+    }
+    for entry in entries {
+        let (sha1, sha256) = digests(&entry.file);
+        components.push(json!({
+            "type": "file",
+            "bom-ref": format!("file:{}", entry.path),
+            "name": entry.path,
+            "hashes": [
+                {"alg": "SHA-1", "content": sha1},
+                {"alg": "SHA-256", "content": sha256},
+            ],
+        }));
+    }
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp(),
+            "tools": [{"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")}],
+            "component": component(root, "application"),
+        },
+        "components": components,
+        "dependencies": dependencies,
+    })
+}
+
+fn spdx_id(prefix: &str, name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    // Different names may be sanitized the same: a part of their digest keeps the IDs unique.
+    let digest = hex(&Sha256::digest(name.as_bytes()));
+    format!("SPDXRef-{prefix}-{sanitized}-{}", &digest[..16])
+}
+
+fn spdx(root: &cargo_metadata::Package, entries: &[Entry]) -> Value {
+    let package_id = |package: &cargo_metadata::Package| {
+        spdx_id("Package", &format!("{}-{}", package.name, package.version))
+    };
+
+    let mut packages: Vec<Value> = vec![];
+    let mut files = vec![];
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": package_id(root),
+    })];
+    let mut list = self::packages(entries);
+    if !list.iter().any(|p| p.id == root.id) {
+        list.insert(0, root);
+    }
+    for package in list {
+        let license = license_expression(package.license.as_deref());
+        packages.push(json!({
+            "SPDXID": package_id(package),
+            "name": package.name,
+            "versionInfo": package.version.to_string(),
+            "downloadLocation": download_location(package),
+            "licenseConcluded": license,
+            "licenseDeclared": license,
+            "copyrightText": "NOASSERTION",
+            "filesAnalyzed": false,
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": purl(package),
+            }],
+        }));
+    }
+    let mut hashes = String::new();
+    for entry in entries {
+        let id = spdx_id("File", entry.path.as_str());
+        let (sha1, sha256) = digests(&entry.file);
+        files.push(json!({
+            "SPDXID": id,
+            "fileName": format!(".{}", entry.path),
+            "checksums": [
+                {"algorithm": "SHA1", "checksumValue": sha1},
+                {"algorithm": "SHA256", "checksumValue": sha256},
+            ],
+            "licenseConcluded": "NOASSERTION",
+            "copyrightText": "NOASSERTION",
+        }));
+        relationships.push(json!({
+            "spdxElementId": package_id(entry.package),
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+        hashes += &sha1;
+    }
+
+    let name = format!("{}-{}", root.name, root.version);
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!(
+            "https://spdx.org/spdxdocs/{name}-{}",
+            hex(&Sha256::digest(hashes.as_bytes()))
+        ),
+        "creationInfo": {
+            "created": timestamp(),
+            "creators": [format!("Tool: {}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "files": files,
+        "relationships": relationships,
+    })
+}
+
+pub fn write(format: Format, output: &Path, root: &cargo_metadata::Package, entries: &[Entry]) {
+    let sbom = match format {
+        Format::CycloneDx => cyclonedx(root, entries),
+        Format::Spdx => spdx(root, entries),
+    };
+    message::status(format_args!("Writing SBOM to {:#?}", output));
+    let data = serde_json::to_string_pretty(&sbom).unwrap();
+    std::fs::write(output, data).unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spdx_ids_are_unique() {
+        assert_ne!(spdx_id("File", "/etc/a-b"), spdx_id("File", "/etc/a/b"));
+        assert!(spdx_id("File", "/etc/a-b").starts_with("SPDXRef-File--etc-a-b-"));
+    }
+
+    #[test]
+    fn license_expressions() {
+        let license = |x| license_expression(Some(x));
+        assert_eq!(license("MIT/Apache-2.0"), "MIT OR Apache-2.0");
+        assert_eq!(license("MIT OR Apache-2.0"), "MIT OR Apache-2.0");
+        assert_eq!(
+            license("(MIT OR Apache-2.0) AND Unicode-3.0"),
+            "(MIT OR Apache-2.0) AND Unicode-3.0"
+        );
+        assert_eq!(
+            license("Apache-2.0 WITH LLVM-exception"),
+            "Apache-2.0 WITH LLVM-exception"
+        );
+        assert_eq!(license("GPL-2.0+"), "GPL-2.0+");
+        assert_eq!(license("MIT WITH A WITH B"), "NOASSERTION");
+        assert_eq!(license("MIT or Apache-2.0"), "NOASSERTION");
+        assert_eq!(license("(MIT"), "NOASSERTION");
+        assert_eq!(license("MIT AND"), "NOASSERTION");
+        assert_eq!(license("Public domain"), "NOASSERTION");
+        assert_eq!(license_expression(None), "NOASSERTION");
+    }
+}
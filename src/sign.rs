@@ -0,0 +1,66 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::process::Command;
+
+/// Directory of the detached signatures of the ELF files, in the rootfs.
+pub const SIGNATURES_DIR: &str = "usr/share/signatures";
+
+/// How the detached signatures are made.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signer {
+    /// `gpg --detach-sign` with the key of this id (`--sign gpg:<KEYID>`).
+    Gpg(String),
+    /// A shell command, with the file to sign as `$1` and the signature to write as `$2`.
+    Command(String),
+}
+
+impl Signer {
+    pub fn parse(spec: &str) -> Self {
+        match spec.split_once(':') {
+            Some(("gpg", keyid)) if !keyid.is_empty() => Self::Gpg(keyid.into()),
+            _ => panic!("Unknown signing method {spec:?} (expected gpg:<KEYID>)"),
+        }
+    }
+
+    /// Write the detached signature of a file.
+    pub fn sign(&self, file: &Path, signature: &Path) {
+        let _ = std::fs::remove_file(signature);
+        match self {
+            Self::Gpg(keyid) => {
+                let gpg = std::env::var("GPG").unwrap_or("gpg".into());
+                message::status(format_args!(
+                    "{gpg} --local-user {keyid} --detach-sign --output {signature} {file}"
+                ));
+                crate::exec::run(
+                    Command::new(&gpg)
+                        .args(["--batch", "--yes", "--local-user", keyid, "--detach-sign"])
+                        .arg("--output")
+                        .arg(signature)
+                        .arg(file),
+                );
+            }
+            Self::Command(command) => {
+                message::status(format_args!("sh -c {command:?} {file} {signature}"));
+                crate::exec::run(
+                    Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .arg("sh")
+                        .arg(file)
+                        .arg(signature),
+                );
+            }
+        }
+        if !signature.is_file() {
+            panic!("Signing {file} did not write {signature}");
+        }
+    }
+
+    /// Sign an output of a subcommand, in `<FILE>.sig` next to it, and return its path.
+    pub fn sign_output(&self, file: &Path) -> PathBuf {
+        let signature = PathBuf::from(format!("{file}.sig"));
+        self.sign(file, &signature);
+        signature
+    }
+}
@@ -0,0 +1,116 @@
+use crate::message;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+
+/// The environment variables of the programs run by an install.
+const TOOLS: [&str; 9] = [
+    "CARGO_BUILD_TARGET",
+    "DEPMOD",
+    "GPG",
+    "LDCONFIG",
+    "PROOT",
+    "SOURCE_DATE_EPOCH",
+    "STRIP",
+    "XZ",
+    "ZSTD",
+];
+
+/// The inputs of an install, recorded while it runs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Inputs {
+    /// The files and directories read, or looked for.
+    pub files: Vec<PathBuf>,
+    /// The environment variables expanded in the rules.
+    pub env: Vec<String>,
+    /// Whether the install has inputs which are not tracked, e.g. a command output.
+    pub untracked: bool,
+}
+
+/// The state of the last install of a rootfs: it is up to date while its fingerprint, its
+/// environment and the stamps of its files are unchanged.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    /// Digest of the cargo-rootfs version, the options and the cargo metadata.
+    pub fingerprint: String,
+    /// Digests of the environment variables, or null when not set.
+    pub env: BTreeMap<String, Option<String>>,
+    /// Stamps of the inputs and of the installed files, or null when they do not exist.
+    pub files: BTreeMap<PathBuf, Option<String>>,
+}
+
+/// Return the hex sha256 digest of some data.
+pub fn digest(data: &[u8]) -> String {
+    crate::hex(&Sha256::digest(data))
+}
+
+/// Return the state file of a destination in the target directory.
+pub fn path(target_directory: &Path, dst: &Path) -> PathBuf {
+    let hash = digest(dst.as_str().as_bytes());
+    target_directory.join(format!("rootfs-state-{}.json", &hash[..16]))
+}
+
+/// Return the stamp of a file: its size, modification time and mode, as compared by the
+/// incremental install. Symbolic links are not followed.
+fn stamp(path: &Path) -> Option<String> {
+    let metadata = path.symlink_metadata().ok()?;
+    Some(format!(
+        "{}:{}.{:09}:{:o}",
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.mode()
+    ))
+}
+
+fn env_digest(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|x| digest(x.as_bytes()))
+}
+
+impl State {
+    /// Return the current state of an install, from its inputs and its installed files.
+    pub fn new<'a>(
+        fingerprint: String,
+        inputs: &'a Inputs,
+        installed: impl Iterator<Item = &'a Path>,
+    ) -> Self {
+        let names = TOOLS
+            .iter()
+            .copied()
+            .chain(inputs.env.iter().map(|x| x.as_str()));
+        let env = names.map(|x| (x.to_string(), env_digest(x))).collect();
+        let files = inputs
+            .files
+            .iter()
+            .map(|x| x.as_path())
+            .chain(installed)
+            .map(|x| (x.to_path_buf(), stamp(x)))
+            .collect();
+        Self {
+            fingerprint,
+            env,
+            files,
+        }
+    }
+
+    pub fn load(file: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(file).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Whether nothing changed since this state was saved.
+    pub fn is_up_to_date(&self, fingerprint: &str) -> bool {
+        self.fingerprint == fingerprint
+            && self.env.iter().all(|(name, x)| env_digest(name) == *x)
+            && self.files.iter().all(|(path, x)| stamp(path) == *x)
+    }
+
+    pub fn save(&self, file: &Path) {
+        let content = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(file, content).unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+        message::debug(format_args!("Wrote state {file}"));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::boot;
+use crate::message;
+use crate::verity;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde::Deserialize;
+use serde_json::value::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::process::Command;
+
+/// Name of the rootfs image in the update package.
+const IMAGE: &str = "rootfs.ext4";
+
+/// The `[workspace.metadata.rootfs.swupdate]` table.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Swupdate {
+    /// Device written with the rootfs image, e.g. `/dev/mmcblk0p2`.
+    pub device: Option<String>,
+    /// Board name, selecting the description of the board (default: any board).
+    pub board: Option<String>,
+    /// Compatible hardware revisions.
+    #[serde(default)]
+    pub hardware: Vec<String>,
+    /// Version of the software (default: the version of the root package).
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// RSA private key signing the sw-description (PEM).
+    pub signing_key: Option<PathBuf>,
+    /// AES-256 key file of swupdate (`swupdate -K`): the key and the IV, in hexadecimal.
+    pub encryption_key: Option<PathBuf>,
+}
+
+impl Swupdate {
+    pub fn load(workspace_metadata: &Value) -> Self {
+        match &workspace_metadata["rootfs"]["swupdate"] {
+            Value::Null => Self::default(),
+            table => serde_json::from_value(table.clone()).unwrap_or_else(|e| {
+                panic!(
+                    "workspace.metadata.rootfs.swupdate: {}",
+                    crate::did_you_mean(&e.to_string())
+                )
+            }),
+        }
+    }
+}
+
+/// Quote a libconfig string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Return the sw-description of the update: the rootfs image written to the device.
+fn sw_description(
+    config: &Swupdate,
+    name: &str,
+    version: &str,
+    sha256: &str,
+    ivt: Option<&str>,
+) -> String {
+    let device = config
+        .device
+        .as_deref()
+        .unwrap_or_else(|| panic!("workspace.metadata.rootfs.swupdate.device is required"));
+    let description = config.description.as_deref().unwrap_or(name);
+    let indent = if config.board.is_some() { "\t\t" } else { "\t" };
+
+    let mut software = String::new();
+    if !config.hardware.is_empty() {
+        let hardware: Vec<String> = config.hardware.iter().map(|x| quote(x)).collect();
+        let _ = writeln!(
+            software,
+            "{indent}hardware-compatibility: [ {} ];",
+            hardware.join(", ")
+        );
+    }
+    let _ = writeln!(software, "{indent}images: (");
+    let _ = writeln!(software, "{indent}\t{{");
+    let _ = writeln!(software, "{indent}\t\tfilename = {};", quote(IMAGE));
+    let _ = writeln!(software, "{indent}\t\ttype = \"raw\";");
+    let _ = writeln!(software, "{indent}\t\tdevice = {};", quote(device));
+    let _ = writeln!(software, "{indent}\t\tsha256 = {};", quote(sha256));
+    if let Some(ivt) = ivt {
+        let _ = writeln!(software, "{indent}\t\tencrypted = true;");
+        let _ = writeln!(software, "{indent}\t\tivt = {};", quote(ivt));
+    }
+    let _ = writeln!(software, "{indent}\t}}");
+    let _ = writeln!(software, "{indent});");
+
+    let mut description = format!(
+        "software =\n{{\n\tversion = {};\n\tdescription = {};\n",
+        quote(version),
+        quote(description)
+    );
+    match &config.board {
+        Some(board) => {
+            let _ = write!(description, "\n\t{board} = {{\n{software}\t}};\n");
+        }
+        None => description += &software,
+    }
+    description += "}\n";
+    description
+}
+
+/// Encrypt the image with the AES-256-CBC key of swupdate, returning the IV of the image.
+fn encrypt(image: &Path, key_file: &Path, output: &Path) -> String {
+    let content = std::fs::read_to_string(key_file)
+        .unwrap_or_else(|e| panic!("Failed to read {key_file}: {e:?}"));
+    let key = content
+        .split_whitespace()
+        .next()
+        .unwrap_or_else(|| panic!("{key_file} has no AES key"));
+    // An IV per image, derived from its content to keep the package reproducible.
+    let ivt = file_digest(image).sha256[..32].to_string();
+
+    message::status(format_args!(
+        "openssl enc -aes-256-cbc -in {image} -out {output}"
+    ));
+    crate::exec::run(
+        Command::new("openssl")
+            .args([
+                "enc",
+                "-aes-256-cbc",
+                "-nosalt",
+                "-K",
+                key,
+                "-iv",
+                &ivt,
+                "-in",
+            ])
+            .arg(image)
+            .arg("-out")
+            .arg(output),
+    );
+    ivt
+}
+
+/// The size of a file, its checksum in a cpio archive (the sum of its bytes) and its sha256.
+struct FileDigest {
+    size: u64,
+    check: u32,
+    sha256: String,
+}
+
+/// Compute the digest of a file, read by blocks since the images can be larger than the memory.
+fn file_digest(file: &Path) -> FileDigest {
+    let mut reader =
+        std::fs::File::open(file).unwrap_or_else(|e| panic!("Failed to open {file}: {e:?}"));
+    let mut hasher = Sha256::new();
+    let (mut size, mut check) = (0u64, 0u32);
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = reader
+            .read(&mut buffer)
+            .unwrap_or_else(|e| panic!("Failed to read {file}: {e:?}"));
+        if n == 0 {
+            break;
+        }
+        let block = &buffer[..n];
+        hasher.update(block);
+        check = block
+            .iter()
+            .fold(check, |sum, x| sum.wrapping_add(*x as u32));
+        size += n as u64;
+    }
+    let sha256 = crate::hex(&hasher.finalize());
+    FileDigest {
+        size,
+        check,
+        sha256,
+    }
+}
+
+/// Write a file of a cpio archive, in the "new ASCII with checksum" format of swupdate,
+/// returning the size of the entry. The trailer entry has no file.
+fn cpio_entry(
+    archive: &mut impl Write,
+    ino: u32,
+    mode: u32,
+    name: &str,
+    file: Option<(&Path, &FileDigest)>,
+    mtime: u32,
+) -> u64 {
+    let (size, check) = file.map_or((0, 0), |(_, digest)| (digest.size, digest.check));
+    let filesize = u32::try_from(size).unwrap_or_else(|_| {
+        panic!("{name} is {size} bytes: the entries of a cpio archive are limited to 4 GiB")
+    });
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        1,
+        mtime,
+        filesize,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        check,
+    ];
+    let mut header: Vec<u8> = b"070702".to_vec();
+    for field in fields {
+        header.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.resize(header.len().div_ceil(4) * 4, 0);
+    archive
+        .write_all(&header)
+        .unwrap_or_else(|e| panic!("Failed to write the cpio archive: {e:?}"));
+    if let Some((file, _)) = file {
+        let mut reader =
+            std::fs::File::open(file).unwrap_or_else(|e| panic!("Failed to open {file}: {e:?}"));
+        let copied = std::io::copy(&mut reader, archive)
+            .unwrap_or_else(|e| panic!("Failed to write {file} in the cpio archive: {e:?}"));
+        if copied != size {
+            panic!("{file} was modified while written in the cpio archive");
+        }
+    }
+    let padding = size.div_ceil(4) * 4 - size;
+    archive
+        .write_all(&[0; 3][..padding as usize])
+        .unwrap_or_else(|e| panic!("Failed to write the cpio archive: {e:?}"));
+    header.len() as u64 + size + padding
+}
+
+/// Build a swupdate package (.swu) of the rootfs: a cpio archive of the sw-description,
+/// of its signature when a signing key is configured, and of the rootfs ext4 image.
+pub fn build(
+    rootfs: &Path,
+    outdir: &Path,
+    config: &Swupdate,
+    workspace_root: &Path,
+    package: &cargo_metadata::Package,
+    verity: Option<&verity::VerityOptions>,
+    output: &Path,
+) {
+    std::fs::create_dir_all(outdir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {outdir}: {e:?}"));
+    let mut image = boot::make_ext4(rootfs, outdir, None);
+    if let Some(options) = verity {
+        verity::format(&image, options);
+    }
+    let ivt = config.encryption_key.as_ref().map(|key| {
+        let encrypted = outdir.join(format!("{IMAGE}.enc"));
+        let ivt = encrypt(&image, &workspace_root.join(key), &encrypted);
+        image = encrypted;
+        ivt
+    });
+    let image_digest = file_digest(&image);
+
+    let version = package.version.to_string();
+    let version = config.version.as_deref().unwrap_or(&version);
+    let sha256 = &image_digest.sha256;
+    let description = sw_description(config, &package.name, version, sha256, ivt.as_deref());
+    let description_file = outdir.join("sw-description");
+    std::fs::write(&description_file, &description)
+        .unwrap_or_else(|e| panic!("Failed to write {description_file}: {e:?}"));
+
+    let mut files = vec![(
+        "sw-description",
+        description_file.clone(),
+        file_digest(&description_file),
+    )];
+    if let Some(key) = &config.signing_key {
+        let signature = outdir.join("sw-description.sig");
+        let key = workspace_root.join(key);
+        message::status(format_args!(
+            "openssl dgst -sha256 -sign {key} -out {signature} {description_file}"
+        ));
+        crate::exec::run(
+            Command::new("openssl")
+                .args(["dgst", "-sha256", "-sign"])
+                .arg(&key)
+                .arg("-out")
+                .arg(&signature)
+                .arg(&description_file),
+        );
+        let digest = file_digest(&signature);
+        files.push(("sw-description.sig", signature, digest));
+    }
+    files.push((IMAGE, image.clone(), image_digest));
+
+    let mtime = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0);
+    message::status(format_args!("Writing swupdate package {output}"));
+    let file = std::fs::File::create(output)
+        .unwrap_or_else(|e| panic!("Failed to create {output}: {e:?}"));
+    let mut archive = std::io::BufWriter::new(file);
+    let mut size = 0;
+    for (i, (name, file, digest)) in files.iter().enumerate() {
+        message::status(format_args!("  {name} ({} bytes)", digest.size));
+        let file = Some((file.as_path(), digest));
+        size += cpio_entry(&mut archive, i as u32 + 1, 0o100644, name, file, mtime);
+    }
+    size += cpio_entry(&mut archive, 0, 0, "TRAILER!!!", None, 0);
+    // cpio archives are padded to 512 bytes blocks.
+    let padding = size.div_ceil(512) * 512 - size;
+    archive
+        .write_all(&vec![0; padding as usize])
+        .and_then(|_| archive.flush())
+        .unwrap_or_else(|e| panic!("Failed to write {output}: {e:?}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the fields of the header of a cpio entry.
+    fn fields(entry: &[u8]) -> Vec<u32> {
+        assert_eq!(&entry[..6], b"070702");
+        entry[6..110]
+            .chunks(8)
+            .map(|x| u32::from_str_radix(std::str::from_utf8(x).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cpio_entry_padding() {
+        let file = std::env::temp_dir().join(format!("cargo-rootfs-{}-cpio", std::process::id()));
+        let file = PathBuf::from_path_buf(file).unwrap();
+        std::fs::write(&file, b"hello").unwrap();
+        let digest = file_digest(&file);
+        assert_eq!((digest.size, digest.check), (5, 532));
+        let mut archive = vec![];
+        // A 125 bytes header padded to 128, and 5 bytes of data padded to 8.
+        let size = cpio_entry(
+            &mut archive,
+            1,
+            0o100644,
+            "sw-description",
+            Some((&file, &digest)),
+            7,
+        );
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(size, 136);
+        assert_eq!(archive.len(), 136);
+        assert_eq!(
+            fields(&archive),
+            [1, 0o100644, 0, 0, 1, 7, 5, 0, 0, 0, 0, 15, 532]
+        );
+        assert_eq!(&archive[110..128], b"sw-description\0\0\0\0");
+        assert_eq!(&archive[128..], b"hello\0\0\0");
+
+        // A 110 + 11 bytes header padded to 124, without data.
+        let mut archive = vec![];
+        let size = cpio_entry(&mut archive, 0, 0, "TRAILER!!!", None, 0);
+        assert_eq!(size, 124);
+        assert_eq!(archive.len(), 124);
+        assert_eq!(fields(&archive), [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 11, 0]);
+        assert_eq!(&archive[110..], b"TRAILER!!!\0\0\0\0");
+
+        // An already aligned header: 110 + 2 bytes.
+        let mut archive = vec![];
+        assert_eq!(cpio_entry(&mut archive, 2, 0o100644, "a", None, 0), 112);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{hex, message};
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::Command;
+
+/// Size of the data and hash blocks.
+const BLOCK: u64 = 4096;
+/// Number of sha256 digests in a hash block.
+const HASHES_PER_BLOCK: u64 = BLOCK / 32;
+/// Size of the veritysetup superblock, in the first hash block.
+const SUPERBLOCK: usize = 512;
+
+/// Location of the hash tree: after the data in the image, or in a separate file.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Hash {
+    #[default]
+    Appended,
+    Separate,
+}
+
+impl Hash {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "appended" => Self::Appended,
+            "separate" => Self::Separate,
+            _ => panic!("Unknown verity hash location {name:?} (expected appended or separate)"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerityOptions {
+    pub hash: Hash,
+    /// Key and certificate signing the root hash, in a PKCS#7 `.roothash.p7s` file.
+    pub key: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+}
+
+/// Number of blocks of each level of the hash tree, from the leaves to the top level.
+fn levels(data_blocks: u64) -> Vec<u64> {
+    let mut levels = vec![];
+    let mut blocks = data_blocks;
+    while blocks > 1 {
+        blocks = blocks.div_ceil(HASHES_PER_BLOCK);
+        levels.push(blocks);
+    }
+    levels
+}
+
+/// Return the size of the hash tree of a data size, with its superblock.
+pub fn hash_size(data_size: u64) -> u64 {
+    let blocks: u64 = levels(data_size.div_ceil(BLOCK)).iter().sum();
+    BLOCK + blocks * BLOCK
+}
+
+fn digest(salt: &[u8], block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+/// Return the veritysetup superblock (format 1, sha256, 4 KiB blocks).
+fn superblock(data_blocks: u64, salt: &[u8]) -> Vec<u8> {
+    let mut superblock = vec![0u8; SUPERBLOCK];
+    superblock[0..8].copy_from_slice(b"verity\0\0");
+    superblock[8..12].copy_from_slice(&1u32.to_le_bytes());
+    superblock[12..16].copy_from_slice(&1u32.to_le_bytes());
+    // A version 4 UUID derived from the salt, to keep the image reproducible.
+    let mut uuid: [u8; 16] = Sha256::digest(salt)[..16].try_into().unwrap();
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    superblock[16..32].copy_from_slice(&uuid);
+    superblock[32..38].copy_from_slice(b"sha256");
+    superblock[64..68].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+    superblock[68..72].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+    superblock[72..80].copy_from_slice(&data_blocks.to_le_bytes());
+    superblock[80..82].copy_from_slice(&(salt.len() as u16).to_le_bytes());
+    superblock[88..88 + salt.len()].copy_from_slice(salt);
+    superblock
+}
+
+/// Compute the dm-verity hash tree of a filesystem image, appended to it or written
+/// to `<image>.verity`, and write its root hash to `<image>.roothash` and its parameters
+/// to `<image>.verity.json`. The salt is derived from the image. Return the root hash.
+pub fn format(image: &Path, options: &VerityOptions) -> String {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap_or_else(|e| panic!("Failed to open {image}: {e:?}"));
+    let size = file.metadata().unwrap().len();
+    let data_blocks = size.div_ceil(BLOCK);
+    let data_size = data_blocks * BLOCK;
+    file.set_len(data_size).unwrap();
+
+    // Leaves: the digests of the data blocks, while deriving the salt from the data.
+    let salt: [u8; 32] = {
+        let mut hasher = Sha256::new();
+        let mut block = vec![0u8; BLOCK as usize];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        for _ in 0..data_blocks {
+            file.read_exact(&mut block).unwrap();
+            hasher.update(&block);
+        }
+        hasher.finalize().into()
+    };
+    let mut block = vec![0u8; BLOCK as usize];
+    let mut digests = Vec::with_capacity(data_blocks as usize * 32);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    for _ in 0..data_blocks {
+        file.read_exact(&mut block).unwrap();
+        digests.extend_from_slice(&digest(&salt, &block));
+    }
+
+    // Levels: the hash blocks of the digests of the lower level, up to a single block.
+    let levels = levels(data_blocks);
+    let mut tree: Vec<Vec<u8>> = vec![];
+    let mut lower = digests;
+    for blocks in &levels {
+        let mut level = lower;
+        level.resize((*blocks * BLOCK) as usize, 0);
+        lower = level
+            .chunks(BLOCK as usize)
+            .flat_map(|block| digest(&salt, block))
+            .collect();
+        tree.push(level);
+    }
+    let root_hash = hex(&lower[..32]);
+
+    // The superblock, then the levels from the top one, as read by dm-verity.
+    let mut hash = superblock(data_blocks, &salt);
+    hash.resize(BLOCK as usize, 0);
+    for level in tree.iter().rev() {
+        hash.extend_from_slice(level);
+    }
+    let (hash_file, hash_offset) = match options.hash {
+        Hash::Appended => {
+            file.seek(SeekFrom::Start(data_size)).unwrap();
+            file.write_all(&hash)
+                .unwrap_or_else(|e| panic!("Failed to write {image}: {e:?}"));
+            (image.to_path_buf(), data_size)
+        }
+        Hash::Separate => {
+            let hash_file = image.with_extension("verity");
+            std::fs::write(&hash_file, &hash)
+                .unwrap_or_else(|e| panic!("Failed to write {hash_file}: {e:?}"));
+            (hash_file, 0)
+        }
+    };
+
+    let roothash = image.with_extension("roothash");
+    std::fs::write(&roothash, &root_hash)
+        .unwrap_or_else(|e| panic!("Failed to write {roothash}: {e:?}"));
+    let params = json!({
+        "root_hash": root_hash,
+        "salt": hex(&salt),
+        "algorithm": "sha256",
+        "data_block_size": BLOCK,
+        "hash_block_size": BLOCK,
+        "data_blocks": data_blocks,
+        "hash_file": hash_file.file_name(),
+        "hash_offset": hash_offset,
+    });
+    let params_file = image.with_extension("verity.json");
+    let mut content = serde_json::to_string_pretty(&params).unwrap();
+    content.push('\n');
+    std::fs::write(&params_file, content)
+        .unwrap_or_else(|e| panic!("Failed to write {params_file}: {e:?}"));
+    message::status(format_args!(
+        "verity {image}: root hash {root_hash}, salt {}, hash offset {hash_offset}",
+        hex(&salt)
+    ));
+
+    if let Some(key) = &options.key {
+        let cert = options
+            .cert
+            .as_ref()
+            .unwrap_or_else(|| panic!("--verity-key requires --verity-cert"));
+        let signature = image.with_extension("roothash.p7s");
+        message::status(format_args!(
+            "openssl smime -sign -in {roothash} -inkey {key} -signer {cert} -out {signature}"
+        ));
+        crate::exec::run(
+            Command::new("openssl")
+                .args(["smime", "-sign", "-nocerts", "-noattr", "-binary", "-in"])
+                .arg(&roothash)
+                .arg("-inkey")
+                .arg(key)
+                .arg("-signer")
+                .arg(cert)
+                .args(["-outform", "der", "-out"])
+                .arg(&signature),
+        );
+    }
+    root_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_levels() {
+        assert_eq!(levels(0), Vec::<u64>::new());
+        assert_eq!(levels(1), Vec::<u64>::new());
+        assert_eq!(levels(2), vec![1]);
+        assert_eq!(levels(HASHES_PER_BLOCK), vec![1]);
+        assert_eq!(levels(HASHES_PER_BLOCK + 1), vec![2, 1]);
+        let blocks = HASHES_PER_BLOCK * HASHES_PER_BLOCK + 1;
+        assert_eq!(levels(blocks), vec![HASHES_PER_BLOCK + 1, 2, 1]);
+        assert_eq!(hash_size(BLOCK), BLOCK);
+        assert_eq!(hash_size(BLOCK * (HASHES_PER_BLOCK + 1)), BLOCK * 4);
+    }
+
+    #[test]
+    fn superblock_fields() {
+        let salt = [0x5a; 32];
+        let superblock = superblock(1000, &salt);
+        let le32 =
+            |offset: usize| u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap());
+        assert_eq!(superblock.len(), SUPERBLOCK);
+        assert_eq!(&superblock[0..8], b"verity\0\0");
+        assert_eq!((le32(8), le32(12)), (1, 1));
+        assert_eq!(superblock[22] >> 4, 4);
+        assert_eq!(superblock[24] >> 6, 2);
+        assert_eq!(&superblock[32..40], b"sha256\0\0");
+        assert_eq!((le32(64), le32(68)), (4096, 4096));
+        assert_eq!(
+            u64::from_le_bytes(superblock[72..80].try_into().unwrap()),
+            1000
+        );
+        assert_eq!(u16::from_le_bytes([superblock[80], superblock[81]]), 32);
+        assert_eq!(&superblock[88..120], &salt);
+        assert!(superblock[120..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn format_appended_tree() {
+        let dir = std::env::temp_dir().join(format!("cargo-rootfs-{}-verity", std::process::id()));
+        let dir = PathBuf::from_path_buf(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("rootfs.img");
+        // Two data blocks, the last one padded by format.
+        let data: Vec<u8> = (0..BLOCK + 100).map(|i| i as u8).collect();
+        std::fs::write(&image, &data).unwrap();
+        let root_hash = format(&image, &VerityOptions::default());
+        let content = std::fs::read(&image).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let size = BLOCK as usize;
+        assert_eq!(content.len(), 2 * size + hash_size(2 * BLOCK) as usize);
+        assert_eq!(&content[..data.len()], &data[..]);
+        assert!(content[data.len()..2 * size].iter().all(|b| *b == 0));
+        let salt: [u8; 32] = Sha256::digest(&content[..2 * size]).into();
+        let superblock = &content[2 * size..3 * size];
+        assert_eq!(&superblock[..SUPERBLOCK], &self::superblock(2, &salt)[..]);
+        let mut level = [
+            digest(&salt, &content[..size]),
+            digest(&salt, &content[size..2 * size]),
+        ]
+        .concat();
+        level.resize(size, 0);
+        assert_eq!(&content[3 * size..], &level[..]);
+        assert_eq!(root_hash, hex(&digest(&salt, &level)));
+    }
+}
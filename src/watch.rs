@@ -0,0 +1,52 @@
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn snapshot_in(dir: &Path, exclude: &Path, files: &mut Vec<(PathBuf, SystemTime)>) {
+    if dir == exclude {
+        return;
+    }
+    let entries = match dir.read_dir_utf8() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().starts_with(".") {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            snapshot_in(entry.path(), exclude, files);
+        } else {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((entry.into_path(), modified));
+        }
+    }
+}
+
+/// Return the files under `dirs` with their modification time, sorted, ignoring the
+/// `exclude` directory (cargo target directory).
+pub fn snapshot(dirs: &[PathBuf], exclude: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let mut files = vec![];
+    for dir in dirs {
+        snapshot_in(dir, exclude, &mut files);
+    }
+    files.sort();
+    files
+}
+
+/// Block until a file under `dirs` is added, modified, renamed or removed.
+pub fn wait_for_change(dirs: &[PathBuf], exclude: &Path) {
+    let since = snapshot(dirs, exclude);
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        if snapshot(dirs, exclude) != since {
+            return;
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::manifest::Whiteout;
+use camino::Utf8Path as Path;
+use camino::Utf8PathBuf as PathBuf;
+use std::ffi::CString;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+/// Prefix of the name of the OCI whiteout files.
+const OCI_PREFIX: &str = ".wh.";
+/// Name of the OCI file marking its directory as opaque.
+const OCI_OPAQUE: &str = ".wh..wh..opq";
+/// Extended attribute marking an OverlayFS directory as opaque.
+const OVERLAY_OPAQUE: &str = "trusted.overlay.opaque";
+
+/// Format of the whiteouts hiding the content of the base image in the rootfs layer.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// `.wh.<name>` files, and a `.wh..wh..opq` file in the opaque directories.
+    #[default]
+    Oci,
+    /// 0/0 character devices, and the trusted.overlay.opaque attribute of the opaque
+    /// directories.
+    Overlay,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "oci" => Self::Oci,
+            "overlay" => Self::Overlay,
+            _ => panic!("Unknown whiteout format {name:?} (expected oci or overlay)"),
+        }
+    }
+}
+
+/// Return the path of a whiteout in the rootfs: its file, or its opaque directory.
+pub fn file(rootfs: &Path, whiteout: &Whiteout, format: Format) -> PathBuf {
+    let path = whiteout.path.strip_prefix("/").unwrap_or(&whiteout.path);
+    let path = rootfs.join(path);
+    match (format, whiteout.opaque) {
+        (Format::Oci, true) => path.join(OCI_OPAQUE),
+        (Format::Oci, false) => {
+            let name = format!("{OCI_PREFIX}{}", path.file_name().unwrap());
+            path.with_file_name(name)
+        }
+        (Format::Overlay, _) => path,
+    }
+}
+
+/// Check if a file of the rootfs is a whiteout, of either format.
+pub fn is_whiteout(file: &Path) -> bool {
+    let Ok(metadata) = file.symlink_metadata() else {
+        return false;
+    };
+    match file.file_name() {
+        Some(name) if name.starts_with(OCI_PREFIX) => metadata.is_file(),
+        _ => metadata.file_type().is_char_device() && metadata.rdev() == 0,
+    }
+}
+
+/// Create a whiteout in the rootfs. Its path must not exist, except for the opaque
+/// directories.
+pub fn create(rootfs: &Path, whiteout: &Whiteout, format: Format) {
+    let file = file(rootfs, whiteout, format);
+    let dir = match (format, whiteout.opaque) {
+        (Format::Overlay, true) => file.as_path(),
+        _ => file.parent().unwrap(),
+    };
+    std::fs::create_dir_all(dir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {dir}: {e:?}"));
+    let path = CString::new(file.as_str()).unwrap();
+    let result = match (format, whiteout.opaque) {
+        (Format::Oci, _) => {
+            std::fs::write(&file, b"").unwrap_or_else(|e| panic!("Failed to write {file}: {e:?}"));
+            return;
+        }
+        (Format::Overlay, false) => unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR, 0) },
+        (Format::Overlay, true) => unsafe {
+            let name = CString::new(OVERLAY_OPAQUE).unwrap();
+            libc::lsetxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0)
+        },
+    };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        panic!("Failed to create the overlay whiteout {file} (requires CAP_MKNOD and CAP_SYS_ADMIN): {e}");
+    }
+}
+
+/// Remove a whiteout from the rootfs, in either format, calling `save` before removing
+/// a file.
+pub fn remove(rootfs: &Path, whiteout: &Whiteout, save: impl Fn(&Path)) {
+    for format in [Format::Oci, Format::Overlay] {
+        let file = file(rootfs, whiteout, format);
+        if whiteout.opaque && format == Format::Overlay {
+            let path = CString::new(file.as_str()).unwrap();
+            let name = CString::new(OVERLAY_OPAQUE).unwrap();
+            unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+        } else if is_whiteout(&file) {
+            save(&file);
+            let _ = std::fs::remove_file(&file);
+        }
+    }
+}